@@ -0,0 +1,179 @@
+use std::convert::TryInto;
+use crate::db_module::{DbModule, DBFileAttr};
+use crate::permission::Credential;
+use crate::sqerror::{Error, ErrorKind, Result};
+
+/// Access ACL of a file
+pub const ACL_ACCESS: &str = "system.posix_acl_access";
+/// Default ACL of a directory which is inherited by new files
+pub const ACL_DEFAULT: &str = "system.posix_acl_default";
+
+const ACL_VERSION: u32 = 2;
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+
+pub const ACL_USER_OBJ: u16 = 0x01;
+pub const ACL_USER: u16 = 0x02;
+pub const ACL_GROUP_OBJ: u16 = 0x04;
+pub const ACL_GROUP: u16 = 0x08;
+pub const ACL_MASK: u16 = 0x10;
+pub const ACL_OTHER: u16 = 0x20;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AclEntry {
+    pub tag: u16,
+    /// Combination of R_OK, W_OK and X_OK
+    pub perm: u16,
+    /// uid or gid of ACL_USER and ACL_GROUP entries
+    pub id: u32,
+}
+
+/// POSIX ACL in the xattr format of Linux.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Acl {
+    pub entries: Vec<AclEntry>,
+}
+
+impl Acl {
+    /// Minimal ACL which is equivalent to permission bits
+    pub fn from_mode(mode: u16) -> Self {
+        Acl{entries: vec![
+            AclEntry{tag: ACL_USER_OBJ, perm: (mode >> 6) & 0o7, id: ACL_UNDEFINED_ID},
+            AclEntry{tag: ACL_GROUP_OBJ, perm: (mode >> 3) & 0o7, id: ACL_UNDEFINED_ID},
+            AclEntry{tag: ACL_OTHER, perm: mode & 0o7, id: ACL_UNDEFINED_ID},
+        ]}
+    }
+
+    /// Parse a xattr value. Entries are sorted and validated.
+    pub fn from_bytes(value: &[u8]) -> Result<Self> {
+        if value.len() < 4 || value.len() % 8 != 4 {
+            return Err(invalid_acl("invalid length"));
+        }
+        if u32::from_le_bytes(value[0..4].try_into().unwrap()) != ACL_VERSION {
+            return Err(invalid_acl("unsupported version"));
+        }
+        let mut entries: Vec<AclEntry> = value[4..].chunks(8).map(|n| AclEntry{
+            tag: u16::from_le_bytes(n[0..2].try_into().unwrap()),
+            perm: u16::from_le_bytes(n[2..4].try_into().unwrap()),
+            id: u32::from_le_bytes(n[4..8].try_into().unwrap()),
+        }).collect();
+        entries.sort_by_key(|n| (n.tag, n.id));
+        let mut named = false;
+        for entry in entries.iter_mut() {
+            if entry.perm & !0o7 != 0 {
+                return Err(invalid_acl("invalid permission"));
+            }
+            match entry.tag {
+                ACL_USER | ACL_GROUP => named = true,
+                ACL_USER_OBJ | ACL_GROUP_OBJ | ACL_MASK | ACL_OTHER => entry.id = ACL_UNDEFINED_ID,
+                _ => return Err(invalid_acl("invalid tag")),
+            }
+        }
+        if entries.windows(2).any(|n| n[0].tag == n[1].tag && n[0].id == n[1].id) {
+            return Err(invalid_acl("duplicated entry"));
+        }
+        let acl = Acl{entries};
+        if acl.find(ACL_USER_OBJ).is_none() || acl.find(ACL_GROUP_OBJ).is_none() || acl.find(ACL_OTHER).is_none() {
+            return Err(invalid_acl("a required entry is missing"));
+        }
+        if named && acl.find(ACL_MASK).is_none() {
+            return Err(invalid_acl("mask entry is missing"));
+        }
+        Ok(acl)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut value = ACL_VERSION.to_le_bytes().to_vec();
+        for entry in &self.entries {
+            value.extend(&entry.tag.to_le_bytes());
+            value.extend(&entry.perm.to_le_bytes());
+            value.extend(&entry.id.to_le_bytes());
+        }
+        value
+    }
+
+    fn find(&self, tag: u16) -> Option<&AclEntry> {
+        self.entries.iter().find(|n| n.tag == tag)
+    }
+
+    fn find_mut(&mut self, tag: u16) -> Option<&mut AclEntry> {
+        self.entries.iter_mut().find(|n| n.tag == tag)
+    }
+
+    /// ACL which has only owner, group and other entries can be replaced with permission bits.
+    pub fn is_minimal(&self) -> bool {
+        self.entries.len() == 3
+    }
+
+    /// Permission bits of the ACL. The group bits come from the mask entry if it exists.
+    pub fn mode(&self) -> u16 {
+        let perm = |tag| self.find(tag).map_or(0, |n| n.perm);
+        let group = match self.find(ACL_MASK) {
+            Some(n) => n.perm,
+            None => perm(ACL_GROUP_OBJ),
+        };
+        perm(ACL_USER_OBJ) << 6 | group << 3 | perm(ACL_OTHER)
+    }
+
+    /// Apply permission bits to the ACL when the file is chmod-ed.
+    pub fn set_mode(&mut self, mode: u16) {
+        let group_tag = if self.find(ACL_MASK).is_some() { ACL_MASK } else { ACL_GROUP_OBJ };
+        for (tag, perm) in [(ACL_USER_OBJ, mode >> 6), (group_tag, mode >> 3), (ACL_OTHER, mode)].iter() {
+            if let Some(n) = self.find_mut(*tag) {
+                n.perm = perm & 0o7;
+            }
+        }
+    }
+
+    /// Permissions which are granted to a user by the ACL of a file for the requested permissions.
+    /// The owner entry, a named user entry, group entries and the other entry are checked in this order.
+    /// A group entry grants only if it has all requested permissions by itself.
+    pub fn granted(&self, attr: &DBFileAttr, cred: &Credential, requested: u16) -> u16 {
+        let mask = self.find(ACL_MASK).map_or(0o7, |n| n.perm);
+        if cred.is_owner(attr) {
+            return self.find(ACL_USER_OBJ).map_or(0, |n| n.perm);
+        }
+        if let Some(n) = self.entries.iter().find(|n| n.tag == ACL_USER && n.id == cred.uid) {
+            return n.perm & mask;
+        }
+        let groups: Vec<&AclEntry> = self.entries.iter().filter(|n| match n.tag {
+            ACL_GROUP_OBJ => cred.in_group(attr.gid),
+            ACL_GROUP => cred.in_group(n.id),
+            _ => false,
+        }).collect();
+        if !groups.is_empty() {
+            // permissions of matched group entries are not combined
+            return groups.iter().map(|n| n.perm & mask).find(|n| n & requested == requested).unwrap_or(0);
+        }
+        self.find(ACL_OTHER).map_or(0, |n| n.perm)
+    }
+
+    /// Access ACL and permission bits of a new file from the default ACL of the parent directory.
+    /// The permission bits requested by create are applied to the owner, mask (or group) and other entries.
+    pub fn inherit(&self, mode: u16) -> (Acl, u16) {
+        let mut acl = self.clone();
+        let mut new_mode = mode & !0o777;
+        let group_tag = if acl.find(ACL_MASK).is_some() { ACL_MASK } else { ACL_GROUP_OBJ };
+        for (tag, shift) in [(ACL_USER_OBJ, 6), (group_tag, 3), (ACL_OTHER, 0)].iter() {
+            if let Some(n) = acl.find_mut(*tag) {
+                n.perm &= (mode >> shift) & 0o7;
+                new_mode |= n.perm << shift;
+            }
+        }
+        (acl, new_mode)
+    }
+}
+
+fn invalid_acl(description: &str) -> Error {
+    Error::from(ErrorKind::FsParm {description: format!("invalid ACL: {}", description)})
+}
+
+/// Read an ACL of a file. Returns None if it doesn't exist.
+pub fn get_acl<T: DbModule>(db: &T, ino: u64, name: &str) -> Result<Option<Acl>> {
+    match db.get_xattr(ino, name) {
+        Ok(n) => Ok(Some(Acl::from_bytes(&n)?)),
+        Err(err) => match err.kind() {
+            ErrorKind::FsNoData {..} => Ok(None),
+            _ => Err(err),
+        }
+    }
+}
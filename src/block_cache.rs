@@ -0,0 +1,164 @@
+use std::cmp;
+use std::collections::{btree_map, hash_map, BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+use crate::db_module::DbModule;
+use crate::sqerror::{Error, ErrorKind, Result};
+
+/// Default limit of dirty data kept in memory
+pub const DEFAULT_MAX_DIRTY_BYTES: usize = 64 * 1024 * 1024;
+/// Default age of dirty data which is written back by the next request
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+struct DirtyFile {
+    blocks: BTreeMap<u64, Vec<u8>>,
+    // file size including dirty blocks
+    size: u64,
+    // file size in the database
+    db_size: u64,
+    since: Instant,
+}
+
+/// Write-back cache of file blocks.
+/// Small writes are merged into whole blocks in memory, and all dirty blocks of a file
+/// are written to the database in one transaction when it is flushed.
+pub struct BlockCache {
+    files: HashMap<u64, DirtyFile>,
+    dirty_bytes: usize,
+    max_dirty_bytes: usize,
+    flush_interval: Duration,
+}
+
+impl Default for BlockCache {
+    fn default() -> Self {
+        BlockCache::new(DEFAULT_MAX_DIRTY_BYTES, DEFAULT_FLUSH_INTERVAL)
+    }
+}
+
+impl BlockCache {
+    pub fn new(max_dirty_bytes: usize, flush_interval: Duration) -> Self {
+        BlockCache{files: HashMap::new(), dirty_bytes: 0, max_dirty_bytes, flush_interval}
+    }
+
+    /// Size of a file including dirty data. None if the file has no dirty data.
+    pub fn size(&self, ino: u64) -> Option<u64> {
+        self.files.get(&ino).map(|n| n.size)
+    }
+
+    /// Bytes which files in the cache have grown. They are not charged to quotas until they are written back.
+    pub fn growth(&self) -> u64 {
+        self.files.values().map(|n| n.size - n.db_size).sum()
+    }
+
+    /// Total bytes of dirty blocks
+    pub fn dirty_bytes(&self) -> usize {
+        self.dirty_bytes
+    }
+
+    /// Write data into the cache. A block which is partially written is read from the database first.
+    /// All dirty data is written back if it exceeds the limit, and files older than the flush interval are written back.
+    /// The data is accepted before them, so their errors are only logged. Blocks which fail are kept, and the next flush of the file returns the error.
+    pub fn write<T: DbModule>(&mut self, db: &mut T, ino: u64, offset: u64, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let end = match offset.checked_add(data.len() as u64) {
+            Some(n) if n <= i64::MAX as u64 => n,
+            _ => return Err(Error::from(ErrorKind::FsParm {description: "invalid range of write".to_string()})),
+        };
+        let block_size = u64::from(db.get_db_block_size());
+        let file = match self.files.entry(ino) {
+            hash_map::Entry::Occupied(n) => n.into_mut(),
+            hash_map::Entry::Vacant(n) => {
+                let size = match db.get_inode(ino)? {
+                    Some(attr) => attr.size,
+                    None => return Err(Error::from(ErrorKind::FsNoEnt {description: format!("inode {} is not found", ino)})),
+                };
+                n.insert(DirtyFile{blocks: BTreeMap::new(), size, db_size: size, since: Instant::now()})
+            }
+        };
+        let mut pos = offset;
+        while pos < end {
+            let block = pos / block_size + 1;
+            let b_offset = (pos % block_size) as usize;
+            let length = cmp::min(block_size - b_offset as u64, end - pos) as usize;
+            let block_data = match file.blocks.entry(block) {
+                btree_map::Entry::Occupied(n) => n.into_mut(),
+                btree_map::Entry::Vacant(n) => {
+                    let mut block_data = if length == block_size as usize {
+                        vec![]
+                    } else {
+                        // writing doesn't access the file
+                        db.get_data(ino, block, block_size as u32, true)?
+                    };
+                    block_data.resize(block_size as usize, 0);
+                    self.dirty_bytes += block_size as usize;
+                    n.insert(block_data)
+                }
+            };
+            let data_offset = (pos - offset) as usize;
+            block_data[b_offset..b_offset + length].copy_from_slice(&data[data_offset..data_offset + length]);
+            pos += length as u64;
+        }
+        file.size = cmp::max(file.size, end);
+
+        let result = if self.dirty_bytes > self.max_dirty_bytes {
+            self.flush_all(db)
+        } else {
+            self.flush_expired(db)
+        };
+        if let Err(err) = result {
+            debug!("{}", err);
+        }
+        Ok(())
+    }
+
+    /// Write back dirty blocks of a file in one transaction.
+    /// The dirty blocks are kept if it fails, so it can be retried. They are dropped if the file is removed.
+    pub fn flush<T: DbModule>(&mut self, db: &mut T, ino: u64) -> Result<()> {
+        let file = match self.files.remove(&ino) {
+            Some(n) => n,
+            None => return Ok(()),
+        };
+        let blocks: Vec<(u64, Vec<u8>)> = file.blocks.into_iter().collect();
+        let bytes = blocks.iter().map(|n| n.1.len()).sum::<usize>();
+        match db.write_blocks(ino, &blocks, file.size) {
+            Ok(_) => (),
+            Err(err) => match err.kind() {
+                ErrorKind::FsNoEnt {..} => (),
+                _ => {
+                    self.files.insert(ino, DirtyFile{blocks: blocks.into_iter().collect(), ..file});
+                    return Err(err);
+                }
+            }
+        }
+        self.dirty_bytes -= bytes;
+        Ok(())
+    }
+
+    /// Write back all dirty blocks. Returns the first error.
+    pub fn flush_all<T: DbModule>(&mut self, db: &mut T) -> Result<()> {
+        let inodes: Vec<u64> = self.files.keys().cloned().collect();
+        self.flush_list(db, inodes)
+    }
+
+    /// Write back files whose dirty data is older than the flush interval. Returns the first error.
+    /// There is no timer, so the filesystem calls it on frequent requests.
+    pub fn flush_expired<T: DbModule>(&mut self, db: &mut T) -> Result<()> {
+        let inodes: Vec<u64> = self.files.iter()
+            .filter(|(_, file)| file.since.elapsed() >= self.flush_interval)
+            .map(|(ino, _)| *ino)
+            .collect();
+        self.flush_list(db, inodes)
+    }
+
+    fn flush_list<T: DbModule>(&mut self, db: &mut T, inodes: Vec<u64>) -> Result<()> {
+        let mut result = Ok(());
+        for ino in inodes {
+            let res = self.flush(db, ino);
+            if result.is_ok() {
+                result = res;
+            }
+        }
+        result
+    }
+}
@@ -0,0 +1,78 @@
+use std::convert::TryInto;
+use crate::sqerror::{Error, ErrorKind, Result};
+
+/// Tag of a data block which is stored without compression
+pub const TAG_RAW: u32 = 0;
+/// Tag of a data block compressed by lz4
+pub const TAG_LZ4: u32 = 1;
+/// Tag of a data block compressed by zstd
+pub const TAG_ZSTD: u32 = 2;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compression of data blocks. It is chosen when a filesystem is created.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Codec {
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl Codec {
+    /// Parse "none", "lz4" or "zstd".
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name.to_lowercase().as_str() {
+            "none" => Ok(Codec::None),
+            "lz4" => Ok(Codec::Lz4),
+            "zstd" => Ok(Codec::Zstd),
+            _ => Err(Error::from(ErrorKind::FsParm {description: format!(
+                "unknown compression {}. none, lz4 and zstd are supported",
+                name
+            )})),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::None => "none",
+            Codec::Lz4 => "lz4",
+            Codec::Zstd => "zstd",
+        }
+    }
+}
+
+/// Compress a block. Returns the tag and the data to store.
+/// A block which doesn't get smaller is stored raw.
+pub fn compress(codec: Codec, data: &[u8]) -> (u32, Vec<u8>) {
+    let compressed = match codec {
+        Codec::None => None,
+        Codec::Lz4 => Some((TAG_LZ4, lz4_flex::compress_prepend_size(data))),
+        Codec::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL).ok().map(|n| (TAG_ZSTD, n)),
+    };
+    match compressed {
+        Some((tag, n)) if n.len() < data.len() => (tag, n),
+        _ => (TAG_RAW, data.to_vec()),
+    }
+}
+
+/// Restore a block which is stored with the tag. A block is never larger than block_size.
+pub fn decompress(tag: u32, data: Vec<u8>, block_size: u32) -> Result<Vec<u8>> {
+    let invalid = |description: String| Error::from(ErrorKind::FsIo {description});
+    match tag {
+        TAG_RAW => Ok(data),
+        TAG_LZ4 => {
+            // the size is prepended. Check it before the buffer is allocated.
+            let size = match data.get(0..4) {
+                Some(n) => u32::from_le_bytes(n.try_into().unwrap()),
+                None => return Err(invalid("lz4 block is too short".to_string())),
+            };
+            if size > block_size {
+                return Err(invalid(format!("lz4 block has {} bytes", size)));
+            }
+            lz4_flex::decompress_size_prepended(&data).map_err(|err| invalid(format!("lz4 block is broken: {}", err)))
+        },
+        TAG_ZSTD => zstd::bulk::decompress(&data, block_size as usize)
+            .map_err(|err| invalid(format!("zstd block is broken: {}", err))),
+        _ => Err(invalid(format!("unknown codec tag {}", tag))),
+    }
+}
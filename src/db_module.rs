@@ -1,60 +1,233 @@
 pub mod sqlite;
 use std::time::SystemTime;
-use crate::sqerror::Result;
-use fuse::{FileAttr, FileType};
-use time::Timespec;
-use chrono::{DateTime, Utc, NaiveDateTime};
+use crate::sqerror::{Error, ErrorKind, Result};
+use fuser::{FileAttr, FileType};
+
+// Modes of fallocate. Same values as Linux.
+pub const FALLOC_FL_KEEP_SIZE: u32 = 0x01;
+pub const FALLOC_FL_PUNCH_HOLE: u32 = 0x02;
+pub const FALLOC_FL_COLLAPSE_RANGE: u32 = 0x08;
+pub const FALLOC_FL_ZERO_RANGE: u32 = 0x10;
+
+/// Name of the read-only directory of snapshots in the root directory
+pub const SNAPSHOT_DIR_NAME: &str = ".snapshots";
+/// Inode number of the directory of snapshots. Files in snapshots have larger inode numbers.
+pub const SNAPSHOT_DIR_INO: u64 = 1 << 63;
+// Lower bits of an inode number in a snapshot are the inode number in the filesystem, and upper bits are the snapshot id.
+const SNAPSHOT_INO_BITS: u32 = 40;
+/// Inode numbers of the filesystem must be less than this to take a snapshot.
+pub const MAX_SNAPSHOT_INO: u64 = 1 << SNAPSHOT_INO_BITS;
+/// Snapshot ids must be less than this.
+pub const MAX_SNAPSHOT_ID: u32 = 1 << (63 - SNAPSHOT_INO_BITS);
+
+/// Inode number of a file in a snapshot
+pub fn snapshot_ino(snapshot: u32, ino: u64) -> u64 {
+    SNAPSHOT_DIR_INO | (u64::from(snapshot) << SNAPSHOT_INO_BITS) | ino
+}
+
+/// Split an inode number of a file in a snapshot into the snapshot id and the inode number when it was taken.
+/// Return None for other inodes and the directory of snapshots.
+pub fn split_snapshot_ino(ino: u64) -> Option<(u32, u64)> {
+    if ino <= SNAPSHOT_DIR_INO {
+        return None;
+    }
+    let ino = ino & !SNAPSHOT_DIR_INO;
+    Some(((ino >> SNAPSHOT_INO_BITS) as u32, ino & (MAX_SNAPSHOT_INO - 1)))
+}
+
+/// Files in snapshots and the directory of snapshots are read-only.
+pub fn is_snapshot_ino(ino: u64) -> bool {
+    ino >= SNAPSHOT_DIR_INO
+}
 
 pub trait DbModule {
-    /// Create tables (if not found) and add root directory (if not found)
+    /// Create or migrate tables and add root directory (if not found).
+    /// Fail if the database has a newer schema version than this module supports.
+    /// Locks left by processes which are gone are released.
     fn init(&mut self) -> Result<()>;
     /// Get metadata. If not found, return None
-    fn get_inode(&self, inode: u32) -> Result<Option<DBFileAttr>>;
+    fn get_inode(&self, inode: u64) -> Result<Option<DBFileAttr>>;
     /// Add a file or a directory.
     /// Update atime, mtime, ctime. Update mtime and ctime of the parent directory.
-    fn add_inode_and_dentry(&mut self, parent: u32, name: &str, attr: &DBFileAttr) -> Result<u32>;
+    fn add_inode_and_dentry(&mut self, parent: u64, name: &str, attr: &DBFileAttr) -> Result<u64>;
     /// Update file metadata.
     /// Update ctime. Update mtime if filesize is changed.
     fn update_inode(&mut self, attr: &DBFileAttr, truncate: bool) -> Result<()>;
     // Delete an inode if the link count is zero.
-    fn delete_inode_if_noref(&mut self, inode: u32) -> Result<()>;
+    fn delete_inode_if_noref(&mut self, inode: u64) -> Result<()>;
     /// Get directory entries
-    fn get_dentry(&self, inode: u32) -> Result<Vec<DEntry>>;
+    fn get_dentry(&self, inode: u64) -> Result<Vec<DEntry>>;
     /// Add a new directory entry which is hard link
-    /// Update mtime, Update mtime and ctime of the parent directory.
-    fn link_dentry(&mut self, inode: u32, parent: u32, name: &str) -> Result<DBFileAttr>;
+    /// Update ctime. Update mtime and ctime of the parent directory.
+    fn link_dentry(&mut self, inode: u64, parent: u64, name: &str) -> Result<DBFileAttr>;
     /// Delete a dentry. returns target inode.
     /// Update ctime. Update mtime and ctime of the parent directory.
-    fn delete_dentry(&mut self, parent: u32, name: &str) -> Result<u32>;
+    fn delete_dentry(&mut self, parent: u64, name: &str) -> Result<u64>;
     /// Move dentry to another parent or name. Return inode number if a new file is overwrote.
     /// Update ctime, and mtime and ctime of the parent directories.
-    fn move_dentry(&mut self, parent: u32, name: &str, new_parent: u32, new_name: &str) -> Result<Option<u32>>;
+    fn move_dentry(&mut self, parent: u64, name: &str, new_parent: u64, new_name: &str) -> Result<Option<u64>>;
     /// check a directory if it is empty.
-    fn check_directory_is_empty(&self, inode: u32) -> Result<bool>;
+    fn check_directory_is_empty(&self, inode: u64) -> Result<bool>;
     /// lookup a directory entry table and get a file attribute.
     /// If not found, return None.
     /// Update atime.
-    fn lookup(&mut self, parent: u32, name: &str) -> Result<Option<DBFileAttr>>;
+    fn lookup(&mut self, parent: u64, name: &str) -> Result<Option<DBFileAttr>>;
     /// Read data from a whole block.
-    /// Update atime.
-    fn get_data(&mut self, inode: u32, block: u32, length: u32) -> Result<Vec<u8>>;
+    /// Update atime unless noatime is true.
+    fn get_data(&mut self, inode: u64, block: u64, length: u32, noatime: bool) -> Result<Vec<u8>>;
     /// Write data into a whole block.
     /// Update mtime and ctime.
-    fn write_data(&mut self, inode:u32, block: u32, data: &[u8], size: u32) -> Result<()>;
+    fn write_data(&mut self, inode: u64, block: u64, data: &[u8], size: u64) -> Result<()>;
+    /// Write whole blocks in one transaction. Each item is a block number and its data.
+    /// Update size if the file grows, mtime and ctime once.
+    fn write_blocks(&mut self, inode: u64, blocks: &[(u64, Vec<u8>)], size: u64) -> Result<()>;
+    /// Get the first offset of data at or after offset, like SEEK_DATA. Blocks which are not stored are holes.
+    /// If there is no data after offset, return FsOutOfRange.
+    fn seek_data(&self, inode: u64, offset: u64) -> Result<u64>;
+    /// Get the first offset of a hole at or after offset, like SEEK_HOLE. The end of the file is a hole.
+    /// If offset is beyond the end of the file, return FsOutOfRange.
+    fn seek_hole(&self, inode: u64, offset: u64) -> Result<u64>;
+    /// Manipulate space of a file like fallocate(2) of Linux. Blocks of zeros are never stored,
+    /// so allocation only checks quotas and extends the size unless FALLOC_FL_KEEP_SIZE.
+    /// FALLOC_FL_PUNCH_HOLE(with FALLOC_FL_KEEP_SIZE) and FALLOC_FL_ZERO_RANGE zero the range and delete whole blocks.
+    /// FALLOC_FL_COLLAPSE_RANGE removes block aligned range and shifts following data.
+    /// Update mtime and ctime unless it only allocates.
+    fn fallocate(&mut self, inode: u64, offset: u64, length: u64, mode: u32) -> Result<()>;
+    /// Copy bytes between regular files like copy_file_range(2). Return the copied bytes, which are fewer
+    /// at the end of the source. Blocks aligned at both offsets are copied as rows in the database.
+    /// Update size if the destination grows, mtime and ctime of the destination.
+    fn copy_file_range(&mut self, inode_in: u64, offset_in: u64, inode_out: u64, offset_out: u64, length: u64) -> Result<u64>;
+    /// Replace the content of a regular file with the whole source like FICLONE.
+    /// Blocks are shared until one of the files writes them. Update size, mtime and ctime of the destination.
+    fn clone_file(&mut self, src: u64, dst: u64) -> Result<()>;
+    /// Take a read-only snapshot of the whole filesystem. Data blocks are shared until they are written.
+    /// The snapshot appears as "/.snapshots/<name>".
+    fn create_snapshot(&mut self, name: &str) -> Result<()>;
+    /// Get all snapshots ordered by name.
+    fn list_snapshot(&self) -> Result<Vec<DBSnapshot>>;
+    /// Delete a snapshot and release its blocks.
+    fn delete_snapshot(&mut self, name: &str) -> Result<()>;
+    /// Replace all files with a snapshot. The snapshot is kept.
+    /// Fail with FsBusy if another process, like a mounted filesystem, uses the database.
+    fn rollback_snapshot(&mut self, name: &str) -> Result<()>;
     /// Release all data related to an inode number.
-    fn release_data(&self, inode: u32) -> Result<()>;
+    fn release_data(&self, inode: u64) -> Result<()>;
     /// Delete all inodes which nlink is 0.
     fn delete_all_noref_inode(&mut self) -> Result<()>;
     /// Get block size of the filesystem
     fn get_db_block_size(&self) -> u32;
     /// Set xattr value.
-    fn set_xattr(&mut self, inode: u32, key: &str, value: &[u8]) -> Result<()>;
+    fn set_xattr(&mut self, inode: u64, key: &str, value: &[u8]) -> Result<()>;
     /// Get xattr value.
-    fn get_xattr(&self, inode: u32, key: &str) -> Result<Vec<u8>>;
+    fn get_xattr(&self, inode: u64, key: &str) -> Result<Vec<u8>>;
     /// List xattr name.
-    fn list_xattr(&self, inode: u32) -> Result<Vec<String>>;
+    fn list_xattr(&self, inode: u64) -> Result<Vec<String>>;
     /// Delete xattr
-    fn delete_xattr(&mut self, inode: u32, key: &str) -> Result<()>;
+    fn delete_xattr(&mut self, inode: u64, key: &str) -> Result<()>;
+    /// Get a lock which conflicts with the given lock. If not found, return None
+    /// Locks of processes which are gone don't conflict, and they are released.
+    fn get_lock(&self, lock: &DBLock) -> Result<Option<DBLock>>;
+    /// Acquire, modify or release(F_UNLCK) a byte range lock.
+    /// Locks of the same owner are merged or split. If the lock conflicts with others, return FsLockConflict.
+    fn set_lock(&mut self, lock: &DBLock) -> Result<()>;
+    /// Release all locks of the lock owner on the inode.
+    fn release_lock(&mut self, inode: u64, lock_owner: u64) -> Result<()>;
+    /// Release all locks acquired through this database connection.
+    fn release_all_lock(&mut self) -> Result<()>;
+    /// Check consistency of the filesystem. The filesystem must not be mounted.
+    /// If repair is true, fix the problems and reattach unreachable inodes to "/lost+found".
+    fn fsck(&mut self, repair: bool) -> Result<Vec<FsckIssue>>;
+    /// Begin a transaction which groups following operations until commit or rollback.
+    /// Each operation is still atomic by itself. Writing many files in one transaction is much faster.
+    fn begin_transaction(&mut self) -> Result<()>;
+    /// Commit the transaction started by begin_transaction.
+    fn commit_transaction(&mut self) -> Result<()>;
+    /// Discard all changes since begin_transaction.
+    fn rollback_transaction(&mut self) -> Result<()>;
+    /// Get capacity and usage of the filesystem.
+    fn statfs(&self) -> Result<DBStatfs>;
+    /// Set limits of a quota and count its current usage. A limit of 0 is unlimited.
+    /// If both limits are 0, the quota is removed.
+    fn set_quota(&mut self, kind: QuotaKind, id: u32, bytes_limit: u64, inodes_limit: u64) -> Result<()>;
+    /// Get all quotas with their usage.
+    fn list_quota(&self) -> Result<Vec<DBQuota>>;
+    /// Set the project of a directory tree. New files inherit the project of the parent directory.
+    fn set_project(&mut self, inode: u64, project: u32) -> Result<()>;
+    /// Check that quotas of an inode have room for more bytes and inodes. If not, return FsQuota.
+    fn check_quota(&self, inode: u64, bytes: u64, inodes: u64) -> Result<()>;
+    /// Make all committed changes durable even if the database doesn't sync each commit.
+    /// If datasync is true, metadata of the database file which is not needed to read it is not synced.
+    fn sync(&mut self, datasync: bool) -> Result<()>;
+
+    /// Read data of a file from offset across blocks.
+    /// Blocks which are not written are filled with zero. Update atime unless noatime is true.
+    fn read_file(&mut self, inode: u64, offset: u64, size: u32, noatime: bool) -> Result<Vec<u8>> {
+        let mut data: Vec<u8> = Vec::with_capacity(size as usize);
+        let block_size = u64::from(self.get_db_block_size());
+        let mut size = u64::from(size);
+        let mut offset = offset;
+        while size > 0 {
+            let b_num = offset / block_size + 1;
+            let mut block_data = self.get_data(inode, b_num, block_size as u32, noatime)?;
+            let b_offset = offset % block_size;
+            let b_end = if (size + b_offset) / block_size >= 1 {block_size} else {size + b_offset};
+            if block_data.len() < b_end as usize {
+                block_data.resize(b_end as usize, 0);
+            }
+            data.extend_from_slice(&block_data[b_offset as usize..b_end as usize]);
+            offset += b_end - b_offset;
+            size -= b_end - b_offset;
+        }
+        Ok(data)
+    }
+
+    /// Write data of a file at offset across blocks in one transaction.
+    /// Partially written blocks keep their old data. Update size if the file grows, mtime and ctime.
+    fn write_file(&mut self, inode: u64, offset: u64, data: &[u8]) -> Result<()> {
+        let block_size = u64::from(self.get_db_block_size());
+        let size = data.len() as u64;
+        if size == 0 {
+            return Ok(());
+        }
+        match offset.checked_add(size) {
+            Some(n) if n <= i64::MAX as u64 => {},
+            _ => return Err(Error::from(ErrorKind::FsParm {description: "invalid range of write".to_string()})),
+        }
+        let start_block = offset / block_size + 1;
+        let end_block = (offset + size - 1) / block_size + 1;
+        let mut blocks = Vec::with_capacity((end_block - start_block + 1) as usize);
+        for i in start_block..=end_block {
+            let mut block_data: Vec<u8> = Vec::with_capacity(block_size as usize);
+            let b_start_index = if i == start_block {offset % block_size} else {0};
+            let b_end_index = if i == end_block {(offset+size-1) % block_size +1} else {block_size};
+            //The blocks don't need to be in perfect alignment.
+            let data_offset = if i > start_block {
+                ((i - start_block - 1) * block_size) + (block_size) - offset % block_size
+            } else {
+                0
+            };
+
+            if (b_start_index != 0) || (b_end_index != block_size) {
+                // writing doesn't access the file
+                let mut data_pre = self.get_data(inode, i, block_size as u32, true)?;
+                if data_pre.len() < block_size as usize {
+                    data_pre.resize(block_size as usize, 0);
+                }
+                if b_start_index != 0 {
+                    block_data.extend_from_slice(&data_pre[0..b_start_index as usize]);
+                }
+                block_data.extend_from_slice(&data[data_offset as usize..(data_offset + b_end_index - b_start_index) as usize]);
+                if b_end_index != block_size {
+                    block_data.extend_from_slice(&data_pre[b_end_index as usize..block_size as usize]);
+                }
+            } else {
+                block_data.extend_from_slice(&data[data_offset as usize..(data_offset + block_size) as usize]);
+            }
+            blocks.push((i, block_data));
+        }
+        // all blocks are written in one transaction
+        self.write_blocks(inode, &blocks, offset + size)
+    }
 }
 
 // Imported from rust-fuse 4.0-dev
@@ -62,11 +235,11 @@ pub trait DbModule {
 #[derive(Clone, Copy, Debug, Hash, PartialEq)]
 pub struct DBFileAttr {
     /// Inode number
-    pub ino: u32,
+    pub ino: u64,
     /// Size in bytes
-    pub size: u32,
-    /// block size
-    pub blocks: u32,
+    pub size: u64,
+    /// Allocated size in 512 byte units. Holes are not counted.
+    pub blocks: u64,
     /// Time of last access
     pub atime: SystemTime,
     /// Time of last modification
@@ -92,43 +265,109 @@ pub struct DBFileAttr {
 }
 
 impl DBFileAttr {
-    fn timespec_from(&self, st: &SystemTime) -> Timespec {
-        if let Ok(dur_since_epoch) = st.duration_since(std::time::UNIX_EPOCH) {
-            Timespec::new(dur_since_epoch.as_secs() as i64,
-                          dur_since_epoch.subsec_nanos() as i32)
-        } else {
-            Timespec::new(0, 0)
-        }
-    }
-
-    pub fn datetime_from(&self, ts: &Timespec) -> SystemTime {
-        let dt = DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(ts.sec, ts.nsec as u32), Utc);
-        SystemTime::from(dt)
-    }
-
     pub fn get_file_attr(&self) -> FileAttr {
         FileAttr {
-            ino: self.ino as u64,
-            size: self.size as u64,
-            blocks: self.blocks as u64,
-            atime: self.timespec_from(&self.atime),
-            mtime: self.timespec_from(&self.mtime),
-            ctime: self.timespec_from(&self.ctime),
-            crtime: self.timespec_from(&self.crtime),
+            ino: self.ino,
+            size: self.size,
+            blocks: self.blocks,
+            atime: self.atime,
+            mtime: self.mtime,
+            ctime: self.ctime,
+            crtime: self.crtime,
             kind: self.kind,
             perm: self.perm,
             nlink: self.nlink,
             uid: self.uid,
             gid: self.gid,
             rdev: self.rdev,
+            // Zero lets the kernel use its default block size.
+            blksize: 0,
             flags: self.flags,
         }
     }
 }
 
+/// POSIX byte range lock
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DBLock {
+    /// Inode number
+    pub ino: u64,
+    /// Lock owner given by the kernel
+    pub lock_owner: u64,
+    /// First byte of the range
+    pub start: u64,
+    /// Last byte of the range (inclusive)
+    pub end: u64,
+    /// F_RDLCK, F_WRLCK or F_UNLCK
+    pub typ: u32,
+    /// Process id which holds the lock
+    pub pid: u32,
+}
+
+/// Capacity and usage of the filesystem
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DBStatfs {
+    /// Total blocks
+    pub blocks: u64,
+    /// Free blocks
+    pub bfree: u64,
+    /// Free blocks for unprivileged users
+    pub bavail: u64,
+    /// Total inodes
+    pub files: u64,
+    /// Free inodes
+    pub ffree: u64,
+    /// Block size in bytes
+    pub bsize: u32,
+    /// Maximum length of a file name
+    pub namelen: u32,
+}
+
+/// Target of a quota
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QuotaKind {
+    User,
+    Group,
+    /// Directory tree which has the same project id
+    Project,
+}
+
+/// Limits and usage of a quota. A limit of 0 is unlimited.
+/// Bytes are the size of data blocks which are stored in the database.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DBQuota {
+    pub kind: QuotaKind,
+    /// uid, gid or project id
+    pub id: u32,
+    pub bytes_limit: u64,
+    pub inodes_limit: u64,
+    pub bytes_used: u64,
+    pub inodes_used: u64,
+}
+
+/// A read-only snapshot of the whole filesystem
+#[derive(Clone, Debug, PartialEq)]
+pub struct DBSnapshot {
+    pub id: u32,
+    pub name: String,
+    /// Time when the snapshot was taken
+    pub created: SystemTime,
+}
+
+/// A problem found by fsck
+#[derive(Clone, Debug, PartialEq)]
+pub struct FsckIssue {
+    /// Inode number related to the problem
+    pub ino: u64,
+    /// Description of the problem
+    pub description: String,
+    /// The problem is repaired
+    pub repaired: bool,
+}
+
 pub struct DEntry {
-    pub parent_ino: u32,
-    pub child_ino: u32,
+    pub parent_ino: u64,
+    pub child_ino: u64,
     pub filename: String,
     pub file_type: FileType,
 }
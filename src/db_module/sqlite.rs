@@ -1,11 +1,29 @@
-use std::path::Path;
-use std::time::SystemTime;
+use std::cmp;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::Deref;
 use chrono::{Utc, DateTime, NaiveDateTime, Timelike};
-use rusqlite::types::ToSql;
+use rusqlite::types::{FromSql, ToSql};
 use rusqlite::{params, Connection, NO_PARAMS, Statement};
-use crate::db_module::{DbModule, DBFileAttr, DEntry};
+use sha2::{Digest, Sha256};
+use crate::db_module::{
+    DbModule, DBFileAttr, DBQuota, DBStatfs, DEntry, DBLock, FsckIssue, QuotaKind,
+    DBSnapshot, FALLOC_FL_COLLAPSE_RANGE, FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE, FALLOC_FL_ZERO_RANGE,
+    MAX_SNAPSHOT_ID, MAX_SNAPSHOT_INO, SNAPSHOT_DIR_INO, SNAPSHOT_DIR_NAME,
+    is_snapshot_ino, snapshot_ino, split_snapshot_ino,
+};
+use crate::compression::{self, Codec};
 use crate::sqerror::{Error, Result, ErrorKind};
-use fuse::FileType;
+use fuser::FileType;
+use libc::{F_UNLCK, F_WRLCK};
+use nix::errno::Errno;
+use nix::sys::signal;
+use nix::sys::statvfs;
+use nix::unistd::Pid;
+#[cfg(target_os = "linux")]
+use nix::sys::sysinfo;
 
 const DB_IFIFO: u32 = 0o0_010_000;
 const DB_IFCHR: u32 = 0o0_020_000;
@@ -15,7 +33,15 @@ const DB_IFREG: u32 = 0o0_100_000;
 const DB_IFLNK: u32 = 0o0_120_000;
 const DB_IFSOCK: u32 = 0o0_140_000;
 
-const BLOCK_SIZE: u32 = 4096;
+/// Default block size of a new filesystem
+pub const DEFAULT_BLOCK_SIZE: u32 = 4096;
+const MIN_BLOCK_SIZE: u32 = 512;
+const MAX_BLOCK_SIZE: u32 = 1024 * 1024;
+const NAME_MAX: usize = 255;
+
+const QUOTA_USER: u32 = 0;
+const QUOTA_GROUP: u32 = 1;
+const QUOTA_PROJECT: u32 = 2;
 
 fn string_to_systemtime(text: String, nsec: u32) -> SystemTime {
     SystemTime::from(DateTime::<Utc>::from_utc(
@@ -49,65 +75,711 @@ fn const_to_file_type(kind: u32) -> FileType {
 }
 
 /// Release all data in "inode", after "offset" byte.
-fn release_data(inode: u32, offset: u32, tx: &Connection) -> Result<()> {
+fn release_data(inode: u64, offset: u64, block_size: u32, codec: Codec, dedup: bool, tx: &Connection) -> Result<()> {
+    let max_size = block_size;
+    let block_size = u64::from(block_size);
+    // blocks after this are deleted or truncated
+    let first_block = offset / block_size + 1;
+    let old_bytes = stored_bytes_local(inode, first_block, tx)?;
     if offset == 0 {
-        tx.execute("DELETE FROM data WHERE file_id=$1", params![inode])?;
+        unref_blocks_local(inode, 0, i64::MAX, tx)?;
+        tx.execute("DELETE FROM data WHERE file_id=$1", params![inode as i64])?;
     } else {
-        let mut block = offset / BLOCK_SIZE;
-        if offset % BLOCK_SIZE != 0 {
-            block = offset / BLOCK_SIZE + 1;
-            let sql = "SELECT data FROM data WHERE file_id=$1 and block_num = $2";
-            let mut stmt = tx.prepare(sql)?;
-            let mut data: Vec<u8> = match stmt.query_row(params![inode, block], |row| row.get(0)) {
-                Ok(n) => n,
-                Err(err) => {
-                    if err == rusqlite::Error::QueryReturnedNoRows {
-                        vec![0; BLOCK_SIZE as usize]
-                    } else {
-                        return Err(Error::from(err))
-                    }
-                }
+        let mut block = offset / block_size;
+        if offset % block_size != 0 {
+            block = offset / block_size + 1;
+            let mut data = match get_block_local(inode, block, max_size, tx)? {
+                Some(n) => n,
+                None => vec![0; block_size as usize],
             };
-            data.resize((offset % BLOCK_SIZE) as usize, 0);
-            tx.execute("REPLACE INTO data \
-            (file_id, block_num, data)
-            VALUES($1, $2, $3)",
-                       params![inode, block, data])?;
+            data.resize((offset % block_size) as usize, 0);
+            put_block_local(inode, block, &data, codec, dedup, tx)?;
         }
-        tx.execute("DELETE FROM data WHERE file_id=$1 and block_num > $2", params![inode, block])?;
+        unref_blocks_local(inode, block as i64 + 1, i64::MAX, tx)?;
+        tx.execute("DELETE FROM data WHERE file_id=$1 and block_num > $2", params![inode as i64, block as i64])?;
+    }
+    let new_bytes = stored_bytes_local(inode, first_block, tx)?;
+    charge_quota_local(inode, new_bytes - old_bytes, 0, tx)?;
+    Ok(())
+}
+
+// Read a data block. None if it is not written.
+fn get_block_local(inode: u64, block: u64, block_size: u32, tx: &Connection) -> Result<Option<Vec<u8>>> {
+    let (table, file) = match split_snapshot_ino(inode) {
+        Some((snapshot, ino)) => (snapshot_data_sql(snapshot), ino),
+        None => ("data".to_string(), inode),
+    };
+    // a deduplicated block is stored in the block table
+    let sql = format!("SELECT coalesce(data.data, block.data), CASE WHEN data.hash IS NULL THEN data.codec ELSE block.codec END \
+        FROM {} AS data LEFT JOIN block ON block.hash = data.hash WHERE file_id=$1 AND block_num=$2", table);
+    let result = tx.query_row(&sql, params![file as i64, block as i64], |row| Ok((row.get(0)?, row.get(1)?)));
+    let (data, tag): (Option<Vec<u8>>, Option<u32>) = match result {
+        Ok(n) => n,
+        Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+        Err(err) => return Err(Error::from(err)),
+    };
+    match (data, tag) {
+        (Some(data), Some(tag)) => Ok(Some(compression::decompress(tag, data, block_size)?)),
+        _ => Err(Error::from(ErrorKind::FsIo {description: format!("block {} of inode {} is missing", block, inode)})),
+    }
+}
+
+// Write a data block. With dedup, the content is stored once in the block table and the data row refers to its hash.
+// A block of zeros is not stored, and becomes a hole.
+fn put_block_local(inode: u64, block: u64, data: &[u8], codec: Codec, dedup: bool, tx: &Connection) -> Result<()> {
+    unref_blocks_local(inode, block as i64, block as i64, tx)?;
+    if data.iter().all(|&n| n == 0) {
+        tx.execute("DELETE FROM data WHERE file_id=$1 AND block_num=$2", params![inode as i64, block as i64])?;
+    } else if dedup {
+        let hash = Sha256::digest(data).to_vec();
+        let exists: u32 = tx.query_row("SELECT count(*) FROM block WHERE hash=$1", params![hash], |row| row.get(0))?;
+        if exists == 0 {
+            let (tag, stored) = compression::compress(codec, data);
+            tx.execute("INSERT INTO block (hash, data, codec, refcount) VALUES($1, $2, $3, 0)", params![hash, stored, tag])?;
+        }
+        tx.execute("UPDATE block SET refcount=refcount+1 WHERE hash=$1", params![hash])?;
+        let sql = format!("REPLACE INTO data (file_id, block_num, data, codec, hash, generation) VALUES($1, $2, NULL, 0, $3, {})", GENERATION_SQL);
+        tx.execute(&sql,
+                   params![inode as i64, block as i64, hash])?;
+    } else {
+        let (tag, stored) = compression::compress(codec, data);
+        let sql = format!("REPLACE INTO data (file_id, block_num, data, codec, hash, generation) VALUES($1, $2, $3, $4, NULL, {})", GENERATION_SQL);
+        tx.execute(&sql,
+                   params![inode as i64, block as i64, stored, tag])?;
+    }
+    Ok(())
+}
+
+// Release references of data rows between the blocks to deduplicated blocks. It must be called before the rows are deleted.
+// Blocks which are not referenced any more are deleted.
+fn unref_blocks_local(inode: u64, from: i64, to: i64, tx: &Connection) -> Result<()> {
+    keep_snapshot_blocks_local(inode, from, to, tx)?;
+    let rows = "SELECT hash FROM data WHERE file_id=$1 AND block_num BETWEEN $2 AND $3 AND hash IS NOT NULL";
+    let sql = format!("UPDATE block SET refcount=refcount - \
+        (SELECT count(*) FROM data WHERE data.hash = block.hash AND file_id=$1 AND block_num BETWEEN $2 AND $3) \
+        WHERE hash IN ({})", rows);
+    tx.execute(&sql, params![inode as i64, from, to])?;
+    let sql = format!("DELETE FROM block WHERE refcount <= 0 AND hash IN ({})", rows);
+    tx.execute(&sql, params![inode as i64, from, to])?;
+    Ok(())
+}
+
+// Release blocks which are referred by all files.
+fn unref_blocks_local_all(tx: &Connection) -> Result<()> {
+    let sql = "UPDATE block SET refcount=refcount - (SELECT count(*) FROM data WHERE data.hash = block.hash) \
+        WHERE hash IN (SELECT hash FROM data)";
+    tx.execute(sql, NO_PARAMS)?;
+    tx.execute("DELETE FROM block WHERE refcount <= 0", NO_PARAMS)?;
+    Ok(())
+}
+
+// Fill a range of a file with zeros. Whole blocks in the range are deleted.
+fn zero_range_local(inode: u64, offset: u64, end: u64, block_size: u32, codec: Codec, dedup: bool, tx: &Connection) -> Result<()> {
+    let max_size = block_size;
+    let block_size = u64::from(block_size);
+    // zero bytes of a block from "from" to "to"
+    let zero_partial = |block: u64, from: u64, to: u64| -> Result<()> {
+        if let Some(mut data) = get_block_local(inode, block, max_size, tx)? {
+            let to = std::cmp::min(to as usize, data.len());
+            if (from as usize) < to {
+                data[from as usize..to].iter_mut().for_each(|n| *n = 0);
+                put_block_local(inode, block, &data, codec, dedup, tx)?;
+            }
+        }
+        Ok(())
+    };
+    let first_block = offset / block_size + 1;
+    let last_block = (end - 1) / block_size + 1;
+    let (head, tail) = (offset % block_size, end % block_size);
+    let first_full = if head == 0 { first_block } else { first_block + 1 };
+    let last_full = if tail == 0 { last_block } else { last_block - 1 };
+    if first_block == last_block && first_full > last_full {
+        let start = (first_block - 1) * block_size;
+        return zero_partial(first_block, offset - start, end - start);
+    }
+    if first_full != first_block {
+        zero_partial(first_block, head, block_size)?;
+    }
+    if last_full != last_block {
+        zero_partial(last_block, 0, tail)?;
+    }
+    if first_full <= last_full {
+        unref_blocks_local(inode, first_full as i64, last_full as i64, tx)?;
+        tx.execute("DELETE FROM data WHERE file_id=$1 AND block_num BETWEEN $2 AND $3",
+                   params![inode as i64, first_full as i64, last_full as i64])?;
+    }
+    Ok(())
+}
+
+// Remove whole blocks from "from" to "to" and shift following blocks.
+fn collapse_blocks_local(inode: u64, from: u64, to: u64, tx: &Connection) -> Result<()> {
+    unref_blocks_local(inode, from as i64, to as i64, tx)?;
+    tx.execute("DELETE FROM data WHERE file_id=$1 AND block_num BETWEEN $2 AND $3",
+               params![inode as i64, from as i64, to as i64])?;
+    // shifted rows are new blocks for snapshots
+    keep_snapshot_blocks_local(inode, to as i64 + 1, i64::MAX, tx)?;
+    // negate first not to conflict with the primary key while shifting
+    tx.execute("UPDATE data SET block_num=-block_num WHERE file_id=$1 AND block_num > $2", params![inode as i64, to as i64])?;
+    tx.execute("UPDATE data SET block_num=-block_num-$1 WHERE file_id=$2 AND block_num < 0",
+               params![(to - from + 1) as i64, inode as i64])?;
+    Ok(())
+}
+
+// Read bytes of a file. Blocks which are not stored are zero.
+fn read_local(inode: u64, offset: u64, length: u64, block_size: u32, tx: &Connection) -> Result<Vec<u8>> {
+    let max_size = block_size;
+    let block_size = u64::from(block_size);
+    let mut data = Vec::with_capacity(length as usize);
+    let end = offset + length;
+    let mut pos = offset;
+    while pos < end {
+        let block = pos / block_size + 1;
+        let start = (pos % block_size) as usize;
+        let size = std::cmp::min(block_size - start as u64, end - pos) as usize;
+        let mut block_data = get_block_local(inode, block, max_size, tx)?.unwrap_or_default();
+        block_data.resize(block_size as usize, 0);
+        data.extend_from_slice(&block_data[start..start + size]);
+        pos += size as u64;
+    }
+    Ok(data)
+}
+
+// Copy data rows from "first" to "last" block of inode_in to inode_out. Block numbers are moved by shift.
+// Existing rows of the destination are replaced, and deduplicated blocks get references.
+fn copy_blocks_local(inode_in: u64, first: u64, last: u64, inode_out: u64, shift: i64, tx: &Connection) -> Result<()> {
+    let (first, last) = (first as i64, last as i64);
+    unref_blocks_local(inode_out, first + shift, last + shift, tx)?;
+    tx.execute("DELETE FROM data WHERE file_id=$1 AND block_num BETWEEN $2 AND $3",
+               params![inode_out as i64, first + shift, last + shift])?;
+    let sql = "UPDATE block SET refcount=refcount + \
+        (SELECT count(*) FROM data WHERE data.hash = block.hash AND file_id=$1 AND block_num BETWEEN $2 AND $3) \
+        WHERE hash IN (SELECT hash FROM data WHERE file_id=$1 AND block_num BETWEEN $2 AND $3)";
+    tx.execute(sql, params![inode_in as i64, first, last])?;
+    let sql = format!("INSERT INTO data (file_id, block_num, data, codec, hash, generation) \
+        SELECT $1, block_num + $2, data, codec, hash, {} FROM data WHERE file_id=$3 AND block_num BETWEEN $4 AND $5", GENERATION_SQL);
+    tx.execute(&sql, params![inode_out as i64, shift, inode_in as i64, first, last])?;
+    Ok(())
+}
+
+// Move blocks which are stored in data rows between the blocks into the block table, so that other inodes and snapshots can share them.
+// Only rows which are written before the generation are moved.
+fn share_blocks_local(inode: u64, from: i64, to: i64, generation: i64, block_size: u32, tx: &Connection) -> Result<()> {
+    // moved rows are not selected again, so a large file is processed in batches
+    let sql = "SELECT block_num, data, codec FROM data \
+        WHERE file_id=$1 AND block_num BETWEEN $2 AND $3 AND generation < $4 AND hash IS NULL LIMIT 256";
+    loop {
+        let mut blocks: Vec<(i64, Vec<u8>, u32)> = Vec::new();
+        {
+            let mut stmt = tx.prepare(sql)?;
+            let rows = stmt.query_map(params![inode as i64, from, to, generation], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+            for row in rows {
+                blocks.push(row?);
+            }
+        }
+        if blocks.is_empty() {
+            return Ok(());
+        }
+        for (block, stored, tag) in blocks {
+            // the hash is the same as dedup mode
+            let hash = Sha256::digest(&compression::decompress(tag, stored.clone(), block_size)?).to_vec();
+            tx.execute("INSERT OR IGNORE INTO block (hash, data, codec, refcount) VALUES($1, $2, $3, 0)", params![hash, stored, tag])?;
+            tx.execute("UPDATE block SET refcount=refcount+1 WHERE hash=$1", params![hash])?;
+            tx.execute("UPDATE data SET data=NULL, codec=0, hash=$1 WHERE file_id=$2 AND block_num=$3", params![hash, inode as i64, block])?;
+        }
+    }
+}
+
+// Generation of data rows which are written now. A snapshot sees rows which are written before its generation.
+const GENERATION_SQL: &str = "(SELECT coalesce(max(value), 0) FROM settings WHERE name='generation')";
+
+// Data rows of a snapshot. Rows which are not changed after the snapshot was taken are shared with the files,
+// and the others were moved to snapshot_data by keep_snapshot_blocks_local().
+fn snapshot_data_sql(snapshot: u32) -> String {
+    format!("(SELECT file_id, block_num, NULL AS data, 0 AS codec, hash FROM snapshot_data WHERE snapshot_id={0} \
+        UNION ALL SELECT file_id, block_num, data, codec, hash FROM data \
+        WHERE generation < (SELECT generation FROM snapshot WHERE id={0}))", snapshot)
+}
+
+// Keep data rows between the blocks in snapshots which were taken after the rows were written.
+// It must be called before the rows are changed or deleted. The blocks are shared with the snapshots through the block table.
+fn keep_snapshot_blocks_local(inode: u64, from: i64, to: i64, tx: &Connection) -> Result<()> {
+    let latest: i64 = tx.query_row("SELECT coalesce(max(generation), 0) FROM snapshot", NO_PARAMS, |row| row.get(0))?;
+    let sql = "SELECT count(*) FROM data WHERE file_id=$1 AND block_num BETWEEN $2 AND $3 AND generation < $4";
+    let count: i64 = tx.query_row(sql, params![inode as i64, from, to, latest], |row| row.get(0))?;
+    if count == 0 {
+        return Ok(());
+    }
+    let block_size = get_setting_local::<u32>("block_size", tx)?.unwrap_or(DEFAULT_BLOCK_SIZE);
+    share_blocks_local(inode, from, to, latest, block_size, tx)?;
+    let sql = "UPDATE block SET refcount=refcount + (SELECT count(*) FROM data, snapshot WHERE data.hash = block.hash \
+        AND file_id=$1 AND block_num BETWEEN $2 AND $3 AND snapshot.generation > data.generation) \
+        WHERE hash IN (SELECT hash FROM data WHERE file_id=$1 AND block_num BETWEEN $2 AND $3 AND generation < $4)";
+    tx.execute(sql, params![inode as i64, from, to, latest])?;
+    let sql = "INSERT INTO snapshot_data (snapshot_id, file_id, block_num, hash) \
+        SELECT snapshot.id, file_id, block_num, hash FROM data, snapshot \
+        WHERE file_id=$1 AND block_num BETWEEN $2 AND $3 AND snapshot.generation > data.generation";
+    tx.execute(sql, params![inode as i64, from, to])?;
+    // snapshots don't see the rows any more
+    let sql = format!("UPDATE data SET generation={} WHERE file_id=$1 AND block_num BETWEEN $2 AND $3 AND generation < $4", GENERATION_SQL);
+    tx.execute(&sql, params![inode as i64, from, to, latest])?;
+    Ok(())
+}
+
+// Columns of metadata, dentry and xattr rows which are kept in snapshot_metadata, snapshot_dentry and snapshot_xattr
+fn snapshot_columns(table: &str) -> &'static str {
+    match table {
+        "metadata" => SNAPSHOT_METADATA_COLUMNS,
+        "dentry" => "parent_id, child_id, file_type, name",
+        _ => "file_id, name, value",
+    }
+}
+
+// Rows of metadata, dentry or xattr in a snapshot. They are shared with the files like data rows.
+fn snapshot_rows_sql(table: &str, snapshot: u32) -> String {
+    format!("(SELECT {1} FROM snapshot_{0} WHERE snapshot_id={2} \
+        UNION ALL SELECT {1} FROM {0} WHERE generation < (SELECT generation FROM snapshot WHERE id={2}))",
+        table, snapshot_columns(table), snapshot)
+}
+
+// Copy rows of metadata, dentry or xattr which match the condition to snapshots which were taken after the rows were written.
+// It must be called before the rows are changed or deleted, like keep_snapshot_blocks_local().
+fn keep_snapshot_rows_local(table: &str, condition: &str, params: &[&dyn ToSql], tx: &Connection) -> Result<()> {
+    let latest: i64 = tx.query_row("SELECT coalesce(max(generation), 0) FROM snapshot", NO_PARAMS, |row| row.get(0))?;
+    if latest == 0 {
+        return Ok(());
+    }
+    let columns = snapshot_columns(table);
+    let row_columns: Vec<String> = columns.split(", ").map(|n| format!("t.{}", n)).collect();
+    let sql = format!("INSERT INTO snapshot_{0} (snapshot_id, {1}) SELECT snapshot.id, {2} \
+        FROM snapshot, (SELECT {1}, generation FROM {0} WHERE {3}) AS t WHERE snapshot.generation > t.generation",
+        table, columns, row_columns.join(", "), condition);
+    tx.execute(&sql, params)?;
+    // snapshots don't see the rows any more
+    let sql = format!("UPDATE {} SET generation={} WHERE ({}) AND generation < {}", table, GENERATION_SQL, condition, latest);
+    tx.execute(&sql, params)?;
+    Ok(())
+}
+
+// Keep the inode and rows which are deleted with it by the foreign keys.
+fn keep_snapshot_inode_local(inode: u64, tx: &Connection) -> Result<()> {
+    keep_snapshot_rows_local("metadata", "id=$1", params![inode as i64], tx)?;
+    keep_snapshot_rows_local("dentry", "parent_id=$1 OR child_id=$1", params![inode as i64], tx)?;
+    keep_snapshot_rows_local("xattr", "file_id=$1", params![inode as i64], tx)
+}
+
+// Copy data rows of a file in a snapshot to inode. The destination must not have data rows.
+fn copy_snapshot_blocks_local(snapshot: u32, file: u64, inode: u64, block_size: u32, tx: &Connection) -> Result<()> {
+    // rows shared with the file are moved to the block table first
+    let generation: i64 = tx.query_row("SELECT generation FROM snapshot WHERE id=$1", params![snapshot], |row| row.get(0))?;
+    share_blocks_local(file, i64::MIN, i64::MAX, generation, block_size, tx)?;
+    let rows = snapshot_data_sql(snapshot);
+    let sql = format!("UPDATE block SET refcount=refcount + \
+        (SELECT count(*) FROM {0} AS d WHERE d.hash = block.hash AND file_id=$1) \
+        WHERE hash IN (SELECT hash FROM {0} WHERE file_id=$1)", rows);
+    tx.execute(&sql, params![file as i64])?;
+    let sql = format!("INSERT INTO data (file_id, block_num, data, codec, hash, generation) \
+        SELECT $1, block_num, NULL, 0, hash, {} FROM {} WHERE file_id=$2", GENERATION_SQL, rows);
+    tx.execute(&sql, params![inode as i64, file as i64])?;
+    Ok(())
+}
+
+// Files in snapshots can't be changed.
+fn check_writable(inode: u64) -> Result<()> {
+    if is_snapshot_ino(inode) {
+        return Err(Error::from(ErrorKind::FsReadOnly {description: format!("inode {} is in a snapshot", inode)}));
+    }
+    Ok(())
+}
+
+// The directory of snapshots hides an entry of the same name in the root directory.
+fn check_reserved_name(parent: u64, name: &str) -> Result<()> {
+    if parent == 1 && name == SNAPSHOT_DIR_NAME {
+        return Err(Error::from(ErrorKind::FsFileExist {description: format!("{} is reserved for snapshots", name)}));
+    }
+    Ok(())
+}
+
+// Rename an entry of the root directory which is hidden by the directory of snapshots. Returns the new name.
+fn rename_reserved_entry_local(tx: &Connection) -> Result<Option<(u64, String)>> {
+    let child = match get_dentry_single(1, SNAPSHOT_DIR_NAME, tx)? {
+        Some(n) => n.child_ino,
+        None => return Ok(None),
+    };
+    let mut n = 1;
+    let name = loop {
+        let name = format!("{}.{}", SNAPSHOT_DIR_NAME, n);
+        if get_dentry_single(1, &name, tx)?.is_none() {
+            break name;
+        }
+        n += 1;
+    };
+    keep_snapshot_rows_local("dentry", "parent_id=1 AND name=$1", params![SNAPSHOT_DIR_NAME], tx)?;
+    tx.execute("UPDATE dentry SET name=$1 WHERE parent_id=1 AND name=$2", params![name, SNAPSHOT_DIR_NAME])?;
+    Ok(Some((child, name)))
+}
+
+fn check_snapshot_name(name: &str) -> Result<()> {
+    check_filename(name)?;
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err(Error::from(ErrorKind::FsParm {description: format!("{:?} is not a valid snapshot name", name)}));
+    }
+    Ok(())
+}
+
+fn get_snapshot_id_local(name: &str, tx: &Connection) -> Result<u32> {
+    match tx.query_row("SELECT id FROM snapshot WHERE name=$1", params![name], |row| row.get(0)) {
+        Ok(n) => Ok(n),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Err(Error::from(ErrorKind::FsNoEnt {description: format!("snapshot {} is not found", name)})),
+        Err(err) => Err(Error::from(err)),
+    }
+}
+
+fn list_snapshot_local(tx: &Connection) -> Result<Vec<DBSnapshot>> {
+    let sql = "SELECT id, name, created, created_nsec FROM snapshot ORDER BY name";
+    let mut stmt = tx.prepare(sql)?;
+    let rows = stmt.query_map(NO_PARAMS, |row| {
+        Ok(DBSnapshot {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            created: string_to_systemtime(row.get(2)?, row.get(3)?),
+        })
+    })?;
+    let mut snapshots = Vec::new();
+    for row in rows {
+        snapshots.push(row?);
+    }
+    Ok(snapshots)
+}
+
+// Columns of metadata which are kept in snapshots
+const SNAPSHOT_METADATA_COLUMNS: &str = "id, size, atime, atime_nsec, mtime, mtime_nsec, ctime, ctime_nsec, \
+    crtime, crtime_nsec, kind, mode, nlink, uid, gid, rdev, flags, project";
+
+// Attributes of the directory of snapshots. It is made from the root directory.
+fn snapshot_dir_attr_local(tx: &Connection) -> Result<Option<DBFileAttr>> {
+    let mut attr = match get_inode_local(1, tx)? {
+        Some(n) => n,
+        None => return Ok(None),
+    };
+    let count: u32 = tx.query_row("SELECT count(*) FROM snapshot", NO_PARAMS, |row| row.get(0))?;
+    attr.ino = SNAPSHOT_DIR_INO;
+    attr.size = 0;
+    attr.blocks = 0;
+    attr.perm = 0o555;
+    attr.nlink = count + 2;
+    attr.flags = 0;
+    Ok(Some(attr))
+}
+
+fn get_snapshot_inode_local(snapshot: u32, inode: u64, tx: &Connection) -> Result<Option<DBFileAttr>> {
+    let sql = format!("SELECT id, size, atime, atime_nsec, mtime, mtime_nsec, ctime, ctime_nsec, crtime, crtime_nsec, kind, mode, \
+        (SELECT count(*) FROM {} WHERE child_id=$1), uid, gid, rdev, flags, \
+        (SELECT count(*) FROM {} WHERE file_id=$1) * \
+        (SELECT value FROM settings WHERE name='block_size') / 512 \
+        FROM {} WHERE id=$1",
+        snapshot_rows_sql("dentry", snapshot), snapshot_data_sql(snapshot), snapshot_rows_sql("metadata", snapshot));
+    let stmt = tx.prepare(&sql)?;
+    let attr = parse_attr(stmt, params![inode as i64])?;
+    Ok(attr.map(|mut n| {
+        n.ino = snapshot_ino(snapshot, inode);
+        n
+    }))
+}
+
+// Look up a name in the directory of snapshots or a directory in a snapshot.
+fn lookup_snapshot_local(parent: u64, name: &str, tx: &Connection) -> Result<Option<DBFileAttr>> {
+    let child: rusqlite::Result<u64> = match split_snapshot_ino(parent) {
+        None => match name {
+            "." => Ok(SNAPSHOT_DIR_INO),
+            ".." => Ok(1),
+            _ => tx.query_row("SELECT id FROM snapshot WHERE name=$1", params![name], |row| row.get(0))
+                .map(|id| snapshot_ino(id, 1)),
+        },
+        // the root directory of a snapshot is in the directory of snapshots
+        Some((_, 1)) if name == ".." => Ok(SNAPSHOT_DIR_INO),
+        Some((snapshot, dir)) => {
+            let sql = format!("SELECT child_id FROM {} WHERE parent_id=$1 AND name=$2", snapshot_rows_sql("dentry", snapshot));
+            tx.query_row(&sql, params![dir as i64, name], |row| row.get::<_, i64>(0))
+                .map(|child| snapshot_ino(snapshot, child as u64))
+        },
+    };
+    match child {
+        Ok(n) => get_inode_local(n, tx),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(err) => Err(Error::from(err)),
+    }
+}
+
+fn get_snapshot_dentry_local(inode: u64, tx: &Connection) -> Result<Vec<DEntry>> {
+    let dir_entry = |child_ino: u64, filename: &str| DEntry {
+        parent_ino: inode,
+        child_ino,
+        filename: filename.to_string(),
+        file_type: FileType::Directory,
+    };
+    let mut entries = Vec::new();
+    match split_snapshot_ino(inode) {
+        None => {
+            entries.push(dir_entry(SNAPSHOT_DIR_INO, "."));
+            entries.push(dir_entry(1, ".."));
+            for snapshot in list_snapshot_local(tx)? {
+                entries.push(dir_entry(snapshot_ino(snapshot.id, 1), &snapshot.name));
+            }
+        },
+        Some((snapshot, dir)) => {
+            let sql = format!("SELECT child_id, file_type, name FROM {} WHERE parent_id=$1 ORDER BY name", snapshot_rows_sql("dentry", snapshot));
+            let mut stmt = tx.prepare(&sql)?;
+            let rows = stmt.query_map(params![dir as i64], |row| {
+                let child: i64 = row.get(0)?;
+                let filename: String = row.get(2)?;
+                let child_ino = if dir == 1 && filename == ".." {
+                    SNAPSHOT_DIR_INO
+                } else {
+                    snapshot_ino(snapshot, child as u64)
+                };
+                Ok(DEntry{parent_ino: inode, child_ino, file_type: const_to_file_type(row.get(1)?), filename})
+            })?;
+            for row in rows {
+                entries.push(row?);
+            }
+        },
+    }
+    Ok(entries)
+}
+
+// Get attributes of a regular file for copying.
+fn get_regular_file_local(inode: u64, tx: &Connection) -> Result<DBFileAttr> {
+    match get_inode_local(inode, tx)? {
+        Some(n) if n.kind == FileType::RegularFile => Ok(n),
+        Some(n) if n.kind == FileType::Directory => Err(Error::from(ErrorKind::FsIsDir {description: format!("inode {} is a directory", inode)})),
+        Some(_) => Err(Error::from(ErrorKind::FsParm {description: format!("inode {} is not a regular file", inode)})),
+        None => Err(Error::from(ErrorKind::FsNoEnt {description: format!("inode {} is not found", inode)})),
+    }
+}
+
+// Find data or a hole at or after offset. Block numbers start from 1.
+fn seek_local(inode: u64, offset: u64, data: bool, block_size: u32, tx: &Connection) -> Result<u64> {
+    let block_size = u64::from(block_size);
+    let out_of_range = || Error::from(ErrorKind::FsOutOfRange {description: format!("offset {} of inode {}", offset, inode)});
+    let size = match get_inode_local(inode, tx)? {
+        Some(n) => n.size,
+        None => return Err(Error::from(ErrorKind::FsNoEnt {description: format!("inode {} is not found", inode)})),
+    };
+    if offset >= size {
+        return Err(out_of_range());
+    }
+    // blocks of a file in a snapshot are in another table
+    let (table, file) = match split_snapshot_ino(inode) {
+        Some((snapshot, ino)) => (snapshot_data_sql(snapshot), ino),
+        None => ("data".to_string(), inode),
+    };
+    let block = offset / block_size + 1;
+    let stored = |block: u64| -> Result<bool> {
+        let sql = format!("SELECT count(*) FROM {} WHERE file_id=$1 AND block_num=$2", table);
+        let count: i64 = tx.query_row(&sql, params![file as i64, block as i64], |row| row.get(0))?;
+        Ok(count > 0)
+    };
+    if stored(block)? == data {
+        return Ok(offset);
+    }
+    let found: Option<i64> = if data {
+        let sql = format!("SELECT min(block_num) FROM {} WHERE file_id=$1 AND block_num > $2", table);
+        tx.query_row(&sql, params![file as i64, block as i64], |row| row.get(0))?
+    } else {
+        // the block after the end of a run of stored blocks
+        let sql = format!("SELECT min(block_num) + 1 FROM {0} AS d WHERE file_id=$1 AND block_num >= $2 \
+            AND NOT EXISTS (SELECT 'x' FROM {0} WHERE file_id=$1 AND block_num=d.block_num + 1)", table);
+        tx.query_row(&sql, params![file as i64, block as i64], |row| row.get(0))?
+    };
+    match found {
+        Some(n) if (n as u64 - 1) * block_size < size => Ok((n as u64 - 1) * block_size),
+        _ if data => Err(out_of_range()),
+        _ => Ok(size),
+    }
+}
+
+// Allocated 512 byte units of metadata.id, like st_blocks.
+const BLOCKS_SQL: &str = "(SELECT count(block_num) FROM data WHERE file_id=metadata.id) * \
+    (SELECT value FROM settings WHERE name='block_size') / 512";
+
+// Size of a stored data row. A deduplicated block is counted by each file which refers to it.
+const BLOCK_LENGTH_SQL: &str = "coalesce(length(data.data), (SELECT length(block.data) FROM block WHERE block.hash = data.hash), 0)";
+
+fn quota_kind_to_const(kind: QuotaKind) -> u32 {
+    match kind {
+        QuotaKind::User => QUOTA_USER,
+        QuotaKind::Group => QUOTA_GROUP,
+        QuotaKind::Project => QUOTA_PROJECT,
+    }
+}
+
+fn const_to_quota_kind(kind: u32) -> QuotaKind {
+    match kind {
+        QUOTA_USER => QuotaKind::User,
+        QUOTA_GROUP => QuotaKind::Group,
+        _ => QuotaKind::Project,
+    }
+}
+
+// Bytes of data blocks of an inode from the block.
+fn stored_bytes_local(inode: u64, block: u64, tx: &Connection) -> Result<i64> {
+    let sql = format!("SELECT coalesce(sum({}), 0) FROM data WHERE file_id=$1 AND block_num >= $2", BLOCK_LENGTH_SQL);
+    Ok(tx.query_row(&sql, params![inode as i64, block as i64], |row| row.get(0))?)
+}
+
+fn block_bytes_local(inode: u64, block: u64, tx: &Connection) -> Result<i64> {
+    let sql = format!("SELECT coalesce((SELECT {} FROM data WHERE file_id=$1 AND block_num=$2), 0)", BLOCK_LENGTH_SQL);
+    Ok(tx.query_row(&sql, params![inode as i64, block as i64], |row| row.get(0))?)
+}
+
+// uid, gid and project which are charged for the usage of an inode
+fn get_quota_owner_local(inode: u64, tx: &Connection) -> Result<Option<(u32, u32, u32)>> {
+    let sql = "SELECT uid, gid, project FROM metadata WHERE id=$1";
+    match tx.query_row(sql, params![inode as i64], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))) {
+        Ok(n) => Ok(Some(n)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(err) => Err(Error::from(err)),
+    }
+}
+
+// Selects quotas of an owner. $1 is uid, $2 is gid and $3 is project, and they must be the first parameters.
+const QUOTA_OWNER_SQL: &str = "((kind=0 AND id=$1) OR (kind=1 AND id=$2) OR (kind=2 AND id=$3))";
+
+fn check_quota_local(owner: (u32, u32, u32), bytes: i64, inodes: i64, tx: &Connection) -> Result<()> {
+    let sql = format!("SELECT kind, id FROM quota WHERE {} AND \
+        (($4 > 0 AND bytes_limit > 0 AND bytes_used + $4 > bytes_limit) OR \
+        ($5 > 0 AND inodes_limit > 0 AND inodes_used + $5 > inodes_limit))", QUOTA_OWNER_SQL);
+    let mut stmt = tx.prepare(&sql)?;
+    let mut rows = stmt.query_map(params![owner.0, owner.1, owner.2, bytes, inodes], |row| Ok((row.get::<_, u32>(0)?, row.get::<_, u32>(1)?)))?;
+    if let Some(row) = rows.next() {
+        let (kind, id) = row?;
+        return Err(Error::from(ErrorKind::FsQuota {description: format!(
+            "{:?} quota of {} is exceeded",
+            const_to_quota_kind(kind),
+            id
+        )}));
     }
     Ok(())
 }
 
-fn update_time(inode: u32, sql: &str, time: DateTime<Utc>, tx: &Connection) -> Result<()> {
+// Add usage of an inode to its quotas. Fail with FsQuota if the usage exceeds a limit.
+fn charge_quota_local(inode: u64, bytes: i64, inodes: i64, tx: &Connection) -> Result<()> {
+    if bytes == 0 && inodes == 0 {
+        return Ok(());
+    }
+    let owner = match get_quota_owner_local(inode, tx)? {
+        Some(n) => n,
+        None => return Ok(()),
+    };
+    check_quota_local(owner, bytes, inodes, tx)?;
+    // sqlite numbers parameters in the order of appearance
+    let sql = "UPDATE quota SET bytes_used=max(bytes_used + $1, 0), inodes_used=max(inodes_used + $2, 0) \
+        WHERE (kind=0 AND id=$3) OR (kind=1 AND id=$4) OR (kind=2 AND id=$5)";
+    tx.execute(sql, params![bytes, inodes, owner.0, owner.1, owner.2])?;
+    Ok(())
+}
+
+// Count bytes and inodes of a quota from the tables.
+fn count_quota_local(kind: u32, id: u32, tx: &Connection) -> Result<(u64, u64)> {
+    let column = match kind {
+        QUOTA_USER => "uid",
+        QUOTA_GROUP => "gid",
+        _ => "project",
+    };
+    let sql = format!("SELECT coalesce(sum((SELECT sum({}) FROM data WHERE file_id=metadata.id)), 0), count(id) \
+        FROM metadata WHERE {}=$1", BLOCK_LENGTH_SQL, column);
+    let (bytes, inodes): (i64, i64) = tx.query_row(&sql, params![id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    Ok((bytes as u64, inodes as u64))
+}
+
+fn update_quota_usage_local(kind: u32, id: u32, bytes: u64, inodes: u64, tx: &Connection) -> Result<()> {
+    let sql = "UPDATE quota SET bytes_used=$1, inodes_used=$2 WHERE kind=$3 AND id=$4";
+    tx.execute(sql, params![bytes as i64, inodes as i64, kind, id])?;
+    Ok(())
+}
+
+fn list_quota_local(tx: &Connection) -> Result<Vec<DBQuota>> {
+    let sql = "SELECT kind, id, bytes_limit, inodes_limit, bytes_used, inodes_used FROM quota ORDER BY kind, id";
+    let mut stmt = tx.prepare(sql)?;
+    let rows = stmt.query_map(params![], |row| {
+        Ok(DBQuota{
+            kind: const_to_quota_kind(row.get(0)?),
+            id: row.get(1)?,
+            bytes_limit: row.get::<_, i64>(2)? as u64,
+            inodes_limit: row.get::<_, i64>(3)? as u64,
+            bytes_used: row.get::<_, i64>(4)? as u64,
+            inodes_used: row.get::<_, i64>(5)? as u64,
+        })
+    })?;
+    let mut quotas = Vec::new();
+    for row in rows {
+        quotas.push(row?);
+    }
+    Ok(quotas)
+}
+
+fn delete_inode_local(inode: u64, tx: &Connection) -> Result<()> {
+    let bytes = stored_bytes_local(inode, 0, tx)?;
+    charge_quota_local(inode, -bytes, -1, tx)?;
+    unref_blocks_local(inode, 0, i64::MAX, tx)?;
+    keep_snapshot_inode_local(inode, tx)?;
+    tx.execute("DELETE FROM metadata WHERE id=$1", params![inode as i64])?;
+    Ok(())
+}
+
+fn update_time(inode: u64, sql: &str, time: DateTime<Utc>, tx: &Connection) -> Result<()> {
+    keep_snapshot_rows_local("metadata", "id=$1", params![inode as i64], tx)?;
     let mut stmt = tx.prepare(sql)?;
-    let params = params![&time.format("%Y-%m-%d %H:%M:%S").to_string(), time.timestamp_subsec_nanos(), inode];
+    let params = params![&time.format("%Y-%m-%d %H:%M:%S").to_string(), time.timestamp_subsec_nanos(), inode as i64];
     stmt.execute(params)?;
     Ok(())
 }
 
-fn update_atime(inode: u32, time: DateTime<Utc>, tx: &Connection) -> Result<()> {
+fn update_size_local(inode: u64, size: u64, tx: &Connection) -> Result<()> {
+    keep_snapshot_rows_local("metadata", "id=$1", params![inode as i64], tx)?;
+    tx.execute("UPDATE metadata SET size=$1 WHERE id=$2", params![size as i64, inode as i64])?;
+    Ok(())
+}
+
+fn update_atime(inode: u64, time: DateTime<Utc>, tx: &Connection) -> Result<()> {
     let sql = "UPDATE metadata SET atime=datetime($1), atime_nsec=$2 WHERE id=$3";
     update_time(inode, sql, time, tx)
 }
 
-fn update_mtime(inode: u32, time: DateTime<Utc>, tx: &Connection) -> Result<()> {
+fn update_mtime(inode: u64, time: DateTime<Utc>, tx: &Connection) -> Result<()> {
     let sql = "UPDATE metadata SET mtime=datetime($1), mtime_nsec=$2 WHERE id=$3";
     update_time(inode, sql, time, tx)
 }
 
-fn update_ctime(inode: u32, time: DateTime<Utc>, tx: &Connection) -> Result<()> {
+fn update_ctime(inode: u64, time: DateTime<Utc>, tx: &Connection) -> Result<()> {
     let sql = "UPDATE metadata SET ctime=datetime($1), ctime_nsec=$2 WHERE id=$3";
     update_time(inode, sql, time, tx)
 }
 
+// A file is charged to one project, so it can't be linked or moved into a tree of another project which has a quota.
+fn other_project_local(inode: u64, parent: u64, tx: &Connection) -> Result<bool> {
+    let sql = "SELECT count(*) FROM quota WHERE kind=$1 \
+        AND id=(SELECT project FROM metadata WHERE id=$2) AND id!=(SELECT project FROM metadata WHERE id=$3)";
+    let count: u32 = tx.query_row(sql, params![QUOTA_PROJECT, parent as i64, inode as i64], |row| row.get(0))?;
+    Ok(count > 0)
+}
+
+fn check_filename(name: &str) -> Result<()> {
+    if name.len() > NAME_MAX {
+        return Err(Error::from(ErrorKind::FsNameTooLong {description: name.to_string()}));
+    }
+    Ok(())
+}
+
 fn add_dentry(entry: DEntry, tx: &Connection) -> Result<()> {
-    let sql = "INSERT INTO dentry VALUES($1, $2, $3, $4)";
+    check_filename(&entry.filename)?;
+    check_reserved_name(entry.parent_ino, &entry.filename)?;
+    let sql = format!("INSERT INTO dentry (parent_id, child_id, file_type, name, generation) VALUES($1, $2, $3, $4, {})", GENERATION_SQL);
     tx.execute(
-        sql,
+        &sql,
         params![
-            entry.parent_ino,
-            entry.child_ino,
+            entry.parent_ino as i64,
+            entry.child_ino as i64,
             file_type_to_const(entry.file_type),
             entry.filename
             ]
@@ -117,10 +789,13 @@ fn add_dentry(entry: DEntry, tx: &Connection) -> Result<()> {
 
 fn parse_attr(mut stmt: Statement, params: &[&dyn ToSql]) -> Result<Option<DBFileAttr>> {
     let rows = stmt.query_map(params, |row| {
+        let ino: i64 = row.get(0)?;
+        let size: i64 = row.get(1)?;
+        let blocks: i64 = row.get(17).unwrap_or(0);
         Ok(DBFileAttr {
-            ino: row.get(0)?,
-            size: row.get(1)?,
-            blocks: row.get(17).unwrap_or(0),
+            ino: ino as u64,
+            size: size as u64,
+            blocks: blocks as u64,
             atime: string_to_systemtime(row.get(2)?, row.get(3)?),
             mtime: string_to_systemtime(row.get(4)?, row.get(5)?),
             ctime: string_to_systemtime(row.get(6)?, row.get(7)?),
@@ -145,8 +820,14 @@ fn parse_attr(mut stmt: Statement, params: &[&dyn ToSql]) -> Result<Option<DBFil
     }
 }
 
-fn get_inode_local(inode: u32, tx: &Connection) -> Result<Option<DBFileAttr>> {
-    let sql = "SELECT \
+fn get_inode_local(inode: u64, tx: &Connection) -> Result<Option<DBFileAttr>> {
+    if inode == SNAPSHOT_DIR_INO {
+        return snapshot_dir_attr_local(tx);
+    }
+    if let Some((snapshot, ino)) = split_snapshot_ino(inode) {
+        return get_snapshot_inode_local(snapshot, ino, tx);
+    }
+    let sql = format!("SELECT \
             metadata.id,\
             metadata.size,\
             metadata.atime,\
@@ -159,28 +840,27 @@ fn get_inode_local(inode: u32, tx: &Connection) -> Result<Option<DBFileAttr>> {
             metadata.crtime_nsec,\
             metadata.kind, \
             metadata.mode,\
-            ncount.nlink,\
+            ifnull(ncount.nlink, 0),\
             metadata.uid,\
             metadata.gid,\
             metadata.rdev,\
             metadata.flags,\
-            blocknum.block_num \
+            {} \
             FROM metadata \
-            LEFT JOIN (SELECT count(block_num) block_num FROM data WHERE file_id=$1) AS blocknum \
             LEFT JOIN ( SELECT COUNT(child_id) nlink FROM dentry WHERE child_id=$1 GROUP BY child_id) AS ncount \
-            WHERE id=$1";
-    let stmt = tx.prepare(sql)?;
-    let params = params![inode];
+            WHERE id=$1", BLOCKS_SQL);
+    let stmt = tx.prepare(&sql)?;
+    let params = params![inode as i64];
     parse_attr(stmt, params)
 }
 
-fn get_dentry_single(parent: u32, name: &str, tx: &Connection) -> Result<Option<DEntry>> {
+fn get_dentry_single(parent: u64, name: &str, tx: &Connection) -> Result<Option<DEntry>> {
     let sql = "SELECT child_id, file_type FROM dentry WHERE  parent_id=$1 and name=$2";
     let mut stmt = tx.prepare(sql)?;
     let res: Option<DEntry> = match stmt.query_row(
-        params![parent, name], |row| Ok(Some(DEntry{
+        params![parent as i64, name], |row| Ok(Some(DEntry{
             parent_ino: parent,
-            child_ino: row.get(0)?,
+            child_ino: row.get::<_, i64>(0)? as u64,
             file_type: const_to_file_type(row.get(1)?),
             filename: name.to_string()
         }))
@@ -197,22 +877,24 @@ fn get_dentry_single(parent: u32, name: &str, tx: &Connection) -> Result<Option<
     Ok(res)
 }
 
-fn delete_dentry_local(parent: u32, name: &str, tx: &Connection) -> Result<()> {
+fn delete_dentry_local(parent: u64, name: &str, tx: &Connection) -> Result<()> {
+    keep_snapshot_rows_local("dentry", "parent_id=$1 AND name=$2", params![parent as i64, name], tx)?;
     let sql = "DELETE FROM dentry WHERE parent_id=$1 and name=$2";
-    tx.execute(sql, params![parent, name])?;
+    tx.execute(sql, params![parent as i64, name])?;
     Ok(())
 }
 
-fn delete_sub_dentry(id: u32, tx: &Connection) -> Result<()> {
+fn delete_sub_dentry(id: u64, tx: &Connection) -> Result<()> {
+    keep_snapshot_rows_local("dentry", "parent_id=$1", params![id as i64], tx)?;
     let sql = "DELETE FROM dentry WHERE parent_id=$1";
-    tx.execute(sql, params![id])?;
+    tx.execute(sql, params![id as i64])?;
     Ok(())
 }
 
-fn check_directory_is_empty_local(inode: u32, tx: &Connection) -> Result<bool> {
+fn check_directory_is_empty_local(inode: u64, tx: &Connection) -> Result<bool> {
     let sql = "SELECT name FROM dentry where parent_id=$1";
     let mut stmt = tx.prepare(sql)?;
-    let rows = stmt.query_map(params![inode], |row| {
+    let rows = stmt.query_map(params![inode as i64], |row| {
         Ok({
             let name: String;
             name = row.get(0)?;
@@ -228,8 +910,8 @@ fn check_directory_is_empty_local(inode: u32, tx: &Connection) -> Result<bool> {
     Ok(true)
 }
 
-fn add_inode_local(attr: &DBFileAttr, tx: &Connection) -> Result<u32> {
-    let sql = "INSERT INTO metadata \
+fn add_inode_local(attr: &DBFileAttr, tx: &Connection) -> Result<u64> {
+    let sql = format!("INSERT INTO metadata \
             (size,\
             atime,\
             atime_nsec,\
@@ -245,16 +927,17 @@ fn add_inode_local(attr: &DBFileAttr, tx: &Connection) -> Result<u32> {
             uid,\
             gid,\
             rdev,\
-            flags\
+            flags,\
+            generation\
             ) \
-            VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16)";
+            VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, {})", GENERATION_SQL);
     let atime = DateTime::<Utc>::from(attr.atime);
     let mtime = DateTime::<Utc>::from(attr.mtime);
     let ctime = DateTime::<Utc>::from(attr.ctime);
     let crtime = DateTime::<Utc>::from(attr.crtime);
     {
-        tx.execute(sql, params![
-            attr.size,
+        tx.execute(&sql, params![
+            attr.size as i64,
             atime.format("%Y-%m-%d %H:%M:%S").to_string(),
             atime.timestamp_subsec_nanos(),
             mtime.format("%Y-%m-%d %H:%M:%S").to_string(),
@@ -272,104 +955,1084 @@ fn add_inode_local(attr: &DBFileAttr, tx: &Connection) -> Result<u32> {
             attr.flags,
         ])?;
     }
-    let sql = "SELECT last_insert_rowid()";
-    let child: u32;
-    {
-        let mut stmt = tx.prepare(sql)?;
-        child = stmt.query_row(params![], |row| row.get(0))?;
+    let sql = "SELECT last_insert_rowid()";
+    let child: i64;
+    {
+        let mut stmt = tx.prepare(sql)?;
+        child = stmt.query_row(params![], |row| row.get(0))?;
+    }
+    Ok(child as u64)
+}
+
+// lock ranges are stored as sqlite integer (i64)
+fn lock_offset(offset: u64) -> i64 {
+    if offset > i64::MAX as u64 {
+        i64::MAX
+    } else {
+        offset as i64
+    }
+}
+
+fn get_conflict_lock_local(lock: &DBLock, session: i64, tx: &Connection) -> Result<Option<DBLock>> {
+    if lock.typ == F_UNLCK as u32 {
+        return Ok(None);
+    }
+    let sql = "SELECT lock_owner, start_offset, end_offset, lock_type, pid FROM lock \
+        WHERE file_id=$1 \
+        AND start_offset <= $2 AND end_offset >= $3 \
+        AND (session != $4 OR lock_owner != $5) \
+        AND (lock_type = $6 OR $7 = $6) \
+        ORDER BY start_offset LIMIT 1";
+    let mut stmt = tx.prepare(sql)?;
+    let res = match stmt.query_row(
+        params![
+            lock.ino as i64,
+            lock_offset(lock.end),
+            lock_offset(lock.start),
+            session,
+            lock.lock_owner as i64,
+            F_WRLCK as u32,
+            lock.typ
+        ], |row| {
+            let lock_owner: i64 = row.get(0)?;
+            let start: i64 = row.get(1)?;
+            let end: i64 = row.get(2)?;
+            Ok(DBLock{
+                ino: lock.ino,
+                lock_owner: lock_owner as u64,
+                start: start as u64,
+                end: end as u64,
+                typ: row.get(3)?,
+                pid: row.get(4)?,
+            })
+        }
+    ) {
+        Ok(n) => Some(n),
+        Err(err) => {
+            if err == rusqlite::Error::QueryReturnedNoRows {
+                None
+            } else {
+                return Err(Error::from(err))
+            }
+        }
+    };
+    Ok(res)
+}
+
+// A conflicting lock of a process which is gone is released, and the lock is checked again.
+fn find_conflict_lock_local(lock: &DBLock, session: i64, tx: &Connection) -> Result<Option<DBLock>> {
+    match get_conflict_lock_local(lock, session, tx)? {
+        Some(_) if release_dead_lock_local(tx)? => get_conflict_lock_local(lock, session, tx),
+        n => Ok(n),
+    }
+}
+
+// The upper bits of a session id are the process id which made it.
+// A lock of a process id which is reused by another process is kept.
+fn session_alive(session: i64) -> bool {
+    let pid = (session >> 32) as i32;
+    if pid as u32 == std::process::id() {
+        return true;
+    }
+    match signal::kill(Pid::from_raw(pid), None) {
+        Ok(_) => true,
+        Err(nix::Error::Sys(Errno::EPERM)) => true,
+        Err(_) => false,
+    }
+}
+
+// Delete locks of crashed or killed processes. Returns true if any lock is deleted.
+fn release_dead_lock_local(tx: &Connection) -> Result<bool> {
+    let mut sessions: Vec<i64> = Vec::new();
+    {
+        let mut stmt = tx.prepare("SELECT DISTINCT session FROM lock")?;
+        let rows = stmt.query_map(NO_PARAMS, |row| row.get(0))?;
+        for row in rows {
+            sessions.push(row?);
+        }
+    }
+    let mut released = false;
+    for session in sessions.into_iter().filter(|n| !session_alive(*n)) {
+        debug!("release locks of dead session {}", session);
+        tx.execute("DELETE FROM lock WHERE session=$1", params![session])?;
+        released = true;
+    }
+    Ok(released)
+}
+
+// Delete sessions of crashed or killed processes.
+fn release_dead_session_local(tx: &Connection) -> Result<()> {
+    for session in query_id_list("SELECT id FROM session", tx)? {
+        if !session_alive(session as i64) {
+            debug!("release dead session {}", session);
+            tx.execute("DELETE FROM session WHERE id=$1", params![session as i64])?;
+        }
+    }
+    Ok(())
+}
+
+fn add_lock_local(lock: &DBLock, session: i64, tx: &Connection) -> Result<()> {
+    let sql = "INSERT INTO lock \
+        (file_id, session, lock_owner, start_offset, end_offset, lock_type, pid) \
+        VALUES($1, $2, $3, $4, $5, $6, $7)";
+    tx.execute(sql, params![
+        lock.ino as i64,
+        session,
+        lock.lock_owner as i64,
+        lock_offset(lock.start),
+        lock_offset(lock.end),
+        lock.typ,
+        lock.pid
+    ])?;
+    Ok(())
+}
+
+// Identify locks of this connection. Other processes may open the same database file.
+fn new_session_id() -> i64 {
+    let nsec = match SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+        Ok(n) => n.subsec_nanos(),
+        Err(_) => 0
+    };
+    (i64::from(std::process::id()) << 32) | i64::from(nsec)
+}
+
+// A savepoint works as a transaction, and also can be nested in a transaction started by begin_transaction.
+// rusqlite's Savepoint doesn't release itself after rollback, which leaves the outer transaction open.
+struct Savepoint<'conn> {
+    conn: &'conn Connection,
+    committed: bool,
+}
+
+impl<'conn> Savepoint<'conn> {
+    fn new(conn: &'conn Connection) -> Result<Self> {
+        conn.execute_batch("SAVEPOINT fs")?;
+        Ok(Savepoint { conn, committed: false })
+    }
+
+    fn commit(mut self) -> Result<()> {
+        self.conn.execute_batch("RELEASE fs")?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Deref for Savepoint<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn
+    }
+}
+
+impl Drop for Savepoint<'_> {
+    fn drop(&mut self) {
+        if !self.committed {
+            if let Err(err) = self.conn.execute_batch("ROLLBACK TO fs; RELEASE fs") {
+                error!("{}", err);
+            }
+        }
+    }
+}
+
+const LOST_FOUND: &str = "lost+found";
+
+fn fsck_issue(issues: &mut Vec<FsckIssue>, ino: u64, description: String) {
+    issues.push(FsckIssue{ino, description, repaired: false});
+}
+
+fn query_id_list(sql: &str, tx: &Connection) -> Result<Vec<u64>> {
+    let mut stmt = tx.prepare(sql)?;
+    let rows = stmt.query_map(params![], |row| row.get::<_, i64>(0))?;
+    let mut list = Vec::new();
+    for row in rows {
+        list.push(row? as u64);
+    }
+    Ok(list)
+}
+
+// Rows which refer to deleted inodes. Old databases may not have foreign key constraints.
+fn fsck_dangling_rows(issues: &mut Vec<FsckIssue>, tx: &Connection) -> Result<()> {
+    let sql = "SELECT parent_id, child_id, name FROM dentry \
+        WHERE child_id NOT IN (SELECT id FROM metadata) OR parent_id NOT IN (SELECT id FROM metadata)";
+    let mut entries: Vec<(i64, i64, String)> = Vec::new();
+    {
+        let mut stmt = tx.prepare(sql)?;
+        let rows = stmt.query_map(params![], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        for row in rows {
+            entries.push(row?);
+        }
+    }
+    for (parent, child, name) in entries {
+        fsck_issue(issues, child as u64, format!("dentry {}/{} refers to a missing inode", parent, name));
+        delete_dentry_local(parent as u64, &name, tx)?;
+    }
+    for (table, what) in [("data", "data blocks"), ("xattr", "xattrs"), ("lock", "locks")].iter() {
+        let sql = format!("SELECT DISTINCT file_id FROM {} WHERE file_id NOT IN (SELECT id FROM metadata)", table);
+        for id in query_id_list(&sql, tx)? {
+            fsck_issue(issues, id, format!("{} of a missing inode", what));
+            if *table == "data" {
+                unref_blocks_local(id, i64::MIN, i64::MAX, tx)?;
+            }
+            tx.execute(&format!("DELETE FROM {} WHERE file_id=$1", table), params![id as i64])?;
+        }
+    }
+    // locks can't be held while the filesystem is not mounted.
+    for id in query_id_list("SELECT DISTINCT file_id FROM lock", tx)? {
+        fsck_issue(issues, id, "stale lock".to_string());
+        tx.execute("DELETE FROM lock WHERE file_id=$1", params![id as i64])?;
+    }
+    Ok(())
+}
+
+fn fsck_file_type(issues: &mut Vec<FsckIssue>, tx: &Connection) -> Result<()> {
+    let sql = "SELECT dentry.parent_id, dentry.name, dentry.child_id, metadata.kind FROM dentry \
+        INNER JOIN metadata ON dentry.child_id = metadata.id \
+        WHERE dentry.file_type != metadata.kind";
+    let mut entries: Vec<(i64, String, i64, u32)> = Vec::new();
+    {
+        let mut stmt = tx.prepare(sql)?;
+        let rows = stmt.query_map(params![], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?;
+        for row in rows {
+            entries.push(row?);
+        }
+    }
+    for (parent, name, child, kind) in entries {
+        fsck_issue(issues, child as u64, format!("file type of dentry {}/{} differs from the inode", parent, name));
+        keep_snapshot_rows_local("dentry", "parent_id=$1 AND name=$2", params![parent, name], tx)?;
+        tx.execute(
+            "UPDATE dentry SET file_type=$1 WHERE parent_id=$2 AND name=$3",
+            params![kind, parent, name]
+        )?;
+    }
+    Ok(())
+}
+
+// Get parent entries of a directory, except "." and "..".
+fn get_parent_dentry_local(inode: u64, tx: &Connection) -> Result<Vec<(u64, String)>> {
+    let sql = "SELECT parent_id, name FROM dentry \
+        WHERE child_id=$1 AND name != '.' AND name != '..' ORDER BY parent_id, name";
+    let mut stmt = tx.prepare(sql)?;
+    let rows = stmt.query_map(params![inode as i64], |row| {
+        Ok((row.get::<_, i64>(0)? as u64, row.get(1)?))
+    })?;
+    let mut entries = Vec::new();
+    for row in rows {
+        entries.push(row?);
+    }
+    Ok(entries)
+}
+
+fn get_dotdot_local(inode: u64, tx: &Connection) -> Result<Option<u64>> {
+    Ok(get_dentry_single(inode, "..", tx)?.map(|n| n.child_ino))
+}
+
+// A directory which has several parents makes a cycle.
+fn fsck_directory_link(issues: &mut Vec<FsckIssue>, tx: &Connection) -> Result<()> {
+    let sql = format!("SELECT id FROM metadata WHERE kind={} AND id != 1", DB_IFDIR);
+    for dir in query_id_list(&sql, tx)? {
+        let parents = get_parent_dentry_local(dir, tx)?;
+        if parents.len() < 2 {
+            continue;
+        }
+        let dotdot = get_dotdot_local(dir, tx)?;
+        let keep = parents.iter().position(|(p, _)| Some(*p) == dotdot).unwrap_or(0);
+        for (i, (parent, name)) in parents.iter().enumerate() {
+            if i != keep {
+                fsck_issue(issues, dir, format!("directory has an extra hard link {}/{}", parent, name));
+                delete_dentry_local(*parent, name, tx)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn get_lost_found_local(tx: &Connection) -> Result<u64> {
+    if let Some(n) = get_dentry_single(1, LOST_FOUND, tx)? {
+        return Ok(n.child_ino);
+    }
+    let now = SystemTime::now();
+    let attr = DBFileAttr {
+        ino: 0,
+        size: 0,
+        blocks: 0,
+        atime: now,
+        mtime: now,
+        ctime: now,
+        crtime: now,
+        kind: FileType::Directory,
+        perm: 0o700,
+        nlink: 0,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        flags: 0
+    };
+    let ino = add_inode_local(&attr, tx)?;
+    charge_quota_local(ino, 0, 1, tx)?;
+    add_dentry(DEntry{parent_ino: 1, child_ino: ino, filename: LOST_FOUND.to_string(), file_type: FileType::Directory}, tx)?;
+    add_dentry(DEntry{parent_ino: ino, child_ino: ino, filename: ".".to_string(), file_type: FileType::Directory}, tx)?;
+    add_dentry(DEntry{parent_ino: ino, child_ino: 1, filename: "..".to_string(), file_type: FileType::Directory}, tx)?;
+    Ok(ino)
+}
+
+fn mark_reachable(from: u64, children: &HashMap<u64, Vec<u64>>, reachable: &mut HashSet<u64>) {
+    let mut queue = VecDeque::new();
+    if reachable.insert(from) {
+        queue.push_back(from);
+    }
+    while let Some(ino) = queue.pop_front() {
+        if let Some(list) = children.get(&ino) {
+            for child in list {
+                if reachable.insert(*child) {
+                    queue.push_back(*child);
+                }
+            }
+        }
+    }
+}
+
+// Reattach inodes which can't be reached from the root directory to lost+found.
+fn fsck_unreachable(issues: &mut Vec<FsckIssue>, tx: &Connection) -> Result<()> {
+    let mut children: HashMap<u64, Vec<u64>> = HashMap::new();
+    let mut parents: HashMap<u64, Vec<u64>> = HashMap::new();
+    {
+        let sql = "SELECT parent_id, child_id FROM dentry WHERE name != '.' AND name != '..'";
+        let mut stmt = tx.prepare(sql)?;
+        let rows = stmt.query_map(params![], |row| Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64)))?;
+        for row in rows {
+            let (parent, child) = row?;
+            children.entry(parent).or_default().push(child);
+            parents.entry(child).or_default().push(parent);
+        }
+    }
+    let mut reachable = HashSet::new();
+    mark_reachable(1, &children, &mut reachable);
+    let mut unreachable: Vec<u64> = query_id_list("SELECT id FROM metadata ORDER BY id", tx)?
+        .into_iter()
+        .filter(|n| !reachable.contains(n))
+        .collect();
+    while !unreachable.is_empty() {
+        // Prefer the top of an unreachable tree. If every inode has an unreachable parent, it is a cycle.
+        let pos = unreachable.iter().position(|n| match parents.get(n) {
+            Some(list) => list.iter().all(|p| reachable.contains(p)),
+            None => true,
+        }).unwrap_or(0);
+        let ino = unreachable[pos];
+        let attr = match get_inode_local(ino, tx)? {
+            Some(n) => n,
+            None => {
+                unreachable.remove(pos);
+                continue;
+            }
+        };
+        let lost_found = get_lost_found_local(tx)?;
+        reachable.insert(lost_found);
+        if attr.kind == FileType::Directory {
+            // a directory has only one parent
+            for (parent, name) in get_parent_dentry_local(ino, tx)? {
+                delete_dentry_local(parent, &name, tx)?;
+            }
+            delete_dentry_local(ino, "..", tx)?;
+            add_dentry(DEntry{parent_ino: ino, child_ino: lost_found, filename: "..".to_string(), file_type: FileType::Directory}, tx)?;
+        }
+        let name = format!("#{}", ino);
+        add_dentry(DEntry{parent_ino: lost_found, child_ino: ino, filename: name.clone(), file_type: attr.kind}, tx)?;
+        fsck_issue(issues, ino, format!("unreachable inode is moved to /{}/{}", LOST_FOUND, name));
+        mark_reachable(ino, &children, &mut reachable);
+        unreachable.retain(|n| !reachable.contains(n));
+    }
+    Ok(())
+}
+
+fn fsck_dot_entry(issues: &mut Vec<FsckIssue>, tx: &Connection) -> Result<()> {
+    let sql = format!("SELECT id FROM metadata WHERE kind={}", DB_IFDIR);
+    for dir in query_id_list(&sql, tx)? {
+        if get_dentry_single(dir, ".", tx)?.map(|n| n.child_ino) != Some(dir) {
+            fsck_issue(issues, dir, "directory has no valid \".\" entry".to_string());
+            delete_dentry_local(dir, ".", tx)?;
+            add_dentry(DEntry{parent_ino: dir, child_ino: dir, filename: ".".to_string(), file_type: FileType::Directory}, tx)?;
+        }
+        let parent = if dir == 1 {
+            1
+        } else {
+            match get_parent_dentry_local(dir, tx)?.first() {
+                Some((n, _)) => *n,
+                None => continue,
+            }
+        };
+        if get_dotdot_local(dir, tx)? != Some(parent) {
+            fsck_issue(issues, dir, "directory has no valid \"..\" entry".to_string());
+            delete_dentry_local(dir, "..", tx)?;
+            add_dentry(DEntry{parent_ino: dir, child_ino: parent, filename: "..".to_string(), file_type: FileType::Directory}, tx)?;
+        }
+    }
+    Ok(())
+}
+
+// An entry created before snapshots were supported is hidden by the directory of snapshots.
+fn fsck_reserved_name(issues: &mut Vec<FsckIssue>, tx: &Connection) -> Result<()> {
+    if let Some((child, name)) = rename_reserved_entry_local(tx)? {
+        fsck_issue(issues, child, format!("dentry 1/{} is hidden by the directory of snapshots, renamed to {}", SNAPSHOT_DIR_NAME, name));
+    }
+    Ok(())
+}
+
+fn fsck_data_block(issues: &mut Vec<FsckIssue>, block_size: u64, tx: &Connection) -> Result<()> {
+    let sql = "SELECT metadata.id, metadata.size FROM metadata \
+        INNER JOIN data ON data.file_id = metadata.id \
+        WHERE data.block_num < 1 OR data.block_num > (metadata.size + $1 - 1) / $1 \
+        GROUP BY metadata.id";
+    let mut files: Vec<(i64, i64)> = Vec::new();
+    {
+        let mut stmt = tx.prepare(sql)?;
+        let rows = stmt.query_map(params![block_size as i64], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        for row in rows {
+            files.push(row?);
+        }
+    }
+    for (ino, size) in files {
+        fsck_issue(issues, ino as u64, "data blocks exist beyond the file size".to_string());
+        let last_block = (size as u64).div_ceil(block_size);
+        unref_blocks_local(ino as u64, i64::MIN, 0, tx)?;
+        unref_blocks_local(ino as u64, last_block as i64 + 1, i64::MAX, tx)?;
+        tx.execute(
+            "DELETE FROM data WHERE file_id=$1 AND (block_num < 1 OR block_num > $2)",
+            params![ino, last_block as i64]
+        )?;
+    }
+    Ok(())
+}
+
+// Reference counts of deduplicated blocks must match the data rows.
+fn fsck_dedup_block(issues: &mut Vec<FsckIssue>, tx: &Connection) -> Result<()> {
+    let sql = "SELECT DISTINCT file_id FROM data WHERE hash IS NOT NULL AND hash NOT IN (SELECT hash FROM block)";
+    for id in query_id_list(sql, tx)? {
+        fsck_issue(issues, id, "data blocks refer to missing deduplicated blocks".to_string());
+        // the blocks become holes
+        tx.execute("DELETE FROM data WHERE file_id=$1 AND hash IS NOT NULL AND hash NOT IN (SELECT hash FROM block)", params![id as i64])?;
+    }
+    let count: i64 = tx.query_row("SELECT count(*) FROM snapshot_data WHERE hash NOT IN (SELECT hash FROM block)", NO_PARAMS, |row| row.get(0))?;
+    if count > 0 {
+        fsck_issue(issues, 0, format!("{} data blocks of snapshots are missing", count));
+        tx.execute("DELETE FROM snapshot_data WHERE hash NOT IN (SELECT hash FROM block)", NO_PARAMS)?;
+    }
+    // blocks are referred by files and snapshots
+    let count = "((SELECT count(*) FROM data WHERE data.hash = block.hash) + \
+        (SELECT count(*) FROM snapshot_data WHERE snapshot_data.hash = block.hash))";
+    let sql = format!("SELECT count(*) FROM block WHERE refcount != {}", count);
+    let wrong: i64 = tx.query_row(&sql, params![], |row| row.get(0))?;
+    if wrong > 0 {
+        fsck_issue(issues, 0, format!("{} deduplicated blocks have wrong reference counts", wrong));
+        tx.execute(&format!("UPDATE block SET refcount={}", count), params![])?;
+        tx.execute("DELETE FROM block WHERE refcount = 0", params![])?;
+    }
+    Ok(())
+}
+
+// Usage of quotas must match the files.
+fn fsck_quota(issues: &mut Vec<FsckIssue>, tx: &Connection) -> Result<()> {
+    for quota in list_quota_local(tx)? {
+        let kind = quota_kind_to_const(quota.kind);
+        let (bytes, inodes) = count_quota_local(kind, quota.id, tx)?;
+        if bytes != quota.bytes_used || inodes != quota.inodes_used {
+            fsck_issue(issues, 0, format!(
+                "{:?} quota of {} records {} bytes and {} inodes, but {} bytes and {} inodes are used",
+                quota.kind, quota.id, quota.bytes_used, quota.inodes_used, bytes, inodes
+            ));
+            update_quota_usage_local(kind, quota.id, bytes, inodes, tx)?;
+        }
+    }
+    Ok(())
+}
+
+// Version 1: tables created before schema versioning.
+fn migrate_v1(tx: &Connection) -> Result<()> {
+    let sql = "CREATE TABLE IF NOT EXISTS metadata(\
+        id integer primary key,\
+        size int default 0 not null,\
+        atime text,\
+        atime_nsec int,\
+        mtime text,\
+        mtime_nsec int,\
+        ctime text,\
+        ctime_nsec int,\
+        crtime text,\
+        crtime_nsec int,\
+        kind int,\
+        mode int,\
+        nlink int default 0 not null,\
+        uid int default 0,\
+        gid int default 0,\
+        rdev int default 0,\
+        flags int default 0 \
+        )";
+    tx.execute(sql, params![])?;
+    let sql = "CREATE TABLE IF NOT EXISTS dentry(\
+        parent_id int,\
+        child_id int,\
+        file_type int,\
+        name text,\
+        foreign key (parent_id) references metadata(id) on delete cascade,\
+        foreign key (child_id) references metadata(id) on delete cascade,\
+        primary key (parent_id, name) \
+        )";
+    tx.execute(sql, params![])?;
+    let sql = "CREATE TABLE IF NOT EXISTS data(\
+        file_id int,\
+        block_num int,\
+        data blob,\
+        foreign key (file_id) references metadata(id) on delete cascade,\
+        primary key (file_id, block_num) \
+        )";
+    tx.execute(sql, params![])?;
+    let sql = "CREATE TABLE IF NOT EXISTS xattr(\
+        file_id int,\
+        name text,\
+        value text,\
+        foreign key (file_id) references metadata(id) on delete cascade,\
+        primary key (file_id, name) \
+        )";
+    tx.execute(sql, params![])?;
+    let sql = "CREATE TABLE IF NOT EXISTS lock(\
+        file_id int,\
+        session int,\
+        lock_owner int,\
+        start_offset int,\
+        end_offset int,\
+        lock_type int,\
+        pid int,\
+        foreign key (file_id) references metadata(id) on delete cascade \
+        )";
+    tx.execute(sql, params![])?;
+    Ok(())
+}
+
+// Version 2: xattr value is binary data.
+fn migrate_v2(tx: &Connection) -> Result<()> {
+    let sql = "CREATE TABLE xattr_v2(\
+        file_id int,\
+        name text,\
+        value blob,\
+        foreign key (file_id) references metadata(id) on delete cascade,\
+        primary key (file_id, name) \
+        )";
+    tx.execute(sql, params![])?;
+    tx.execute("INSERT INTO xattr_v2 SELECT file_id, name, CAST(value AS blob) FROM xattr", params![])?;
+    tx.execute("DROP TABLE xattr", params![])?;
+    // execute() fails because ALTER TABLE RENAME returns rows internally.
+    tx.execute_batch("ALTER TABLE xattr_v2 RENAME TO xattr")?;
+    Ok(())
+}
+
+// Version 3: filesystem settings which are decided at creation.
+fn migrate_v3(tx: &Connection) -> Result<()> {
+    let sql = "CREATE TABLE settings(\
+        name text primary key,\
+        value \
+        )";
+    tx.execute(sql, params![])?;
+    // existing filesystems were created with 4096 byte blocks.
+    let sql = "INSERT INTO settings SELECT 'block_size', 4096 WHERE EXISTS (SELECT id FROM metadata)";
+    tx.execute(sql, params![])?;
+    Ok(())
+}
+
+// Version 4: quotas, and the project of each inode.
+fn migrate_v4(tx: &Connection) -> Result<()> {
+    tx.execute_batch("ALTER TABLE metadata ADD COLUMN project int default 0 not null")?;
+    let sql = "CREATE TABLE quota(\
+        kind int,\
+        id int,\
+        bytes_limit int default 0 not null,\
+        inodes_limit int default 0 not null,\
+        bytes_used int default 0 not null,\
+        inodes_used int default 0 not null,\
+        primary key (kind, id) \
+        )";
+    tx.execute(sql, params![])?;
+    Ok(())
+}
+
+// Version 5: codec tag of each data block. Existing blocks are raw.
+fn migrate_v5(tx: &Connection) -> Result<()> {
+    tx.execute_batch("ALTER TABLE data ADD COLUMN codec int default 0 not null")?;
+    Ok(())
+}
+
+// Version 6: deduplicated blocks which are referred by the hash of data rows.
+fn migrate_v6(tx: &Connection) -> Result<()> {
+    let sql = "CREATE TABLE block(\
+        hash blob primary key,\
+        data blob,\
+        codec int default 0 not null,\
+        refcount int default 0 not null \
+        )";
+    tx.execute(sql, params![])?;
+    tx.execute_batch("ALTER TABLE data ADD COLUMN hash blob")?;
+    // reference counts are recounted by hash
+    tx.execute_batch("CREATE INDEX data_hash ON data(hash)")?;
+    Ok(())
+}
+
+// Version 7: read-only snapshots of the whole filesystem. Rows are shared with files until they are changed.
+// Then rows of metadata, dentry and xattr are copied to the snapshot tables, and data rows are moved to snapshot_data,
+// which refers to the block table.
+fn migrate_v7(tx: &Connection) -> Result<()> {
+    let sql = "CREATE TABLE snapshot(\
+        id integer primary key,\
+        name text unique not null,\
+        created text,\
+        created_nsec int,\
+        generation int default 0 not null \
+        )";
+    tx.execute(sql, params![])?;
+    // rows written before the generation of a snapshot belong to it too
+    for table in &["metadata", "dentry", "xattr", "data"] {
+        tx.execute_batch(&format!("ALTER TABLE {} ADD COLUMN generation int default 0 not null", table))?;
+    }
+    let sql = "CREATE TABLE snapshot_metadata(\
+        snapshot_id int,\
+        id int,\
+        size int,\
+        atime text,\
+        atime_nsec int,\
+        mtime text,\
+        mtime_nsec int,\
+        ctime text,\
+        ctime_nsec int,\
+        crtime text,\
+        crtime_nsec int,\
+        kind int,\
+        mode int,\
+        nlink int,\
+        uid int,\
+        gid int,\
+        rdev int,\
+        flags int,\
+        project int,\
+        primary key (snapshot_id, id) \
+        )";
+    tx.execute(sql, params![])?;
+    let sql = "CREATE TABLE snapshot_dentry(\
+        snapshot_id int,\
+        parent_id int,\
+        child_id int,\
+        file_type int,\
+        name text,\
+        primary key (snapshot_id, parent_id, name) \
+        )";
+    tx.execute(sql, params![])?;
+    let sql = "CREATE TABLE snapshot_data(\
+        snapshot_id int,\
+        file_id int,\
+        block_num int,\
+        hash blob,\
+        primary key (snapshot_id, file_id, block_num) \
+        )";
+    tx.execute(sql, params![])?;
+    let sql = "CREATE TABLE snapshot_xattr(\
+        snapshot_id int,\
+        file_id int,\
+        name text,\
+        value blob,\
+        primary key (snapshot_id, file_id, name) \
+        )";
+    tx.execute(sql, params![])?;
+    // connections which use the filesystem. A snapshot can't be rolled back while others use it.
+    tx.execute("CREATE TABLE session(id int primary key)", params![])?;
+    // the directory of snapshots would hide an existing entry
+    if let Some((_, name)) = rename_reserved_entry_local(tx)? {
+        warn!("/{} is renamed to /{}, because the name is reserved for snapshots", SNAPSHOT_DIR_NAME, name);
+    }
+    Ok(())
+}
+
+/// Schema version of the database. It is stored in "PRAGMA user_version".
+pub const SCHEMA_VERSION: u32 = 7;
+
+/// Migrations applied by init(). MIGRATIONS[n] upgrades the schema from version n to n + 1.
+const MIGRATIONS: [fn(&Connection) -> Result<()>; SCHEMA_VERSION as usize] = [
+    migrate_v1,
+    migrate_v2,
+    migrate_v3,
+    migrate_v4,
+    migrate_v5,
+    migrate_v6,
+    migrate_v7,
+];
+
+fn get_setting_local<T: FromSql>(name: &str, tx: &Connection) -> Result<Option<T>> {
+    let sql = "SELECT value FROM settings WHERE name=$1";
+    let mut stmt = tx.prepare(sql)?;
+    let res = match stmt.query_row(params![name], |row| row.get(0)) {
+        Ok(n) => Some(n),
+        Err(err) => {
+            if err == rusqlite::Error::QueryReturnedNoRows {
+                None
+            } else {
+                return Err(Error::from(err))
+            }
+        }
+    };
+    Ok(res)
+}
+
+fn set_setting_local<T: ToSql>(name: &str, value: T, tx: &Connection) -> Result<()> {
+    tx.execute("REPLACE INTO settings (name, value) VALUES($1, $2)", params![name, value])?;
+    Ok(())
+}
+
+const JOURNAL_MODES: [&str; 6] = ["delete", "truncate", "persist", "memory", "wal", "off"];
+const SYNCHRONOUS_LEVELS: [&str; 4] = ["off", "normal", "full", "extra"];
+
+/// Connection settings of a database file.
+/// Each of them is a sqlite PRAGMA. They are not stored in the database.
+#[derive(Debug, Clone)]
+pub struct SqliteOptions {
+    /// delete, truncate, persist, memory, wal or off
+    pub journal_mode: String,
+    /// off, normal, full or extra
+    pub synchronous: String,
+    /// Milliseconds to wait for a lock held by another connection
+    pub busy_timeout: u32,
+    /// Pages if positive, KiB if negative. Sqlite default if None.
+    pub cache_size: Option<i64>,
+    /// Bytes of memory-mapped I/O. Sqlite default if None.
+    pub mmap_size: Option<u64>,
+    /// Maximum size of the database file in bytes. A write which exceeds it fails with ENOSPC.
+    pub max_size: Option<u64>,
+}
+
+impl Default for SqliteOptions {
+    /// WAL lets other processes read the database while it is written,
+    /// and synchronous=normal is safe with WAL.
+    fn default() -> Self {
+        SqliteOptions {
+            journal_mode: "wal".to_string(),
+            synchronous: "normal".to_string(),
+            busy_timeout: 5000,
+            cache_size: None,
+            mmap_size: None,
+            max_size: None,
+        }
+    }
+}
+
+impl SqliteOptions {
+    /// Parse a mount option such as "journal_mode=wal".
+    /// Returns false if the option is not for sqlite, so it can be passed to fuse.
+    pub fn parse_option(&mut self, option: &str) -> Result<bool> {
+        let (name, value) = match option.find('=') {
+            Some(n) => (&option[..n], &option[n + 1..]),
+            None => return Ok(false),
+        };
+        let invalid = || Error::from(ErrorKind::FsParm {description: format!("invalid option {}", option)});
+        match name {
+            "journal_mode" => {
+                let value = value.to_lowercase();
+                if !JOURNAL_MODES.contains(&value.as_str()) {
+                    return Err(invalid());
+                }
+                self.journal_mode = value;
+            },
+            "synchronous" => {
+                let value = value.to_lowercase();
+                if !SYNCHRONOUS_LEVELS.contains(&value.as_str()) {
+                    return Err(invalid());
+                }
+                self.synchronous = value;
+            },
+            "busy_timeout" => self.busy_timeout = value.parse().map_err(|_| invalid())?,
+            "cache_size" => self.cache_size = Some(value.parse().map_err(|_| invalid())?),
+            "mmap_size" => self.mmap_size = Some(value.parse().map_err(|_| invalid())?),
+            "max_size" => self.max_size = Some(value.parse().map_err(|_| invalid())?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn apply(&self, conn: &Connection) -> Result<()> {
+        conn.busy_timeout(Duration::from_millis(u64::from(self.busy_timeout)))?;
+        // journal_mode and mmap_size return the new value
+        let _: String = conn.query_row(&format!("PRAGMA journal_mode={}", self.journal_mode), NO_PARAMS, |row| row.get(0))?;
+        conn.execute_batch(&format!("PRAGMA synchronous={}", self.synchronous))?;
+        if let Some(n) = self.cache_size {
+            conn.execute_batch(&format!("PRAGMA cache_size={}", n))?;
+        }
+        if let Some(n) = self.mmap_size {
+            let _: i64 = conn.query_row(&format!("PRAGMA mmap_size={}", n), NO_PARAMS, |row| row.get(0))?;
+        }
+        if let Some(n) = self.max_size {
+            let page_size: i64 = conn.query_row("PRAGMA page_size", NO_PARAMS, |row| row.get(0))?;
+            let pages = cmp::max(n as i64 / page_size, 1);
+            let _: i64 = conn.query_row(&format!("PRAGMA max_page_count={}", pages), NO_PARAMS, |row| row.get(0))?;
+        }
+        Ok(())
+    }
+}
+
+// Free bytes of the host filesystem which has the database file, or free memory for an in-memory database.
+fn host_available(path: &Option<PathBuf>) -> u64 {
+    match path {
+        Some(path) => {
+            let dir = match path.parent() {
+                Some(n) if n != Path::new("") => n,
+                _ => Path::new("."),
+            };
+            match statvfs::statvfs(dir) {
+                Ok(n) => n.blocks_available() * n.fragment_size(),
+                Err(_) => 0,
+            }
+        },
+        None => memory_available(),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn memory_available() -> u64 {
+    match sysinfo::sysinfo() {
+        Ok(n) => n.ram_unused(),
+        Err(_) => 0,
     }
-    Ok(child)
 }
 
+#[cfg(not(target_os = "linux"))]
+fn memory_available() -> u64 {
+    0
+}
+
+fn sync_file(path: &Path, datasync: bool) -> Result<()> {
+    let file = File::open(path)?;
+    if datasync {
+        file.sync_data()?;
+    } else {
+        file.sync_all()?;
+    }
+    Ok(())
+}
 
 pub struct Sqlite {
-    conn: Connection
+    conn: Connection,
+    session: i64,
+    block_size: u32,
+    // block size requested for a new filesystem
+    new_block_size: Option<u32>,
+    compression: Codec,
+    // compression requested for a new filesystem
+    new_compression: Option<Codec>,
+    dedup: bool,
+    // dedup mode requested for a new filesystem
+    new_dedup: Option<bool>,
+    // None if the database is in memory
+    path: Option<PathBuf>,
+    max_size: Option<u64>,
 }
 
 impl Sqlite {
+    /// Open a database file without changing its journal mode. Only busy_timeout of the default options is applied.
     pub fn new(path: &Path) -> Result<Self> {
         let conn = Connection::open(path)?;
         // enable foreign key. Sqlite ignores foreign key by default.
         conn.execute("PRAGMA foreign_keys=ON", NO_PARAMS)?;
-        Ok(Sqlite { conn })
+        conn.busy_timeout(Duration::from_millis(u64::from(SqliteOptions::default().busy_timeout)))?;
+        Ok(Self::with_connection(conn, Some(path.to_path_buf()), None))
+    }
+
+    /// Open a database file with connection settings. The journal mode is stored in the database file.
+    pub fn new_with_options(path: &Path, options: &SqliteOptions) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        // enable foreign key. Sqlite ignores foreign key by default.
+        conn.execute("PRAGMA foreign_keys=ON", NO_PARAMS)?;
+        options.apply(&conn)?;
+        Ok(Self::with_connection(conn, Some(path.to_path_buf()), options.max_size))
     }
 
     pub fn new_in_memory() -> Result<Self> {
         let conn = Connection::open_in_memory()?;
         // enable foreign key. Sqlite ignores foreign key by default.
         conn.execute("PRAGMA foreign_keys=ON", NO_PARAMS)?;
-        Ok(Sqlite { conn })
+        Ok(Self::with_connection(conn, None, None))
+    }
+
+    fn with_connection(conn: Connection, path: Option<PathBuf>, max_size: Option<u64>) -> Self {
+        Sqlite {
+            conn,
+            session: new_session_id(),
+            block_size: DEFAULT_BLOCK_SIZE,
+            new_block_size: None,
+            compression: Codec::None,
+            new_compression: None,
+            dedup: false,
+            new_dedup: None,
+            path,
+            max_size,
+        }
+    }
+
+    /// Set block size of a new filesystem. It must be called before init().
+    /// The block size is a power of two between 512 byte and 1 MiB.
+    pub fn set_block_size(&mut self, block_size: u32) -> Result<()> {
+        if !block_size.is_power_of_two() || !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) {
+            return Err(Error::from(ErrorKind::FsParm {description: format!(
+                "block size {} must be a power of two between {} and {}",
+                block_size, MIN_BLOCK_SIZE, MAX_BLOCK_SIZE
+            )}));
+        }
+        self.new_block_size = Some(block_size);
+        Ok(())
+    }
+
+    /// Set compression of data blocks of a new filesystem. It must be called before init().
+    pub fn set_compression(&mut self, codec: Codec) {
+        self.new_compression = Some(codec);
+    }
+
+    /// Compression of data blocks which are written
+    pub fn get_compression(&self) -> Codec {
+        self.compression
+    }
+
+    /// Store identical data blocks only once in a new filesystem. It must be called before init().
+    pub fn set_dedup(&mut self, dedup: bool) {
+        self.new_dedup = Some(dedup);
+    }
+
+    /// Whether data blocks which are written are deduplicated
+    pub fn get_dedup(&self) -> bool {
+        self.dedup
+    }
+
+    /// Write all WAL content back to the database file and truncate the WAL file.
+    /// It does nothing if the database is not in WAL mode.
+    pub fn checkpoint(&self) -> Result<()> {
+        // returns busy flag, WAL pages and checkpointed pages
+        let busy: i64 = self.conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", NO_PARAMS, |row| row.get(0))?;
+        if busy != 0 {
+            return Err(Error::from(ErrorKind::FsLocked {description: "checkpoint is blocked by another connection".to_string()}));
+        }
+        Ok(())
+    }
+
+    /// Open an existing filesystem without changing the database, unlike init().
+    /// Fail with FsSchemaVersion unless the schema is the current version, because an old schema must be migrated.
+    pub fn load(&mut self) -> Result<()> {
+        let version = self.get_schema_version()?;
+        if version != SCHEMA_VERSION {
+            return Err(Error::from(ErrorKind::FsSchemaVersion {description: format!(
+                "database schema version {} is not the current version {}",
+                version, SCHEMA_VERSION
+            )}));
+        }
+        self.block_size = match get_setting_local::<u32>("block_size", &self.conn)? {
+            Some(n) => n,
+            None => return Err(Error::from(ErrorKind::FsSchemaVersion {description: "block size is not stored".to_string()})),
+        };
+        if let Some(n) = get_setting_local::<String>("compression", &self.conn)? {
+            self.compression = Codec::from_name(&n)?;
+        }
+        self.dedup = get_setting_local::<bool>("dedup", &self.conn)?.unwrap_or(false);
+        Ok(())
+    }
+
+    /// Get schema version of the database. A database which is not initialized returns 0.
+    pub fn get_schema_version(&self) -> Result<u32> {
+        let version: u32 = self.conn.query_row("PRAGMA user_version", params![], |row| row.get(0))?;
+        Ok(version)
+    }
+}
+
+impl Drop for Sqlite {
+    fn drop(&mut self) {
+        // the table doesn't exist until init() is called
+        if self.get_schema_version().unwrap_or(0) == SCHEMA_VERSION {
+            if let Err(err) = self.conn.execute("DELETE FROM session WHERE id=$1", params![self.session]) {
+                error!("{}", err);
+            }
+        }
     }
 }
 
 impl DbModule for Sqlite {
     fn init(&mut self) -> Result<()> {
-        let table_search_sql = "SELECT count(name) FROM sqlite_master WHERE type='table' AND name=$1";
-        {
-            let row_count: u32 = self.conn.query_row(table_search_sql, params!["metadata"], |row| row.get(0) )?;
-            if row_count == 0 {
-                let sql = "CREATE TABLE metadata(\
-                    id integer primary key,\
-                    size int default 0 not null,\
-                    atime text,\
-                    atime_nsec int,\
-                    mtime text,\
-                    mtime_nsec int,\
-                    ctime text,\
-                    ctime_nsec int,\
-                    crtime text,\
-                    crtime_nsec int,\
-                    kind int,\
-                    mode int,\
-                    nlink int default 0 not null,\
-                    uid int default 0,\
-                    gid int default 0,\
-                    rdev int default 0,\
-                    flags int default 0 \
-                    )";
-                let res = self.conn.execute(sql, params![])?;
-                debug!("metadata table: {}", res);
-            }
+        let version = self.get_schema_version()?;
+        if version > SCHEMA_VERSION {
+            return Err(Error::from(ErrorKind::FsSchemaVersion {description: format!(
+                "database schema version {} is newer than supported version {}",
+                version, SCHEMA_VERSION
+            )}));
         }
-        {
-            let row_count: u32 = self.conn.query_row(table_search_sql, params!["dentry"], |row| row.get(0) )?;
-            if row_count == 0 {
-                let sql = "CREATE TABLE dentry(\
-                    parent_id int,\
-                    child_id int,\
-                    file_type int,\
-                    name text,\
-                    foreign key (parent_id) references metadata(id) on delete cascade,\
-                    foreign key (child_id) references metadata(id) on delete cascade,\
-                    primary key (parent_id, name) \
-                    )";
-                self.conn.execute(sql, params![])?;
+        if version < SCHEMA_VERSION {
+            let tx = self.conn.transaction()?;
+            for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+                debug!("migrate database schema: version {} -> {}", i, i + 1);
+                migration(&tx)?;
             }
+            tx.execute_batch(&format!("PRAGMA user_version={}", SCHEMA_VERSION))?;
+            tx.commit()?;
         }
-        {
-            let row_count: u32 = self.conn.query_row(table_search_sql, params!["data"], |row| row.get(0) )?;
-            if row_count == 0 {
-                let sql = "CREATE TABLE data(\
-                    file_id int,\
-                    block_num int,\
-                    data blob,\
-                    foreign key (file_id) references metadata(id) on delete cascade,\
-                    primary key (file_id, block_num) \
-                    )";
-                self.conn.execute(sql, params![])?;
+        // block size is stored when the filesystem is created
+        let created = match get_setting_local::<u32>("block_size", &self.conn)? {
+            Some(n) => {
+                if let Some(v) = self.new_block_size {
+                    if v != n {
+                        return Err(Error::from(ErrorKind::FsParm {description: format!(
+                            "block size of the existing filesystem is {}, and can't be changed to {}",
+                            n, v
+                        )}));
+                    }
+                }
+                self.block_size = n;
+                false
+            },
+            None => {
+                self.block_size = self.new_block_size.unwrap_or(DEFAULT_BLOCK_SIZE);
+                set_setting_local("block_size", self.block_size, &self.conn)?;
+                true
+            }
+        };
+        // filesystems created before compression is supported have no setting, and they are not compressed.
+        let compression = match get_setting_local::<String>("compression", &self.conn)? {
+            Some(n) => Some(Codec::from_name(&n)?),
+            None if created => None,
+            None => Some(Codec::None),
+        };
+        match compression {
+            Some(n) => {
+                if let Some(v) = self.new_compression {
+                    if v != n {
+                        return Err(Error::from(ErrorKind::FsParm {description: format!(
+                            "compression of the existing filesystem is {}, and can't be changed to {}",
+                            n.name(), v.name()
+                        )}));
+                    }
+                }
+                self.compression = n;
+            },
+            None => {
+                self.compression = self.new_compression.unwrap_or(Codec::None);
+                set_setting_local("compression", self.compression.name(), &self.conn)?;
             }
         }
-        {
-            let row_count: u32 = self.conn.query_row(table_search_sql, params!["xattr"], |row| row.get(0) )?;
-            if row_count == 0 {
-                let sql = "CREATE TABLE xattr(\
-                    file_id int,\
-                    name text,\
-                    value text,\
-                    foreign key (file_id) references metadata(id) on delete cascade,\
-                    primary key (file_id, name) \
-                    )";
-                self.conn.execute(sql, params![])?;
+        let dedup = match get_setting_local::<bool>("dedup", &self.conn)? {
+            Some(n) => Some(n),
+            None if created => None,
+            None => Some(false),
+        };
+        match dedup {
+            Some(n) => {
+                if let Some(v) = self.new_dedup {
+                    if v != n {
+                        return Err(Error::from(ErrorKind::FsParm {description: format!(
+                            "dedup mode of the existing filesystem is {}, and can't be changed",
+                            if n {"on"} else {"off"}
+                        )}));
+                    }
+                }
+                self.dedup = n;
+            },
+            None => {
+                self.dedup = self.new_dedup.unwrap_or(false);
+                set_setting_local("dedup", self.dedup, &self.conn)?;
             }
         }
         {
@@ -422,18 +2085,26 @@ impl DbModule for Sqlite {
                 add_dentry(root_dir, &self.conn)?;
             }
         }
+        release_dead_lock_local(&self.conn)?;
+        self.conn.execute("INSERT OR IGNORE INTO session (id) VALUES($1)", params![self.session])?;
         Ok(())
     }
 
-    fn get_inode(&self, inode: u32) -> Result<Option<DBFileAttr>> {
+    fn get_inode(&self, inode: u64) -> Result<Option<DBFileAttr>> {
         get_inode_local(inode, &self.conn)
     }
 
-    fn add_inode_and_dentry(&mut self, parent: u32, name: &str, attr: &DBFileAttr) -> Result<u32> {
-        let tx = self.conn.transaction()?;
+    fn add_inode_and_dentry(&mut self, parent: u64, name: &str, attr: &DBFileAttr) -> Result<u64> {
+        check_writable(parent)?;
+        let tx = Savepoint::new(&self.conn)?;
         let child = add_inode_local(attr, &tx)?;
         let dentry = DEntry{parent_ino: parent, child_ino: child, filename: String::from(name), file_type: attr.kind};
         add_dentry(dentry, &tx)?;
+        tx.execute(
+            "UPDATE metadata SET project=(SELECT project FROM metadata WHERE id=$1) WHERE id=$2",
+            params![parent as i64, child as i64]
+        )?;
+        charge_quota_local(child, 0, 1, &tx)?;
         if attr.kind == FileType::Directory {
             let dentry = DEntry{parent_ino: child, child_ino: parent, filename: String::from(".."), file_type: attr.kind};
             add_dentry(dentry, &tx)?;
@@ -464,7 +2135,8 @@ impl DbModule for Sqlite {
             rdev=$13,\
             flags=$14 \
              WHERE id=$15";
-        let tx = self.conn.transaction()?;
+        check_writable(attr.ino)?;
+        let tx = Savepoint::new(&self.conn)?;
         let oldattr = get_inode_local(attr.ino, &tx)?;
         let oldattr = match oldattr {
             Some(n) => n,
@@ -484,10 +2156,17 @@ impl DbModule for Sqlite {
             };
         let ctime = now;
         let crtime = DateTime::<Utc>::from(attr.crtime);
+        // usage moves to the new owner
+        let chown = oldattr.uid != attr.uid || oldattr.gid != attr.gid;
+        let bytes = if chown { stored_bytes_local(attr.ino, 0, &tx)? } else { 0 };
+        if chown {
+            charge_quota_local(attr.ino, -bytes, -1, &tx)?;
+        }
+        keep_snapshot_rows_local("metadata", "id=$1", params![attr.ino as i64], &tx)?;
         {
             let mut stmt = tx.prepare(sql)?;
             stmt.execute(params![
-            attr.size,
+            attr.size as i64,
             atime.format("%Y-%m-%d %H:%M:%S").to_string(),
             atime.timestamp_subsec_nanos(),
             mtime.format("%Y-%m-%d %H:%M:%S").to_string(),
@@ -501,38 +2180,43 @@ impl DbModule for Sqlite {
             attr.gid,
             attr.rdev,
             attr.flags,
-            attr.ino
+            attr.ino as i64
             ])?;
         }
+        if chown {
+            charge_quota_local(attr.ino, bytes, 1, &tx)?;
+        }
         if truncate {
-            release_data(attr.ino, attr.size, &tx)?;
+            release_data(attr.ino, attr.size, self.block_size, self.compression, self.dedup, &tx)?;
         }
         tx.commit()?;
         Ok(())
     }
 
-    fn delete_inode_if_noref(&mut self, inode: u32) -> Result<()> {
+    fn delete_inode_if_noref(&mut self, inode: u64) -> Result<()> {
         let sql = "SELECT count(child_id) FROM dentry WHERE child_id=$1";
-        let tx = self.conn.transaction()?;
+        let tx = Savepoint::new(&self.conn)?;
         let nlink: u32;
         {
             let mut stmt = tx.prepare(sql)?;
-            nlink = stmt.query_row(params![inode], |row| row.get(0))?;
+            nlink = stmt.query_row(params![inode as i64], |row| row.get(0))?;
         }
         if nlink == 0 {
-            let sql = "DELETE FROM metadata WHERE id=$1";
-            tx.execute(sql, params![inode])?;
+            delete_inode_local(inode, &tx)?;
         }
         tx.commit()?;
         Ok(())
     }
 
-    fn get_dentry(&self, inode: u32) -> Result<Vec<DEntry>> {
+    fn get_dentry(&self, inode: u64) -> Result<Vec<DEntry>> {
+        if is_snapshot_ino(inode) {
+            return get_snapshot_dentry_local(inode, &self.conn);
+        }
         let sql = "SELECT child_id, file_type, name FROM dentry WHERE parent_id=$1 ORDER BY name";
         let mut stmt = self.conn.prepare(sql)?;
-        let rows = stmt.query_map(params![inode], |row| {
+        let rows = stmt.query_map(params![inode as i64], |row| {
             Ok(DEntry{parent_ino: inode,
-                child_ino: row.get(0)?,
+                child_ino: row.get::<_, i64>(0)? as u64,
                 file_type: const_to_file_type(row.get(1)?),
                 filename: row.get(2)?,
             })
@@ -544,9 +2228,11 @@ impl DbModule for Sqlite {
         Ok(entries)
     }
 
-    fn link_dentry(&mut self, inode: u32, parent: u32, name: &str) -> Result<DBFileAttr> {
+    fn link_dentry(&mut self, inode: u64, parent: u64, name: &str) -> Result<DBFileAttr> {
+        check_writable(inode)?;
+        check_writable(parent)?;
         let now = Utc::now();
-        let tx = self.conn.transaction()?;
+        let tx = Savepoint::new(&self.conn)?;
         let attr = match get_inode_local(inode, &tx)? {
             Some(n) => n,
             None => {
@@ -557,11 +2243,18 @@ impl DbModule for Sqlite {
             }
         };
         if attr.kind != FileType::RegularFile {
-            return Err(Error::from(ErrorKind::FsParm {description: format!(
+            return Err(Error::from(ErrorKind::FsNotPermitted {description: format!(
                 "old path {} is not a regular file",
                 inode
             )}));
         };
+        if other_project_local(inode, parent, &tx)? {
+            return Err(Error::from(ErrorKind::FsCrossDevice {description: format!(
+                "{} belongs to another project than {}",
+                inode,
+                parent
+            )}));
+        }
         let new_inode = get_dentry_single(parent, name, &tx)?;
         if new_inode.is_some() {
             return Err(Error::from(ErrorKind::FsFileExist {description: format!(
@@ -577,21 +2270,24 @@ impl DbModule for Sqlite {
             filename: name.to_string()
         };
         add_dentry(entry, &tx)?;
-        update_mtime(inode, now, &tx)?;
+        update_ctime(inode, now, &tx)?;
         update_mtime(parent, now, &tx)?;
         update_ctime(parent, now, &tx)?;
+        // nlink and ctime are changed
+        let attr = get_inode_local(inode, &tx)?.unwrap_or(attr);
         tx.commit()?;
         Ok(attr)
     }
 
-    fn delete_dentry(&mut self, parent: u32, name: &str) -> Result<u32> {
+    fn delete_dentry(&mut self, parent: u64, name: &str) -> Result<u64> {
         let sql = "SELECT child_id FROM dentry WHERE parent_id=$1 and name=$2";
+        check_writable(parent)?;
         let now = Utc::now();
-        let tx = self.conn.transaction()?;
-        let child: u32;
+        let tx = Savepoint::new(&self.conn)?;
+        let child: u64;
         {
             let mut stmt = tx.prepare(sql)?;
-            child = stmt.query_row(params![parent, name], |row| row.get(0))?;
+            child = stmt.query_row(params![parent as i64, name], |row| row.get::<_, i64>(0))? as u64;
         }
         delete_dentry_local(parent, name, &tx)?;
         delete_sub_dentry(child, &tx)?;
@@ -602,51 +2298,49 @@ impl DbModule for Sqlite {
         Ok(child)
     }
 
-    fn move_dentry(&mut self, parent: u32, name: &str, new_parent: u32, new_name: &str) -> Result<Option<u32>> {
+    fn move_dentry(&mut self, parent: u64, name: &str, new_parent: u64, new_name: &str) -> Result<Option<u64>> {
+        check_filename(new_name)?;
+        check_reserved_name(new_parent, new_name)?;
+        check_writable(parent)?;
+        check_writable(new_parent)?;
         let sql = "UPDATE dentry SET parent_id=$1, name=$2 where parent_id=$3 and name=$4";
         let now = Utc::now();
-        let tx = self.conn.transaction()?;
+        let tx = Savepoint::new(&self.conn)?;
         let dentry = match get_dentry_single(parent, name, &tx)? {
             Some(n) => n,
             None => {
                 return Err(Error::from(ErrorKind::FsNoEnt {description: format!("parent: {} name:{}", parent, name)}));
             }
         };
+        if parent != new_parent && other_project_local(dentry.child_ino, new_parent, &tx)? {
+            return Err(Error::from(ErrorKind::FsCrossDevice {description: format!(
+                "{} belongs to another project than {}",
+                dentry.child_ino,
+                new_parent
+            )}));
+        }
         let mut res = None;
         let exist_entry = get_dentry_single(new_parent, new_name, &tx)?;
         if let Some(v) = exist_entry {
             let exist_id = v.child_ino;
             let exist_file_type = v.file_type;
-            if dentry.file_type != exist_file_type {
-                match exist_file_type {
-                    FileType::Directory => {
-                        return Err(Error::from(ErrorKind::FsIsDir {
-                            description: format!(
-                                "parent: {} name:{}",
-                                new_parent, new_name
-                            )
-                        }));
-                    },
-                    FileType::RegularFile => {
-                        return Err(Error::from(ErrorKind::FsIsNotDir {
-                            description: format!(
-                                "parent: {} name:{}",
-                                new_parent,
-                                new_name
-                            )
-                        }));
-                    },
-                    _ => {
-                        return Err(Error::from(ErrorKind::Undefined {
-                            description: format!(
-                                "parent: {} name:{} has invalid type: {:?}",
-                                new_parent,
-                                new_name,
-                                exist_file_type
-                            )
-                        }));
-                    }
-                };
+            // Any type except a directory can replace each other.
+            if dentry.file_type != FileType::Directory && exist_file_type == FileType::Directory {
+                return Err(Error::from(ErrorKind::FsIsDir {
+                    description: format!(
+                        "parent: {} name:{}",
+                        new_parent, new_name
+                    )
+                }));
+            }
+            if dentry.file_type == FileType::Directory && exist_file_type != FileType::Directory {
+                return Err(Error::from(ErrorKind::FsIsNotDir {
+                    description: format!(
+                        "parent: {} name:{}",
+                        new_parent,
+                        new_name
+                    )
+                }));
             }
             if exist_file_type ==FileType::Directory {
                 let empty = check_directory_is_empty_local(exist_id, &tx)?;
@@ -661,10 +2355,12 @@ impl DbModule for Sqlite {
             delete_dentry_local(new_parent, new_name, &tx)?;
             res = Some(v.child_ino);
         }
-        tx.execute(sql, params![new_parent, new_name, parent, name])?;
+        keep_snapshot_rows_local("dentry", "parent_id=$1 AND name=$2", params![parent as i64, name], &tx)?;
+        tx.execute(sql, params![new_parent as i64, new_name, parent as i64, name])?;
         if parent != new_parent && dentry.file_type == FileType::Directory {
+            keep_snapshot_rows_local("dentry", "parent_id=$1 AND name='..'", params![dentry.child_ino as i64], &tx)?;
             let sql = "UPDATE dentry set child_id=$1 WHERE parent_id=$2 and name='..'";
-            tx.execute(sql, params![new_parent, dentry.child_ino])?;
+            tx.execute(sql, params![new_parent as i64, dentry.child_ino as i64])?;
         }
         update_ctime(dentry.child_ino, now, &tx)?;
         update_mtime(parent, now, &tx)?;
@@ -677,12 +2373,18 @@ impl DbModule for Sqlite {
         Ok(res)
     }
 
-    fn check_directory_is_empty(&self, inode: u32) -> Result<bool> {
+    fn check_directory_is_empty(&self, inode: u64) -> Result<bool> {
         check_directory_is_empty_local(inode,&self.conn)
     }
 
-    fn lookup(&mut self, parent: u32, name: &str) -> Result<Option<DBFileAttr>> {
-        let sql = "SELECT \
+    fn lookup(&mut self, parent: u64, name: &str) -> Result<Option<DBFileAttr>> {
+        if parent == 1 && name == SNAPSHOT_DIR_NAME {
+            return get_inode_local(SNAPSHOT_DIR_INO, &self.conn);
+        }
+        if is_snapshot_ino(parent) {
+            return lookup_snapshot_local(parent, name, &self.conn);
+        }
+        let sql = format!("SELECT \
             metadata.id,\
             metadata.size,\
             metadata.atime,\
@@ -700,59 +2402,47 @@ impl DbModule for Sqlite {
             metadata.gid,\
             metadata.rdev,\
             metadata.flags, \
-            blocknum.block_num \
+            {} \
             FROM dentry \
             INNER JOIN metadata \
             ON metadata.id=dentry.child_id \
             AND dentry.parent_id=$1 \
             AND dentry.name=$2 \
-            LEFT JOIN (SELECT file_id file_id, count(block_num) block_num from data) AS blocknum \
-            ON dentry.child_id = blocknum.file_id \
             LEFT JOIN ( SELECT child_id, COUNT(child_id) nlink FROM dentry GROUP BY child_id) AS ncount \
             ON dentry.child_id = ncount.child_id \
-            ";
-        let tx = self.conn.transaction()?;
-        let stmt = tx.prepare(sql)?;
-        let params = params![parent, name];
+            ", BLOCKS_SQL);
+        let tx = Savepoint::new(&self.conn)?;
+        let stmt = tx.prepare(&sql)?;
+        let params = params![parent as i64, name];
         let result = parse_attr(stmt, params);
         update_atime(parent, Utc::now(), &tx)?;
         tx.commit()?;
         result
     }
 
-    fn get_data(&mut self, inode:u32, block: u32, length: u32) -> Result<Vec<u8>> {
-        let tx = self.conn.transaction()?;
-        let row: Vec<u8>;
-        {
-            let mut stmt = tx.prepare(
-                "SELECT \
-                data FROM data WHERE file_id=$1 AND block_num=$2")?;
-            row = match stmt.query_row(params![inode, block], |row| row.get(0)) {
-                Ok(n) => n,
-                Err(err) => {
-                    if err == rusqlite::Error::QueryReturnedNoRows {
-                        vec![0; length as usize]
-                    } else {
-                        return Err(Error::from(err))
-                    }
-                }
-            };
+    fn get_data(&mut self, inode: u64, block: u64, length: u32, noatime: bool) -> Result<Vec<u8>> {
+        let tx = Savepoint::new(&self.conn)?;
+        let row = match get_block_local(inode, block, self.block_size, &tx)? {
+            Some(n) => n,
+            None => vec![0; length as usize],
+        };
+        if !noatime {
+            update_atime(inode, Utc::now(), &tx)?;
         }
-        update_atime(inode, Utc::now(), &tx)?;
         tx.commit()?;
         Ok(row)
     }
 
-    fn write_data(&mut self, inode:u32, block: u32, data: &[u8], size: u32) -> Result<()> {
-        let tx = self.conn.transaction()?;
+    fn write_data(&mut self, inode: u64, block: u64, data: &[u8], size: u64) -> Result<()> {
+        check_writable(inode)?;
+        let tx = Savepoint::new(&self.conn)?;
         {
-            let db_size: u32 = tx.query_row("SELECT size FROM metadata WHERE id=$1", params![inode], |row| row.get(0))?;
-            tx.execute("REPLACE INTO data \
-            (file_id, block_num, data)
-            VALUES($1, $2, $3)",
-                       params![inode, block, data])?;
-            if size > db_size {
-                tx.execute("UPDATE metadata SET size=$1 WHERE id=$2", params![size, inode])?;
+            let db_size: i64 = tx.query_row("SELECT size FROM metadata WHERE id=$1", params![inode as i64], |row| row.get(0))?;
+            let old_bytes = block_bytes_local(inode, block, &tx)?;
+            put_block_local(inode, block, data, self.compression, self.dedup, &tx)?;
+            charge_quota_local(inode, block_bytes_local(inode, block, &tx)? - old_bytes, 0, &tx)?;
+            if size > db_size as u64 {
+                update_size_local(inode, size, &tx)?;
             }
         }
         let time = Utc::now();
@@ -762,30 +2452,326 @@ impl DbModule for Sqlite {
         Ok(())
     }
 
-    fn release_data(&self, inode: u32) -> Result<()> {
-        self.conn.execute("DELETE FROM data WHERE file_id=$1", params![inode])?;
+    fn write_blocks(&mut self, inode: u64, blocks: &[(u64, Vec<u8>)], size: u64) -> Result<()> {
+        check_writable(inode)?;
+        let max_block = i64::MAX as u64 / u64::from(self.block_size) + 1;
+        if size > i64::MAX as u64 || blocks.iter().any(|n| n.0 == 0 || n.0 > max_block) {
+            return Err(Error::from(ErrorKind::FsParm {description: "invalid range of write".to_string()}));
+        }
+        let tx = Savepoint::new(&self.conn)?;
+        {
+            let db_size: i64 = tx.query_row("SELECT size FROM metadata WHERE id=$1", params![inode as i64], |row| row.get(0))?;
+            let mut bytes = 0;
+            for (block, data) in blocks {
+                bytes -= block_bytes_local(inode, *block, &tx)?;
+                put_block_local(inode, *block, data, self.compression, self.dedup, &tx)?;
+                bytes += block_bytes_local(inode, *block, &tx)?;
+            }
+            charge_quota_local(inode, bytes, 0, &tx)?;
+            if size > db_size as u64 {
+                update_size_local(inode, size, &tx)?;
+            }
+        }
+        let time = Utc::now();
+        update_mtime(inode, time, &tx)?;
+        update_ctime(inode, time, &tx)?;
+        tx.commit()?;
         Ok(())
     }
 
-    fn delete_all_noref_inode(&mut self) -> Result<()> {
-        self.conn.execute(
-            "DELETE FROM metadata WHERE NOT EXISTS (SELECT 'x' FROM dentry WHERE metadata.id = dentry.child_id)",
-            params![]
+    fn seek_data(&self, inode: u64, offset: u64) -> Result<u64> {
+        seek_local(inode, offset, true, self.block_size, &self.conn)
+    }
+
+    fn seek_hole(&self, inode: u64, offset: u64) -> Result<u64> {
+        seek_local(inode, offset, false, self.block_size, &self.conn)
+    }
+
+    fn fallocate(&mut self, inode: u64, offset: u64, length: u64, mode: u32) -> Result<()> {
+        let invalid = |description: &str| Error::from(ErrorKind::FsParm {description: description.to_string()});
+        check_writable(inode)?;
+        let end = match offset.checked_add(length) {
+            Some(n) if length > 0 && n <= i64::MAX as u64 => n,
+            _ => return Err(invalid("invalid range of fallocate")),
+        };
+        let tx = Savepoint::new(&self.conn)?;
+        let attr = match get_inode_local(inode, &tx)? {
+            Some(n) => n,
+            None => return Err(Error::from(ErrorKind::FsNoEnt {description: format!("inode {} is not found", inode)})),
+        };
+        match attr.kind {
+            FileType::RegularFile => {},
+            FileType::Directory => return Err(Error::from(ErrorKind::FsIsDir {description: format!("inode {} is a directory", inode)})),
+            _ => return Err(invalid("fallocate needs a regular file")),
+        }
+        let block_size = u64::from(self.block_size);
+        let keep_size = mode & FALLOC_FL_KEEP_SIZE != 0;
+        let old_bytes = stored_bytes_local(inode, 0, &tx)?;
+        let mut size = attr.size;
+        match mode & !FALLOC_FL_KEEP_SIZE {
+            0 => {
+                // holes are not stored, but the quota must have room for them
+                let (first, last) = (offset / block_size + 1, (end - 1) / block_size + 1);
+                let sql = "SELECT count(*) FROM data WHERE file_id=$1 AND block_num BETWEEN $2 AND $3";
+                let stored: i64 = tx.query_row(sql, params![inode as i64, first as i64, last as i64], |row| row.get(0))?;
+                let holes = (last - first + 1 - stored as u64) * block_size;
+                if let Some(owner) = get_quota_owner_local(inode, &tx)? {
+                    check_quota_local(owner, holes as i64, 0, &tx)?;
+                }
+                if !keep_size && end > size {
+                    size = end;
+                }
+            },
+            FALLOC_FL_PUNCH_HOLE => {
+                if !keep_size {
+                    return Err(invalid("FALLOC_FL_PUNCH_HOLE must be used with FALLOC_FL_KEEP_SIZE"));
+                }
+                zero_range_local(inode, offset, end, self.block_size, self.compression, self.dedup, &tx)?;
+            },
+            FALLOC_FL_ZERO_RANGE => {
+                zero_range_local(inode, offset, end, self.block_size, self.compression, self.dedup, &tx)?;
+                if !keep_size && end > size {
+                    size = end;
+                }
+            },
+            FALLOC_FL_COLLAPSE_RANGE if !keep_size => {
+                let misaligned = (offset | length) % block_size;
+                if misaligned != 0 {
+                    return Err(invalid("FALLOC_FL_COLLAPSE_RANGE needs a range aligned to blocks"));
+                }
+                if end >= size {
+                    return Err(invalid("FALLOC_FL_COLLAPSE_RANGE must end before the end of file"));
+                }
+                collapse_blocks_local(inode, offset / block_size + 1, end / block_size, &tx)?;
+                size -= length;
+            },
+            _ => return Err(Error::from(ErrorKind::FsNotSupported {description: format!("fallocate mode {:#x}", mode)})),
+        }
+        let new_bytes = stored_bytes_local(inode, 0, &tx)?;
+        charge_quota_local(inode, new_bytes - old_bytes, 0, &tx)?;
+        if size != attr.size {
+            update_size_local(inode, size, &tx)?;
+        }
+        if mode & !FALLOC_FL_KEEP_SIZE != 0 || size != attr.size {
+            let time = Utc::now();
+            update_mtime(inode, time, &tx)?;
+            update_ctime(inode, time, &tx)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn copy_file_range(&mut self, inode_in: u64, offset_in: u64, inode_out: u64, offset_out: u64, length: u64) -> Result<u64> {
+        check_writable(inode_out)?;
+        let tx = Savepoint::new(&self.conn)?;
+        let src = get_regular_file_local(inode_in, &tx)?;
+        let dst = get_regular_file_local(inode_out, &tx)?;
+        let length = std::cmp::min(length, src.size.saturating_sub(offset_in));
+        if length == 0 {
+            return Ok(0);
+        }
+        match offset_out.checked_add(length) {
+            Some(n) if n <= i64::MAX as u64 => {},
+            _ => return Err(Error::from(ErrorKind::FsParm {description: "invalid range of copy_file_range".to_string()})),
+        }
+        if inode_in == inode_out && offset_in < offset_out + length && offset_out < offset_in + length {
+            return Err(Error::from(ErrorKind::FsParm {description: "ranges of copy_file_range overlap".to_string()}));
+        }
+        let block_size = u64::from(self.block_size);
+        let old_bytes = stored_bytes_local(inode_out, 0, &tx)?;
+        let mut done = 0;
+        while done < length {
+            let (pos_in, pos_out) = (offset_in + done, offset_out + done);
+            let (head_in, head_out) = (pos_in % block_size, pos_out % block_size);
+            // rows of a snapshot are copied through memory too
+            if head_in == 0 && head_out == 0 && length - done >= block_size && !is_snapshot_ino(inode_in) {
+                let count = (length - done) / block_size;
+                let first = pos_in / block_size + 1;
+                let shift = (pos_out / block_size) as i64 - (pos_in / block_size) as i64;
+                copy_blocks_local(inode_in, first, first + count - 1, inode_out, shift, &tx)?;
+                done += count * block_size;
+            } else {
+                // bytes to the next block of the destination are copied through memory
+                let size = std::cmp::min(length - done, block_size - head_out);
+                let data = read_local(inode_in, pos_in, size, self.block_size, &tx)?;
+                let block = pos_out / block_size + 1;
+                let mut block_data = get_block_local(inode_out, block, self.block_size, &tx)?.unwrap_or_default();
+                block_data.resize(block_size as usize, 0);
+                block_data[head_out as usize..(head_out + size) as usize].copy_from_slice(&data);
+                put_block_local(inode_out, block, &block_data, self.compression, self.dedup, &tx)?;
+                done += size;
+            }
+        }
+        let new_bytes = stored_bytes_local(inode_out, 0, &tx)?;
+        charge_quota_local(inode_out, new_bytes - old_bytes, 0, &tx)?;
+        if offset_out + length > dst.size {
+            update_size_local(inode_out, offset_out + length, &tx)?;
+        }
+        let time = Utc::now();
+        update_mtime(inode_out, time, &tx)?;
+        update_ctime(inode_out, time, &tx)?;
+        tx.commit()?;
+        Ok(length)
+    }
+
+    fn clone_file(&mut self, src: u64, dst: u64) -> Result<()> {
+        if src == dst {
+            return Err(Error::from(ErrorKind::FsParm {description: "a file can't be cloned to itself".to_string()}));
+        }
+        check_writable(dst)?;
+        let tx = Savepoint::new(&self.conn)?;
+        let attr = get_regular_file_local(src, &tx)?;
+        get_regular_file_local(dst, &tx)?;
+        let old_bytes = stored_bytes_local(dst, 0, &tx)?;
+        unref_blocks_local(dst, 0, i64::MAX, &tx)?;
+        tx.execute("DELETE FROM data WHERE file_id=$1", params![dst as i64])?;
+        match split_snapshot_ino(src) {
+            // blocks of a snapshot are always in the block table
+            Some((snapshot, ino)) => copy_snapshot_blocks_local(snapshot, ino, dst, self.block_size, &tx)?,
+            None => {
+                share_blocks_local(src, i64::MIN, i64::MAX, i64::MAX, self.block_size, &tx)?;
+                copy_blocks_local(src, 1, i64::MAX as u64, dst, 0, &tx)?;
+            },
+        }
+        let new_bytes = stored_bytes_local(dst, 0, &tx)?;
+        charge_quota_local(dst, new_bytes - old_bytes, 0, &tx)?;
+        update_size_local(dst, attr.size, &tx)?;
+        let time = Utc::now();
+        update_mtime(dst, time, &tx)?;
+        update_ctime(dst, time, &tx)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn create_snapshot(&mut self, name: &str) -> Result<()> {
+        check_snapshot_name(name)?;
+        let tx = Savepoint::new(&self.conn)?;
+        let exists: u32 = tx.query_row("SELECT count(*) FROM snapshot WHERE name=$1", params![name], |row| row.get(0))?;
+        if exists > 0 {
+            return Err(Error::from(ErrorKind::FsFileExist {description: format!("snapshot {} exists", name)}));
+        }
+        // inode numbers of a snapshot are made from its id and inode numbers of the filesystem
+        let max_ino: i64 = tx.query_row("SELECT coalesce(max(id), 0) FROM metadata", NO_PARAMS, |row| row.get(0))?;
+        if max_ino as u64 >= MAX_SNAPSHOT_INO {
+            return Err(Error::from(ErrorKind::FsNoSpace {description: format!("inode {} is too large for a snapshot", max_ino)}));
+        }
+        let id: u32 = tx.query_row("SELECT coalesce(max(id), 0) + 1 FROM snapshot", NO_PARAMS, |row| row.get(0))?;
+        if id >= MAX_SNAPSHOT_ID {
+            return Err(Error::from(ErrorKind::FsNoSpace {description: "no more snapshot ids".to_string()}));
+        }
+        // data rows which are written from now on are not in the snapshot
+        let generation: i64 = tx.query_row(&format!("SELECT {} + 1", GENERATION_SQL), NO_PARAMS, |row| row.get(0))?;
+        set_setting_local("generation", generation, &tx)?;
+        let now = Utc::now();
+        tx.execute(
+            "INSERT INTO snapshot (id, name, created, created_nsec, generation) VALUES($1, $2, datetime($3), $4, $5)",
+            params![id, name, now.format("%Y-%m-%d %H:%M:%S").to_string(), now.timestamp_subsec_nanos(), generation]
         )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn list_snapshot(&self) -> Result<Vec<DBSnapshot>> {
+        list_snapshot_local(&self.conn)
+    }
+
+    fn delete_snapshot(&mut self, name: &str) -> Result<()> {
+        let tx = Savepoint::new(&self.conn)?;
+        let id = get_snapshot_id_local(name, &tx)?;
+        let rows = "SELECT hash FROM snapshot_data WHERE snapshot_id=$1";
+        let sql = format!("UPDATE block SET refcount=refcount - \
+            (SELECT count(*) FROM snapshot_data WHERE snapshot_data.hash = block.hash AND snapshot_id=$1) \
+            WHERE hash IN ({})", rows);
+        tx.execute(&sql, params![id])?;
+        let sql = format!("DELETE FROM block WHERE refcount <= 0 AND hash IN ({})", rows);
+        tx.execute(&sql, params![id])?;
+        for table in &["snapshot_data", "snapshot_xattr", "snapshot_dentry", "snapshot_metadata"] {
+            tx.execute(&format!("DELETE FROM {} WHERE snapshot_id=$1", table), params![id])?;
+        }
+        tx.execute("DELETE FROM snapshot WHERE id=$1", params![id])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn rollback_snapshot(&mut self, name: &str) -> Result<()> {
+        let tx = Savepoint::new(&self.conn)?;
+        let id = get_snapshot_id_local(name, &tx)?;
+        // files of a mounted filesystem would be replaced under its cache
+        release_dead_session_local(&tx)?;
+        let others: u32 = tx.query_row("SELECT count(*) FROM session WHERE id != $1", params![self.session], |row| row.get(0))?;
+        if others > 0 {
+            return Err(Error::from(ErrorKind::FsBusy {description: "the filesystem is used by another process".to_string()}));
+        }
+        // all snapshots get their own rows, because all rows are replaced
+        for table in &["metadata", "dentry", "xattr"] {
+            keep_snapshot_rows_local(table, "1", NO_PARAMS, &tx)?;
+        }
+        let sql = "SELECT DISTINCT file_id FROM data WHERE generation < (SELECT coalesce(max(generation), 0) FROM snapshot)";
+        for inode in query_id_list(sql, &tx)? {
+            keep_snapshot_blocks_local(inode, i64::MIN, i64::MAX, &tx)?;
+        }
+        // blocks of the snapshot are referred by files first, so that blocks shared with current files are kept
+        let sql = "UPDATE block SET refcount=refcount + \
+            (SELECT count(*) FROM snapshot_data WHERE snapshot_data.hash = block.hash AND snapshot_id=$1) \
+            WHERE hash IN (SELECT hash FROM snapshot_data WHERE snapshot_id=$1)";
+        tx.execute(sql, params![id])?;
+        unref_blocks_local_all(&tx)?;
+        // rows of dentry, data, xattr and lock are deleted by the foreign keys too
+        for table in &["lock", "xattr", "data", "dentry", "metadata"] {
+            tx.execute(&format!("DELETE FROM {}", table), NO_PARAMS)?;
+        }
+        for table in &["metadata", "dentry", "xattr"] {
+            let sql = format!("INSERT INTO {0} ({1}, generation) SELECT {1}, {2} FROM snapshot_{0} WHERE snapshot_id=$1",
+                table, snapshot_columns(table), GENERATION_SQL);
+            tx.execute(&sql, params![id])?;
+        }
+        let sql = format!("INSERT INTO data (file_id, block_num, data, codec, hash, generation) \
+            SELECT file_id, block_num, NULL, 0, hash, {} FROM snapshot_data WHERE snapshot_id=$1", GENERATION_SQL);
+        tx.execute(&sql, params![id])?;
+        // files are replaced, so usage is counted again
+        for quota in list_quota_local(&tx)? {
+            let kind = quota_kind_to_const(quota.kind);
+            let (bytes, inodes) = count_quota_local(kind, quota.id, &tx)?;
+            update_quota_usage_local(kind, quota.id, bytes, inodes, &tx)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn release_data(&self, inode: u64) -> Result<()> {
+        check_writable(inode)?;
+        let tx = Savepoint::new(&self.conn)?;
+        let bytes = stored_bytes_local(inode, 0, &tx)?;
+        unref_blocks_local(inode, 0, i64::MAX, &tx)?;
+        tx.execute("DELETE FROM data WHERE file_id=$1", params![inode as i64])?;
+        charge_quota_local(inode, -bytes, 0, &tx)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn delete_all_noref_inode(&mut self) -> Result<()> {
+        let tx = Savepoint::new(&self.conn)?;
+        let sql = "SELECT id FROM metadata WHERE NOT EXISTS (SELECT 'x' FROM dentry WHERE metadata.id = dentry.child_id)";
+        for inode in query_id_list(sql, &tx)? {
+            delete_inode_local(inode, &tx)?;
+        }
+        tx.commit()?;
         Ok(())
     }
 
     fn get_db_block_size(&self) -> u32 {
-        BLOCK_SIZE
+        self.block_size
     }
 
-    fn set_xattr(&mut self, inode: u32, key: &str, value: &[u8]) -> Result<()> {
-        let tx = self.conn.transaction()?;
+    fn set_xattr(&mut self, inode: u64, key: &str, value: &[u8]) -> Result<()> {
+        check_writable(inode)?;
+        let tx = Savepoint::new(&self.conn)?;
         {
-            tx.execute("REPLACE INTO xattr \
-            (file_id, name, value)
-            VALUES($1, $2, $3)",
-                       params![inode, key, value])?;
+            keep_snapshot_rows_local("xattr", "file_id=$1 AND name=$2", params![inode as i64, key], &tx)?;
+            let sql = format!("REPLACE INTO xattr \
+            (file_id, name, value, generation)
+            VALUES($1, $2, $3, {})", GENERATION_SQL);
+            tx.execute(&sql, params![inode as i64, key, value])?;
         }
         let time = Utc::now();
         update_ctime(inode, time, &tx)?;
@@ -793,15 +2779,24 @@ impl DbModule for Sqlite {
         Ok(())
     }
 
-    fn get_xattr(&self, inode: u32, key: &str) -> Result<Vec<u8>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT \
-            value FROM xattr WHERE file_id=$1 AND name=$2")?;
-        let row: Vec<u8> = match stmt.query_row(params![inode, key], |row| row.get(0)) {
+    fn get_xattr(&self, inode: u64, key: &str) -> Result<Vec<u8>> {
+        let result = match split_snapshot_ino(inode) {
+            Some((snapshot, ino)) => {
+                let sql = format!("SELECT value FROM {} WHERE file_id=$1 AND name=$2", snapshot_rows_sql("xattr", snapshot));
+                self.conn.query_row(&sql, params![ino as i64, key], |row| row.get(0))
+            },
+            None => {
+                let mut stmt = self.conn.prepare(
+                    "SELECT \
+                    value FROM xattr WHERE file_id=$1 AND name=$2")?;
+                stmt.query_row(params![inode as i64, key], |row| row.get(0))
+            },
+        };
+        let row: Vec<u8> = match result {
             Ok(n) => n,
             Err(err) => {
                 if err == rusqlite::Error::QueryReturnedNoRows {
-                    return Err(Error::from(ErrorKind::FsNoEnt {
+                    return Err(Error::from(ErrorKind::FsNoData {
                         description: format!(
                             "inode: {} name:{}",
                             inode, key
@@ -815,10 +2810,14 @@ impl DbModule for Sqlite {
         Ok(row)
     }
 
-    fn list_xattr(&self, inode: u32) -> Result<Vec<String>> {
-        let sql = "SELECT name FROM xattr WHERE file_id=$1 ORDER BY name";
-        let mut stmt = self.conn.prepare(sql)?;
-        let rows = stmt.query_map(params![inode], |row| {
+    fn list_xattr(&self, inode: u64) -> Result<Vec<String>> {
+        let (table, file) = match split_snapshot_ino(inode) {
+            Some((snapshot, ino)) => (snapshot_rows_sql("xattr", snapshot), ino),
+            None => ("xattr".to_string(), inode),
+        };
+        let sql = format!("SELECT name FROM {} WHERE file_id=$1 ORDER BY name", table);
+        let mut stmt = self.conn.prepare(&sql)?;
+        let rows = stmt.query_map(params![file as i64], |row| {
             Ok(row.get(0)?)
         })?;
         let mut name_list: Vec<String> = Vec::new();
@@ -828,16 +2827,241 @@ impl DbModule for Sqlite {
         Ok(name_list)
     }
 
-    fn delete_xattr(&mut self, inode: u32, key: &str) -> Result<()> {
-        let tx = self.conn.transaction()?;
+    fn delete_xattr(&mut self, inode: u64, key: &str) -> Result<()> {
+        check_writable(inode)?;
+        let tx = Savepoint::new(&self.conn)?;
         {
+            keep_snapshot_rows_local("xattr", "file_id=$1 AND name=$2", params![inode as i64, key], &tx)?;
             tx.execute("DELETE FROM xattr \
             WHERE file_id = $1 AND name = $2",
-                       params![inode, key])?;
+                       params![inode as i64, key])?;
         }
         let time = Utc::now();
         update_ctime(inode, time, &tx)?;
         tx.commit()?;
         Ok(())
     }
+
+    fn get_lock(&self, lock: &DBLock) -> Result<Option<DBLock>> {
+        find_conflict_lock_local(lock, self.session, &self.conn)
+    }
+
+    fn set_lock(&mut self, lock: &DBLock) -> Result<()> {
+        // files in snapshots can't be written, so their locks never conflict
+        if is_snapshot_ino(lock.ino) {
+            return Ok(());
+        }
+        let session = self.session;
+        let tx = Savepoint::new(&self.conn)?;
+        if let Some(n) = find_conflict_lock_local(lock, session, &tx)? {
+            return Err(Error::from(ErrorKind::FsLockConflict {description: format!(
+                "inode: {} range: {}-{} is locked by pid: {}",
+                lock.ino, n.start, n.end, n.pid
+            )}));
+        }
+        // locks of the same owner which overlap or adjoin the new range
+        let sql = "SELECT rowid, start_offset, end_offset, lock_type FROM lock \
+            WHERE file_id=$1 AND session=$2 AND lock_owner=$3 \
+            AND start_offset <= $4 AND end_offset >= $5";
+        let start = lock_offset(lock.start);
+        let end = lock_offset(lock.end);
+        let mut owned: Vec<(i64, i64, i64, u32)> = Vec::new();
+        {
+            let mut stmt = tx.prepare(sql)?;
+            let rows = stmt.query_map(params![
+                lock.ino as i64,
+                session,
+                lock.lock_owner as i64,
+                end.saturating_add(1),
+                start.saturating_sub(1)
+            ], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })?;
+            for row in rows {
+                owned.push(row?);
+            }
+        }
+        let mut new_lock = *lock;
+        let mut new_start = start;
+        let mut new_end = end;
+        for (rowid, old_start, old_end, old_type) in owned {
+            if old_type == lock.typ {
+                new_start = new_start.min(old_start);
+                new_end = new_end.max(old_end);
+            } else if old_start <= end && old_end >= start {
+                if old_start < start {
+                    let head = DBLock{start: old_start as u64, end: (start - 1) as u64, typ: old_type, ..*lock};
+                    add_lock_local(&head, session, &tx)?;
+                }
+                if old_end > end {
+                    let tail = DBLock{start: (end + 1) as u64, end: old_end as u64, typ: old_type, ..*lock};
+                    add_lock_local(&tail, session, &tx)?;
+                }
+            } else {
+                // adjoining lock of another type is not changed
+                continue;
+            }
+            tx.execute("DELETE FROM lock WHERE rowid=$1", params![rowid])?;
+        }
+        if lock.typ != F_UNLCK as u32 {
+            new_lock.start = new_start as u64;
+            new_lock.end = new_end as u64;
+            add_lock_local(&new_lock, session, &tx)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn release_lock(&mut self, inode: u64, lock_owner: u64) -> Result<()> {
+        let sql = "DELETE FROM lock WHERE file_id=$1 AND session=$2 AND lock_owner=$3";
+        self.conn.execute(sql, params![inode as i64, self.session, lock_owner as i64])?;
+        Ok(())
+    }
+
+    fn release_all_lock(&mut self) -> Result<()> {
+        self.conn.execute("DELETE FROM lock WHERE session=$1", params![self.session])?;
+        Ok(())
+    }
+
+    fn fsck(&mut self, repair: bool) -> Result<Vec<FsckIssue>> {
+        let mut issues = Vec::new();
+        // Checks run on the repaired state. Without repair, the transaction is rolled back.
+        let tx = Savepoint::new(&self.conn)?;
+        fsck_dangling_rows(&mut issues, &tx)?;
+        fsck_file_type(&mut issues, &tx)?;
+        fsck_directory_link(&mut issues, &tx)?;
+        fsck_unreachable(&mut issues, &tx)?;
+        fsck_dot_entry(&mut issues, &tx)?;
+        fsck_reserved_name(&mut issues, &tx)?;
+        fsck_data_block(&mut issues, u64::from(self.block_size), &tx)?;
+        fsck_dedup_block(&mut issues, &tx)?;
+        fsck_quota(&mut issues, &tx)?;
+        if repair {
+            tx.commit()?;
+            for issue in issues.iter_mut() {
+                issue.repaired = true;
+            }
+        }
+        Ok(issues)
+    }
+
+    fn begin_transaction(&mut self) -> Result<()> {
+        self.conn.execute_batch("BEGIN")?;
+        Ok(())
+    }
+
+    fn commit_transaction(&mut self) -> Result<()> {
+        self.conn.execute_batch("COMMIT")?;
+        Ok(())
+    }
+
+    fn rollback_transaction(&mut self) -> Result<()> {
+        self.conn.execute_batch("ROLLBACK")?;
+        Ok(())
+    }
+
+    fn statfs(&self) -> Result<DBStatfs> {
+        let pragma = |name: &str| -> Result<u64> {
+            let n: i64 = self.conn.query_row(&format!("PRAGMA {}", name), NO_PARAMS, |row| row.get(0))?;
+            Ok(n as u64)
+        };
+        let page_size = pragma("page_size")?;
+        let page_count = pragma("page_count")?;
+        let freelist = pragma("freelist_count")?;
+        let used = (page_count - freelist) * page_size;
+        // free pages in the database file are reused first
+        let mut available = freelist * page_size + host_available(&self.path);
+        if let Some(max_size) = self.max_size {
+            available = cmp::min(available, max_size.saturating_sub(used));
+        }
+        let inodes: i64 = self.conn.query_row("SELECT count(*) FROM metadata", NO_PARAMS, |row| row.get(0))?;
+        let block_size = u64::from(self.block_size);
+        let bfree = available / block_size;
+        Ok(DBStatfs {
+            blocks: used / block_size + u64::from(used % block_size != 0) + bfree,
+            bfree,
+            bavail: bfree,
+            // an inode needs at least a row, so the number of free inodes is limited by free blocks
+            files: inodes as u64 + bfree,
+            ffree: bfree,
+            bsize: self.block_size,
+            namelen: NAME_MAX as u32,
+        })
+    }
+
+    fn set_quota(&mut self, kind: QuotaKind, id: u32, bytes_limit: u64, inodes_limit: u64) -> Result<()> {
+        let kind = quota_kind_to_const(kind);
+        let tx = Savepoint::new(&self.conn)?;
+        if bytes_limit == 0 && inodes_limit == 0 {
+            tx.execute("DELETE FROM quota WHERE kind=$1 AND id=$2", params![kind, id])?;
+        } else {
+            let (bytes, inodes) = count_quota_local(kind, id, &tx)?;
+            tx.execute(
+                "REPLACE INTO quota (kind, id, bytes_limit, inodes_limit, bytes_used, inodes_used) VALUES($1, $2, $3, $4, $5, $6)",
+                params![kind, id, bytes_limit as i64, inodes_limit as i64, bytes as i64, inodes as i64]
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn list_quota(&self) -> Result<Vec<DBQuota>> {
+        list_quota_local(&self.conn)
+    }
+
+    fn set_project(&mut self, inode: u64, project: u32) -> Result<()> {
+        check_writable(inode)?;
+        let tx = Savepoint::new(&self.conn)?;
+        if get_inode_local(inode, &tx)?.is_none() {
+            return Err(Error::from(ErrorKind::FsNoEnt {description: format!("inode {} is not found", inode)}));
+        }
+        // sqlite numbers parameters in the order of appearance
+        let tree = |param: &str| format!("WITH RECURSIVE tree(id) AS (\
+            SELECT {} UNION SELECT child_id FROM dentry INNER JOIN tree ON dentry.parent_id = tree.id \
+            WHERE name != '.' AND name != '..') SELECT id FROM tree", param);
+        keep_snapshot_rows_local("metadata", &format!("id IN ({})", tree("$1")), params![inode as i64], &tx)?;
+        let sql = format!("UPDATE metadata SET project=$1 WHERE id IN ({})", tree("$2"));
+        tx.execute(&sql, params![project, inode as i64])?;
+        // files are moved between projects
+        for quota in list_quota_local(&tx)?.iter().filter(|n| n.kind == QuotaKind::Project) {
+            let (bytes, inodes) = count_quota_local(QUOTA_PROJECT, quota.id, &tx)?;
+            update_quota_usage_local(QUOTA_PROJECT, quota.id, bytes, inodes, &tx)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn check_quota(&self, inode: u64, bytes: u64, inodes: u64) -> Result<()> {
+        match get_quota_owner_local(inode, &self.conn)? {
+            Some(owner) => check_quota_local(owner, bytes as i64, inodes as i64, &self.conn),
+            None => Ok(()),
+        }
+    }
+
+    fn sync(&mut self, datasync: bool) -> Result<()> {
+        let path = match &self.path {
+            Some(n) => n.clone(),
+            None => return Ok(()),
+        };
+        // full and extra sync every commit
+        let synchronous: i64 = self.conn.query_row("PRAGMA synchronous", NO_PARAMS, |row| row.get(0))?;
+        if synchronous >= 2 {
+            return Ok(());
+        }
+        let journal_mode: String = self.conn.query_row("PRAGMA journal_mode", NO_PARAMS, |row| row.get(0))?;
+        if journal_mode.eq_ignore_ascii_case("wal") {
+            // checkpoint syncs the WAL file and the database file with synchronous=full
+            self.conn.execute_batch("PRAGMA synchronous=FULL")?;
+            let result = self.conn.query_row("PRAGMA wal_checkpoint(FULL)", NO_PARAMS, |row| row.get::<_, i64>(0));
+            self.conn.execute_batch(&format!("PRAGMA synchronous={}", synchronous))?;
+            if result? == 0 {
+                return Ok(());
+            }
+            // the checkpoint is blocked by readers. Committed data is in the WAL file.
+            let mut wal_path = path.clone().into_os_string();
+            wal_path.push("-wal");
+            sync_file(Path::new(&wal_path), datasync)?;
+        }
+        sync_file(&path, datasync)
+    }
 }
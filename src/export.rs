@@ -0,0 +1,300 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufReader, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use fuser::FileType;
+use nix::sys::stat::{self, Mode, SFlag, UtimensatFlags};
+use nix::sys::time::{TimeSpec, TimeValLike};
+use nix::unistd::{self, FchownatFlags, Gid, Uid};
+use tar::{EntryType, Header};
+use crate::db_module::{DbModule, DBFileAttr};
+use crate::file::File;
+use crate::sqerror::{Error, ErrorKind, Result};
+
+/// Copy all files of the filesystem into a host directory.
+/// The directory is created if it doesn't exist, and it must be empty.
+/// Permissions, timestamps, symlinks, hard links and xattrs are preserved.
+/// Owners are preserved only if the process runs as root.
+/// Returns the number of exported inodes.
+pub fn export_to_dir<T: DbModule>(db: &mut T, dest: &Path) -> Result<u64> {
+    fs::create_dir_all(dest)?;
+    if fs::read_dir(dest)?.next().is_some() {
+        return Err(Error::from(ErrorKind::FsNotEmpty {description: format!(
+            "{} is not empty",
+            dest.display()
+        )}));
+    }
+    let writer = DirWriter{dest: dest.to_path_buf(), same_owner: unistd::geteuid().is_root()};
+    export(db, writer)
+}
+
+/// Write all files of the filesystem to a tar archive in the POSIX pax format.
+/// Timestamps in nanoseconds and xattrs are stored in pax extended headers.
+/// Sockets can't be stored in a tar archive, and they are skipped.
+/// Returns the number of exported inodes.
+pub fn export_to_tar<T: DbModule, W: Write>(db: &mut T, writer: W) -> Result<u64> {
+    export(db, TarWriter{builder: tar::Builder::new(writer)})
+}
+
+fn export<T: DbModule, W: ExportWriter>(db: &mut T, writer: W) -> Result<u64> {
+    let root = match db.get_inode(1)? {
+        Some(n) => n,
+        None => return Err(Error::from(ErrorKind::FsNoEnt {description: "root directory is not found".to_string()})),
+    };
+    let mut exporter = Exporter{db, writer, links: HashMap::new(), count: 0};
+    exporter.export_dir(1, Path::new(""))?;
+    exporter.writer.finish(&root)?;
+    Ok(exporter.count)
+}
+
+// Content of an exported file
+enum Content<'a> {
+    Directory,
+    File(&'a mut dyn Read),
+    Symlink(Vec<u8>),
+    // path of the file which was exported first
+    HardLink(&'a Path),
+    Special,
+}
+
+trait ExportWriter {
+    /// Write a file. path is relative to the root directory.
+    fn write_entry(&mut self, path: &Path, attr: &DBFileAttr, xattrs: &[(String, Vec<u8>)], content: Content) -> Result<()>;
+    /// Called after all children of a directory are written.
+    fn finish_dir(&mut self, path: &Path, attr: &DBFileAttr) -> Result<()>;
+    /// Called after all files are written. attr is the root directory.
+    fn finish(&mut self, root: &DBFileAttr) -> Result<()>;
+}
+
+struct Exporter<'a, T: DbModule, W: ExportWriter> {
+    db: &'a mut T,
+    writer: W,
+    // inode -> path of a file which has hard links
+    links: HashMap<u64, PathBuf>,
+    count: u64,
+}
+
+impl<'a, T: DbModule, W: ExportWriter> Exporter<'a, T, W> {
+    fn export_dir(&mut self, ino: u64, path: &Path) -> Result<()> {
+        let mut entries = self.db.get_dentry(ino)?;
+        entries.retain(|e| e.filename != "." && e.filename != "..");
+        entries.sort_by(|a, b| a.filename.cmp(&b.filename));
+        for entry in entries {
+            let child_path = path.join(&entry.filename);
+            let attr = match self.db.get_inode(entry.child_ino)? {
+                Some(n) => n,
+                None => {
+                    return Err(Error::from(ErrorKind::FsNoEnt {description: format!(
+                        "inode {} of {} is not found",
+                        entry.child_ino,
+                        child_path.display()
+                    )}));
+                }
+            };
+            if let Some(first) = self.links.get(&attr.ino) {
+                self.writer.write_entry(&child_path, &attr, &[], Content::HardLink(first))?;
+                continue;
+            }
+            let mut xattrs = vec![];
+            for key in self.db.list_xattr(attr.ino)? {
+                let value = self.db.get_xattr(attr.ino, &key)?;
+                xattrs.push((key, value));
+            }
+            self.count += 1;
+            match attr.kind {
+                FileType::Directory => {
+                    self.writer.write_entry(&child_path, &attr, &xattrs, Content::Directory)?;
+                    self.export_dir(attr.ino, &child_path)?;
+                    self.writer.finish_dir(&child_path, &attr)?;
+                },
+                FileType::RegularFile => {
+                    let block_size = self.db.get_db_block_size() as usize;
+                    let mut file = File::open(&mut *self.db, attr.ino)?;
+                    file.set_noatime(true);
+                    let mut reader = BufReader::with_capacity(block_size, file);
+                    self.writer.write_entry(&child_path, &attr, &xattrs, Content::File(&mut reader))?;
+                    if attr.nlink > 1 {
+                        self.links.insert(attr.ino, child_path);
+                    }
+                },
+                FileType::Symlink => {
+                    let target = self.db.read_file(attr.ino, 0, attr.size as u32, true)?;
+                    self.writer.write_entry(&child_path, &attr, &xattrs, Content::Symlink(target))?;
+                },
+                _ => self.writer.write_entry(&child_path, &attr, &xattrs, Content::Special)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn timespec(time: SystemTime) -> TimeSpec {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(n) => TimeSpec::nanoseconds(n.as_nanos() as i64),
+        Err(err) => TimeSpec::nanoseconds(-(err.duration().as_nanos() as i64)),
+    }
+}
+
+struct DirWriter {
+    dest: PathBuf,
+    // set owners of files. Only root can change owners.
+    same_owner: bool,
+}
+
+impl DirWriter {
+    fn set_attr(&self, path: &Path, attr: &DBFileAttr) -> Result<()> {
+        if self.same_owner {
+            unistd::fchownat(
+                None,
+                path,
+                Some(Uid::from_raw(attr.uid)),
+                Some(Gid::from_raw(attr.gid)),
+                FchownatFlags::NoFollowSymlink
+            )?;
+        }
+        // permission of a symlink is ignored
+        if attr.kind != FileType::Symlink {
+            fs::set_permissions(path, std::os::unix::fs::PermissionsExt::from_mode(u32::from(attr.perm)))?;
+        }
+        stat::utimensat(None, path, &timespec(attr.atime), &timespec(attr.mtime), UtimensatFlags::NoFollowSymlink)?;
+        Ok(())
+    }
+}
+
+impl ExportWriter for DirWriter {
+    fn write_entry(&mut self, path: &Path, attr: &DBFileAttr, xattrs: &[(String, Vec<u8>)], content: Content) -> Result<()> {
+        let host_path = self.dest.join(path);
+        match content {
+            Content::Directory => {
+                fs::create_dir(&host_path)?;
+                // attributes are set in finish_dir. A read only directory can't have children.
+                for (key, value) in xattrs {
+                    xattr::set(&host_path, key, value)?;
+                }
+                return Ok(());
+            },
+            Content::File(reader) => {
+                let mut file = fs::File::create(&host_path)?;
+                io::copy(reader, &mut file)?;
+            },
+            Content::Symlink(target) => symlink(Path::new(std::ffi::OsStr::from_bytes(&target)), &host_path)?,
+            Content::HardLink(first) => {
+                fs::hard_link(self.dest.join(first), &host_path)?;
+                return Ok(());
+            },
+            Content::Special => {
+                let kind = match attr.kind {
+                    FileType::NamedPipe => SFlag::S_IFIFO,
+                    FileType::CharDevice => SFlag::S_IFCHR,
+                    FileType::BlockDevice => SFlag::S_IFBLK,
+                    _ => SFlag::S_IFSOCK,
+                };
+                stat::mknod(&host_path, kind, Mode::from_bits_truncate(0o600), attr.rdev.into())?;
+            },
+        }
+        // Linux allows user xattrs only on regular files and directories
+        if attr.kind == FileType::RegularFile {
+            for (key, value) in xattrs {
+                xattr::set(&host_path, key, value)?;
+            }
+        }
+        self.set_attr(&host_path, attr)
+    }
+
+    fn finish_dir(&mut self, path: &Path, attr: &DBFileAttr) -> Result<()> {
+        self.set_attr(&self.dest.join(path), attr)
+    }
+
+    fn finish(&mut self, root: &DBFileAttr) -> Result<()> {
+        self.set_attr(&self.dest, root)
+    }
+}
+
+struct TarWriter<W: Write> {
+    builder: tar::Builder<W>,
+}
+
+// Seconds since the epoch with nanoseconds. e.g. "1577836800.500000000"
+fn pax_time(time: SystemTime) -> Vec<u8> {
+    let (sign, duration) = match time.duration_since(UNIX_EPOCH) {
+        Ok(n) => ("", n),
+        Err(err) => ("-", err.duration()),
+    };
+    format!("{}{}.{:09}", sign, duration.as_secs(), duration.subsec_nanos()).into_bytes()
+}
+
+impl<W: Write> ExportWriter for TarWriter<W> {
+    fn write_entry(&mut self, path: &Path, attr: &DBFileAttr, xattrs: &[(String, Vec<u8>)], content: Content) -> Result<()> {
+        let (entry_type, size) = match content {
+            Content::Directory => (EntryType::Directory, 0),
+            Content::File(_) => (EntryType::Regular, attr.size),
+            Content::Symlink(_) => (EntryType::Symlink, 0),
+            Content::HardLink(_) => (EntryType::Link, 0),
+            Content::Special => match attr.kind {
+                FileType::NamedPipe => (EntryType::Fifo, 0),
+                FileType::CharDevice => (EntryType::Char, 0),
+                FileType::BlockDevice => (EntryType::Block, 0),
+                _ => {
+                    warn!("{}: socket ignored", path.display());
+                    return Ok(());
+                }
+            },
+        };
+        let mut header = Header::new_ustar();
+        let mut pax: Vec<(String, Vec<u8>)> = vec![];
+        header.set_entry_type(entry_type);
+        header.set_size(size);
+        header.set_mode(u32::from(attr.perm));
+        header.set_uid(u64::from(attr.uid));
+        header.set_gid(u64::from(attr.gid));
+        header.set_mtime(cmp::max(timespec(attr.mtime).tv_sec(), 0) as u64);
+        pax.push(("mtime".to_string(), pax_time(attr.mtime)));
+        pax.push(("atime".to_string(), pax_time(attr.atime)));
+        if header.set_path(path).is_err() {
+            // too long for ustar
+            pax.push(("path".to_string(), path.as_os_str().as_bytes().to_vec()));
+            let name = &mut header.as_old_mut().name;
+            let bytes = path.as_os_str().as_bytes();
+            let length = cmp::min(bytes.len(), name.len());
+            name[..length].copy_from_slice(&bytes[..length]);
+        }
+        let link_name = match &content {
+            Content::Symlink(target) => Some(target.clone()),
+            Content::HardLink(first) => Some(first.as_os_str().as_bytes().to_vec()),
+            _ => None,
+        };
+        if let Some(link_name) = link_name {
+            if header.set_link_name(Path::new(std::ffi::OsStr::from_bytes(&link_name))).is_err() {
+                pax.push(("linkpath".to_string(), link_name));
+            }
+        }
+        if entry_type == EntryType::Char || entry_type == EntryType::Block {
+            let rdev = u64::from(attr.rdev);
+            header.set_device_major(stat::major(rdev) as u32)?;
+            header.set_device_minor(stat::minor(rdev) as u32)?;
+        }
+        for (key, value) in xattrs {
+            pax.push((format!("SCHILY.xattr.{}", key), value.clone()));
+        }
+        self.builder.append_pax_extensions(pax.iter().map(|(k, v)| (k.as_str(), v.as_slice())))?;
+        header.set_cksum();
+        match content {
+            Content::File(reader) => self.builder.append(&header, reader)?,
+            _ => self.builder.append(&header, io::empty())?,
+        }
+        Ok(())
+    }
+
+    fn finish_dir(&mut self, _path: &Path, _attr: &DBFileAttr) -> Result<()> {
+        Ok(())
+    }
+
+    fn finish(&mut self, _root: &DBFileAttr) -> Result<()> {
+        self.builder.finish()?;
+        Ok(())
+    }
+}
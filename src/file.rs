@@ -0,0 +1,140 @@
+use std::cmp;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use fuser::FileType;
+use crate::db_module::{DbModule, DBFileAttr};
+use crate::sqerror::{Error, ErrorKind, Result};
+
+/// An open regular file of the filesystem which implements Read, Write and Seek.
+/// Each write is committed to the database immediately.
+pub struct File<'a, T: DbModule> {
+    db: &'a mut T,
+    ino: u64,
+    pos: u64,
+    noatime: bool,
+}
+
+impl<'a, T: DbModule> File<'a, T> {
+    /// Open a regular file by inode number. The position starts at 0.
+    pub fn open(db: &'a mut T, ino: u64) -> Result<Self> {
+        let attr = match db.get_inode(ino)? {
+            Some(n) => n,
+            None => {
+                return Err(Error::from(ErrorKind::FsNoEnt {description: format!("inode {} is not found", ino)}));
+            }
+        };
+        if attr.kind == FileType::Directory {
+            return Err(Error::from(ErrorKind::FsIsDir {description: format!("inode {} is a directory", ino)}));
+        }
+        // data of a symlink is its target, and data of a special file is not stored
+        if attr.kind != FileType::RegularFile {
+            return Err(Error::from(ErrorKind::FsParm {description: format!("inode {} is not a regular file", ino)}));
+        }
+        Ok(File{db, ino, pos: 0, noatime: false})
+    }
+
+    /// Inode number of the file
+    pub fn ino(&self) -> u64 {
+        self.ino
+    }
+
+    /// Get current metadata of the file.
+    pub fn metadata(&self) -> Result<DBFileAttr> {
+        match self.db.get_inode(self.ino)? {
+            Some(n) => Ok(n),
+            None => Err(Error::from(ErrorKind::FsNoEnt {description: format!("inode {} is not found", self.ino)})),
+        }
+    }
+
+    /// Don't update atime when the file is read. Same as O_NOATIME.
+    pub fn set_noatime(&mut self, noatime: bool) {
+        self.noatime = noatime;
+    }
+
+    /// Truncate or extend the file. Extended area is filled with zero. The position is not changed.
+    pub fn set_len(&mut self, size: u64) -> Result<()> {
+        let mut attr = self.metadata()?;
+        let truncate = attr.size > size;
+        attr.size = size;
+        self.db.update_inode(&attr, truncate)
+    }
+
+    /// Move the position to the next data at or after offset, like lseek with SEEK_DATA.
+    /// If there is no data after offset, return FsOutOfRange.
+    pub fn seek_data(&mut self, offset: u64) -> Result<u64> {
+        self.pos = self.db.seek_data(self.ino, offset)?;
+        Ok(self.pos)
+    }
+
+    /// Move the position to the next hole at or after offset, like lseek with SEEK_HOLE.
+    /// The end of the file is also a hole.
+    pub fn seek_hole(&mut self, offset: u64) -> Result<u64> {
+        self.pos = self.db.seek_hole(self.ino, offset)?;
+        Ok(self.pos)
+    }
+
+    /// Allocate, punch or collapse a range like fallocate(2). The position is not changed.
+    pub fn fallocate(&mut self, offset: u64, length: u64, mode: u32) -> Result<()> {
+        self.db.fallocate(self.ino, offset, length, mode)
+    }
+
+    /// Make all data and metadata durable. Every write is already committed to the database.
+    pub fn sync_all(&mut self) -> Result<()> {
+        self.db.sync(false)
+    }
+
+    /// Make all data durable like fdatasync.
+    pub fn sync_data(&mut self) -> Result<()> {
+        self.db.sync(true)
+    }
+}
+
+impl<'a, T: DbModule> Read for File<'a, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.metadata()?.size;
+        if self.pos >= size || buf.is_empty() {
+            return Ok(0);
+        }
+        let length = cmp::min(cmp::min(buf.len() as u64, size - self.pos), u64::from(u32::MAX)) as usize;
+        let data = self.db.read_file(self.ino, self.pos, length as u32, self.noatime)?;
+        buf[..length].copy_from_slice(&data);
+        self.pos += length as u64;
+        Ok(length)
+    }
+}
+
+impl<'a, T: DbModule> Write for File<'a, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.db.write_file(self.ino, self.pos, buf)?;
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a, T: DbModule> Seek for File<'a, T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(n) => {
+                self.pos = n;
+                return Ok(n);
+            },
+            SeekFrom::End(n) => (self.metadata()?.size, n),
+            SeekFrom::Current(n) => (self.pos, n),
+        };
+        let pos = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.unsigned_abs())
+        };
+        match pos {
+            Some(n) => {
+                self.pos = n;
+                Ok(n)
+            },
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position")),
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use fuse::{
+use fuser::{
     Filesystem,
     ReplyAttr,
     ReplyData,
@@ -10,27 +10,46 @@ use fuse::{
     ReplyOpen,
     ReplyStatfs,
     ReplyXattr,
+    ReplyLock,
+    ReplyLseek,
     Request,
-    FileType
+    FileType,
+    KernelConfig,
+    TimeOrNow
 };
 use libc::{
     c_int,
     ENOENT,
+    ENXIO,
     ENOTEMPTY,
-    EISDIR,
-    ENOTDIR,
-    EPERM,
     EEXIST,
     EINVAL,
     ENAMETOOLONG,
-    ENODATA,
+    PATH_MAX,
     ERANGE,
+    EAGAIN,
+    EINTR,
+    F_UNLCK,
+    O_ACCMODE,
     O_RDONLY,
     O_APPEND,
-    S_ISGID,
+    O_TRUNC,
+    O_WRONLY,
+    O_RDWR,
+    R_OK,
+    W_OK,
+    X_OK,
     S_ISVTX,
+    S_IFMT,
+    S_IFREG,
+    S_IFIFO,
+    S_IFCHR,
+    S_IFBLK,
+    S_IFSOCK,
     XATTR_CREATE,
-    XATTR_REPLACE
+    XATTR_REPLACE,
+    SEEK_DATA,
+    SEEK_HOLE
 };
 
 #[cfg(not(target_os = "macos"))]
@@ -39,21 +58,20 @@ use libc::O_NOATIME;
 #[cfg(target_os = "macos")]
 const O_NOATIME: u32=0;
 
-use nix::sys::statvfs;
 use std::path::Path;
 use std::ffi::OsStr;
-use crate::db_module::{DbModule, DBFileAttr, DEntry};
+use crate::db_module::{DbModule, DBFileAttr, DEntry, DBLock, is_snapshot_ino};
 use crate::db_module::sqlite::Sqlite;
+use crate::permission::{self, Credential};
+use crate::acl::{self, Acl, ACL_ACCESS, ACL_DEFAULT};
+use crate::block_cache::BlockCache;
 use crate::sqerror::{Error, ErrorKind};
-use time::Timespec;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use std::sync::{Arc, Mutex};
 use std::collections::HashMap;
-const ONE_SEC: Timespec = Timespec{
-    sec: 1,
-    nsec: 0
-};
+const ONE_SEC: Duration = Duration::from_secs(1);
 
+#[derive(Clone, Copy, Default)]
 struct OpenFileStat {
     readonly: bool,
     append: bool,
@@ -66,9 +84,26 @@ struct OpenFileHandler {
 }
 
 impl OpenFileStat {
-    fn new() -> Self {
-        Self{readonly: false, append: false, noatime: false}
+    fn new(flags: u32) -> Self {
+        Self{
+            readonly: flags & O_ACCMODE as u32 == O_RDONLY as u32,
+            append: flags & O_APPEND as u32 > 0,
+            noatime: flags & O_NOATIME as u32 > 0,
+        }
+    }
+}
+
+// Permission which is required to open a file
+fn open_mask(flags: u32) -> u32 {
+    let mut mask = match flags & O_ACCMODE as u32 {
+        n if n == O_WRONLY as u32 => W_OK,
+        n if n == O_RDWR as u32 => R_OK | W_OK,
+        _ => R_OK,
+    } as u32;
+    if flags & O_TRUNC as u32 > 0 {
+        mask |= W_OK as u32;
     }
+    mask
 }
 
 impl OpenFileHandler {
@@ -88,11 +123,20 @@ impl OpenDirHandler {
     }
 }
 
+// setlk request which waits for a conflicting lock to be released
+struct LockWaiter {
+    lock: DBLock,
+    reply: ReplyEmpty,
+}
+
 pub struct SqliteFs{
     db: Sqlite,
-    lookup_count: Arc<Mutex<HashMap<u32, u32>>>,
-    open_file_handler: Arc<Mutex<HashMap<u32, OpenFileHandler>>>,
-    open_dir_handler: Arc<Mutex<HashMap<u32, OpenDirHandler>>>,
+    lookup_count: Arc<Mutex<HashMap<u64, u64>>>,
+    open_file_handler: Arc<Mutex<HashMap<u64, OpenFileHandler>>>,
+    open_dir_handler: Arc<Mutex<HashMap<u64, OpenDirHandler>>>,
+    lock_waiter: Arc<Mutex<Vec<LockWaiter>>>,
+    block_cache: BlockCache,
+    default_permissions: bool,
 }
 
 impl SqliteFs {
@@ -102,22 +146,305 @@ impl SqliteFs {
             Err(err) => return Err(err)
         };
         db.init()?;
-        let lookup_count = Arc::new(Mutex::new(HashMap::<u32, u32>::new()));
-        let open_file_handler = Arc::new(Mutex::new(HashMap::<u32, OpenFileHandler>::new()));
-        let open_dir_handler = Arc::new(Mutex::new(HashMap::<u32, OpenDirHandler>::new()));
-        Ok(SqliteFs{db, lookup_count, open_file_handler, open_dir_handler})
+        let lookup_count = Arc::new(Mutex::new(HashMap::<u64, u64>::new()));
+        let open_file_handler = Arc::new(Mutex::new(HashMap::<u64, OpenFileHandler>::new()));
+        let open_dir_handler = Arc::new(Mutex::new(HashMap::<u64, OpenDirHandler>::new()));
+        let lock_waiter = Arc::new(Mutex::new(Vec::<LockWaiter>::new()));
+        Ok(SqliteFs{db, lookup_count, open_file_handler, open_dir_handler, lock_waiter, block_cache: BlockCache::default(), default_permissions: false})
     }
 
     pub fn new_with_db(db: Sqlite) -> Result<SqliteFs, Error> {
-        let lookup_count = Arc::new(Mutex::new(HashMap::<u32, u32>::new()));
-        let open_file_handler = Arc::new(Mutex::new(HashMap::<u32, OpenFileHandler>::new()));
-        let open_dir_handler = Arc::new(Mutex::new(HashMap::<u32, OpenDirHandler>::new()));
-        Ok(SqliteFs{db, lookup_count, open_file_handler, open_dir_handler})
+        let lookup_count = Arc::new(Mutex::new(HashMap::<u64, u64>::new()));
+        let open_file_handler = Arc::new(Mutex::new(HashMap::<u64, OpenFileHandler>::new()));
+        let open_dir_handler = Arc::new(Mutex::new(HashMap::<u64, OpenDirHandler>::new()));
+        let lock_waiter = Arc::new(Mutex::new(Vec::<LockWaiter>::new()));
+        Ok(SqliteFs{db, lookup_count, open_file_handler, open_dir_handler, lock_waiter, block_cache: BlockCache::default(), default_permissions: false})
+    }
+
+    /// Retry blocked setlk requests. Called after locks are released.
+    /// Fuse requests are processed in a single thread, so a blocking setlk can't wait in place.
+    /// Only locks released through this mount wake waiters. A lock released by another process which opens
+    /// the same database is noticed when a lock of this mount is released next.
+    fn wake_lock_waiter(&mut self) {
+        let mut waiter_list = self.lock_waiter.lock().unwrap();
+        let waiters = std::mem::take(&mut *waiter_list);
+        for waiter in waiters {
+            match self.db.set_lock(&waiter.lock) {
+                Ok(_) => waiter.reply.ok(),
+                Err(err) => match err.kind() {
+                    ErrorKind::FsLockConflict {description: _} => waiter_list.push(waiter),
+                    _ => {waiter.reply.error(err.errno()); debug!("{}", err);}
+                }
+            }
+        }
+    }
+
+    // Write back dirty data older than the flush interval. Errors are returned by the next flush of the file.
+    fn flush_expired(&mut self) {
+        if let Err(err) = self.block_cache.flush_expired(&mut self.db) {
+            debug!("{}", err);
+        }
+    }
+
+    // Dirty blocks in the cache may extend the file.
+    fn with_cached_size(&self, mut attr: DBFileAttr) -> DBFileAttr {
+        if let Some(size) = self.block_cache.size(attr.ino) {
+            attr.size = size;
+        }
+        attr
+    }
+
+    fn get_attr(&self, ino: u64) -> Result<DBFileAttr, Error> {
+        match self.db.get_inode(ino)? {
+            Some(n) => Ok(n),
+            None => Err(Error::from(ErrorKind::FsNoEnt {description: format!("inode {} is not found", ino)})),
+        }
+    }
+
+    // Check the permission of a file with its access ACL.
+    fn check_permission(&self, attr: &DBFileAttr, cred: &Credential, mask: u32) -> Result<(), Error> {
+        if self.default_permissions {
+            return Ok(());
+        }
+        let acl = acl::get_acl(&self.db, attr.ino, ACL_ACCESS)?;
+        permission::check_access(attr, acl.as_ref(), cred, mask)
+    }
+
+    // Get attributes of a file after checking the permission.
+    fn check_access(&self, ino: u64, cred: &Credential, mask: u32) -> Result<DBFileAttr, Error> {
+        let attr = self.get_attr(ino)?;
+        self.check_permission(&attr, cred, mask)?;
+        Ok(attr)
+    }
+
+    fn check_create(&self, parent_attr: &DBFileAttr, cred: &Credential) -> Result<(), Error> {
+        if self.default_permissions {
+            return Ok(());
+        }
+        let acl = acl::get_acl(&self.db, parent_attr.ino, ACL_ACCESS)?;
+        permission::check_create(parent_attr, acl.as_ref(), cred)
+    }
+
+    // Get attributes of a directory entry after checking that it can be removed.
+    fn check_remove(&mut self, parent: u64, name: &str, cred: &Credential) -> Result<DBFileAttr, Error> {
+        let parent_attr = self.check_access(parent, cred, X_OK as u32)?;
+        let attr = match self.db.lookup(parent, name)? {
+            Some(n) => n,
+            None => return Err(Error::from(ErrorKind::FsNoEnt {description: format!("{} is not found in inode {}", name, parent)})),
+        };
+        if !self.default_permissions {
+            let acl = acl::get_acl(&self.db, parent, ACL_ACCESS)?;
+            permission::check_remove(&parent_attr, acl.as_ref(), &attr, cred)?;
+        }
+        Ok(attr)
+    }
+
+    fn check_xattr(&self, ino: u64, name: &str, cred: &Credential, write: bool) -> Result<(), Error> {
+        if self.default_permissions {
+            return Ok(());
+        }
+        let attr = self.get_attr(ino)?;
+        let acl = acl::get_acl(&self.db, ino, ACL_ACCESS)?;
+        permission::check_xattr(&attr, acl.as_ref(), cred, name, write)
+    }
+
+    // Set an ACL xattr. Permission bits follow the access ACL, and an ACL which is equivalent to them is not stored.
+    fn set_acl(&mut self, ino: u64, name: &str, value: &[u8], cred: &Credential) -> Result<(), Error> {
+        let mut attr = self.get_attr(ino)?;
+        if name == ACL_DEFAULT {
+            if attr.kind != FileType::Directory {
+                return Err(Error::from(ErrorKind::FsPermission {description: format!("inode {} is not a directory", ino)}));
+            }
+            // an empty default ACL removes it
+            if value.len() == 4 {
+                return self.delete_acl(ino, name);
+            }
+            let acl = Acl::from_bytes(value)?;
+            return self.db.set_xattr(ino, name, &acl.to_bytes());
+        }
+        let acl = Acl::from_bytes(value)?;
+        attr.perm = permission::chmod_mode(&attr, cred, (attr.perm & !0o777) | acl.mode());
+        self.db.update_inode(&attr, false)?;
+        if acl.is_minimal() {
+            self.delete_acl(ino, name)
+        } else {
+            self.db.set_xattr(ino, name, &acl.to_bytes())
+        }
+    }
+
+    fn delete_acl(&mut self, ino: u64, name: &str) -> Result<(), Error> {
+        match self.db.delete_xattr(ino, name) {
+            Ok(_) => Ok(()),
+            Err(err) => match err.kind() {
+                ErrorKind::FsNoData {..} => Ok(()),
+                _ => Err(err),
+            }
+        }
+    }
+
+    // Keep the access ACL in sync with permission bits after chmod.
+    fn chmod_acl(&mut self, attr: &DBFileAttr) -> Result<(), Error> {
+        if let Some(mut acl) = acl::get_acl(&self.db, attr.ino, ACL_ACCESS)? {
+            acl.set_mode(attr.perm);
+            self.db.set_xattr(attr.ino, ACL_ACCESS, &acl.to_bytes())?;
+        }
+        Ok(())
+    }
+
+    // Apply the default ACL of the parent directory to a new file.
+    // A directory also inherits it as its default ACL.
+    // The kernel has already applied umask to the mode because FUSE_DONT_MASK is not requested.
+    fn inherit_acl(&mut self, parent: u64, attr: &mut DBFileAttr) -> Result<(), Error> {
+        let default_acl = match acl::get_acl(&self.db, parent, ACL_DEFAULT)? {
+            Some(n) => n,
+            None => return Ok(()),
+        };
+        let (access_acl, mode) = default_acl.inherit(attr.perm);
+        attr.perm = mode;
+        self.db.update_inode(attr, false)?;
+        if !access_acl.is_minimal() {
+            self.db.set_xattr(attr.ino, ACL_ACCESS, &access_acl.to_bytes())?;
+        }
+        if attr.kind == FileType::Directory {
+            self.db.set_xattr(attr.ino, ACL_DEFAULT, &default_acl.to_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// The kernel checks permission bits when the filesystem is mounted with default_permissions,
+    /// so permission isn't checked again in the filesystem. ACLs are not enforced in this mode.
+    pub fn set_default_permissions(&mut self, enabled: bool) {
+        self.default_permissions = enabled;
+    }
+
+    // Clear setuid and setgid bits when a file is written by a non-root user.
+    fn clear_suid(&mut self, ino: u64) -> Result<(), Error> {
+        let mut attr = self.get_attr(ino)?;
+        if permission::clear_suid(&mut attr) {
+            self.db.update_inode(&attr, false)?;
+        }
+        Ok(())
+    }
+
+    /// Register a file handle of open(2) flags, and return it. Permission is not checked.
+    /// O_TRUNC truncates the file unless it is opened read only.
+    pub fn open_file(&mut self, ino: u64, flags: u32) -> Result<u64, Error> {
+        // writes are cached, so files in snapshots are refused when they are opened
+        if open_mask(flags) & W_OK as u32 > 0 && is_snapshot_ino(ino) {
+            return Err(Error::from(ErrorKind::FsReadOnly {description: format!("inode {} is in a snapshot", ino)}));
+        }
+        let stat = OpenFileStat::new(flags);
+        if flags & O_TRUNC as u32 > 0 && !stat.readonly {
+            self.block_cache.flush(&mut self.db, ino)?;
+            if let Some(mut attr) = self.db.get_inode(ino)? {
+                if attr.size > 0 {
+                    attr.size = 0;
+                    self.db.update_inode(&attr, true)?;
+                }
+            }
+        }
+        let mut handler = self.open_file_handler.lock().unwrap();
+        let handle_list = handler.entry(ino).or_insert_with(OpenFileHandler::new);
+        let fh = handle_list.count;
+        (*handle_list).list.insert(fh, stat);
+        (*handle_list).count += 1;
+        Ok(fh)
+    }
+
+    /// Read data of a file handle. Data is shorter than size at the end of the file.
+    /// The access time is not updated for O_NOATIME.
+    pub fn read_file(&mut self, ino: u64, fh: u64, offset: u64, size: u32) -> Result<Vec<u8>, Error> {
+        let noatime = self.get_open_file_stat(ino, fh).noatime;
+        self.flush_expired();
+        self.block_cache.flush(&mut self.db, ino)?;
+        let file_size = self.get_attr(ino)?.size;
+        if offset >= file_size {
+            return Ok(vec![]);
+        }
+        let size = std::cmp::min(u64::from(size), file_size - offset) as u32;
+        self.db.read_file(ino, offset, size, noatime)
+    }
+
+    /// Write data through a file handle by a user, and return the written size.
+    /// A read only handle fails with EBADF, and O_APPEND always writes at the end of the file.
+    pub fn write_file(&mut self, ino: u64, fh: u64, offset: u64, data: &[u8], uid: u32) -> Result<u32, Error> {
+        let stat = self.get_open_file_stat(ino, fh);
+        if stat.readonly {
+            return Err(Error::from(ErrorKind::FsBadHandle {description: format!("handle {} of inode {} is read only", fh, ino)}));
+        }
+        let size = self.with_cached_size(self.get_attr(ino)?).size;
+        let offset = if stat.append {size} else {offset};
+        // dirty blocks are charged to quotas when they are written back, so the growth of all cached files is checked here
+        let end = match offset.checked_add(data.len() as u64) {
+            Some(n) if n <= i64::MAX as u64 => n,
+            _ => return Err(Error::from(ErrorKind::FsParm {description: "invalid range of write".to_string()})),
+        };
+        if end > size {
+            self.db.check_quota(ino, end - size + self.block_cache.growth(), 0)?;
+        }
+        if uid != 0 {
+            self.clear_suid(ino)?;
+        }
+        self.block_cache.write(&mut self.db, ino, offset, data)?;
+        Ok(data.len() as u32)
+    }
+
+    /// Find data or a hole at or after offset for SEEK_DATA or SEEK_HOLE. Cached data is written back first.
+    /// Other whence values are handled by the kernel and fail with EINVAL.
+    pub fn seek_file(&mut self, ino: u64, offset: u64, whence: i32) -> Result<u64, Error> {
+        self.block_cache.flush(&mut self.db, ino)?;
+        match whence {
+            SEEK_DATA => self.db.seek_data(ino, offset),
+            SEEK_HOLE => self.db.seek_hole(ino, offset),
+            _ => Err(Error::from(ErrorKind::FsParm {description: format!("whence {} is not supported", whence)})),
+        }
+    }
+
+    /// Allocate, punch, zero or collapse a range of a file like fallocate(2). Cached data is written back first.
+    pub fn fallocate_file(&mut self, ino: u64, offset: u64, length: u64, mode: u32) -> Result<(), Error> {
+        self.block_cache.flush(&mut self.db, ino)?;
+        self.db.fallocate(ino, offset, length, mode)
+    }
+
+    /// Copy bytes between files by a user like copy_file_range(2), and return the copied size.
+    /// Cached data of both files is written back first.
+    pub fn copy_file_range(&mut self, ino_in: u64, offset_in: u64, ino_out: u64, offset_out: u64, length: u64, uid: u32) -> Result<u64, Error> {
+        self.block_cache.flush(&mut self.db, ino_in)?;
+        self.block_cache.flush(&mut self.db, ino_out)?;
+        if uid != 0 {
+            self.clear_suid(ino_out)?;
+        }
+        self.db.copy_file_range(ino_in, offset_in, ino_out, offset_out, length)
+    }
+
+    // Flags of a file handle. A handle which is not registered has no restriction.
+    fn get_open_file_stat(&self, ino: u64, fh: u64) -> OpenFileStat {
+        let handler = self.open_file_handler.lock().unwrap();
+        match handler.get(&ino).and_then(|n| n.list.get(&fh)) {
+            Some(n) => *n,
+            None => OpenFileStat::default(),
+        }
+    }
+
+    // The binding doesn't pass FUSE_INTERRUPT. A waiter of a killed process is cancelled when its files are closed.
+    fn release_lock_and_wake(&mut self, ino: u64, lock_owner: u64) -> Result<(), Error> {
+        {
+            let mut waiter_list = self.lock_waiter.lock().unwrap();
+            let (cancelled, waiters) = std::mem::take(&mut *waiter_list).into_iter()
+                .partition(|n| n.lock.ino == ino && n.lock.lock_owner == lock_owner);
+            *waiter_list = waiters;
+            for waiter in cancelled {
+                waiter.reply.error(EINTR);
+            }
+        }
+        self.db.release_lock(ino, lock_owner)?;
+        self.wake_lock_waiter();
+        Ok(())
     }
 }
 
 impl Filesystem for SqliteFs {
-    fn init(&mut self, _req: &Request<'_>) -> Result<(), c_int> {
+    fn init(&mut self, _req: &Request<'_>, _config: &mut KernelConfig) -> Result<(), c_int> {
         match self.db.delete_all_noref_inode() {
             Ok(n) => n,
             Err(err) => debug!("{}", err)
@@ -125,7 +452,15 @@ impl Filesystem for SqliteFs {
         Ok(())
     }
 
-    fn destroy(&mut self, _req: &Request<'_>) {
+    fn destroy(&mut self) {
+        match self.block_cache.flush_all(&mut self.db) {
+            Ok(n) => n,
+            Err(err) => debug!("{}", err)
+        }
+        match self.db.release_all_lock() {
+            Ok(n) => n,
+            Err(err) => debug!("{}", err)
+        }
         let lc_list = self.lookup_count.lock().unwrap();
         for key in lc_list.keys() {
             match self.db.delete_inode_if_noref(*key) {
@@ -133,14 +468,24 @@ impl Filesystem for SqliteFs {
                 Err(err) => debug!("{}", err)
             }
         }
+        // leave a complete database file without WAL
+        match self.db.checkpoint() {
+            Ok(n) => n,
+            Err(err) => debug!("{}", err)
+        }
     }
 
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        let parent = parent as u32;
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        self.flush_expired();
+        match self.check_access(parent, &Credential::from_request(req), X_OK as u32) {
+            Ok(_) => (),
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+        };
         let child = match self.db.lookup(parent, name.to_str().unwrap()) {
             Ok(n) => {
                 match n {
                     Some(v) => {
+                        let v = self.with_cached_size(v);
                         reply.entry(&ONE_SEC, &v.get_file_attr() , 0);
                         debug!("filesystem:lookup, return:{:?}", v.get_file_attr());
                         v.ino
@@ -148,7 +493,7 @@ impl Filesystem for SqliteFs {
                     None => { reply.error(ENOENT); return;}
                 }
             },
-            Err(err) => {reply.error(ENOENT); debug!("{}", err); return;}
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
         };
         let mut lc_list = self.lookup_count.lock().unwrap();
         let lc = lc_list.entry(child).or_insert(0);
@@ -157,10 +502,9 @@ impl Filesystem for SqliteFs {
     }
 
     fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
-        let ino = ino as u32;
         let mut lc_list = self.lookup_count.lock().unwrap();
         let lc = lc_list.entry(ino).or_insert(0);
-        *lc -= nlookup as u32;
+        *lc -= nlookup;
         debug!("filesystem:forget, lookup count:{:?}", *lc);
         if *lc == 0 {
             lc_list.remove(&ino);
@@ -171,11 +515,13 @@ impl Filesystem for SqliteFs {
         }
     }
 
-    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        match self.db.get_inode(ino as u32) {
+    fn getattr(&mut self, _req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        self.flush_expired();
+        match self.db.get_inode(ino) {
             Ok(n) => {
                 match n {
                     Some(v) => {
+                        let v = self.with_cached_size(v);
                         reply.attr(&ONE_SEC, &v.get_file_attr());
                         debug!("filesystem:getattr, return:{:?}", v.get_file_attr());
                     },
@@ -183,78 +529,121 @@ impl Filesystem for SqliteFs {
                 }
 
             },
-            Err(_err) => reply.error(ENOENT)
+            Err(err) => {reply.error(err.errno()); debug!("{}", err);}
         };
     }
 
     fn setattr(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         ino: u64,
         mode: Option<u32>,
         uid: Option<u32>,
         gid: Option<u32>,
         size: Option<u64>,
-        atime: Option<Timespec>,
-        mtime: Option<Timespec>,
-        _fh: Option<u64>,
-        crtime: Option<Timespec>,
-        _chgtime: Option<Timespec>,
-        _bkuptime: Option<Timespec>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
         flags: Option<u32>,
         reply: ReplyAttr
     ) {
-        let mut attr = match self.db.get_inode(ino as u32) {
+        match self.block_cache.flush(&mut self.db, ino) {
+            Ok(_) => (),
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+        };
+        let mut attr = match self.db.get_inode(ino) {
             Ok(n) => {
                 match n {
                     Some(v) => v,
                     None => {reply.error(ENOENT); return;}
                 }
             },
-            Err(err) => {reply.error(ENOENT); debug!("{}", err); return;}
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+        };
+        let cred = Credential::from_request(req);
+        // only the owner can set times other than the current time
+        let set_time = |n: &Option<TimeOrNow>| matches!(n, Some(TimeOrNow::SpecificTime(_)));
+        if (mode.is_some() || crtime.is_some() || flags.is_some() || set_time(&atime) || set_time(&mtime)) && !self.default_permissions {
+            match permission::check_owner(&attr, &cred) {
+                Ok(_) => (),
+                Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+            };
+        }
+        if !self.default_permissions {
+            match permission::check_chown(&attr, &cred, uid, gid) {
+                Ok(_) => (),
+                Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+            };
+        }
+        // ftruncate of a writable handle is always allowed
+        let writable_handle = match fh {
+            Some(n) => !self.get_open_file_stat(ino, n).readonly,
+            None => false,
         };
+        if (size.is_some() && !writable_handle) ||
+            ((atime.is_some() || mtime.is_some()) && !cred.is_root() && !cred.is_owner(&attr)) {
+            match self.check_permission(&attr, &cred, W_OK as u32) {
+                Ok(_) => (),
+                Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+            };
+        }
         let old_size = attr.size;
-        if let Some(n) = mode {attr.perm = n as u16};
         if let Some(n) = uid {attr.uid = n};
         if let Some(n) = gid {attr.gid = n};
-        if let Some(n) = size {attr.size = n as u32};
-        if let Some(n) = atime {attr.atime = attr.datetime_from(&n)};
-        if let Some(n) = mtime {attr.mtime = attr.datetime_from(&n)};
-        if let Some(n) = crtime {attr.crtime = attr.datetime_from(&n)};
+        if let Some(n) = size {attr.size = n};
+        if (uid.is_some() || gid.is_some() || (size.is_some() && !cred.is_root())) && mode.is_none() {
+            permission::clear_suid(&mut attr);
+        }
+        if let Some(n) = mode {attr.perm = permission::chmod_mode(&attr, &cred, n as u16)};
+        let now = SystemTime::now();
+        let time_of = |n: TimeOrNow| match n {
+            TimeOrNow::SpecificTime(t) => t,
+            TimeOrNow::Now => now,
+        };
+        if let Some(n) = atime {attr.atime = time_of(n)};
+        if let Some(n) = mtime {attr.mtime = time_of(n)};
+        if let Some(n) = crtime {attr.crtime = n};
         if let Some(n) = flags {attr.flags = n};
         match self.db.update_inode(&attr, old_size > attr.size) {
             Ok(_n) => (),
-            Err(err) => {reply.error(ENOENT); debug!("{}", err); return;}
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
         };
+        if mode.is_some() {
+            match self.chmod_acl(&attr) {
+                Ok(_) => (),
+                Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+            };
+        }
         reply.attr(&ONE_SEC, &attr.get_file_attr());
     }
 
     fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
-        let ino = ino as u32;
         let attr = match self.db.get_inode(ino) {
             Ok(n) => match n {
                 Some(attr) => attr,
                 None => {reply.error(ENOENT); return;}
             },
-            Err(err) => {reply.error(ENOENT); debug!("{}", err); return;}
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
         };
 
         if attr.kind != FileType::Symlink {
             reply.error(EINVAL);
             return;
         }
-        let size = attr.size;
-        let mut data = match self.db.get_data(ino as u32, 1, size) {
+        // a long link is stored in several blocks
+        let data = match self.db.read_file(ino, 0, attr.size as u32, false) {
             Ok(n) => n,
-            Err(_err) => {reply.error(ENOENT); return; }
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
         };
-        data.resize(size as usize, 0);
         reply.data(&data);
     }
 
-    fn mkdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
+    fn mkdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, _umask: u32, reply: ReplyEntry) {
         let now = SystemTime::now();
-        let parent = parent as u32;
         let mut attr = DBFileAttr {
             ino: 0,
             size: 0,
@@ -276,20 +665,25 @@ impl Filesystem for SqliteFs {
                 Some(n) => n,
                 None => {reply.error(ENOENT); return;}
             },
-            Err(err) => {reply.error(ENOENT); debug!("{}", err); return;}
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
         };
-        if parent_attr.perm & S_ISGID as u16 > 0 {
-            attr.perm |= S_ISGID as u16;
-            attr.gid = parent_attr.gid;
-        }
+        match self.check_create(&parent_attr, &Credential::from_request(req)) {
+            Ok(_) => (),
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+        };
+        permission::inherit_setgid(&parent_attr, &mut attr);
         if parent_attr.perm & S_ISVTX as u16 > 0 {
             attr.perm |= S_ISVTX as u16;
         }
         let ino =  match self.db.add_inode_and_dentry(parent, name.to_str().unwrap(), &attr) {
             Ok(n) => n,
-            Err(err) => {reply.error(ENOENT); debug!("{}", err); return;}
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
         };
         attr.ino = ino;
+        match self.inherit_acl(parent, &mut attr) {
+            Ok(_) => (),
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+        };
         reply.entry(&ONE_SEC, &attr.get_file_attr(), 0);
         let mut lc_list = self.lookup_count.lock().unwrap();
         let lc = lc_list.entry(ino).or_insert(0);
@@ -297,17 +691,76 @@ impl Filesystem for SqliteFs {
         debug!("filesystem:mkdir, inode: {:?} lookup count:{:?}", ino, *lc);
     }
 
-    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        let ino = match self.db.delete_dentry(parent as u32, name.to_str().unwrap()) {
+    fn mknod(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, _umask: u32, rdev: u32, reply: ReplyEntry) {
+        let kind = match mode & S_IFMT {
+            S_IFREG => FileType::RegularFile,
+            S_IFIFO => FileType::NamedPipe,
+            S_IFCHR => FileType::CharDevice,
+            S_IFBLK => FileType::BlockDevice,
+            S_IFSOCK => FileType::Socket,
+            _ => {reply.error(EINVAL); return;}
+        };
+        let now = SystemTime::now();
+        let mut attr = DBFileAttr {
+            ino: 0,
+            size: 0,
+            blocks: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            kind,
+            perm: (mode & 0o7777) as u16,
+            nlink: 0,
+            uid: req.uid(),
+            gid: req.gid(),
+            rdev,
+            flags: 0
+        };
+        let parent_attr = match self.db.get_inode(parent) {
+            Ok(n) => match n {
+                Some(n) => n,
+                None => {reply.error(ENOENT); return;}
+            },
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+        };
+        match self.check_create(&parent_attr, &Credential::from_request(req)) {
+            Ok(_) => (),
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+        };
+        permission::inherit_setgid(&parent_attr, &mut attr);
+        let ino = match self.db.add_inode_and_dentry(parent, name.to_str().unwrap(), &attr) {
+            Ok(n) => n,
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+        };
+        attr.ino = ino;
+        match self.inherit_acl(parent, &mut attr) {
+            Ok(_) => (),
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+        };
+        reply.entry(&ONE_SEC, &attr.get_file_attr(), 0);
+        let mut lc_list = self.lookup_count.lock().unwrap();
+        let lc = lc_list.entry(ino).or_insert(0);
+        *lc += 1;
+        debug!("filesystem:mknod, inode: {:?} lookup count:{:?}", ino, *lc);
+    }
+
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let name = name.to_str().unwrap();
+        match self.check_remove(parent, name, &Credential::from_request(req)) {
+            Ok(_) => (),
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+        };
+        let ino = match self.db.delete_dentry(parent, name) {
             Ok(n) => n,
-            Err(err) => {reply.error(ENOENT); debug!("{}", err); return;}
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
         };
         let lc_list = self.lookup_count.lock().unwrap();
         if !lc_list.contains_key(&ino) {
             match self.db.delete_inode_if_noref(ino) {
                 Ok(n) => n,
                 Err(err) => {
-                    reply.error(ENOENT);
+                    reply.error(err.errno());
                     debug!("{}", err);
                     return;
                 }
@@ -316,21 +769,15 @@ impl Filesystem for SqliteFs {
         reply.ok();
     }
 
-    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        let parent = parent as u32;
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         let name = name.to_str().unwrap();
-        let attr = match self.db.lookup(parent, name) {
-            Ok(n) => {
-                match n {
-                    Some(v) => v,
-                    None => {reply.error(ENOENT); return;}
-                }
-            },
-            Err(err) => {reply.error(ENOENT); debug!("{}", err); return;}
+        let attr = match self.check_remove(parent, name, &Credential::from_request(req)) {
+            Ok(n) => n,
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
         };
         let empty = match self.db.check_directory_is_empty(attr.ino){
             Ok(n) => n,
-            Err(err) => {reply.error(ENOENT); debug!("{}", err); return;}
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
         };
         if !empty {
             reply.error(ENOTEMPTY);
@@ -338,14 +785,14 @@ impl Filesystem for SqliteFs {
         }
         let ino = match self.db.delete_dentry(parent, name) {
             Ok(n) => n,
-            Err(err) => {reply.error(ENOENT); debug!("{}", err); return;}
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
         };
         let lc_list = self.lookup_count.lock().unwrap();
         if !lc_list.contains_key(&ino) {
             match self.db.delete_inode_if_noref(ino) {
                 Ok(n) => n,
                 Err(err) => {
-                    reply.error(ENOENT);
+                    reply.error(err.errno());
                     debug!("{}", err);
                     return;
                 }
@@ -355,6 +802,15 @@ impl Filesystem for SqliteFs {
     }
 
     fn symlink(&mut self, req: &Request, parent: u64, name: &OsStr, link: &Path, reply: ReplyEntry) {
+        let data = link.to_str().unwrap().as_bytes();
+        if data.len() >= PATH_MAX as usize {
+            reply.error(ENAMETOOLONG);
+            return;
+        }
+        match self.check_access(parent, &Credential::from_request(req), (W_OK | X_OK) as u32) {
+            Ok(_) => (),
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+        };
         let now = SystemTime::now();
         let mut attr = DBFileAttr {
             ino: 0,
@@ -372,21 +828,16 @@ impl Filesystem for SqliteFs {
             rdev: 0,
             flags: 0
         };
-        let ino = match self.db.add_inode_and_dentry(parent as u32, name.to_str().unwrap(), &attr) {
+        let ino = match self.db.add_inode_and_dentry(parent, name.to_str().unwrap(), &attr) {
             Ok(n) => n,
-            Err(err) => {reply.error(ENOENT); debug!("{}", err); return;}
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
         };
-        let data = link.to_str().unwrap().as_bytes();
-        let block_size = self.db.get_db_block_size() as usize;
-        if data.len() > block_size {
-            reply.error(ENAMETOOLONG);
-            return;
-        }
-        match self.db.write_data(ino, 1, &data, data.len() as u32) {
+        match self.db.write_file(ino, 0, data) {
             Ok(n) => n,
-            Err(err) => {reply.error(ENOENT); debug!("{}", err); return;}
-        }
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+        };
         attr.ino = ino;
+        attr.size = data.len() as u64;
         reply.entry(&ONE_SEC, &attr.get_file_attr(), 0);
         let mut lc_list = self.lookup_count.lock().unwrap();
         let lc = lc_list.entry(ino).or_insert(0);
@@ -396,159 +847,211 @@ impl Filesystem for SqliteFs {
 
     fn rename(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         parent: u64,
         name: &OsStr,
         newparent: u64,
         newname: &OsStr,
+        flags: u32,
         reply: ReplyEmpty
     ) {
-        let parent = parent as u32;
+        // RENAME_NOREPLACE and RENAME_EXCHANGE are not supported
+        if flags != 0 {
+            reply.error(EINVAL);
+            return;
+        }
         let name = name.to_str().unwrap();
-        let newparent = newparent as u32;
         let newname = newname.to_str().unwrap();
-        let entry =  match self.db.move_dentry(parent, name, newparent, newname) {
+        let cred = Credential::from_request(req);
+        let attr = match self.check_remove(parent, name, &cred) {
             Ok(n) => n,
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+        };
+        // ".." of a directory is changed when it is moved to another directory
+        if attr.kind == FileType::Directory && parent != newparent {
+            match self.check_permission(&attr, &cred, W_OK as u32) {
+                Ok(_) => (),
+                Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+            };
+        }
+        match self.check_access(newparent, &cred, (W_OK | X_OK) as u32) {
+            Ok(_) => (),
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+        };
+        // the sticky bit protects the file which is replaced
+        match self.check_remove(newparent, newname, &cred) {
+            Ok(_) => (),
             Err(err) => match err.kind() {
-                ErrorKind::FsNotEmpty {description} => {reply.error(ENOTEMPTY); debug!("{}", &description); return;},
-                ErrorKind::FsIsDir{description} => {reply.error(EISDIR); debug!("{}", &description); return;},
-                ErrorKind::FsIsNotDir{description} => {reply.error(ENOTDIR); debug!("{}", &description); return;},
-                _ => {reply.error(ENOENT); debug!("{}", err); return;},
+                ErrorKind::FsNoEnt {..} => (),
+                _ => {reply.error(err.errno()); debug!("{}", err); return;}
             }
         };
+        let entry =  match self.db.move_dentry(parent, name, newparent, newname) {
+            Ok(n) => n,
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+        };
         if let Some(ino) = entry {
             let lc_list = self.lookup_count.lock().unwrap();
             if !lc_list.contains_key(&ino) {
                 match self.db.delete_inode_if_noref(ino) {
                     Ok(n) => n,
-                    Err(err) => {reply.error(ENOENT); debug!("{}", err); return;},
+                    Err(err) => {reply.error(err.errno()); debug!("{}", err); return;},
                 };
             }
         }
         reply.ok();
     }
 
-    fn link(&mut self, _req: &Request<'_>, ino: u64, newparent: u64, newname: &OsStr, reply: ReplyEntry) {
-        let attr = match self.db.link_dentry(ino as u32, newparent as u32, newname.to_str().unwrap()) {
-            Ok(n) => n,
-            Err(err) => match err.kind() {
-                ErrorKind::FsParm{description} => {reply.error(EPERM); debug!("{}", &description); return;},
-                ErrorKind::FsFileExist{description} => {reply.error(EEXIST); debug!("{}", &description); return;},
-                _ => {reply.error(ENOENT); debug!("{}", err); return;}
-            }
+    fn link(&mut self, req: &Request<'_>, ino: u64, newparent: u64, newname: &OsStr, reply: ReplyEntry) {
+        match self.check_access(newparent, &Credential::from_request(req), (W_OK | X_OK) as u32) {
+            Ok(_) => (),
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+        };
+        let attr = match self.db.link_dentry(ino, newparent, newname.to_str().unwrap()) {
+            Ok(n) => self.with_cached_size(n),
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
         };
         reply.entry(&ONE_SEC, &attr.get_file_attr(), 0);
         let mut lc_list = self.lookup_count.lock().unwrap();
-        let lc = lc_list.entry(ino as u32).or_insert(0);
+        let lc = lc_list.entry(ino).or_insert(0);
         *lc += 1;
         debug!("filesystem:link, lookup count:{:?}", *lc);
     }
 
-    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: u32, reply: ReplyOpen) {
-        let ino = ino as u32;
-        let mut stat = OpenFileStat::new();
-        if flags & O_APPEND as u32 > 0 {
-            stat.append = true;
-        }
-        if flags & O_RDONLY as u32 > 0 {
-            stat.readonly = true;
-        }
-        if flags & O_NOATIME as u32 > 0 {
-            stat.noatime = true;
-        }
-        let mut handler = self.open_file_handler.lock().unwrap();
-        let handle_list = handler.entry(ino).or_insert_with(OpenFileHandler::new);
-        let fh = handle_list.count;
-        (*handle_list).list.insert(fh, stat);
-        (*handle_list).count += 1;
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        let flags = flags as u32;
+        match self.check_access(ino, &Credential::from_request(req), open_mask(flags)) {
+            Ok(_) => (),
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+        };
+        let fh = match self.open_file(ino, flags) {
+            Ok(n) => n,
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+        };
         reply.opened(fh, 0);
     }
 
-    fn read(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, size: u32, reply: ReplyData) {
-        let mut data: Vec<u8> = Vec::with_capacity(size as usize);
-        let block_size = self.db.get_db_block_size();
-        let mut size = size;
-        let mut offset = offset as u32;
-        while size > 0 {
-            let b_num = offset / block_size + 1;
-            let mut block_data = match self.db.get_data(ino as u32, b_num, block_size) {
-                Ok(n) => n,
-                Err(_err) => {reply.error(ENOENT); return; }
-            };
-            let b_offset = offset % block_size;
-            let b_end = if (size + b_offset) / block_size >= 1 {block_size} else {size + b_offset};
-            if block_data.len() < b_end as usize {
-                block_data.resize(b_end as usize, 0);
-            }
-            data.append(&mut block_data[b_offset as usize..b_end as usize].to_vec());
-            offset += b_end - b_offset;
-            size -= b_end - b_offset;
-        }
+    fn read(&mut self, _req: &Request, ino: u64, fh: u64, offset: i64, size: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyData) {
+        let data = match self.read_file(ino, fh, offset as u64, size) {
+            Ok(n) => n,
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+        };
         reply.data(&data);
     }
 
-    fn write(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, data: &[u8], _flags: u32, reply: ReplyWrite) {
-        let block_size = self.db.get_db_block_size();
-        let ino = ino as u32;
-        let size = data.len() as u32;
-        let offset = offset as u32;
-        let start_block = offset / block_size + 1;
-        let end_block = (offset + size - 1) / block_size + 1;
-        for i in start_block..=end_block {
-            let mut block_data: Vec<u8> = Vec::with_capacity(block_size as usize);
-            let b_start_index = if i == start_block {offset % block_size} else {0};
-            let b_end_index = if i == end_block {(offset+size-1) % block_size +1} else {block_size};
-            let data_offset;
-            if (i > start_block) {
-                //The blocks don't need to be in perfect alignment.
-                data_offset =
-                        ((i - start_block - 1) * block_size) + (block_size) - offset % block_size;
-            } else {
-                data_offset = 0;
-            }
+    fn write(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, data: &[u8], _write_flags: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyWrite) {
+        match self.write_file(ino, fh, offset as u64, data, req.uid()) {
+            Ok(n) => reply.written(n),
+            Err(err) => {reply.error(err.errno()); debug!("{}", err);}
+        };
+    }
 
-            if (b_start_index != 0) || (b_end_index != block_size) {
-                let mut data_pre = match self.db.get_data(ino, i, block_size) {
-                    Ok(n) => n,
-                    Err(err) => {reply.error(ENOENT); debug!("{}", err); return;}
-                };
-                if data_pre.len() < block_size as usize {
-                    data_pre.resize(block_size as usize, 0);
-                }
-                if b_start_index != 0 {
-                    block_data.extend_from_slice(&data_pre[0..b_start_index as usize]);
-                }
-                block_data.extend_from_slice(&data[data_offset as usize..(data_offset + b_end_index - b_start_index) as usize]);
-                if b_end_index != block_size {
-                    block_data.extend_from_slice(&data_pre[b_end_index as usize..block_size as usize]);
-                }
-            } else {
-                block_data.extend_from_slice(&data[data_offset as usize..(data_offset + block_size) as usize]);
-            }
-            match self.db.write_data(ino, i, &block_data, (i-1) * block_size + b_end_index) {
-                Ok(n) => n,
-                Err(err) => {reply.error(ENOENT); debug!("{}", err); return;}
-            }
-        }
-        reply.written(size);
+    fn flush(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        match self.block_cache.flush(&mut self.db, ino) {
+            Ok(n) => n,
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+        };
+        match self.release_lock_and_wake(ino, lock_owner) {
+            Ok(n) => n,
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+        };
+        reply.ok();
     }
 
-    fn release(&mut self, _req: &Request<'_>, ino: u64, fh: u64, _flags: u32, _lock_owner: u64, _flush: bool, reply: ReplyEmpty) {
-        let ino = ino as u32;
+    fn release(&mut self, _req: &Request<'_>, ino: u64, fh: u64, _flags: i32, lock_owner: Option<u64>, _flush: bool, reply: ReplyEmpty) {
+        match self.block_cache.flush(&mut self.db, ino) {
+            Ok(n) => n,
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+        };
+        if let Some(lock_owner) = lock_owner {
+            match self.release_lock_and_wake(ino, lock_owner) {
+                Ok(n) => n,
+                Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+            };
+        }
         let mut handler = self.open_file_handler.lock().unwrap();
         let handle_list = handler.entry(ino).or_insert_with(OpenFileHandler::new);
         (*handle_list).list.remove(&fh);
-        if (*handle_list).count == 0 {
+        if (*handle_list).list.is_empty() {
             handler.remove(&ino);
         }
         reply.ok();
     }
 
-    fn opendir(&mut self, _req: &Request<'_>, ino: u64, _flags: u32, reply: ReplyOpen) {
-        let ino = ino as u32;
+    fn lseek(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, whence: i32, reply: ReplyLseek) {
+        if offset < 0 {
+            reply.error(ENXIO);
+            return;
+        }
+        match self.seek_file(ino, offset as u64, whence) {
+            Ok(n) => reply.offset(n as i64),
+            Err(err) => {reply.error(err.errno()); debug!("{}", err);}
+        };
+    }
+
+    fn fallocate(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, length: i64, mode: i32, reply: ReplyEmpty) {
+        if offset < 0 || length < 0 {
+            reply.error(EINVAL);
+            return;
+        }
+        match self.fallocate_file(ino, offset as u64, length as u64, mode as u32) {
+            Ok(_) => reply.ok(),
+            Err(err) => {reply.error(err.errno()); debug!("{}", err);}
+        };
+    }
+
+    fn copy_file_range(
+        &mut self,
+        req: &Request<'_>,
+        ino_in: u64,
+        _fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        _fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        _flags: u32,
+        reply: ReplyWrite
+    ) {
+        if offset_in < 0 || offset_out < 0 {
+            reply.error(EINVAL);
+            return;
+        }
+        // the reply has a 32 bit size, and the caller copies the rest again
+        let len = std::cmp::min(len, u64::from(u32::MAX));
+        match self.copy_file_range(ino_in, offset_in as u64, ino_out, offset_out as u64, len, req.uid()) {
+            Ok(n) => reply.written(n as u32),
+            Err(err) => {reply.error(err.errno()); debug!("{}", err);}
+        };
+    }
+
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
+        match self.check_access(ino, &Credential::from_request(req), mask as u32) {
+            Ok(_) => reply.ok(),
+            Err(err) => {reply.error(err.errno()); debug!("{}", err);}
+        };
+    }
+
+    fn fsync(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, datasync: bool, reply: ReplyEmpty) {
+        match self.block_cache.flush(&mut self.db, ino) {
+            Ok(n) => n,
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+        };
+        match self.db.sync(datasync) {
+            Ok(n) => n,
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+        };
+        reply.ok();
+    }
+
+    fn opendir(&mut self, req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        match self.check_access(ino, &Credential::from_request(req), R_OK as u32) {
+            Ok(_) => (),
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+        };
         let dentries = match self.db.get_dentry(ino) {
             Ok(n) => n,
-            Err(err) => {reply.error(ENOENT); debug!("{}", err); return;}
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
         };
         let mut handler = self.open_dir_handler.lock().unwrap();
         let handle_list = handler.entry(ino).or_insert_with(OpenDirHandler::new);
@@ -560,11 +1063,10 @@ impl Filesystem for SqliteFs {
 
     #[cfg(not(target_os = "macos"))]
     fn readdir(&mut self, _req: &Request, ino: u64, fh: u64, offset: i64, mut reply: ReplyDirectory) {
-        /*let db_entries = match self.db.get_dentry(ino as u32) {
+        /*let db_entries = match self.db.get_dentry(ino) {
             Ok(n) => n,
-            Err(err) => {reply.error(ENOENT); debug!("{}", err); return;}
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
         };*/
-        let ino = ino as u32;
         let handler = self.open_dir_handler.lock().unwrap();
         let db_entries: &Vec<DEntry> = match match handler.get(&ino) {
             Some(n) => n.list.get(&fh),
@@ -575,35 +1077,33 @@ impl Filesystem for SqliteFs {
         };
 
         for (i, entry) in db_entries.iter().enumerate().skip(offset as usize) {
-            let full = reply.add(entry.child_ino as u64, (i + 1) as i64, entry.file_type, &entry.filename);
+            let full = reply.add(entry.child_ino, (i + 1) as i64, entry.file_type, &entry.filename);
             if full {
                 break;
             }
-            debug!("filesystem:readdir, ino: {:?} offset: {:?} kind: {:?} name: {}", entry.child_ino as u64, (i + 1) as i64, entry.file_type, entry.filename);
+            debug!("filesystem:readdir, ino: {:?} offset: {:?} kind: {:?} name: {}", entry.child_ino, (i + 1) as i64, entry.file_type, entry.filename);
         }
         reply.ok();
     }
 
     #[cfg(target_os = "macos")]
     fn readdir(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
-        let ino = ino as u32;
         let db_entries = match self.db.get_dentry(ino) {
             Ok(n) => n,
-            Err(err) => {reply.error(ENOENT); debug!("{}", err); return;}
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
         };
 
         for (i, entry) in db_entries.iter().enumerate().skip(offset as usize) {
-            let full = reply.add(entry.child_ino as u64, (i + 1) as i64, entry.file_type, &entry.filename);
+            let full = reply.add(entry.child_ino, (i + 1) as i64, entry.file_type, &entry.filename);
             if full {
                 break;
             }
-            debug!("filesystem:readdir, ino: {:?} offset: {:?} kind: {:?} name: {}", entry.child_ino as u64, (i + 1) as i64, entry.file_type, entry.filename);
+            debug!("filesystem:readdir, ino: {:?} offset: {:?} kind: {:?} name: {}", entry.child_ino, (i + 1) as i64, entry.file_type, entry.filename);
         }
         reply.ok();
     }
 
-    fn releasedir(&mut self, _req: &Request<'_>, ino: u64, fh: u64, _flags: u32, reply: ReplyEmpty) {
-        let ino = ino as u32;
+    fn releasedir(&mut self, _req: &Request<'_>, ino: u64, fh: u64, _flags: i32, reply: ReplyEmpty) {
         let mut handler = self.open_dir_handler.lock().unwrap();
         let handle_list = handler.entry(ino).or_insert_with(OpenDirHandler::new);
         (*handle_list).list.remove(&fh);
@@ -613,24 +1113,33 @@ impl Filesystem for SqliteFs {
         reply.ok();
     }
 
+    fn fsyncdir(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, datasync: bool, reply: ReplyEmpty) {
+        // directory operations are committed immediately
+        match self.db.sync(datasync) {
+            Ok(n) => n,
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+        };
+        reply.ok();
+    }
+
     fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
-        let stat = statvfs::statvfs("/").unwrap();
-        reply.statfs(
-            stat.blocks() as u64,
-            stat.blocks_free() as u64,
-            stat.blocks_available() as u64,
-            stat.files() as u64,
-            stat.files_free() as u64,
-            stat.block_size() as u32,
-            stat.name_max() as u32,
-            stat.fragment_size() as u32
-        );
+        self.flush_expired();
+        let stat = match self.db.statfs() {
+            Ok(n) => n,
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+        };
+        reply.statfs(stat.blocks, stat.bfree, stat.bavail, stat.files, stat.ffree, stat.bsize, stat.namelen, stat.bsize);
         debug!("statfs {:?}", stat);
     }
 
-    fn setxattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, value: &[u8], flags: u32, _position: u32, reply: ReplyEmpty) {
-        let ino = ino as u32;
+    fn setxattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, value: &[u8], flags: i32, _position: u32, reply: ReplyEmpty) {
         let name = name.to_str().unwrap();
+        let flags = flags as u32;
+        let cred = Credential::from_request(req);
+        match self.check_xattr(ino, name, &cred, true) {
+            Ok(_) => (),
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+        };
         if flags & XATTR_CREATE as u32 > 0 || flags & XATTR_REPLACE as u32 > 0 {
             match self.db.get_xattr(ino, name) {
                 Ok(_) => {
@@ -641,33 +1150,42 @@ impl Filesystem for SqliteFs {
                 },
                 Err(err) => {
                     match err.kind() {
-                        ErrorKind::FsNoEnt {description: _} => {
+                        ErrorKind::FsNoData {description: _} => {
                             if flags & XATTR_REPLACE as u32 > 0 {
-                                reply.error(ENODATA);
+                                reply.error(err.errno());
                                 return;
                             }
                         },
                         _ => {
-                            reply.error(ENOENT);
+                            reply.error(err.errno());
+                            debug!("{}", err);
                             return;
                         }
                     }
                 }
             };
         }
-        match self.db.set_xattr(ino, name, value) {
+        let result = if name == ACL_ACCESS || name == ACL_DEFAULT {
+            self.set_acl(ino, name, value, &cred)
+        } else {
+            self.db.set_xattr(ino, name, value)
+        };
+        match result {
             Ok(n) => n,
-            Err(err) => {reply.error(ENOENT); debug!("{}", err); return;}
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
         };
         reply.ok();
     }
 
-    fn getxattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
-        let ino = ino as u32;
+    fn getxattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
         let name = name.to_str().unwrap();
+        match self.check_xattr(ino, name, &Credential::from_request(req), false) {
+            Ok(_) => (),
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+        };
         let value = match self.db.get_xattr(ino, name) {
             Ok(n) => n,
-            Err(err) => {reply.error(ENODATA); debug!("{}", err); return;}
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
         };
         if size == 0 {
             reply.size(value.len() as u32);
@@ -678,14 +1196,14 @@ impl Filesystem for SqliteFs {
         }
     }
 
-    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
-        let ino = ino as u32;
+    fn listxattr(&mut self, req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        let cred = Credential::from_request(req);
         let names =  match self.db.list_xattr(ino) {
             Ok(n) => n,
-            Err(err) => {reply.error(ENODATA); debug!("{}", err); return;}
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
         };
         let mut data: Vec<u8> = Vec::new();
-        for v in names {
+        for v in names.iter().filter(|n| permission::xattr_visible(&cred, n)) {
             data.extend(v.bytes());
             data.push(0);
         }
@@ -698,23 +1216,27 @@ impl Filesystem for SqliteFs {
         }
     }
 
-    fn removexattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
-        let ino = ino as u32;
+    fn removexattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
         let name = name.to_str().unwrap();
+        match self.check_xattr(ino, name, &Credential::from_request(req), true) {
+            Ok(_) => (),
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+        };
         match self.db.delete_xattr(ino, name) {
             Ok(n) => n,
-            Err(err) => {reply.error(ENOENT); debug!("{}", err); return;}
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
         };
         reply.ok();
     }
 
-    fn create(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, _flags: u32, reply: ReplyCreate) {
+    fn create(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, _umask: u32, flags: i32, reply: ReplyCreate) {
+        let flags = flags as u32;
         let ino;
-        let parent = parent as u32;
         let name = name.to_str().unwrap();
+        let cred = Credential::from_request(req);
         let lookup_result = match self.db.lookup(parent, name) {
             Ok(n) => n,
-            Err(err) => {reply.error(ENOENT); debug!("{}", err); return;}
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
         };
         let mut attr: DBFileAttr;
         match lookup_result {
@@ -728,11 +1250,15 @@ impl Filesystem for SqliteFs {
                         }
                     },
                     Err(err) => {
-                        reply.error(ENOENT);
+                        reply.error(err.errno());
                         debug!("{}", err);
                         return;
                     }
                 };
+                match self.check_create(&parent_attr, &cred) {
+                    Ok(_) => (),
+                    Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+                };
                 let now = SystemTime::now();
                 attr = DBFileAttr {
                     ino: 0,
@@ -746,23 +1272,32 @@ impl Filesystem for SqliteFs {
                     perm: mode as u16,
                     nlink: 0,
                     uid: req.uid(),
-                    gid: if parent_attr.perm & S_ISGID as u16 > 0 { parent_attr.gid } else { req.gid() },
+                    gid: req.gid(),
                     rdev: 0,
                     flags: 0
                 };
+                permission::inherit_setgid(&parent_attr, &mut attr);
                 ino = match self.db.add_inode_and_dentry(parent, name, &attr) {
                     Ok(n) => n,
                     Err(err) => {
-                        reply.error(ENOENT);
+                        reply.error(err.errno());
                         debug!("{}", err);
                         return;
                     }
                 };
                 attr.ino = ino;
+                match self.inherit_acl(parent, &mut attr) {
+                    Ok(_) => (),
+                    Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+                };
                 debug!("filesystem:create, created:{:?}", attr);
             },
             Some(n) => {
-                attr = n;
+                match self.check_permission(&n, &cred, open_mask(flags)) {
+                    Ok(_) => (),
+                    Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+                };
+                attr = self.with_cached_size(n);
                 ino = attr.ino;
                 debug!("filesystem:create, existed:{:?}", attr);
             }
@@ -770,6 +1305,49 @@ impl Filesystem for SqliteFs {
         let mut lc_list = self.lookup_count.lock().unwrap();
         let lc = lc_list.entry(ino).or_insert(0);
         *lc += 1;
-        reply.created(&ONE_SEC, &attr.get_file_attr(), 0, 0, 0);
+        drop(lc_list);
+        let fh = match self.open_file(ino, flags) {
+            Ok(n) => n,
+            Err(err) => {reply.error(err.errno()); debug!("{}", err); return;}
+        };
+        if flags & O_TRUNC as u32 > 0 && !OpenFileStat::new(flags).readonly {
+            attr.size = 0;
+        }
+        reply.created(&ONE_SEC, &attr.get_file_attr(), 0, fh, 0);
+    }
+
+    fn getlk(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, lock_owner: u64, start: u64, end: u64, typ: i32, pid: u32, reply: ReplyLock) {
+        let lock = DBLock{ino, lock_owner, start, end, typ: typ as u32, pid};
+        match self.db.get_lock(&lock) {
+            Ok(n) => match n {
+                Some(v) => reply.locked(v.start, v.end, v.typ as i32, v.pid),
+                None => reply.locked(start, end, F_UNLCK, pid)
+            },
+            Err(err) => {reply.error(err.errno()); debug!("{}", err);}
+        };
+    }
+
+    fn setlk(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, lock_owner: u64, start: u64, end: u64, typ: i32, pid: u32, sleep: bool, reply: ReplyEmpty) {
+        let lock = DBLock{ino, lock_owner, start, end, typ: typ as u32, pid};
+        match self.db.set_lock(&lock) {
+            Ok(n) => n,
+            Err(err) => match err.kind() {
+                ErrorKind::FsLockConflict {description} => {
+                    debug!("{}", &description);
+                    if sleep {
+                        // reply later, when the conflicting lock is released.
+                        self.lock_waiter.lock().unwrap().push(LockWaiter{lock, reply});
+                    } else {
+                        reply.error(EAGAIN);
+                    }
+                    return;
+                },
+                _ => {reply.error(err.errno()); debug!("{}", err); return;}
+            }
+        };
+        reply.ok();
+        if typ == F_UNLCK {
+            self.wake_lock_waiter();
+        }
     }
 }
@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use fuser::FileType;
+use libc::ENOTSUP;
+use crate::db_module::{DbModule, DBFileAttr};
+use crate::sqerror::{Error, ErrorKind, Result};
+
+/// Copy the contents of a host directory into the directory `parent` of the filesystem.
+/// Modes, owners, timestamps, symlinks, hard links and xattrs are preserved.
+/// All files are written in one transaction, so a failed import leaves the filesystem unchanged.
+/// Returns the number of imported inodes.
+pub fn import<T: DbModule>(db: &mut T, src: &Path, parent: u64) -> Result<u64> {
+    if !fs::metadata(src)?.is_dir() {
+        return Err(Error::from(ErrorKind::FsIsNotDir {description: format!(
+            "{} is not a directory",
+            src.display()
+        )}));
+    }
+    let mut importer = Importer{db, links: HashMap::new(), count: 0};
+    importer.db.begin_transaction()?;
+    match importer.import_dir(src, parent) {
+        Ok(_) => importer.db.commit_transaction()?,
+        Err(err) => {
+            importer.db.rollback_transaction()?;
+            return Err(err);
+        }
+    }
+    Ok(importer.count)
+}
+
+struct Importer<'a, T: DbModule> {
+    db: &'a mut T,
+    // (device, host inode) -> inode of a file which has hard links
+    links: HashMap<(u64, u64), u64>,
+    count: u64,
+}
+
+impl<'a, T: DbModule> Importer<'a, T> {
+    fn import_dir(&mut self, dir: &Path, parent: u64) -> Result<()> {
+        let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let file_name = entry.file_name();
+            let name = match file_name.to_str() {
+                Some(n) => n,
+                None => {
+                    return Err(Error::from(ErrorKind::FsParm {description: format!(
+                        "{} is not a valid UTF-8 name",
+                        entry.path().display()
+                    )}));
+                }
+            };
+            self.import_entry(&entry.path(), name, parent)?;
+        }
+        Ok(())
+    }
+
+    fn import_entry(&mut self, path: &Path, name: &str, parent: u64) -> Result<()> {
+        let meta = fs::symlink_metadata(path)?;
+        // Only regular files can be hard linked in the database.
+        let has_links = meta.file_type().is_file() && meta.nlink() > 1;
+        if has_links {
+            if let Some(&ino) = self.links.get(&(meta.dev(), meta.ino())) {
+                self.db.link_dentry(ino, parent, name)?;
+                return Ok(());
+            }
+        }
+        let mut attr = file_attr(&meta);
+        let ino = self.db.add_inode_and_dentry(parent, name, &attr)?;
+        self.count += 1;
+        match attr.kind {
+            FileType::Directory => self.import_dir(path, ino)?,
+            FileType::RegularFile => attr.size = self.write_data(ino, &mut File::open(path)?)?,
+            FileType::Symlink => {
+                let target = fs::read_link(path)?;
+                attr.size = self.write_data(ino, &mut target.as_os_str().as_bytes())?;
+            },
+            _ => {},
+        }
+        for key in list_xattr(path)? {
+            // None if the xattr was removed after it was listed
+            if let Some(value) = xattr::get(path, &key)? {
+                self.db.set_xattr(ino, &key.to_string_lossy(), &value)?;
+            }
+        }
+        // restore timestamps after data and children are written
+        attr.ino = ino;
+        self.db.update_inode(&attr, false)?;
+        if has_links {
+            self.links.insert((meta.dev(), meta.ino()), ino);
+        }
+        Ok(())
+    }
+
+    // Copy data block by block. Returns the size of data.
+    fn write_data(&mut self, ino: u64, reader: &mut dyn Read) -> Result<u64> {
+        let block_size = self.db.get_db_block_size() as usize;
+        let mut buf = vec![0; block_size];
+        let mut size: u64 = 0;
+        let mut block: u64 = 1;
+        loop {
+            let length = read_block(reader, &mut buf)?;
+            if length == 0 {
+                break;
+            }
+            size += length as u64;
+            self.db.write_data(ino, block, &buf[..length], size)?;
+            if length < block_size {
+                break;
+            }
+            block += 1;
+        }
+        Ok(size)
+    }
+}
+
+// Fill the buffer unless the reader reaches EOF.
+fn read_block(reader: &mut dyn Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut length = 0;
+    while length < buf.len() {
+        match reader.read(&mut buf[length..]) {
+            Ok(0) => break,
+            Ok(n) => length += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(length)
+}
+
+fn file_attr(meta: &Metadata) -> DBFileAttr {
+    let file_type = meta.file_type();
+    let kind = if file_type.is_dir() {
+        FileType::Directory
+    } else if file_type.is_symlink() {
+        FileType::Symlink
+    } else if file_type.is_block_device() {
+        FileType::BlockDevice
+    } else if file_type.is_char_device() {
+        FileType::CharDevice
+    } else if file_type.is_fifo() {
+        FileType::NamedPipe
+    } else if file_type.is_socket() {
+        FileType::Socket
+    } else {
+        FileType::RegularFile
+    };
+    let ctime = system_time(meta.ctime(), meta.ctime_nsec());
+    DBFileAttr {
+        ino: 0,
+        size: 0,
+        blocks: 0,
+        atime: system_time(meta.atime(), meta.atime_nsec()),
+        mtime: system_time(meta.mtime(), meta.mtime_nsec()),
+        ctime,
+        crtime: meta.created().unwrap_or(ctime),
+        kind,
+        perm: (meta.mode() & 0o7777) as u16,
+        nlink: 0,
+        uid: meta.uid(),
+        gid: meta.gid(),
+        rdev: meta.rdev() as u32,
+        flags: 0,
+    }
+}
+
+fn system_time(sec: i64, nsec: i64) -> SystemTime {
+    if sec >= 0 {
+        UNIX_EPOCH + Duration::new(sec as u64, nsec as u32)
+    } else {
+        UNIX_EPOCH - Duration::new((-sec) as u64, 0) + Duration::new(0, nsec as u32)
+    }
+}
+
+// List xattr names of a host file. Returns an empty list if the host filesystem doesn't support xattr.
+fn list_xattr(path: &Path) -> Result<Vec<OsString>> {
+    match xattr::list(path) {
+        Ok(n) => Ok(n.collect()),
+        Err(ref err) if err.raw_os_error() == Some(ENOTSUP) => Ok(vec![]),
+        Err(err) => Err(Error::from(err)),
+    }
+}
@@ -3,6 +3,14 @@ extern crate failure;
 #[macro_use]
 extern crate log;
 extern crate nix;
+pub mod acl;
+pub mod block_cache;
+pub mod compression;
 pub mod db_module;
+pub mod export;
+pub mod file;
 pub mod filesystem;
+pub mod import;
+pub mod permission;
 pub mod sqerror;
+pub mod vfs;
@@ -2,11 +2,16 @@
 #[macro_use] extern crate log;
 #[macro_use] extern crate clap;
 use std::env;
-use std::ffi::OsStr;
+use std::path::Path;
+use std::process;
 use sqlite_fs::filesystem::SqliteFs;
-use clap::{App, Arg};
-use sqlite_fs::db_module::sqlite::Sqlite;
-use sqlite_fs::db_module::DbModule;
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, SubCommand};
+use sqlite_fs::db_module::sqlite::{Sqlite, SqliteOptions, SCHEMA_VERSION};
+use sqlite_fs::db_module::{DbModule, FsckIssue, QuotaKind};
+use sqlite_fs::vfs::Vfs;
+use sqlite_fs::compression::Codec;
+use chrono::{DateTime, Local};
+use fuser::MountOption;
 
 fn main() {
     env_logger::init();
@@ -14,7 +19,8 @@ fn main() {
     let mount_option_arg = Arg::with_name("mount_option")
         .short("o")
         .long("option")
-        .help("Additional mount option for this filesystem")
+        .help("Additional mount option for this filesystem. \
+            journal_mode, synchronous, busy_timeout, cache_size, mmap_size and max_size are passed to sqlite")
         .takes_value(true)
         .multiple(true);
 
@@ -27,52 +33,558 @@ fn main() {
         .help("Sqlite database file path. If not set, open database in memory.")
         .index(2);
 
+    let block_size_arg = Arg::with_name("block_size")
+        .short("b")
+        .long("block-size")
+        .help("Block size in bytes of a new filesystem. A power of two between 512 and 1048576 (default 4096)")
+        .takes_value(true);
+
+    let compression_arg = Arg::with_name("compression")
+        .short("c")
+        .long("compression")
+        .help("Compression of data blocks of a new filesystem")
+        .possible_values(&["none", "lz4", "zstd"])
+        .takes_value(true);
+
+    let dedup_arg = Arg::with_name("dedup")
+        .long("dedup")
+        .help("Store identical data blocks only once in a new filesystem");
+
+    let no_default_permissions_arg = Arg::with_name("no_default_permissions")
+        .long("no-default-permissions")
+        .help("Don't let the kernel check permissions. The filesystem checks them by itself");
+
+    let db_path_required_arg = Arg::with_name("db_path")
+        .help("Sqlite database file path.")
+        .index(1)
+        .required(true);
+
+    let mkfs_subcommand = SubCommand::with_name("mkfs")
+        .about("Create a new filesystem database.")
+        .arg(db_path_required_arg.clone())
+        .arg(Arg::with_name("force")
+            .short("f")
+            .long("force")
+            .help("Overwrite the database file if it exists"))
+        .arg(block_size_arg.clone())
+        .arg(compression_arg.clone())
+        .arg(dedup_arg.clone())
+        .arg(Arg::with_name("uid")
+            .long("uid")
+            .help("Owner user id of the root directory")
+            .takes_value(true))
+        .arg(Arg::with_name("gid")
+            .long("gid")
+            .help("Owner group id of the root directory")
+            .takes_value(true))
+        .arg(Arg::with_name("mode")
+            .long("mode")
+            .help("Permission of the root directory in octal (e.g. 755)")
+            .takes_value(true));
+
+    let fsck_subcommand = SubCommand::with_name("fsck")
+        .about("Check consistency of a filesystem database. The filesystem must not be mounted.")
+        .arg(db_path_required_arg.clone())
+        .arg(Arg::with_name("repair")
+            .short("r")
+            .long("repair")
+            .help("Repair problems. Unreachable files are moved to /lost+found"));
+
+    let import_subcommand = SubCommand::with_name("import")
+        .about("Copy a host directory tree into the root directory of a filesystem database.")
+        .arg(Arg::with_name("src_dir")
+            .help("Host directory to copy")
+            .index(1)
+            .required(true))
+        .arg(Arg::with_name("db_path")
+            .help("Sqlite database file path. If it doesn't exist, a new filesystem is created.")
+            .index(2)
+            .required(true))
+        .arg(block_size_arg.clone())
+        .arg(compression_arg.clone())
+        .arg(dedup_arg.clone());
+
+    let export_subcommand = SubCommand::with_name("export")
+        .about("Copy all files of a filesystem database into a host directory or a tar archive.")
+        .arg(Arg::with_name("db_path")
+            .help("Sqlite database file path.")
+            .index(1)
+            .required(true))
+        .arg(Arg::with_name("dest_dir")
+            .help("Host directory to write files. It must be empty")
+            .index(2)
+            .required_unless("tar"))
+        .arg(Arg::with_name("tar")
+            .short("t")
+            .long("tar")
+            .help("Write a tar archive (pax format) to stdout")
+            .conflicts_with("dest_dir"));
+
+    let cp_subcommand = SubCommand::with_name("cp")
+        .about("Copy a file or a directory tree inside a filesystem database.")
+        .arg(db_path_required_arg.clone())
+        .arg(Arg::with_name("src")
+            .help("Source path in the filesystem")
+            .index(2)
+            .required(true))
+        .arg(Arg::with_name("dest")
+            .help("Destination path in the filesystem. It must not exist unless it is a regular file")
+            .index(3)
+            .required(true))
+        .arg(Arg::with_name("reflink")
+            .long("reflink")
+            .help("Share data blocks with the source until either of them is written"));
+
+    let quota_subcommand = SubCommand::with_name("quota")
+        .about("Set or report quotas of a filesystem database.")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(SubCommand::with_name("set")
+            .about("Set limits of a user, group or project quota. A quota without limits is removed.")
+            .arg(db_path_required_arg.clone())
+            .arg(Arg::with_name("user")
+                .short("u")
+                .long("user")
+                .help("User id")
+                .takes_value(true))
+            .arg(Arg::with_name("group")
+                .short("g")
+                .long("group")
+                .help("Group id")
+                .takes_value(true))
+            .arg(Arg::with_name("project")
+                .short("p")
+                .long("project")
+                .help("Project id")
+                .takes_value(true))
+            .group(ArgGroup::with_name("target")
+                .args(&["user", "group", "project"])
+                .required(true))
+            .arg(Arg::with_name("bytes")
+                .long("bytes")
+                .help("Limit of data size in bytes. 0 is unlimited")
+                .takes_value(true)
+                .default_value("0"))
+            .arg(Arg::with_name("inodes")
+                .long("inodes")
+                .help("Limit of the number of files. 0 is unlimited")
+                .takes_value(true)
+                .default_value("0")))
+        .subcommand(SubCommand::with_name("project")
+            .about("Set the project id of a directory tree. New files inherit it from the parent directory.")
+            .arg(db_path_required_arg.clone())
+            .arg(Arg::with_name("path")
+                .help("Directory path in the filesystem")
+                .index(2)
+                .required(true))
+            .arg(Arg::with_name("project_id")
+                .help("Project id")
+                .index(3)
+                .required(true)))
+        .subcommand(SubCommand::with_name("report")
+            .about("Show usage and limits of all quotas.")
+            .arg(db_path_required_arg.clone()));
+
+    let snapshot_name_arg = Arg::with_name("name")
+        .help("Snapshot name")
+        .index(2)
+        .required(true);
+
+    let snapshot_subcommand = SubCommand::with_name("snapshot")
+        .about("Manage read-only snapshots of a filesystem database. They appear in /.snapshots.")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(SubCommand::with_name("create")
+            .about("Take a snapshot of the whole filesystem. Data blocks are shared with the files.")
+            .arg(db_path_required_arg.clone())
+            .arg(snapshot_name_arg.clone()))
+        .subcommand(SubCommand::with_name("list")
+            .about("Show all snapshots.")
+            .arg(db_path_required_arg.clone()))
+        .subcommand(SubCommand::with_name("delete")
+            .about("Delete a snapshot and release its data blocks.")
+            .arg(db_path_required_arg.clone())
+            .arg(snapshot_name_arg.clone()))
+        .subcommand(SubCommand::with_name("rollback")
+            .about("Replace all files with a snapshot. The filesystem must not be mounted.")
+            .arg(db_path_required_arg.clone())
+            .arg(snapshot_name_arg));
+
     let matches = App::new("sqlitefs")
         .about("Sqlite database as a filesystem.")
         .version(crate_version!())
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .setting(AppSettings::ArgsNegateSubcommands)
         .arg(mount_option_arg)
         .arg(mount_point_arg)
         .arg(db_path_arg)
+        .arg(block_size_arg)
+        .arg(compression_arg)
+        .arg(dedup_arg)
+        .arg(no_default_permissions_arg)
+        .subcommand(mkfs_subcommand)
+        .subcommand(fsck_subcommand)
+        .subcommand(import_subcommand)
+        .subcommand(export_subcommand)
+        .subcommand(cp_subcommand)
+        .subcommand(quota_subcommand)
+        .subcommand(snapshot_subcommand)
         .get_matches();
 
-    let mut option_vals = ["-o", "fsname=sqlitefs", "-o", "default_permissions", "-o", "allow_other"].to_vec();
-    if let Some(v) = matches.values_of("mount_option") {
-        for i in v {
-            option_vals.push("-o");
-            option_vals.push(i);
+    match matches.subcommand() {
+        ("mkfs", Some(sub_matches)) => mkfs(sub_matches),
+        ("fsck", Some(sub_matches)) => fsck(sub_matches),
+        ("import", Some(sub_matches)) => import(sub_matches),
+        ("export", Some(sub_matches)) => export(sub_matches),
+        ("cp", Some(sub_matches)) => cp(sub_matches),
+        ("quota", Some(sub_matches)) => quota(sub_matches),
+        ("snapshot", Some(sub_matches)) => snapshot(sub_matches),
+        _ => mount(&matches),
+    }
+}
+
+fn mkfs(matches: &ArgMatches) {
+    let db_path = Path::new(matches.value_of("db_path").expect("Database path is missing."));
+    if db_path.exists() {
+        if !matches.is_present("force") {
+            println!("{} already exists. Use --force to overwrite it.", db_path.display());
+            process::exit(1);
+        }
+        if let Err(err) = std::fs::remove_file(db_path) {
+            println!("{:?}", err);
+            process::exit(1);
         }
     }
+    let uid = if matches.is_present("uid") {Some(value_t_or_exit!(matches, "uid", u32))} else {None};
+    let gid = if matches.is_present("gid") {Some(value_t_or_exit!(matches, "gid", u32))} else {None};
+    let mode = match matches.value_of("mode") {
+        Some(v) => match u16::from_str_radix(v, 8) {
+            Ok(n) => Some(n),
+            Err(err) => {println!("Invalid mode {}: {:?}", v, err); process::exit(1);}
+        },
+        None => None,
+    };
+    let mut db = match Sqlite::new(db_path) {
+        Ok(n) => n,
+        Err(err) => {println!("{:?}", err); process::exit(1);}
+    };
+    if matches.is_present("block_size") {
+        let block_size = value_t_or_exit!(matches, "block_size", u32);
+        if let Err(err) = db.set_block_size(block_size) {
+            println!("{}", err);
+            process::exit(1);
+        }
+    }
+    if let Some(v) = matches.value_of("compression") {
+        match Codec::from_name(v) {
+            Ok(n) => db.set_compression(n),
+            Err(err) => {println!("{}", err); process::exit(1);}
+        };
+    }
+    if matches.is_present("dedup") {
+        db.set_dedup(true);
+    }
+    match db.init() {
+        Ok(n) => n,
+        Err(err) => {println!("{:?}", err); process::exit(1);}
+    };
+    let mut root = match db.get_inode(1) {
+        Ok(Some(n)) => n,
+        Ok(None) => {println!("root directory is not found"); process::exit(1);}
+        Err(err) => {println!("{:?}", err); process::exit(1);}
+    };
+    if let Some(n) = uid {root.uid = n};
+    if let Some(n) = gid {root.gid = n};
+    if let Some(n) = mode {root.perm = n};
+    match db.update_inode(&root, false) {
+        Ok(n) => n,
+        Err(err) => {println!("{:?}", err); process::exit(1);}
+    };
+}
 
-    let mountpoint = matches.value_of("mount_point").expect("Mount point path is missing.");
-    let db_path = matches.value_of("db_path");
-    let options = option_vals
-        .iter()
-        .map(|o| o.as_ref())
-        .collect::<Vec<&OsStr>>();
-    let fs: SqliteFs;
-    match db_path {
-        Some(path) => {
-            fs = match SqliteFs::new(path) {
-                Ok(n) => n,
-                Err(err) => {println!("{:?}", err); return;}
-            };
+fn fsck(matches: &ArgMatches) {
+    let db_path = Path::new(matches.value_of("db_path").expect("Database path is missing."));
+    if !db_path.exists() {
+        println!("{} is not found.", db_path.display());
+        process::exit(8);
+    }
+    let repair = matches.is_present("repair");
+    let mut db = match Sqlite::new(db_path) {
+        Ok(n) => n,
+        Err(err) => {println!("{:?}", err); process::exit(8);}
+    };
+    let version = match db.get_schema_version() {
+        Ok(n) => n,
+        Err(err) => {println!("{:?}", err); process::exit(8);}
+    };
+    // checking doesn't change the database. An old schema is only migrated by repair.
+    let old_schema = format!("database schema version {} is older than {}", version, SCHEMA_VERSION);
+    if version < SCHEMA_VERSION && !repair {
+        println!("inode 0: {}", old_schema);
+        println!("{}: 1 problems found", db_path.display());
+        process::exit(4);
+    }
+    let result = if repair {db.init()} else {db.load()};
+    match result {
+        Ok(n) => n,
+        Err(err) => {println!("{:?}", err); process::exit(8);}
+    };
+    let mut issues = match db.fsck(repair) {
+        Ok(n) => n,
+        Err(err) => {println!("{:?}", err); process::exit(8);}
+    };
+    if version < SCHEMA_VERSION {
+        issues.insert(0, FsckIssue{ino: 0, description: old_schema, repaired: true});
+    }
+    for issue in &issues {
+        println!(
+            "inode {}: {}{}",
+            issue.ino,
+            issue.description,
+            if issue.repaired {" (repaired)"} else {""}
+        );
+    }
+    // exit code follows e2fsck: 0 no problem, 1 problems repaired, 4 problems left.
+    if issues.is_empty() {
+        println!("{}: clean", db_path.display());
+    } else if repair {
+        println!("{}: {} problems repaired", db_path.display(), issues.len());
+        process::exit(1);
+    } else {
+        println!("{}: {} problems found", db_path.display(), issues.len());
+        process::exit(4);
+    }
+}
+
+fn import(matches: &ArgMatches) {
+    let src_dir = Path::new(matches.value_of("src_dir").expect("Source directory is missing."));
+    let db_path = Path::new(matches.value_of("db_path").expect("Database path is missing."));
+    let mut db = match Sqlite::new(db_path) {
+        Ok(n) => n,
+        Err(err) => {println!("{:?}", err); process::exit(1);}
+    };
+    if matches.is_present("block_size") {
+        let block_size = value_t_or_exit!(matches, "block_size", u32);
+        if let Err(err) = db.set_block_size(block_size) {
+            println!("{}", err);
+            process::exit(1);
         }
-        None => {
-            let mut db = match Sqlite::new_in_memory() {
-                Ok(n) => n,
-                Err(err) => {println!("{:?}", err); return;}
+    }
+    if let Some(v) = matches.value_of("compression") {
+        match Codec::from_name(v) {
+            Ok(n) => db.set_compression(n),
+            Err(err) => {println!("{}", err); process::exit(1);}
+        };
+    }
+    if matches.is_present("dedup") {
+        db.set_dedup(true);
+    }
+    match db.init() {
+        Ok(n) => n,
+        Err(err) => {println!("{:?}", err); process::exit(1);}
+    };
+    match sqlite_fs::import::import(&mut db, src_dir, 1) {
+        Ok(n) => println!("{} files imported", n),
+        Err(err) => {println!("{}", err); process::exit(1);}
+    };
+}
+
+fn export(matches: &ArgMatches) {
+    let db_path = Path::new(matches.value_of("db_path").expect("Database path is missing."));
+    if !db_path.exists() {
+        eprintln!("{} is not found.", db_path.display());
+        process::exit(1);
+    }
+    let mut db = match Sqlite::new(db_path) {
+        Ok(n) => n,
+        Err(err) => {eprintln!("{:?}", err); process::exit(1);}
+    };
+    // the source is not changed. An old schema is upgraded by mount or fsck --repair.
+    match db.load() {
+        Ok(n) => n,
+        Err(err) => {eprintln!("{}", err); process::exit(1);}
+    };
+    // stdout may be a tar archive, so messages are written to stderr.
+    let result = match matches.value_of("dest_dir") {
+        Some(dest_dir) => sqlite_fs::export::export_to_dir(&mut db, Path::new(dest_dir)),
+        None => sqlite_fs::export::export_to_tar(&mut db, std::io::stdout().lock()),
+    };
+    match result {
+        Ok(n) => eprintln!("{} files exported", n),
+        Err(err) => {eprintln!("{}", err); process::exit(1);}
+    };
+}
+
+fn cp(matches: &ArgMatches) {
+    let db_path = Path::new(matches.value_of("db_path").expect("Database path is missing."));
+    if !db_path.exists() {
+        println!("{} is not found.", db_path.display());
+        process::exit(1);
+    }
+    let src = matches.value_of("src").expect("Source path is missing.");
+    let dest = matches.value_of("dest").expect("Destination path is missing.");
+    let result = Vfs::new(db_path).and_then(|mut vfs| vfs.copy_all(src, dest, matches.is_present("reflink")));
+    match result {
+        Ok(n) => println!("{} files copied", n),
+        Err(err) => {println!("{}", err); process::exit(1);}
+    };
+}
+
+fn quota(matches: &ArgMatches) {
+    let (command, matches) = match matches.subcommand() {
+        (command, Some(n)) => (command, n),
+        _ => {println!("{}", matches.usage()); process::exit(1);}
+    };
+    let db_path = Path::new(matches.value_of("db_path").expect("Database path is missing."));
+    if !db_path.exists() {
+        println!("{} is not found.", db_path.display());
+        process::exit(1);
+    }
+    let mut db = match Sqlite::new(db_path) {
+        Ok(n) => n,
+        Err(err) => {println!("{:?}", err); process::exit(1);}
+    };
+    match db.init() {
+        Ok(n) => n,
+        Err(err) => {println!("{:?}", err); process::exit(1);}
+    };
+    match command {
+        "set" => {
+            let (kind, id) = if matches.is_present("user") {
+                (QuotaKind::User, value_t_or_exit!(matches, "user", u32))
+            } else if matches.is_present("group") {
+                (QuotaKind::Group, value_t_or_exit!(matches, "group", u32))
+            } else {
+                (QuotaKind::Project, value_t_or_exit!(matches, "project", u32))
             };
-            match db.init() {
+            let bytes = value_t_or_exit!(matches, "bytes", u64);
+            let inodes = value_t_or_exit!(matches, "inodes", u64);
+            if let Err(err) = db.set_quota(kind, id, bytes, inodes) {
+                println!("{}", err);
+                process::exit(1);
+            }
+        },
+        "project" => {
+            let project = value_t_or_exit!(matches, "project_id", u32);
+            let path = matches.value_of("path").expect("Path is missing.");
+            let result = Vfs::new_with_db(db).and_then(|mut vfs| vfs.set_project(path, project));
+            if let Err(err) = result {
+                println!("{}", err);
+                process::exit(1);
+            }
+        },
+        _ => {
+            let quotas = match db.list_quota() {
                 Ok(n) => n,
-                Err(err) => {println!("{:?}", err); return;}
+                Err(err) => {println!("{}", err); process::exit(1);}
             };
-            fs = match SqliteFs::new_with_db(db) {
-                Ok(n) => n,
-                Err(err) => {println!("{:?}", err); return;}
+            let limit = |n: u64| if n == 0 {"-".to_string()} else {n.to_string()};
+            println!("{:<8} {:>10} {:>16} {:>16} {:>12} {:>12}", "kind", "id", "bytes", "bytes limit", "inodes", "inodes limit");
+            for quota in quotas {
+                println!(
+                    "{:<8} {:>10} {:>16} {:>16} {:>12} {:>12}",
+                    format!("{:?}", quota.kind).to_lowercase(),
+                    quota.id,
+                    quota.bytes_used,
+                    limit(quota.bytes_limit),
+                    quota.inodes_used,
+                    limit(quota.inodes_limit)
+                );
+            }
+        },
+    }
+}
+
+fn snapshot(matches: &ArgMatches) {
+    let (command, matches) = match matches.subcommand() {
+        (command, Some(n)) => (command, n),
+        _ => {println!("{}", matches.usage()); process::exit(1);}
+    };
+    let db_path = Path::new(matches.value_of("db_path").expect("Database path is missing."));
+    if !db_path.exists() {
+        println!("{} is not found.", db_path.display());
+        process::exit(1);
+    }
+    let mut db = match Sqlite::new(db_path) {
+        Ok(n) => n,
+        Err(err) => {println!("{:?}", err); process::exit(1);}
+    };
+    match db.init() {
+        Ok(n) => n,
+        Err(err) => {println!("{:?}", err); process::exit(1);}
+    };
+    let name = matches.value_of("name").unwrap_or_default();
+    let result = match command {
+        "create" => db.create_snapshot(name),
+        "delete" => db.delete_snapshot(name),
+        "rollback" => db.rollback_snapshot(name),
+        _ => db.list_snapshot().map(|snapshots| {
+            println!("{:<10} {:<20} name", "id", "created");
+            for snapshot in snapshots {
+                let created = DateTime::<Local>::from(snapshot.created);
+                println!("{:<10} {:<20} {}", snapshot.id, created.format("%Y-%m-%d %H:%M:%S"), snapshot.name);
+            }
+        }),
+    };
+    if let Err(err) = result {
+        println!("{}", err);
+        process::exit(1);
+    }
+}
+
+fn mount(matches: &ArgMatches) {
+    let mut options = vec![MountOption::FSName("sqlitefs".to_string()), MountOption::AllowOther];
+    if !matches.is_present("no_default_permissions") {
+        options.push(MountOption::DefaultPermissions);
+    }
+    let mut sqlite_options = SqliteOptions::default();
+    if let Some(v) = matches.values_of("mount_option") {
+        // "-o a,b" has several options
+        for i in v.flat_map(|n| n.split(',')) {
+            match sqlite_options.parse_option(i) {
+                Ok(true) => continue,
+                Ok(false) => (),
+                Err(err) => {println!("{}", err); return;}
             };
+            options.push(MountOption::CUSTOM(i.to_string()));
         }
     }
-    match fuse::mount(fs, &mountpoint, &options) {
+
+    let mountpoint = matches.value_of("mount_point").expect("Mount point path is missing.");
+    let db_path = matches.value_of("db_path");
+    let db = match db_path {
+        Some(path) => Sqlite::new_with_options(Path::new(path), &sqlite_options),
+        None => Sqlite::new_in_memory(),
+    };
+    let mut db = match db {
+        Ok(n) => n,
+        Err(err) => {println!("{:?}", err); return;}
+    };
+    // block size is only applied when the database is created
+    if matches.is_present("block_size") {
+        let block_size = value_t_or_exit!(matches, "block_size", u32);
+        if let Err(err) = db.set_block_size(block_size) {
+            println!("{}", err);
+            return;
+        }
+    }
+    if let Some(v) = matches.value_of("compression") {
+        match Codec::from_name(v) {
+            Ok(n) => db.set_compression(n),
+            Err(err) => {println!("{}", err); return;}
+        };
+    }
+    if matches.is_present("dedup") {
+        db.set_dedup(true);
+    }
+    match db.init() {
+        Ok(n) => n,
+        Err(err) => {println!("{:?}", err); return;}
+    };
+    let mut fs = match SqliteFs::new_with_db(db) {
+        Ok(n) => n,
+        Err(err) => {println!("{:?}", err); return;}
+    };
+    fs.set_default_permissions(!matches.is_present("no_default_permissions"));
+    match fuser::mount2(fs, mountpoint, &options) {
         Ok(n) => n,
         Err(err) => error!("{}", err)
     }
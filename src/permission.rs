@@ -0,0 +1,202 @@
+use std::cell::RefCell;
+use std::fs;
+use fuser::{FileType, Request};
+use libc::{S_ISUID, S_ISGID, S_ISVTX, S_IXGRP, R_OK, W_OK, X_OK};
+use crate::acl::Acl;
+use crate::db_module::DBFileAttr;
+use crate::sqerror::{Error, ErrorKind, Result};
+
+/// User and groups of a process which accesses the filesystem.
+#[derive(Debug, Clone)]
+pub struct Credential {
+    pub uid: u32,
+    pub gid: u32,
+    pid: u32,
+    // Supplementary groups. None until they are read from the process.
+    groups: RefCell<Option<Vec<u32>>>,
+}
+
+impl Credential {
+    pub fn new(uid: u32, gid: u32, groups: Vec<u32>) -> Self {
+        Credential{uid, gid, pid: 0, groups: RefCell::new(Some(groups))}
+    }
+
+    /// Credential of a fuse request.
+    /// Fuse doesn't send supplementary groups, so they are read from /proc/<pid>/status when a group is checked.
+    pub fn from_request(req: &Request<'_>) -> Self {
+        Credential{uid: req.uid(), gid: req.gid(), pid: req.pid(), groups: RefCell::new(None)}
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+
+    pub fn is_owner(&self, attr: &DBFileAttr) -> bool {
+        self.uid == attr.uid
+    }
+
+    /// Check the primary group and supplementary groups
+    pub fn in_group(&self, gid: u32) -> bool {
+        if self.gid == gid {
+            return true;
+        }
+        let mut groups = self.groups.borrow_mut();
+        groups.get_or_insert_with(|| get_groups(self.pid)).contains(&gid)
+    }
+}
+
+fn get_groups(pid: u32) -> Vec<u32> {
+    let status = match fs::read_to_string(format!("/proc/{}/status", pid)) {
+        Ok(n) => n,
+        Err(_) => return vec![],
+    };
+    match status.lines().find(|line| line.starts_with("Groups:")) {
+        Some(line) => line["Groups:".len()..].split_whitespace().filter_map(|n| n.parse().ok()).collect(),
+        None => vec![],
+    }
+}
+
+/// Check read, write and execute permission with the access ACL of the file if it exists.
+/// mask is a combination of R_OK, W_OK and X_OK. Returns EACCES if any of them is not granted.
+/// Root can do anything except executing a file which has no execute bit.
+pub fn check_access(attr: &DBFileAttr, acl: Option<&Acl>, cred: &Credential, mask: u32) -> Result<()> {
+    let mask = mask & (R_OK | W_OK | X_OK) as u32;
+    let granted = if cred.is_root() {
+        if attr.kind != FileType::Directory && attr.perm & 0o111 == 0 {
+            (R_OK | W_OK) as u32
+        } else {
+            (R_OK | W_OK | X_OK) as u32
+        }
+    } else if let Some(acl) = acl {
+        acl.granted(attr, cred, mask as u16) as u32
+    } else if cred.is_owner(attr) {
+        (attr.perm as u32 >> 6) & 0o7
+    } else if cred.in_group(attr.gid) {
+        (attr.perm as u32 >> 3) & 0o7
+    } else {
+        attr.perm as u32 & 0o7
+    };
+    if mask & !granted != 0 {
+        return Err(Error::from(ErrorKind::FsPermission {description: format!("inode {} mask {:o}", attr.ino, mask)}));
+    }
+    Ok(())
+}
+
+/// Check that an entry can be created in a directory.
+pub fn check_create(parent: &DBFileAttr, parent_acl: Option<&Acl>, cred: &Credential) -> Result<()> {
+    check_access(parent, parent_acl, cred, (W_OK | X_OK) as u32)
+}
+
+/// Check that an entry can be removed from a directory or replaced.
+/// Only the owner of the entry or the directory can do it if the directory has the sticky bit.
+pub fn check_remove(parent: &DBFileAttr, parent_acl: Option<&Acl>, attr: &DBFileAttr, cred: &Credential) -> Result<()> {
+    check_access(parent, parent_acl, cred, (W_OK | X_OK) as u32)?;
+    if parent.perm & S_ISVTX as u16 > 0 && !cred.is_root() && !cred.is_owner(parent) && !cred.is_owner(attr) {
+        return Err(Error::from(ErrorKind::FsNotPermitted {
+            description: format!("inode {} is in the sticky directory {}", attr.ino, parent.ino)
+        }));
+    }
+    Ok(())
+}
+
+/// Check that the user is the owner of the file or root. Returns EPERM otherwise.
+pub fn check_owner(attr: &DBFileAttr, cred: &Credential) -> Result<()> {
+    if !cred.is_root() && !cred.is_owner(attr) {
+        return Err(Error::from(ErrorKind::FsNotPermitted {description: format!("inode {} is owned by {}", attr.ino, attr.uid)}));
+    }
+    Ok(())
+}
+
+/// Check chown. Only root can change the owner.
+/// The owner can change the group to a group which the owner belongs to.
+pub fn check_chown(attr: &DBFileAttr, cred: &Credential, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+    if cred.is_root() {
+        return Ok(());
+    }
+    if let Some(n) = uid {
+        if n != attr.uid {
+            return Err(Error::from(ErrorKind::FsNotPermitted {description: format!("can't change the owner of inode {}", attr.ino)}));
+        }
+    }
+    if let Some(n) = gid {
+        if n != attr.gid && (!cred.is_owner(attr) || !cred.in_group(n)) {
+            return Err(Error::from(ErrorKind::FsNotPermitted {description: format!("can't change the group of inode {} to {}", attr.ino, n)}));
+        }
+    }
+    Ok(())
+}
+
+/// Permission bits after chmod. The setgid bit is cleared if the user is not in the group of the file.
+pub fn chmod_mode(attr: &DBFileAttr, cred: &Credential, mode: u16) -> u16 {
+    let mut mode = mode & 0o7777;
+    if !cred.is_root() && !cred.in_group(attr.gid) {
+        mode &= !(S_ISGID as u16);
+    }
+    mode
+}
+
+/// A new file in a setgid directory belongs to the group of the directory, and a new directory also gets the setgid bit.
+pub fn inherit_setgid(parent: &DBFileAttr, attr: &mut DBFileAttr) {
+    if parent.perm & S_ISGID as u16 == 0 {
+        return;
+    }
+    attr.gid = parent.gid;
+    if attr.kind == FileType::Directory {
+        attr.perm |= S_ISGID as u16;
+    }
+}
+
+/// Clear setuid and setgid bits of a regular file. It is done when the file is written or its owner is changed.
+/// The setgid bit without the group execute bit means mandatory locking, so it is kept.
+pub fn clear_suid(attr: &mut DBFileAttr) -> bool {
+    if attr.kind != FileType::RegularFile {
+        return false;
+    }
+    let mut mask = S_ISUID as u16;
+    if attr.perm & S_IXGRP as u16 > 0 {
+        mask |= S_ISGID as u16;
+    }
+    if attr.perm & mask == 0 {
+        return false;
+    }
+    attr.perm &= !mask;
+    true
+}
+
+/// Check access to an extended attribute.
+/// "trusted." is only for root, "security." is written only by root and "system." is written by the owner.
+/// "user." is allowed for regular files and directories, and follows the permission of the file.
+pub fn check_xattr(attr: &DBFileAttr, acl: Option<&Acl>, cred: &Credential, name: &str, write: bool) -> Result<()> {
+    if name.starts_with("trusted.") {
+        if !cred.is_root() {
+            return Err(Error::from(ErrorKind::FsNotPermitted {description: format!("{} is only for root", name)}));
+        }
+        return Ok(());
+    }
+    if name.starts_with("security.") {
+        if write && !cred.is_root() {
+            return Err(Error::from(ErrorKind::FsNotPermitted {description: format!("{} is written only by root", name)}));
+        }
+        return Ok(());
+    }
+    if name.starts_with("system.") {
+        if write {
+            check_owner(attr, cred)?;
+        }
+        return Ok(());
+    }
+    if name.starts_with("user.") {
+        if attr.kind != FileType::RegularFile && attr.kind != FileType::Directory {
+            return Err(Error::from(ErrorKind::FsNotPermitted {description: format!("{} is not allowed for inode {}", name, attr.ino)}));
+        }
+        if write && attr.kind == FileType::Directory && attr.perm & S_ISVTX as u16 > 0 {
+            check_owner(attr, cred)?;
+        }
+    }
+    check_access(attr, acl, cred, if write { W_OK as u32 } else { R_OK as u32 })
+}
+
+/// Check whether the name of an extended attribute is shown by listxattr.
+pub fn xattr_visible(cred: &Credential, name: &str) -> bool {
+    cred.is_root() || !name.starts_with("trusted.")
+}
@@ -3,6 +3,30 @@ use std::fmt::Display;
 use std::error::Error as stdError;
 
 use failure::{Backtrace, Context, Fail};
+use libc::{
+    c_int,
+    EACCES,
+    EAGAIN,
+    EBADF,
+    EBUSY,
+    EDQUOT,
+    EEXIST,
+    EINVAL,
+    EIO,
+    EISDIR,
+    ENAMETOOLONG,
+    ENODATA,
+    ENOENT,
+    ENOSPC,
+    ENOTDIR,
+    ENOTEMPTY,
+    ENXIO,
+    EOPNOTSUPP,
+    EPERM,
+    EROFS,
+    EXDEV,
+};
+use rusqlite::ffi::{ErrorCode, SQLITE_CONSTRAINT_FOREIGNKEY, SQLITE_CONSTRAINT_PRIMARYKEY};
 
 pub type Result<T> = ::std::result::Result<T, Error>;
 
@@ -27,6 +51,38 @@ pub enum ErrorKind {
     FsFileExist{description: String},
     #[fail(display = "Invalid argument: {}", description)]
     FsParm{description: String},
+    #[fail(display = "Lock conflicts: {}", description)]
+    FsLockConflict{description: String},
+    #[fail(display = "Target is busy: {}", description)]
+    FsBusy{description: String},
+    #[fail(display = "Database is locked by another connection: {}", description)]
+    FsLocked{description: String},
+    #[fail(display = "File handle is not opened for the operation: {}", description)]
+    FsBadHandle{description: String},
+    #[fail(display = "No space left: {}", description)]
+    FsNoSpace{description: String},
+    #[fail(display = "Disk quota exceeded: {}", description)]
+    FsQuota{description: String},
+    #[fail(display = "Invalid cross-device link: {}", description)]
+    FsCrossDevice{description: String},
+    #[fail(display = "Database is read-only: {}", description)]
+    FsReadOnly{description: String},
+    #[fail(display = "I/O error: {}", description)]
+    FsIo{description: String},
+    #[fail(display = "Permission denied: {}", description)]
+    FsPermission{description: String},
+    #[fail(display = "Operation not permitted: {}", description)]
+    FsNotPermitted{description: String},
+    #[fail(display = "Name is too long: {}", description)]
+    FsNameTooLong{description: String},
+    #[fail(display = "Attribute is not found: {}", description)]
+    FsNoData{description: String},
+    #[fail(display = "Operation is not supported: {}", description)]
+    FsNotSupported{description: String},
+    #[fail(display = "Offset is beyond the end of file: {}", description)]
+    FsOutOfRange{description: String},
+    #[fail(display = "Unsupported database schema: {}", description)]
+    FsSchemaVersion{description: String},
     #[fail(display = "Undefined error: {}", description)]
     Undefined{description: String},
 }
@@ -51,6 +107,37 @@ impl Error {
     pub fn kind(&self) -> &ErrorKind {
         self.inner.get_context()
     }
+
+    /// Error number which is returned to the kernel
+    pub fn errno(&self) -> c_int {
+        match self.kind() {
+            ErrorKind::SqliteError {..} => EIO,
+            ErrorKind::FsIsDir {..} => EISDIR,
+            ErrorKind::FsIsNotDir {..} => ENOTDIR,
+            ErrorKind::FsNoEnt {..} => ENOENT,
+            ErrorKind::FsNotEmpty {..} => ENOTEMPTY,
+            ErrorKind::FsFileExist {..} => EEXIST,
+            ErrorKind::FsParm {..} => EINVAL,
+            ErrorKind::FsLockConflict {..} => EAGAIN,
+            ErrorKind::FsBusy {..} => EBUSY,
+            // sqlite has already waited for busy_timeout. EBUSY is not expected from read or write.
+            ErrorKind::FsLocked {..} => EAGAIN,
+            ErrorKind::FsBadHandle {..} => EBADF,
+            ErrorKind::FsNoSpace {..} => ENOSPC,
+            ErrorKind::FsQuota {..} => EDQUOT,
+            ErrorKind::FsCrossDevice {..} => EXDEV,
+            ErrorKind::FsReadOnly {..} => EROFS,
+            ErrorKind::FsIo {..} => EIO,
+            ErrorKind::FsPermission {..} => EACCES,
+            ErrorKind::FsNotPermitted {..} => EPERM,
+            ErrorKind::FsNameTooLong {..} => ENAMETOOLONG,
+            ErrorKind::FsNoData {..} => ENODATA,
+            ErrorKind::FsNotSupported {..} => EOPNOTSUPP,
+            ErrorKind::FsOutOfRange {..} => ENXIO,
+            ErrorKind::FsSchemaVersion {..} => EIO,
+            ErrorKind::Undefined {..} => EIO,
+        }
+    }
 }
 
 impl From<ErrorKind> for Error {
@@ -69,12 +156,60 @@ impl From<Context<ErrorKind>> for Error {
 
 impl From<rusqlite::Error> for Error {
     fn from(err: rusqlite::Error) -> Error {
+        let description = format!("{} {:?}", err.description(), err);
+        let kind = match &err {
+            rusqlite::Error::QueryReturnedNoRows => ErrorKind::FsNoEnt {description},
+            rusqlite::Error::SqliteFailure(e, _) => match e.code {
+                ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked => ErrorKind::FsLocked {description},
+                ErrorCode::DiskFull => ErrorKind::FsNoSpace {description},
+                ErrorCode::ReadOnly => ErrorKind::FsReadOnly {description},
+                ErrorCode::SystemIOFailure | ErrorCode::CannotOpen => ErrorKind::FsIo {description},
+                ErrorCode::PermissionDenied => ErrorKind::FsPermission {description},
+                ErrorCode::ConstraintViolation => match e.extended_code {
+                    // dentry is duplicated
+                    SQLITE_CONSTRAINT_PRIMARYKEY => ErrorKind::FsFileExist {description},
+                    // parent or target inode is not exist
+                    SQLITE_CONSTRAINT_FOREIGNKEY => ErrorKind::FsNoEnt {description},
+                    _ => ErrorKind::SqliteError {description},
+                },
+                _ => ErrorKind::SqliteError {description},
+            },
+            _ => ErrorKind::SqliteError {description},
+        };
+        Error {
+            inner: Context::new(kind)
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Error {
+        let description = format!("{}", err);
+        let kind = match err.kind() {
+            std::io::ErrorKind::NotFound => ErrorKind::FsNoEnt {description},
+            std::io::ErrorKind::PermissionDenied => ErrorKind::FsPermission {description},
+            std::io::ErrorKind::AlreadyExists => ErrorKind::FsFileExist {description},
+            _ => ErrorKind::FsIo {description},
+        };
         Error {
-            inner: Context::new(
-                ErrorKind::SqliteError {
-                    description: String::from(format!("{} {:?}", err.description(), err))
-                }
-            )
+            inner: Context::new(kind)
         }
     }
 }
+
+impl From<nix::Error> for Error {
+    fn from(err: nix::Error) -> Error {
+        match err {
+            nix::Error::Sys(errno) => Error::from(std::io::Error::from_raw_os_error(errno as i32)),
+            _ => Error::from(ErrorKind::FsParm {description: format!("{}", err)}),
+        }
+    }
+}
+
+impl From<Error> for std::io::Error {
+    fn from(err: Error) -> std::io::Error {
+        // kind follows errno, and the message keeps the description
+        let kind = std::io::Error::from_raw_os_error(err.errno()).kind();
+        std::io::Error::new(kind, err.to_string())
+    }
+}
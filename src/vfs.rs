@@ -0,0 +1,464 @@
+use std::path::{Component, Path};
+use std::time::SystemTime;
+use fuser::FileType;
+use libc::{PATH_MAX, S_ISVTX};
+use nix::unistd;
+use crate::db_module::{DbModule, DBFileAttr, DEntry};
+use crate::db_module::sqlite::Sqlite;
+use crate::file::File;
+use crate::permission;
+use crate::sqerror::{Error, ErrorKind, Result};
+
+// read() reads a large file in several parts
+const READ_CHUNK_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Path based access to a filesystem database without FUSE.
+/// Paths are resolved from the root directory. Symlinks in a path are not followed.
+/// New files are owned by the user and the group of this process.
+pub struct Vfs {
+    db: Sqlite,
+    uid: u32,
+    gid: u32,
+}
+
+impl Vfs {
+    /// Open a database file. Tables are created if the database is empty.
+    pub fn new(path: &Path) -> Result<Vfs> {
+        let mut db = Sqlite::new(path)?;
+        db.init()?;
+        Vfs::new_with_db(db)
+    }
+
+    /// Create a filesystem in memory. All data is deleted when it is dropped.
+    pub fn new_in_memory() -> Result<Vfs> {
+        let mut db = Sqlite::new_in_memory()?;
+        db.init()?;
+        Vfs::new_with_db(db)
+    }
+
+    /// Use an initialized database.
+    pub fn new_with_db(db: Sqlite) -> Result<Vfs> {
+        let uid = unistd::getuid().as_raw();
+        let gid = unistd::getgid().as_raw();
+        Ok(Vfs{db, uid, gid})
+    }
+
+    /// Get metadata of a file. A symlink itself is returned.
+    pub fn metadata<P: AsRef<Path>>(&mut self, path: P) -> Result<DBFileAttr> {
+        let path = path.as_ref();
+        let mut attr = match self.db.get_inode(1)? {
+            Some(n) => n,
+            None => return Err(no_entry(path)),
+        };
+        for component in path.components() {
+            let name = match component {
+                Component::RootDir | Component::CurDir => continue,
+                Component::ParentDir => "..",
+                Component::Normal(n) => to_str(n.as_ref())?,
+                Component::Prefix(_) => return Err(no_entry(path)),
+            };
+            if attr.kind != FileType::Directory {
+                return Err(Error::from(ErrorKind::FsIsNotDir {description: format!(
+                    "{} is not a directory",
+                    path.display()
+                )}));
+            }
+            attr = match self.db.lookup(attr.ino, name)? {
+                Some(n) => n,
+                None => return Err(no_entry(path)),
+            };
+        }
+        Ok(attr)
+    }
+
+    /// Return true if the path exists.
+    pub fn exists<P: AsRef<Path>>(&mut self, path: P) -> Result<bool> {
+        match self.metadata(path) {
+            Ok(_) => Ok(true),
+            Err(err) => match err.kind() {
+                ErrorKind::FsNoEnt {..} => Ok(false),
+                _ => Err(err),
+            }
+        }
+    }
+
+    /// List entries of a directory, except "." and "..".
+    pub fn read_dir<P: AsRef<Path>>(&mut self, path: P) -> Result<Vec<DEntry>> {
+        let attr = self.dir_metadata(path.as_ref())?;
+        let mut entries = self.db.get_dentry(attr.ino)?;
+        entries.retain(|e| e.filename != "." && e.filename != "..");
+        Ok(entries)
+    }
+
+    /// Create a directory. The parent directory must exist.
+    pub fn create_dir<P: AsRef<Path>>(&mut self, path: P, mode: u16) -> Result<DBFileAttr> {
+        let (parent, name) = self.parent_and_name(path.as_ref())?;
+        let mut attr = self.new_attr(FileType::Directory, mode);
+        // Same as mkdir of the FUSE layer
+        permission::inherit_setgid(&parent, &mut attr);
+        if parent.perm & S_ISVTX as u16 > 0 {
+            attr.perm |= S_ISVTX as u16;
+        }
+        attr.ino = self.db.add_inode_and_dentry(parent.ino, name, &attr)?;
+        Ok(attr)
+    }
+
+    /// Create a directory and all missing parent directories.
+    pub fn create_dir_all<P: AsRef<Path>>(&mut self, path: P, mode: u16) -> Result<()> {
+        let path = path.as_ref();
+        if self.exists(path)? {
+            self.dir_metadata(path)?;
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent, mode)?;
+        }
+        self.create_dir(path, mode)?;
+        Ok(())
+    }
+
+    /// Create a regular file, or truncate it if it exists.
+    pub fn create<P: AsRef<Path>>(&mut self, path: P, mode: u16) -> Result<DBFileAttr> {
+        let path = path.as_ref();
+        if self.exists(path)? {
+            let mut attr = self.file_metadata(path)?;
+            if attr.size > 0 {
+                attr.size = 0;
+                self.db.update_inode(&attr, true)?;
+            }
+            return Ok(attr);
+        }
+        let (parent, name) = self.parent_and_name(path)?;
+        let mut attr = self.new_attr(FileType::RegularFile, mode);
+        permission::inherit_setgid(&parent, &mut attr);
+        attr.ino = self.db.add_inode_and_dentry(parent.ino, name, &attr)?;
+        Ok(attr)
+    }
+
+    /// Open an existing file to use Read, Write and Seek.
+    pub fn open<P: AsRef<Path>>(&mut self, path: P) -> Result<File<'_, Sqlite>> {
+        let attr = self.file_metadata(path.as_ref())?;
+        File::open(&mut self.db, attr.ino)
+    }
+
+    /// Create a special file (fifo, socket or device node) or an empty regular file.
+    /// rdev is the device number of a device node.
+    pub fn mknod<P: AsRef<Path>>(&mut self, path: P, kind: FileType, mode: u16, rdev: u32) -> Result<DBFileAttr> {
+        if kind == FileType::Directory || kind == FileType::Symlink {
+            return Err(Error::from(ErrorKind::FsParm {description: format!(
+                "mknod can't create {:?}",
+                kind
+            )}));
+        }
+        let (parent, name) = self.parent_and_name(path.as_ref())?;
+        let mut attr = self.new_attr(kind, mode);
+        attr.rdev = rdev;
+        // Same as mknod of the FUSE layer
+        permission::inherit_setgid(&parent, &mut attr);
+        attr.ino = self.db.add_inode_and_dentry(parent.ino, name, &attr)?;
+        Ok(attr)
+    }
+
+    /// Read the whole file.
+    pub fn read<P: AsRef<Path>>(&mut self, path: P) -> Result<Vec<u8>> {
+        let attr = self.file_metadata(path.as_ref())?;
+        let mut data = Vec::with_capacity(attr.size as usize);
+        let mut offset = 0;
+        while offset < attr.size {
+            let size = std::cmp::min(attr.size - offset, READ_CHUNK_SIZE);
+            data.append(&mut self.db.read_file(attr.ino, offset, size as u32, false)?);
+            offset += size;
+        }
+        Ok(data)
+    }
+
+    /// Read data from offset. Data is shorter than size if it reaches the end of the file.
+    pub fn read_at<P: AsRef<Path>>(&mut self, path: P, offset: u64, size: u32) -> Result<Vec<u8>> {
+        let attr = self.file_metadata(path.as_ref())?;
+        if offset >= attr.size {
+            return Ok(vec![]);
+        }
+        let size = std::cmp::min(u64::from(size), attr.size - offset) as u32;
+        self.db.read_file(attr.ino, offset, size, false)
+    }
+
+    /// Write data to a file. The file is created with mode 0o644 or truncated.
+    pub fn write<P: AsRef<Path>>(&mut self, path: P, data: &[u8]) -> Result<()> {
+        let attr = self.create(path, 0o644)?;
+        self.db.write_file(attr.ino, 0, data)
+    }
+
+    /// Write data to an existing file at offset. The file grows if needed.
+    pub fn write_at<P: AsRef<Path>>(&mut self, path: P, offset: u64, data: &[u8]) -> Result<()> {
+        let attr = self.file_metadata(path.as_ref())?;
+        self.db.write_file(attr.ino, offset, data)
+    }
+
+    /// Change the size of a file. Extended area is filled with zero.
+    pub fn set_len<P: AsRef<Path>>(&mut self, path: P, size: u64) -> Result<()> {
+        let mut attr = self.file_metadata(path.as_ref())?;
+        let truncate = attr.size > size;
+        attr.size = size;
+        self.db.update_inode(&attr, truncate)
+    }
+
+    /// Change permission of a file.
+    pub fn set_permissions<P: AsRef<Path>>(&mut self, path: P, mode: u16) -> Result<()> {
+        let mut attr = self.metadata(path)?;
+        attr.perm = mode;
+        self.db.update_inode(&attr, false)
+    }
+
+    /// Create a symlink at link which points to target.
+    pub fn symlink<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, target: P, link: Q) -> Result<DBFileAttr> {
+        let data = to_str(target.as_ref())?.as_bytes();
+        if data.len() >= PATH_MAX as usize {
+            return Err(Error::from(ErrorKind::FsNameTooLong {description: format!(
+                "symlink target of {} is too long",
+                link.as_ref().display()
+            )}));
+        }
+        let (parent, name) = self.parent_and_name(link.as_ref())?;
+        // permission of a symlink is never used
+        let mut attr = self.new_attr(FileType::Symlink, 0o777);
+        attr.ino = self.db.add_inode_and_dentry(parent.ino, name, &attr)?;
+        self.db.write_file(attr.ino, 0, data)?;
+        attr.size = data.len() as u64;
+        Ok(attr)
+    }
+
+    /// Get the target of a symlink.
+    pub fn read_link<P: AsRef<Path>>(&mut self, path: P) -> Result<String> {
+        let path = path.as_ref();
+        let attr = self.metadata(path)?;
+        if attr.kind != FileType::Symlink {
+            return Err(Error::from(ErrorKind::FsParm {description: format!(
+                "{} is not a symlink",
+                path.display()
+            )}));
+        }
+        let data = self.db.read_file(attr.ino, 0, attr.size as u32, false)?;
+        match String::from_utf8(data) {
+            Ok(n) => Ok(n),
+            Err(err) => Err(Error::from(ErrorKind::FsParm {description: format!("{:?}", err)})),
+        }
+    }
+
+    /// Set the project of a directory tree for project quotas.
+    pub fn set_project<P: AsRef<Path>>(&mut self, path: P, project: u32) -> Result<()> {
+        let attr = self.metadata(path)?;
+        self.db.set_project(attr.ino, project)
+    }
+
+    /// Copy a regular file like std::fs::copy. The destination is created or truncated with the same permission.
+    /// Data is copied inside the database. Return the number of copied bytes.
+    pub fn copy<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, from: P, to: Q) -> Result<u64> {
+        let attr = self.file_metadata(from.as_ref())?;
+        self.check_copy_target(&attr, to.as_ref())?;
+        let dst = self.create(to, attr.perm)?;
+        self.db.copy_file_range(attr.ino, 0, dst.ino, 0, attr.size)
+    }
+
+    /// Copy a regular file which shares blocks with the original until either of them is written, like cp --reflink.
+    /// The destination is created or truncated with the same permission.
+    pub fn reflink<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, from: P, to: Q) -> Result<()> {
+        let attr = self.file_metadata(from.as_ref())?;
+        self.check_copy_target(&attr, to.as_ref())?;
+        let dst = self.create(to, attr.perm)?;
+        self.db.clone_file(attr.ino, dst.ino)
+    }
+
+    /// Copy a directory tree, or a file. Regular files are cloned by reflink if it is true.
+    /// Symlinks and special files are recreated. Return the number of copied entries.
+    pub fn copy_all<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, from: P, to: Q, reflink: bool) -> Result<u64> {
+        let (from, to) = (from.as_ref(), to.as_ref());
+        let attr = self.metadata(from)?;
+        match attr.kind {
+            FileType::Directory => {
+                let parent = self.parent_and_name(to)?.0;
+                if attr.ino == 1 || self.is_ancestor(attr.ino, parent.ino)? {
+                    return Err(Error::from(ErrorKind::FsParm {description: format!(
+                        "{} can't be copied into itself",
+                        from.display()
+                    )}));
+                }
+                self.create_dir(to, attr.perm)?;
+                let mut count = 1;
+                for entry in self.read_dir(from)? {
+                    count += self.copy_all(from.join(&entry.filename), to.join(&entry.filename), reflink)?;
+                }
+                Ok(count)
+            },
+            FileType::RegularFile if reflink => self.reflink(from, to).map(|_| 1),
+            FileType::RegularFile => self.copy(from, to).map(|_| 1),
+            FileType::Symlink => {
+                let target = self.read_link(from)?;
+                self.symlink(target, to).map(|_| 1)
+            },
+            kind => self.mknod(to, kind, attr.perm, attr.rdev).map(|_| 1),
+        }
+    }
+
+    /// Create a hard link of a regular file.
+    pub fn hard_link<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, original: P, link: Q) -> Result<DBFileAttr> {
+        let attr = self.metadata(original)?;
+        let (parent, name) = self.parent_and_name(link.as_ref())?;
+        self.db.link_dentry(attr.ino, parent.ino, name)
+    }
+
+    /// Rename a file or a directory. An existing target is replaced.
+    pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, from: P, to: Q) -> Result<()> {
+        let (parent, name) = self.parent_and_name(from.as_ref())?;
+        let (new_parent, new_name) = self.parent_and_name(to.as_ref())?;
+        // The kernel checks it for the FUSE layer.
+        let attr = self.metadata(from.as_ref())?;
+        if attr.kind == FileType::Directory && self.is_ancestor(attr.ino, new_parent.ino)? {
+            return Err(Error::from(ErrorKind::FsParm {description: format!(
+                "{} can't be moved to its subdirectory",
+                from.as_ref().display()
+            )}));
+        }
+        if let Some(ino) = self.db.move_dentry(parent.ino, name, new_parent.ino, new_name)? {
+            self.db.delete_inode_if_noref(ino)?;
+        }
+        Ok(())
+    }
+
+    /// Remove a file which is not a directory.
+    pub fn remove_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        if self.metadata(path)?.kind == FileType::Directory {
+            return Err(Error::from(ErrorKind::FsIsDir {description: format!(
+                "{} is a directory",
+                path.display()
+            )}));
+        }
+        let (parent, name) = self.parent_and_name(path)?;
+        let ino = self.db.delete_dentry(parent.ino, name)?;
+        self.db.delete_inode_if_noref(ino)
+    }
+
+    /// Remove an empty directory.
+    pub fn remove_dir<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let attr = self.dir_metadata(path)?;
+        if attr.ino == 1 {
+            return Err(Error::from(ErrorKind::FsBusy {description: "root directory can't be removed".to_string()}));
+        }
+        if !self.db.check_directory_is_empty(attr.ino)? {
+            return Err(Error::from(ErrorKind::FsNotEmpty {description: format!(
+                "{} is not empty",
+                path.display()
+            )}));
+        }
+        let (parent, name) = self.parent_and_name(path)?;
+        let ino = self.db.delete_dentry(parent.ino, name)?;
+        self.db.delete_inode_if_noref(ino)
+    }
+
+    fn new_attr(&self, kind: FileType, mode: u16) -> DBFileAttr {
+        let now = SystemTime::now();
+        DBFileAttr {
+            ino: 0,
+            size: 0,
+            blocks: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            kind,
+            perm: mode,
+            nlink: 0,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            flags: 0
+        }
+    }
+
+    // Resolve the parent directory and return it with the last component of the path.
+    fn parent_and_name<'a>(&mut self, path: &'a Path) -> Result<(DBFileAttr, &'a str)> {
+        let name = match path.file_name() {
+            Some(n) => to_str(n.as_ref())?,
+            None => {
+                return Err(Error::from(ErrorKind::FsParm {description: format!(
+                    "{} doesn't have a file name",
+                    path.display()
+                )}));
+            }
+        };
+        let parent = self.dir_metadata(path.parent().unwrap_or_else(|| Path::new("/")))?;
+        Ok((parent, name))
+    }
+
+    // The target of a copy must not be the source file or its hard link, because it is truncated first.
+    fn check_copy_target(&mut self, attr: &DBFileAttr, to: &Path) -> Result<()> {
+        if self.exists(to)? && self.metadata(to)?.ino == attr.ino {
+            return Err(Error::from(ErrorKind::FsParm {description: format!(
+                "{} is the same file as the source",
+                to.display()
+            )}));
+        }
+        Ok(())
+    }
+
+    // Return true if dir is ino or one of its parent directories.
+    fn is_ancestor(&mut self, dir: u64, ino: u64) -> Result<bool> {
+        let mut ino = ino;
+        loop {
+            if ino == dir {
+                return Ok(true);
+            }
+            if ino == 1 {
+                return Ok(false);
+            }
+            ino = match self.db.lookup(ino, "..")? {
+                Some(n) => n.ino,
+                None => return Ok(false),
+            };
+        }
+    }
+
+    fn dir_metadata(&mut self, path: &Path) -> Result<DBFileAttr> {
+        let attr = self.metadata(path)?;
+        if attr.kind != FileType::Directory {
+            return Err(Error::from(ErrorKind::FsIsNotDir {description: format!(
+                "{} is not a directory",
+                path.display()
+            )}));
+        }
+        Ok(attr)
+    }
+
+    fn file_metadata(&mut self, path: &Path) -> Result<DBFileAttr> {
+        let attr = self.metadata(path)?;
+        if attr.kind == FileType::Directory {
+            return Err(Error::from(ErrorKind::FsIsDir {description: format!(
+                "{} is a directory",
+                path.display()
+            )}));
+        }
+        // a symlink is not followed, and data of a special file is not stored
+        if attr.kind != FileType::RegularFile {
+            return Err(Error::from(ErrorKind::FsParm {description: format!(
+                "{} is not a regular file",
+                path.display()
+            )}));
+        }
+        Ok(attr)
+    }
+}
+
+fn no_entry(path: &Path) -> Error {
+    Error::from(ErrorKind::FsNoEnt {description: format!("{} is not found", path.display())})
+}
+
+fn to_str(path: &Path) -> Result<&str> {
+    match path.to_str() {
+        Some(n) => Ok(n),
+        None => Err(Error::from(ErrorKind::FsParm {description: format!(
+            "{} is not a valid UTF-8 name",
+            path.display()
+        )})),
+    }
+}
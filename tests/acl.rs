@@ -0,0 +1,106 @@
+use fuser::FileType;
+use libc::{EACCES, EINVAL, R_OK, W_OK};
+use sqlite_fs::acl::{self, Acl, AclEntry, ACL_ACCESS, ACL_USER_OBJ, ACL_USER, ACL_GROUP_OBJ, ACL_GROUP, ACL_MASK, ACL_OTHER};
+use sqlite_fs::db_module::{sqlite, DbModule};
+use sqlite_fs::permission::{self, Credential};
+
+mod helpers;
+
+// user::rw-, user:1001:rw-, group::r--, group:200:rwx, mask::r--, other::---
+fn extended_acl() -> Acl {
+    Acl{entries: vec![
+        AclEntry{tag: ACL_OTHER, perm: 0, id: u32::MAX},
+        AclEntry{tag: ACL_USER_OBJ, perm: 6, id: u32::MAX},
+        AclEntry{tag: ACL_USER, perm: 6, id: 1001},
+        AclEntry{tag: ACL_GROUP_OBJ, perm: 4, id: u32::MAX},
+        AclEntry{tag: ACL_GROUP, perm: 7, id: 200},
+        AclEntry{tag: ACL_MASK, perm: 4, id: u32::MAX},
+    ]}
+}
+
+#[test]
+fn acl_parse() {
+    let acl = Acl::from_bytes(&extended_acl().to_bytes()).unwrap();
+    // entries are sorted
+    assert_eq!(acl.entries[0].tag, ACL_USER_OBJ);
+    assert_eq!(acl.entries[5].tag, ACL_OTHER);
+    assert_eq!(Acl::from_bytes(&acl.to_bytes()).unwrap(), acl);
+    assert_eq!(acl.mode(), 0o640);
+    assert!(!acl.is_minimal());
+    assert!(Acl::from_mode(0o750).is_minimal());
+    assert_eq!(Acl::from_mode(0o750).mode(), 0o750);
+
+    assert_eq!(Acl::from_bytes(&[2, 0, 0, 0, 1]).unwrap_err().errno(), EINVAL);
+    assert_eq!(Acl::from_bytes(&[1, 0, 0, 0]).unwrap_err().errno(), EINVAL);
+    // mask is required with named entries
+    let mut invalid = extended_acl();
+    invalid.entries.retain(|n| n.tag != ACL_MASK);
+    assert!(Acl::from_bytes(&invalid.to_bytes()).is_err());
+    let mut invalid = extended_acl();
+    invalid.entries.push(AclEntry{tag: ACL_USER, perm: 4, id: 1001});
+    assert!(Acl::from_bytes(&invalid.to_bytes()).is_err());
+}
+
+#[test]
+fn acl_mode() {
+    let mut acl = extended_acl();
+    acl.set_mode(0o750);
+    assert_eq!(acl.mode(), 0o750);
+    // chmod changes the mask, not the group entry
+    let group = acl.entries.iter().find(|n| n.tag == ACL_GROUP_OBJ).unwrap();
+    assert_eq!(group.perm, 4);
+
+    let default = Acl::from_bytes(&extended_acl().to_bytes()).unwrap();
+    let (inherited, mode) = default.inherit(0o100644);
+    assert_eq!(mode, 0o100640);
+    assert_eq!(inherited.mode(), 0o640);
+    let (_, mode) = default.inherit(0o600);
+    assert_eq!(mode, 0o600);
+    let (inherited, mode) = Acl::from_mode(0o755).inherit(0o666);
+    assert_eq!(mode, 0o644);
+    assert!(inherited.is_minimal());
+}
+
+#[test]
+fn acl_access() {
+    let acl = extended_acl();
+    let attr = helpers::file_attr(FileType::RegularFile, 0o640, 1000, 100);
+    let check = |cred: &Credential, mask: i32| permission::check_access(&attr, Some(&acl), cred, mask as u32);
+    check(&Credential::new(1000, 1000, vec![]), R_OK | W_OK).unwrap();
+    // named user is limited by the mask
+    check(&Credential::new(1001, 1001, vec![]), R_OK).unwrap();
+    assert_eq!(check(&Credential::new(1001, 1001, vec![]), W_OK).unwrap_err().errno(), EACCES);
+    check(&Credential::new(1002, 1002, vec![200]), R_OK).unwrap();
+    assert!(check(&Credential::new(1002, 1002, vec![200]), W_OK).is_err());
+    check(&Credential::new(1002, 100, vec![]), R_OK).unwrap();
+    assert!(check(&Credential::new(1002, 1002, vec![]), R_OK).is_err());
+    // without ACL, the user 1001 is other
+    assert!(permission::check_access(&attr, None, &Credential::new(1001, 1001, vec![]), R_OK as u32).is_err());
+
+    // group:300:r--, group:400:-w-. Permissions of two group entries are not combined.
+    let acl = Acl{entries: vec![
+        AclEntry{tag: ACL_USER_OBJ, perm: 6, id: u32::MAX},
+        AclEntry{tag: ACL_GROUP_OBJ, perm: 0, id: u32::MAX},
+        AclEntry{tag: ACL_GROUP, perm: 4, id: 300},
+        AclEntry{tag: ACL_GROUP, perm: 2, id: 400},
+        AclEntry{tag: ACL_MASK, perm: 6, id: u32::MAX},
+        AclEntry{tag: ACL_OTHER, perm: 6, id: u32::MAX},
+    ]};
+    let cred = Credential::new(1002, 1002, vec![300, 400]);
+    permission::check_access(&attr, Some(&acl), &cred, R_OK as u32).unwrap();
+    permission::check_access(&attr, Some(&acl), &cred, W_OK as u32).unwrap();
+    let err = permission::check_access(&attr, Some(&acl), &cred, (R_OK | W_OK) as u32).unwrap_err();
+    assert_eq!(err.errno(), EACCES);
+}
+
+#[test]
+fn acl_get() {
+    let mut db = sqlite::Sqlite::new_in_memory().unwrap();
+    db.init().unwrap();
+    let ino = db.add_inode_and_dentry(1, "file", &helpers::file_attr(FileType::RegularFile, 0o640, 1000, 100)).unwrap();
+    assert!(acl::get_acl(&db, ino, ACL_ACCESS).unwrap().is_none());
+    db.set_xattr(ino, ACL_ACCESS, &extended_acl().to_bytes()).unwrap();
+    assert_eq!(acl::get_acl(&db, ino, ACL_ACCESS).unwrap().unwrap().mode(), 0o640);
+    db.set_xattr(ino, ACL_ACCESS, b"broken").unwrap();
+    assert!(acl::get_acl(&db, ino, ACL_ACCESS).is_err());
+}
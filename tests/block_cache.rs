@@ -0,0 +1,110 @@
+use std::time::Duration;
+use fuser::FileType;
+use sqlite_fs::block_cache::BlockCache;
+use libc::EDQUOT;
+use sqlite_fs::db_module::{sqlite, DbModule, QuotaKind};
+
+mod helpers;
+
+#[test]
+fn block_cache_write_back() {
+    let mut db = sqlite::Sqlite::new_in_memory().unwrap();
+    db.init().unwrap();
+    let ino = db.add_inode_and_dentry(1, "file", &helpers::file_attr(FileType::RegularFile, 0o644, 0, 0)).unwrap();
+    db.write_file(ino, 0, &[1; 10000]).unwrap();
+    let mut cache = BlockCache::new(1024 * 1024, Duration::from_secs(3600));
+    // small writes are merged
+    for i in 0..100 {
+        cache.write(&mut db, ino, 9000 + i * 10, &[2; 10]).unwrap();
+    }
+    assert_eq!(cache.size(ino), Some(10000));
+    cache.write(&mut db, ino, 12300, b"end").unwrap();
+    assert_eq!(cache.size(ino), Some(12303));
+    // block 3 and 4
+    assert_eq!(cache.dirty_bytes(), 4096 * 2);
+    // nothing is written yet
+    assert_eq!(db.get_inode(ino).unwrap().unwrap().size, 10000);
+    assert_eq!(db.read_file(ino, 9000, 10, true).unwrap(), vec![1; 10]);
+
+    cache.flush(&mut db, ino).unwrap();
+    assert_eq!(cache.size(ino), None);
+    assert_eq!(cache.dirty_bytes(), 0);
+    assert_eq!(db.get_inode(ino).unwrap().unwrap().size, 12303);
+    let data = db.read_file(ino, 0, 12303, true).unwrap();
+    assert_eq!(data[..9000].to_vec(), vec![1; 9000]);
+    assert_eq!(data[9000..10000].to_vec(), vec![2; 1000]);
+    assert_eq!(data[10000..12300].to_vec(), vec![0; 2300]);
+    assert_eq!(data[12300..].to_vec(), b"end".to_vec());
+    // flushing a clean file does nothing
+    cache.flush(&mut db, ino).unwrap();
+}
+
+#[test]
+fn block_cache_limit() {
+    let mut db = sqlite::Sqlite::new_in_memory().unwrap();
+    db.init().unwrap();
+    let ino = db.add_inode_and_dentry(1, "file", &helpers::file_attr(FileType::RegularFile, 0o644, 0, 0)).unwrap();
+    let mut cache = BlockCache::new(4096 * 2, Duration::from_secs(3600));
+    cache.write(&mut db, ino, 0, &[1; 8192]).unwrap();
+    assert_eq!(db.get_inode(ino).unwrap().unwrap().size, 0);
+    // exceeds the limit
+    cache.write(&mut db, ino, 8192, &[1; 1]).unwrap();
+    assert_eq!(cache.dirty_bytes(), 0);
+    assert_eq!(db.get_inode(ino).unwrap().unwrap().size, 8193);
+
+    // old dirty data is written back by the next write
+    let mut cache = BlockCache::new(1024 * 1024, Duration::from_secs(0));
+    cache.write(&mut db, ino, 0, b"x").unwrap();
+    assert_eq!(cache.size(ino), None);
+    assert_eq!(db.read_file(ino, 0, 2, true).unwrap(), vec![b'x', 1]);
+    assert!(cache.write(&mut db, 100, 0, b"x").is_err());
+
+    // only old files are written back
+    let mut cache = BlockCache::new(1024 * 1024, Duration::from_millis(100));
+    cache.write(&mut db, ino, 0, b"y").unwrap();
+    cache.flush_expired(&mut db).unwrap();
+    assert_eq!(cache.size(ino), Some(8193));
+    std::thread::sleep(Duration::from_millis(100));
+    cache.flush_expired(&mut db).unwrap();
+    assert_eq!(cache.size(ino), None);
+    assert_eq!(db.read_file(ino, 0, 1, true).unwrap(), b"y".to_vec());
+}
+
+#[test]
+fn block_cache_flush_error() {
+    let mut db = sqlite::Sqlite::new_in_memory().unwrap();
+    db.init().unwrap();
+    let ino = db.add_inode_and_dentry(1, "file", &helpers::file_attr(FileType::RegularFile, 0o644, 1000, 1000)).unwrap();
+    let mut cache = BlockCache::new(1024 * 1024, Duration::from_secs(3600));
+    cache.write(&mut db, ino, 0, &[1; 8192]).unwrap();
+    // dirty blocks are kept when they can't be written
+    db.set_quota(QuotaKind::User, 1000, 4096, 0).unwrap();
+    assert_eq!(cache.flush(&mut db, ino).unwrap_err().errno(), EDQUOT);
+    assert_eq!(cache.size(ino), Some(8192));
+    assert_eq!(cache.dirty_bytes(), 8192);
+    assert!(cache.flush_all(&mut db).is_err());
+    db.set_quota(QuotaKind::User, 1000, 0, 0).unwrap();
+    cache.flush_all(&mut db).unwrap();
+    assert_eq!(cache.dirty_bytes(), 0);
+    assert_eq!(db.read_file(ino, 0, 8192, true).unwrap(), vec![1; 8192]);
+
+    // a write is accepted even if other files can't be written back
+    let attr = helpers::file_attr(FileType::RegularFile, 0o644, 0, 0);
+    let ino2 = db.add_inode_and_dentry(1, "file2", &attr).unwrap();
+    let mut cache = BlockCache::new(4096, Duration::from_secs(3600));
+    db.set_quota(QuotaKind::User, 1000, 8192, 0).unwrap();
+    cache.write(&mut db, ino, 8192, &[1; 1]).unwrap();
+    cache.write(&mut db, ino2, 0, &[2; 4096]).unwrap();
+    assert_eq!(cache.size(ino), Some(8193));
+    assert_eq!(cache.size(ino2), None);
+    assert_eq!(cache.flush(&mut db, ino).unwrap_err().errno(), EDQUOT);
+    db.set_quota(QuotaKind::User, 1000, 0, 0).unwrap();
+    cache.flush(&mut db, ino).unwrap();
+
+    // dirty blocks of a removed file are dropped
+    cache.write(&mut db, ino, 0, b"x").unwrap();
+    db.delete_dentry(1, "file").unwrap();
+    db.delete_inode_if_noref(ino).unwrap();
+    cache.flush(&mut db, ino).unwrap();
+    assert_eq!(cache.dirty_bytes(), 0);
+}
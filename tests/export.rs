@@ -0,0 +1,85 @@
+use std::fs;
+use std::io::Read;
+use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+use nix::sys::stat::utimes;
+use nix::sys::time::{TimeVal, TimeValLike};
+use sqlite_fs::db_module::{sqlite, DbModule};
+use sqlite_fs::export::{export_to_dir, export_to_tar};
+use sqlite_fs::import::import;
+
+// Create a filesystem which has a directory, a large file, a hard link and a symlink.
+fn create_fs() -> sqlite::Sqlite {
+    let src = tempfile::tempdir().unwrap();
+    let dir = src.path().join("dir");
+    fs::create_dir(&dir).unwrap();
+    let data: Vec<u8> = (0..10000).map(|n| n as u8).collect();
+    fs::write(dir.join("file"), &data).unwrap();
+    fs::set_permissions(dir.join("file"), fs::Permissions::from_mode(0o640)).unwrap();
+    fs::hard_link(dir.join("file"), src.path().join("link")).unwrap();
+    symlink("dir/file", src.path().join("symlink")).unwrap();
+    utimes(&dir.join("file"), &TimeVal::seconds(1_000_000), &TimeVal::seconds(2_000_000)).unwrap();
+    utimes(&dir, &TimeVal::seconds(3_000_000), &TimeVal::seconds(4_000_000)).unwrap();
+    let mut db = sqlite::Sqlite::new_in_memory().unwrap();
+    db.init().unwrap();
+    import(&mut db, src.path(), 1).unwrap();
+    let file = db.lookup(1, "dir").unwrap().unwrap().ino;
+    let file = db.lookup(file, "file").unwrap().unwrap().ino;
+    db.set_xattr(file, "user.comment", b"hello").unwrap();
+    db
+}
+
+#[test]
+fn export_directory() {
+    let mut db = create_fs();
+    let dest = tempfile::tempdir().unwrap();
+    assert_eq!(export_to_dir(&mut db, dest.path()).unwrap(), 3);
+    let file = dest.path().join("dir/file");
+    let data: Vec<u8> = (0..10000).map(|n| n as u8).collect();
+    assert_eq!(fs::read(&file).unwrap(), data);
+    let meta = fs::metadata(&file).unwrap();
+    assert_eq!(meta.mode() & 0o7777, 0o640);
+    assert_eq!(meta.mtime(), 2_000_000);
+    assert_eq!(meta.ino(), fs::metadata(dest.path().join("link")).unwrap().ino());
+    assert_eq!(fs::read_link(dest.path().join("symlink")).unwrap().to_str().unwrap(), "dir/file");
+    assert_eq!(fs::metadata(dest.path().join("dir")).unwrap().mtime(), 4_000_000);
+    // destination must be empty
+    assert!(export_to_dir(&mut db, dest.path()).is_err());
+}
+
+#[test]
+fn export_tar() {
+    let mut db = create_fs();
+    let mut buf = vec![];
+    assert_eq!(export_to_tar(&mut db, &mut buf).unwrap(), 3);
+    let mut archive = tar::Archive::new(&buf[..]);
+    let mut names = vec![];
+    for entry in archive.entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let name = entry.path().unwrap().to_str().unwrap().to_string();
+        match name.as_str() {
+            "dir/file" => {
+                assert_eq!(entry.header().mode().unwrap(), 0o640);
+                assert_eq!(entry.header().mtime().unwrap(), 2_000_000);
+                let pax = entry.pax_extensions().unwrap().unwrap()
+                    .map(|e| e.unwrap())
+                    .find(|e| e.key().unwrap() == "SCHILY.xattr.user.comment")
+                    .map(|e| e.value_bytes().to_vec());
+                assert_eq!(pax, Some(b"hello".to_vec()));
+                let mut data = vec![];
+                entry.read_to_end(&mut data).unwrap();
+                assert_eq!(data.len(), 10000);
+            },
+            "link" => {
+                assert_eq!(entry.header().entry_type(), tar::EntryType::Link);
+                assert_eq!(entry.link_name().unwrap().unwrap().to_str().unwrap(), "dir/file");
+            },
+            "symlink" => {
+                assert_eq!(entry.header().entry_type(), tar::EntryType::Symlink);
+                assert_eq!(entry.link_name().unwrap().unwrap().to_str().unwrap(), "dir/file");
+            },
+            _ => {},
+        }
+        names.push(name);
+    }
+    assert_eq!(names, vec!["dir", "dir/file", "link", "symlink"]);
+}
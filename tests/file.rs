@@ -0,0 +1,152 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use fuser::FileType;
+use libc::{EINVAL, ENXIO, EOPNOTSUPP};
+use sqlite_fs::db_module::{
+    sqlite, DbModule, FALLOC_FL_COLLAPSE_RANGE, FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE, FALLOC_FL_ZERO_RANGE,
+};
+use sqlite_fs::file::File;
+use sqlite_fs::sqerror::{Error, ErrorKind};
+use sqlite_fs::vfs::Vfs;
+
+mod helpers;
+
+#[test]
+fn file_read_write_seek() {
+    let mut vfs = Vfs::new_in_memory().unwrap();
+    vfs.write("/file", b"").unwrap();
+    let data: Vec<u8> = (0..10000).map(|n| n as u8).collect();
+    {
+        let mut file = vfs.open("/file").unwrap();
+        file.write_all(&data).unwrap();
+        assert_eq!(file.stream_position().unwrap(), 10000);
+        assert_eq!(file.seek(SeekFrom::End(-10)).unwrap(), 9990);
+        let mut buf = vec![];
+        file.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, data[9990..].to_vec());
+        // write beyond the end of the file
+        file.seek(SeekFrom::Start(20000)).unwrap();
+        file.write_all(b"end").unwrap();
+        assert_eq!(file.metadata().unwrap().size, 20003);
+        assert!(file.seek(SeekFrom::Current(-30000)).is_err());
+        file.seek(SeekFrom::Start(4090)).unwrap();
+        let mut buf = [0; 20];
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf.to_vec(), data[4090..4110].to_vec());
+        // the end of a write must fit in a file size
+        file.seek(SeekFrom::Start(u64::MAX - 1)).unwrap();
+        assert_eq!(file.write(b"abc").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(file.metadata().unwrap().size, 20003);
+        file.set_len(5).unwrap();
+        file.sync_all().unwrap();
+        file.sync_data().unwrap();
+    }
+    assert_eq!(vfs.read("/file").unwrap(), data[..5].to_vec());
+    assert!(vfs.open("/").is_err());
+}
+
+#[test]
+fn file_io_error() {
+    let err = Error::from(ErrorKind::FsNoEnt {description: "file 10 is removed".to_string()});
+    // io::Error keeps the description of the error
+    let err = io::Error::from(err);
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    assert!(err.to_string().contains("file 10 is removed"), "{}", err);
+}
+
+#[test]
+fn file_sparse() {
+    let mut vfs = Vfs::new_in_memory().unwrap();
+    vfs.write("/file", b"").unwrap();
+    {
+        let mut file = vfs.open("/file").unwrap();
+        // block 3 and 5 have data, and block 4 is all zero
+        file.seek(SeekFrom::Start(8192)).unwrap();
+        file.write_all(&[1; 100]).unwrap();
+        file.seek(SeekFrom::Start(12288)).unwrap();
+        file.write_all(&[0; 4096]).unwrap();
+        file.seek(SeekFrom::Start(20000)).unwrap();
+        file.write_all(&[2; 10]).unwrap();
+        let attr = file.metadata().unwrap();
+        assert_eq!(attr.size, 20010);
+        assert_eq!(attr.blocks, 16);
+        assert_eq!(file.seek_data(0).unwrap(), 8192);
+        assert_eq!(file.seek_hole(0).unwrap(), 0);
+        assert_eq!(file.seek_hole(8200).unwrap(), 12288);
+        assert_eq!(file.seek_data(12288).unwrap(), 16384);
+        // the end of the file is a hole
+        assert_eq!(file.seek_hole(16384).unwrap(), 20010);
+        assert_eq!(file.seek_data(20005).unwrap(), 20005);
+        assert_eq!(file.seek_data(20010).unwrap_err().errno(), ENXIO);
+        assert_eq!(file.seek_hole(30000).unwrap_err().errno(), ENXIO);
+
+        // overwriting with zeros makes a hole
+        file.seek(SeekFrom::Start(8192)).unwrap();
+        file.write_all(&[0; 4096]).unwrap();
+        assert_eq!(file.metadata().unwrap().blocks, 8);
+        assert_eq!(file.seek_data(0).unwrap(), 16384);
+        // extending doesn't allocate blocks
+        file.set_len(30000).unwrap();
+        assert_eq!(file.metadata().unwrap().blocks, 8);
+        assert_eq!(file.seek_hole(20009).unwrap(), 20480);
+        assert_eq!(file.seek_data(20480).unwrap_err().errno(), ENXIO);
+        // truncating a block to zeros frees it
+        file.set_len(16385).unwrap();
+        file.seek(SeekFrom::Start(16384)).unwrap();
+        file.write_all(&[0]).unwrap();
+        assert_eq!(file.metadata().unwrap().blocks, 0);
+    }
+    let data = vec![0; 16385];
+    assert_eq!(vfs.read("/file").unwrap(), data);
+    assert_eq!(vfs.metadata("/file").unwrap().blocks, 0);
+}
+
+#[test]
+fn file_fallocate() {
+    let mut vfs = Vfs::new_in_memory().unwrap();
+    let mut data: Vec<u8> = (0..16384).map(|n| (n % 251 + 1) as u8).collect();
+    vfs.write("/file", &data).unwrap();
+    {
+        let mut file = vfs.open("/file").unwrap();
+        // allocation only changes the size
+        file.fallocate(0, 20000, 0).unwrap();
+        data.resize(20000, 0);
+        file.fallocate(30000, 100, FALLOC_FL_KEEP_SIZE).unwrap();
+        let attr = file.metadata().unwrap();
+        assert_eq!(attr.size, 20000);
+        assert_eq!(attr.blocks, 32);
+
+        assert_eq!(file.fallocate(100, 8192, FALLOC_FL_PUNCH_HOLE).unwrap_err().errno(), EINVAL);
+        file.fallocate(100, 8192, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE).unwrap();
+        data[100..8292].iter_mut().for_each(|n| *n = 0);
+        assert_eq!(file.metadata().unwrap().blocks, 24);
+        assert_eq!(file.seek_hole(0).unwrap(), 4096);
+
+        file.fallocate(16000, 10000, FALLOC_FL_ZERO_RANGE).unwrap();
+        data[16000..].iter_mut().for_each(|n| *n = 0);
+        data.resize(26000, 0);
+        assert_eq!(file.metadata().unwrap().size, 26000);
+
+        assert_eq!(file.fallocate(4000, 4096, FALLOC_FL_COLLAPSE_RANGE).unwrap_err().errno(), EINVAL);
+        assert_eq!(file.fallocate(8192, 20480, FALLOC_FL_COLLAPSE_RANGE).unwrap_err().errno(), EINVAL);
+        file.fallocate(4096, 4096, FALLOC_FL_COLLAPSE_RANGE).unwrap();
+        data.drain(4096..8192);
+        assert_eq!(file.metadata().unwrap().size, 21904);
+
+        assert_eq!(file.fallocate(0, 4096, 0x20).unwrap_err().errno(), EOPNOTSUPP);
+        assert!(file.fallocate(0, 0, 0).is_err());
+    }
+    assert_eq!(vfs.read("/file").unwrap(), data);
+}
+
+#[test]
+fn file_open_inode() {
+    let mut db = sqlite::Sqlite::new_in_memory().unwrap();
+    db.init().unwrap();
+    // root directory
+    assert!(File::open(&mut db, 1).is_err());
+    assert!(File::open(&mut db, 100).is_err());
+    let ino = db.add_inode_and_dentry(1, "symlink", &helpers::file_attr(FileType::Symlink, 0o777, 0, 0)).unwrap();
+    assert_eq!(File::open(&mut db, ino).err().unwrap().errno(), EINVAL);
+    let ino = db.add_inode_and_dentry(1, "fifo", &helpers::file_attr(FileType::NamedPipe, 0o644, 0, 0)).unwrap();
+    assert_eq!(File::open(&mut db, ino).err().unwrap().errno(), EINVAL);
+}
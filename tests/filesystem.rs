@@ -1 +1,105 @@
+use fuser::FileType;
+use libc::{EBADF, EDQUOT, EINVAL, ENXIO, O_APPEND, O_NOATIME, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY, SEEK_DATA, SEEK_HOLE, SEEK_SET};
+use sqlite_fs::db_module::{sqlite, DbModule, QuotaKind, FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE};
+use sqlite_fs::filesystem::SqliteFs;
 
+mod helpers;
+
+#[test]
+fn filesystem_open_flags() {
+    let mut db = sqlite::Sqlite::new_in_memory().unwrap();
+    db.init().unwrap();
+    let ino = db.add_inode_and_dentry(1, "file", &helpers::file_attr(FileType::RegularFile, 0o644, 0, 0)).unwrap();
+    db.write_file(ino, 0, b"hello").unwrap();
+    let mut fs = SqliteFs::new_with_db(db).unwrap();
+
+    // O_APPEND ignores the offset
+    let fh = fs.open_file(ino, (O_WRONLY | O_APPEND) as u32).unwrap();
+    assert_eq!(fs.write_file(ino, fh, 0, b" world", 0).unwrap(), 6);
+    fs.write_file(ino, fh, 0, b"!", 0).unwrap();
+    let fh = fs.open_file(ino, (O_RDONLY | O_NOATIME) as u32).unwrap();
+    assert_eq!(fs.read_file(ino, fh, 0, 100).unwrap(), b"hello world!".to_vec());
+    // a read only handle can't write
+    assert_eq!(fs.write_file(ino, fh, 0, b"x", 0).unwrap_err().errno(), EBADF);
+
+    // O_TRUNC is ignored by a read only open
+    let fh = fs.open_file(ino, (O_RDONLY | O_TRUNC) as u32).unwrap();
+    assert_eq!(fs.read_file(ino, fh, 0, 100).unwrap().len(), 12);
+    let fh = fs.open_file(ino, (O_RDWR | O_TRUNC) as u32).unwrap();
+    assert_eq!(fs.read_file(ino, fh, 0, 100).unwrap(), vec![]);
+    fs.write_file(ino, fh, 2, b"x", 0).unwrap();
+    assert_eq!(fs.read_file(ino, fh, 0, 100).unwrap(), b"\0\0x".to_vec());
+}
+
+#[test]
+fn filesystem_quota_of_cached_data() {
+    let mut db = sqlite::Sqlite::new_in_memory().unwrap();
+    db.init().unwrap();
+    let attr = helpers::file_attr(FileType::RegularFile, 0o644, 1000, 1000);
+    let ino1 = db.add_inode_and_dentry(1, "file1", &attr).unwrap();
+    let ino2 = db.add_inode_and_dentry(1, "file2", &attr).unwrap();
+    db.set_quota(QuotaKind::User, 1000, 3 * 4096, 0).unwrap();
+    let mut fs = SqliteFs::new_with_db(db).unwrap();
+    let fh1 = fs.open_file(ino1, O_WRONLY as u32).unwrap();
+    let fh2 = fs.open_file(ino2, O_WRONLY as u32).unwrap();
+    fs.write_file(ino1, fh1, 0, &[1; 8192], 1000).unwrap();
+    // data of file1 is still in the cache
+    assert_eq!(fs.write_file(ino2, fh2, 0, &[1; 8192], 1000).unwrap_err().errno(), EDQUOT);
+    fs.write_file(ino2, fh2, 0, &[1; 4096], 1000).unwrap();
+    // overwriting cached data doesn't need more space
+    fs.write_file(ino1, fh1, 0, &[2; 8192], 1000).unwrap();
+}
+
+#[test]
+fn filesystem_seek_cached_data() {
+    let mut db = sqlite::Sqlite::new_in_memory().unwrap();
+    db.init().unwrap();
+    let ino = db.add_inode_and_dentry(1, "file", &helpers::file_attr(FileType::RegularFile, 0o644, 0, 0)).unwrap();
+    let mut fs = SqliteFs::new_with_db(db).unwrap();
+    let fh = fs.open_file(ino, O_WRONLY as u32).unwrap();
+    // the block is only in the cache
+    fs.write_file(ino, fh, 8192, &[1; 4096], 0).unwrap();
+    assert_eq!(fs.seek_file(ino, 0, SEEK_DATA).unwrap(), 8192);
+    assert_eq!(fs.seek_file(ino, 0, SEEK_HOLE).unwrap(), 0);
+    assert_eq!(fs.seek_file(ino, 8192, SEEK_HOLE).unwrap(), 12288);
+    assert_eq!(fs.seek_file(ino, 12288, SEEK_DATA).unwrap_err().errno(), ENXIO);
+    assert_eq!(fs.seek_file(ino, 0, SEEK_SET).unwrap_err().errno(), EINVAL);
+}
+
+#[test]
+fn filesystem_fallocate_cached_data() {
+    let mut db = sqlite::Sqlite::new_in_memory().unwrap();
+    db.init().unwrap();
+    let ino = db.add_inode_and_dentry(1, "file", &helpers::file_attr(FileType::RegularFile, 0o644, 0, 0)).unwrap();
+    let mut fs = SqliteFs::new_with_db(db).unwrap();
+    let fh = fs.open_file(ino, O_RDWR as u32).unwrap();
+    fs.write_file(ino, fh, 0, &[1; 8192], 0).unwrap();
+    // the hole is punched after the cached data is written back
+    fs.fallocate_file(ino, 0, 4096, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE).unwrap();
+    let data = fs.read_file(ino, fh, 0, 8192).unwrap();
+    assert_eq!(data[..4096], [0; 4096][..]);
+    assert_eq!(data[4096..], [1; 4096][..]);
+    fs.fallocate_file(ino, 8192, 4096, 0).unwrap();
+    assert_eq!(fs.read_file(ino, fh, 0, 16384).unwrap().len(), 12288);
+    assert_eq!(fs.fallocate_file(ino, 0, 0, 0).unwrap_err().errno(), EINVAL);
+}
+
+#[test]
+fn filesystem_copy_file_range_cached_data() {
+    let mut db = sqlite::Sqlite::new_in_memory().unwrap();
+    db.init().unwrap();
+    let attr = helpers::file_attr(FileType::RegularFile, 0o644, 0, 0);
+    let ino1 = db.add_inode_and_dentry(1, "file1", &attr).unwrap();
+    let ino2 = db.add_inode_and_dentry(1, "file2", &attr).unwrap();
+    let mut fs = SqliteFs::new_with_db(db).unwrap();
+    let fh1 = fs.open_file(ino1, O_RDWR as u32).unwrap();
+    let fh2 = fs.open_file(ino2, O_RDWR as u32).unwrap();
+    fs.write_file(ino1, fh1, 0, &[1; 8192], 0).unwrap();
+    fs.write_file(ino2, fh2, 0, &[2; 100], 0).unwrap();
+    // both files are written back before the copy
+    assert_eq!(fs.copy_file_range(ino1, 4096, ino2, 50, 10000, 0).unwrap(), 4096);
+    let data = fs.read_file(ino2, fh2, 0, 8192).unwrap();
+    assert_eq!(data.len(), 4146);
+    assert_eq!(data[..50], [2; 50][..]);
+    assert_eq!(data[50..], [1; 4096][..]);
+}
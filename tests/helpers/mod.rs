@@ -1,9 +1,10 @@
 #![allow(dead_code)]
 extern crate tempfile;
-use nix::dir::Dir;
-use sqlite_fs::db_module::{sqlite, DbModule};
-use std::fs::File;
+use fuser::FileType;
+use sqlite_fs::db_module::{sqlite, DBFileAttr};
 use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 enum DirOrNot {
     Empty,
@@ -13,6 +14,7 @@ enum DirOrNot {
 pub struct DBWithTempFile {
     pub db: sqlite::Sqlite,
     dir: DirOrNot,
+    path: PathBuf,
 }
 
 impl DBWithTempFile {
@@ -21,7 +23,12 @@ impl DBWithTempFile {
         let file_path = dir.path().join("filesystem.db");
         let db = sqlite::Sqlite::new(file_path.as_path()).unwrap();
         let dir = DirOrNot::Exist(dir);
-        Self { db, dir }
+        Self { db, dir, path: file_path }
+    }
+
+    /// Path of the database file, to open another connection
+    pub fn path(&self) -> &Path {
+        &self.path
     }
 }
 
@@ -36,3 +43,24 @@ impl Drop for DBWithTempFile {
         }
     }
 }
+
+/// Attributes of a new file. Times are now, and the inode number is given when it is added.
+pub fn file_attr(kind: FileType, perm: u16, uid: u32, gid: u32) -> DBFileAttr {
+    let now = SystemTime::now();
+    DBFileAttr {
+        ino: 0,
+        size: 0,
+        blocks: 0,
+        atime: now,
+        mtime: now,
+        ctime: now,
+        crtime: now,
+        kind,
+        perm,
+        nlink: 0,
+        uid,
+        gid,
+        rdev: 0,
+        flags: 0
+    }
+}
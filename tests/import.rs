@@ -0,0 +1,67 @@
+use std::fs;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::time::{Duration, UNIX_EPOCH};
+use nix::sys::stat::utimes;
+use nix::sys::time::{TimeVal, TimeValLike};
+use sqlite_fs::db_module::{sqlite, DbModule};
+use sqlite_fs::import::import;
+use fuser::FileType;
+
+#[test]
+fn import_directory() {
+    let src = tempfile::tempdir().unwrap();
+    let dir = src.path().join("dir");
+    fs::create_dir(&dir).unwrap();
+    // larger than a block
+    let data: Vec<u8> = (0..10000).map(|n| n as u8).collect();
+    fs::write(dir.join("file"), &data).unwrap();
+    fs::set_permissions(dir.join("file"), fs::Permissions::from_mode(0o640)).unwrap();
+    fs::hard_link(dir.join("file"), src.path().join("link")).unwrap();
+    symlink("dir/file", src.path().join("symlink")).unwrap();
+    let xattr_supported = xattr::set(dir.join("file"), "user.comment", b"hello").is_ok();
+    utimes(&dir.join("file"), &TimeVal::seconds(1_000_000), &TimeVal::seconds(2_000_000)).unwrap();
+    utimes(&dir, &TimeVal::seconds(3_000_000), &TimeVal::seconds(4_000_000)).unwrap();
+
+    let mut db = sqlite::Sqlite::new_in_memory().unwrap();
+    db.set_block_size(4096).unwrap();
+    db.init().unwrap();
+    // dir, file and symlink. link shares the inode of file.
+    assert_eq!(import(&mut db, src.path(), 1).unwrap(), 3);
+
+    let dir_attr = db.lookup(1, "dir").unwrap().unwrap();
+    assert_eq!(dir_attr.kind, FileType::Directory);
+    assert_eq!(dir_attr.mtime, UNIX_EPOCH + Duration::from_secs(4_000_000));
+    let file_attr = db.lookup(dir_attr.ino, "file").unwrap().unwrap();
+    assert_eq!(file_attr.kind, FileType::RegularFile);
+    assert_eq!(file_attr.size, 10000);
+    assert_eq!(file_attr.perm, 0o640);
+    assert_eq!(file_attr.nlink, 2);
+    assert_eq!(file_attr.atime, UNIX_EPOCH + Duration::from_secs(1_000_000));
+    assert_eq!(file_attr.mtime, UNIX_EPOCH + Duration::from_secs(2_000_000));
+    let mut file_data = Vec::new();
+    for block in 1..=3 {
+        file_data.append(&mut db.get_data(file_attr.ino, block, 4096, false).unwrap());
+    }
+    assert_eq!(file_data, data);
+    let link_attr = db.lookup(1, "link").unwrap().unwrap();
+    assert_eq!(link_attr.ino, file_attr.ino);
+    if xattr_supported {
+        assert_eq!(db.get_xattr(file_attr.ino, "user.comment").unwrap(), b"hello".to_vec());
+    }
+    let symlink_attr = db.lookup(1, "symlink").unwrap().unwrap();
+    assert_eq!(symlink_attr.kind, FileType::Symlink);
+    assert_eq!(db.get_data(symlink_attr.ino, 1, 4096, false).unwrap(), b"dir/file".to_vec());
+}
+
+#[test]
+fn import_rollback_on_error() {
+    let src = tempfile::tempdir().unwrap();
+    fs::write(src.path().join("file"), b"data").unwrap();
+    let mut db = sqlite::Sqlite::new_in_memory().unwrap();
+    db.init().unwrap();
+    import(&mut db, src.path(), 1).unwrap();
+    fs::write(src.path().join("other"), b"data").unwrap();
+    // "file" already exists
+    assert!(import(&mut db, src.path(), 1).is_err());
+    assert!(db.lookup(1, "other").unwrap().is_none());
+}
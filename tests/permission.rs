@@ -0,0 +1,108 @@
+use fuser::FileType;
+use libc::{EACCES, EPERM, R_OK, W_OK, X_OK};
+use sqlite_fs::permission::{self, Credential};
+
+mod helpers;
+use helpers::file_attr;
+
+#[test]
+fn permission_access() {
+    let attr = file_attr(FileType::RegularFile, 0o640, 1000, 100);
+    let owner = Credential::new(1000, 1000, vec![]);
+    let member = Credential::new(1001, 1001, vec![100]);
+    let other = Credential::new(1002, 1002, vec![]);
+    let root = Credential::new(0, 0, vec![]);
+    permission::check_access(&attr, None, &owner, (R_OK | W_OK) as u32).unwrap();
+    assert_eq!(permission::check_access(&attr, None, &owner, X_OK as u32).unwrap_err().errno(), EACCES);
+    permission::check_access(&attr, None, &member, R_OK as u32).unwrap();
+    assert_eq!(permission::check_access(&attr, None, &member, W_OK as u32).unwrap_err().errno(), EACCES);
+    assert_eq!(permission::check_access(&attr, None, &other, R_OK as u32).unwrap_err().errno(), EACCES);
+    // F_OK
+    permission::check_access(&attr, None, &other, 0).unwrap();
+    permission::check_access(&attr, None, &root, (R_OK | W_OK) as u32).unwrap();
+    // root can't execute a file without any execute bit
+    assert!(permission::check_access(&attr, None, &root, X_OK as u32).is_err());
+    let dir = file_attr(FileType::Directory, 0o700, 1000, 1000);
+    permission::check_access(&dir, None, &root, X_OK as u32).unwrap();
+}
+
+#[test]
+fn permission_sticky_directory() {
+    let tmp = file_attr(FileType::Directory, 0o1777, 0, 0);
+    let file = file_attr(FileType::RegularFile, 0o666, 1000, 1000);
+    let owner = Credential::new(1000, 1000, vec![]);
+    let other = Credential::new(1001, 1001, vec![]);
+    permission::check_create(&tmp, None, &other).unwrap();
+    permission::check_remove(&tmp, None, &file, &owner).unwrap();
+    assert_eq!(permission::check_remove(&tmp, None, &file, &other).unwrap_err().errno(), EPERM);
+    permission::check_remove(&tmp, None, &file, &Credential::new(0, 0, vec![])).unwrap();
+    // without the sticky bit
+    let dir = file_attr(FileType::Directory, 0o777, 0, 0);
+    permission::check_remove(&dir, None, &file, &other).unwrap();
+    let dir = file_attr(FileType::Directory, 0o755, 0, 0);
+    assert_eq!(permission::check_remove(&dir, None, &file, &owner).unwrap_err().errno(), EACCES);
+}
+
+#[test]
+fn permission_setattr() {
+    let mut attr = file_attr(FileType::RegularFile, 0o6755, 1000, 100);
+    let owner = Credential::new(1000, 1000, vec![100, 200]);
+    let other = Credential::new(1001, 1001, vec![]);
+    permission::check_owner(&attr, &owner).unwrap();
+    assert_eq!(permission::check_owner(&attr, &other).unwrap_err().errno(), EPERM);
+    permission::check_chown(&attr, &owner, Some(1000), Some(200)).unwrap();
+    assert_eq!(permission::check_chown(&attr, &owner, Some(1001), None).unwrap_err().errno(), EPERM);
+    assert_eq!(permission::check_chown(&attr, &owner, None, Some(300)).unwrap_err().errno(), EPERM);
+    assert_eq!(permission::check_chown(&attr, &other, None, Some(1001)).unwrap_err().errno(), EPERM);
+    permission::check_chown(&attr, &Credential::new(0, 0, vec![]), Some(1), Some(1)).unwrap();
+    // setgid is cleared if the owner is not in the group
+    assert_eq!(permission::chmod_mode(&attr, &owner, 0o2755), 0o2755);
+    assert_eq!(permission::chmod_mode(&attr, &Credential::new(1000, 1000, vec![]), 0o2755), 0o755);
+
+    assert!(permission::clear_suid(&mut attr));
+    assert_eq!(attr.perm, 0o755);
+    assert!(!permission::clear_suid(&mut attr));
+    // setgid without group execute is kept
+    attr.perm = 0o2644;
+    assert!(!permission::clear_suid(&mut attr));
+    assert_eq!(attr.perm, 0o2644);
+}
+
+#[test]
+fn permission_inherit_setgid() {
+    let dir = file_attr(FileType::Directory, 0o2775, 0, 100);
+    let mut file = file_attr(FileType::CharDevice, 0o666, 1000, 1000);
+    permission::inherit_setgid(&dir, &mut file);
+    assert_eq!(file.gid, 100);
+    assert_eq!(file.perm, 0o666);
+    let mut sub = file_attr(FileType::Directory, 0o755, 1000, 1000);
+    permission::inherit_setgid(&dir, &mut sub);
+    assert_eq!(sub.gid, 100);
+    assert_eq!(sub.perm, 0o2755);
+    // without the setgid bit
+    let dir = file_attr(FileType::Directory, 0o775, 0, 100);
+    let mut file = file_attr(FileType::NamedPipe, 0o644, 1000, 1000);
+    permission::inherit_setgid(&dir, &mut file);
+    assert_eq!(file.gid, 1000);
+}
+
+#[test]
+fn permission_xattr() {
+    let file = file_attr(FileType::RegularFile, 0o644, 1000, 1000);
+    let owner = Credential::new(1000, 1000, vec![]);
+    let other = Credential::new(1001, 1001, vec![]);
+    let root = Credential::new(0, 0, vec![]);
+    permission::check_xattr(&file, None, &owner, "user.comment", true).unwrap();
+    permission::check_xattr(&file, None, &other, "user.comment", false).unwrap();
+    assert_eq!(permission::check_xattr(&file, None, &other, "user.comment", true).unwrap_err().errno(), EACCES);
+    assert_eq!(permission::check_xattr(&file, None, &owner, "trusted.key", false).unwrap_err().errno(), EPERM);
+    permission::check_xattr(&file, None, &root, "trusted.key", true).unwrap();
+    permission::check_xattr(&file, None, &other, "security.selinux", false).unwrap();
+    assert_eq!(permission::check_xattr(&file, None, &owner, "security.selinux", true).unwrap_err().errno(), EPERM);
+    permission::check_xattr(&file, None, &owner, "system.posix_acl_access", true).unwrap();
+    assert_eq!(permission::check_xattr(&file, None, &other, "system.posix_acl_access", true).unwrap_err().errno(), EPERM);
+    let symlink = file_attr(FileType::Symlink, 0o777, 1000, 1000);
+    assert_eq!(permission::check_xattr(&symlink, None, &owner, "user.comment", true).unwrap_err().errno(), EPERM);
+    assert!(!permission::xattr_visible(&owner, "trusted.key"));
+    assert!(permission::xattr_visible(&root, "trusted.key"));
+}
@@ -1,4 +1,8 @@
-use sqlite_fs::db_module::{sqlite, DbModule};
+use sqlite_fs::compression::Codec;
+use sqlite_fs::db_module::{sqlite, DbModule, DBFileAttr, DBLock, QuotaKind, SNAPSHOT_DIR_INO};
+use std::time::SystemTime;
+use fuser::FileType;
+use libc::{EAGAIN, F_RDLCK, F_WRLCK, F_UNLCK, EEXIST, ENOENT, EPERM, ENODATA, ENAMETOOLONG, ENOSPC, EDQUOT, EROFS, EXDEV, EBUSY, EINVAL, FALLOC_FL_COLLAPSE_RANGE};
 
 mod helpers;
 #[test]
@@ -33,3 +37,823 @@ fn sqlite_init_db() {
         Err(_) => assert!(false, "failed to init db"),
     }
 }
+
+#[test]
+fn sqlite_lock() {
+    let mut db = sqlite::Sqlite::new_in_memory().unwrap();
+    db.init().unwrap();
+    let attr = helpers::file_attr(FileType::RegularFile, 0o644, 0, 0);
+    let ino = db.add_inode_and_dentry(1, "file", &attr).unwrap();
+    let read_lock = DBLock{ino, lock_owner: 1, start: 0, end: 99, typ: F_RDLCK as u32, pid: 10};
+    db.set_lock(&read_lock).unwrap();
+    // shared locks don't conflict
+    let other_read = DBLock{lock_owner: 2, pid: 20, ..read_lock};
+    assert_eq!(db.get_lock(&other_read).unwrap(), None);
+    db.set_lock(&other_read).unwrap();
+    // exclusive lock conflicts with shared locks of other owners
+    let write_lock = DBLock{ino, lock_owner: 3, start: 50, end: 149, typ: F_WRLCK as u32, pid: 30};
+    assert_eq!(db.get_lock(&write_lock).unwrap().unwrap().pid, 10);
+    assert!(db.set_lock(&write_lock).is_err());
+    // the owner can upgrade its own lock after the other owner releases it
+    db.release_lock(ino, 2).unwrap();
+    let upgrade = DBLock{start: 50, end: 149, typ: F_WRLCK as u32, ..read_lock};
+    db.set_lock(&upgrade).unwrap();
+    let test_lock = DBLock{ino, lock_owner: 3, start: 0, end: 49, typ: F_WRLCK as u32, pid: 30};
+    assert_eq!(db.get_lock(&test_lock).unwrap().unwrap().typ, F_RDLCK as u32);
+    // unlock splits the range
+    let unlock = DBLock{start: 40, end: 59, typ: F_UNLCK as u32, ..read_lock};
+    db.set_lock(&unlock).unwrap();
+    let test_lock = DBLock{ino, lock_owner: 3, start: 40, end: 59, typ: F_WRLCK as u32, pid: 30};
+    assert_eq!(db.get_lock(&test_lock).unwrap(), None);
+    let test_lock = DBLock{start: 60, end: 60, ..test_lock};
+    assert_eq!(db.get_lock(&test_lock).unwrap().unwrap().typ, F_WRLCK as u32);
+    db.release_all_lock().unwrap();
+    assert_eq!(db.get_lock(&write_lock).unwrap(), None);
+}
+
+#[test]
+fn sqlite_lock_of_dead_process() {
+    let mut dbf = helpers::DBWithTempFile::new();
+    dbf.db.init().unwrap();
+    let ino = dbf.db.add_inode_and_dentry(1, "file", &helpers::file_attr(FileType::RegularFile, 0o644, 0, 0)).unwrap();
+    // a session id has the process id in the upper bits
+    let mut child = std::process::Command::new("true").spawn().unwrap();
+    let dead_session = i64::from(child.id()) << 32;
+    child.wait().unwrap();
+    let conn = rusqlite::Connection::open(dbf.path()).unwrap();
+    conn.execute(
+        "INSERT INTO lock (file_id, session, lock_owner, start_offset, end_offset, lock_type, pid) VALUES($1, $2, 1, 0, 99, $3, 10)",
+        rusqlite::params![ino as i64, dead_session, F_WRLCK as u32]
+    ).unwrap();
+    let lock = DBLock{ino, lock_owner: 2, start: 0, end: 0, typ: F_WRLCK as u32, pid: 20};
+    assert_eq!(dbf.db.get_lock(&lock).unwrap(), None);
+    dbf.db.set_lock(&lock).unwrap();
+    let count: u32 = conn.query_row("SELECT count(*) FROM lock", rusqlite::NO_PARAMS, |row| row.get(0)).unwrap();
+    assert_eq!(count, 1);
+}
+
+#[test]
+fn sqlite_error_errno() {
+    let mut db = sqlite::Sqlite::new_in_memory().unwrap();
+    db.init().unwrap();
+    let attr = helpers::file_attr(FileType::Directory, 0o755, 0, 0);
+    let ino = db.add_inode_and_dentry(1, "dir", &attr).unwrap();
+    assert_eq!(db.add_inode_and_dentry(1, "dir", &attr).unwrap_err().errno(), EEXIST);
+    assert_eq!(db.delete_dentry(1, "none").unwrap_err().errno(), ENOENT);
+    assert_eq!(db.link_dentry(ino, 1, "link").unwrap_err().errno(), EPERM);
+    assert_eq!(db.get_xattr(ino, "user.none").unwrap_err().errno(), ENODATA);
+    let long_name = "a".repeat(256);
+    assert_eq!(db.add_inode_and_dentry(1, &long_name, &attr).unwrap_err().errno(), ENAMETOOLONG);
+}
+
+#[test]
+fn sqlite_link_keeps_mtime() {
+    let mut db = sqlite::Sqlite::new_in_memory().unwrap();
+    db.init().unwrap();
+    let old = SystemTime::UNIX_EPOCH;
+    let attr = DBFileAttr {mtime: old, ctime: old, ..helpers::file_attr(FileType::RegularFile, 0o644, 0, 0)};
+    let ino = db.add_inode_and_dentry(1, "file", &attr).unwrap();
+    // link(2) changes only ctime of the file
+    let attr = db.link_dentry(ino, 1, "link").unwrap();
+    assert_eq!(attr.nlink, 2);
+    assert_eq!(attr.mtime, old);
+    assert!(attr.ctime > old);
+}
+
+#[test]
+fn sqlite_locked_database() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("filesystem.db");
+    let options = sqlite::SqliteOptions{busy_timeout: 10, ..sqlite::SqliteOptions::default()};
+    let mut db = sqlite::Sqlite::new_with_options(&path, &options).unwrap();
+    db.init().unwrap();
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    conn.execute_batch("BEGIN EXCLUSIVE").unwrap();
+    // another connection holds the lock longer than busy_timeout
+    let attr = helpers::file_attr(FileType::RegularFile, 0o644, 0, 0);
+    assert_eq!(db.add_inode_and_dentry(1, "file", &attr).unwrap_err().errno(), EAGAIN);
+    conn.execute_batch("COMMIT").unwrap();
+    db.add_inode_and_dentry(1, "file", &attr).unwrap();
+}
+
+#[test]
+fn sqlite_large_file() {
+    let mut db = sqlite::Sqlite::new_in_memory().unwrap();
+    db.init().unwrap();
+    let attr = helpers::file_attr(FileType::RegularFile, 0o644, 0, 0);
+    let ino = db.add_inode_and_dentry(1, "image", &attr).unwrap();
+    let block_size = u64::from(db.get_db_block_size());
+    // the last block of a 5 GiB file
+    let size = 5 * 1024 * 1024 * 1024;
+    let block = size / block_size;
+    db.write_data(ino, block, &[1; 4096], size).unwrap();
+    let attr = db.get_inode(ino).unwrap().unwrap();
+    assert_eq!(attr.size, size);
+    assert_eq!(db.get_data(ino, block, 4096, false).unwrap(), vec![1; 4096]);
+    // truncate to 4 GiB + 1 byte
+    let mut attr = attr;
+    attr.size = 4 * 1024 * 1024 * 1024 + 1;
+    db.update_inode(&attr, true).unwrap();
+    assert_eq!(db.get_inode(ino).unwrap().unwrap().size, attr.size);
+    assert_eq!(db.get_data(ino, block, 4096, false).unwrap(), vec![0; 4096]);
+}
+
+#[test]
+fn sqlite_write_range() {
+    let mut db = sqlite::Sqlite::new_in_memory().unwrap();
+    db.init().unwrap();
+    let attr = helpers::file_attr(FileType::RegularFile, 0o644, 0, 0);
+    let ino = db.add_inode_and_dentry(1, "file", &attr).unwrap();
+    // the end of a write must fit in a file size
+    assert_eq!(db.write_file(ino, u64::MAX - 1, b"abc").unwrap_err().errno(), EINVAL);
+    assert_eq!(db.write_file(ino, i64::MAX as u64, b"a").unwrap_err().errno(), EINVAL);
+    assert_eq!(db.write_blocks(ino, &[(1, vec![1; 4096])], u64::MAX).unwrap_err().errno(), EINVAL);
+    assert_eq!(db.write_blocks(ino, &[(u64::MAX, vec![1; 4096])], 4096).unwrap_err().errno(), EINVAL);
+    assert_eq!(db.get_inode(ino).unwrap().unwrap().size, 0);
+    db.write_file(ino, i64::MAX as u64 - 1, b"a").unwrap();
+    assert_eq!(db.get_inode(ino).unwrap().unwrap().size, i64::MAX as u64);
+}
+
+#[test]
+fn sqlite_schema_version() {
+    let mut dbf = helpers::DBWithTempFile::new();
+    assert_eq!(dbf.db.get_schema_version().unwrap(), 0);
+    dbf.db.init().unwrap();
+    assert_eq!(dbf.db.get_schema_version().unwrap(), sqlite::SCHEMA_VERSION);
+    // init twice doesn't change anything
+    dbf.db.init().unwrap();
+    assert_eq!(dbf.db.get_schema_version().unwrap(), sqlite::SCHEMA_VERSION);
+    let conn = rusqlite::Connection::open(dbf.path()).unwrap();
+    let sql = "SELECT count(*) FROM sqlite_master WHERE type='index' AND name='data_hash'";
+    let count: u32 = conn.query_row(sql, rusqlite::NO_PARAMS, |row| row.get(0)).unwrap();
+    assert_eq!(count, 1);
+}
+
+#[test]
+fn sqlite_refuse_newer_schema() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("filesystem.db");
+    {
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch(&format!("PRAGMA user_version={}", sqlite::SCHEMA_VERSION + 1)).unwrap();
+    }
+    let mut db = sqlite::Sqlite::new(&path).unwrap();
+    assert!(db.init().is_err());
+}
+
+#[test]
+fn sqlite_migrate_legacy_schema() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("filesystem.db");
+    {
+        // tables created before schema versioning
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch("
+            CREATE TABLE metadata(id integer primary key, size int default 0 not null, atime text, atime_nsec int, \
+                mtime text, mtime_nsec int, ctime text, ctime_nsec int, crtime text, crtime_nsec int, kind int, mode int, \
+                nlink int default 0 not null, uid int default 0, gid int default 0, rdev int default 0, flags int default 0);
+            CREATE TABLE dentry(parent_id int, child_id int, file_type int, name text, primary key (parent_id, name));
+            CREATE TABLE data(file_id int, block_num int, data blob, primary key (file_id, block_num));
+            CREATE TABLE xattr(file_id int, name text, value text, primary key (file_id, name));
+            INSERT INTO metadata VALUES(1,0,'1970-01-01 00:00:00',0,'1970-01-01 00:00:00',0,'1970-01-01 00:00:00',0,\
+                '1970-01-01 00:00:00',0,16384,16832,1,0,0,0,0);
+            INSERT INTO dentry VALUES(1,1,16384,'.');
+            INSERT INTO dentry VALUES(1,1,16384,'..');
+            INSERT INTO xattr VALUES(1,'user.comment','hello');
+        ").unwrap();
+    }
+    let mut db = sqlite::Sqlite::new(&path).unwrap();
+    db.init().unwrap();
+    assert_eq!(db.get_schema_version().unwrap(), sqlite::SCHEMA_VERSION);
+    assert_eq!(db.get_xattr(1, "user.comment").unwrap(), b"hello".to_vec());
+    assert_eq!(db.get_dentry(1).unwrap().len(), 2);
+    assert_eq!(db.get_db_block_size(), sqlite::DEFAULT_BLOCK_SIZE);
+}
+
+#[test]
+fn sqlite_migrate_reserved_name() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("filesystem.db");
+    {
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch("
+            CREATE TABLE metadata(id integer primary key, size int default 0 not null, atime text, atime_nsec int, \
+                mtime text, mtime_nsec int, ctime text, ctime_nsec int, crtime text, crtime_nsec int, kind int, mode int, \
+                nlink int default 0 not null, uid int default 0, gid int default 0, rdev int default 0, flags int default 0);
+            CREATE TABLE dentry(parent_id int, child_id int, file_type int, name text, primary key (parent_id, name));
+            CREATE TABLE data(file_id int, block_num int, data blob, primary key (file_id, block_num));
+            CREATE TABLE xattr(file_id int, name text, value text, primary key (file_id, name));
+            INSERT INTO metadata VALUES(1,0,'1970-01-01 00:00:00',0,'1970-01-01 00:00:00',0,'1970-01-01 00:00:00',0,\
+                '1970-01-01 00:00:00',0,16384,16832,1,0,0,0,0);
+            INSERT INTO metadata VALUES(2,0,'1970-01-01 00:00:00',0,'1970-01-01 00:00:00',0,'1970-01-01 00:00:00',0,\
+                '1970-01-01 00:00:00',0,32768,33188,1,0,0,0,0);
+            INSERT INTO dentry VALUES(1,1,16384,'.');
+            INSERT INTO dentry VALUES(1,1,16384,'..');
+            INSERT INTO dentry VALUES(1,2,32768,'.snapshots');
+        ").unwrap();
+    }
+    let mut db = sqlite::Sqlite::new(&path).unwrap();
+    db.init().unwrap();
+    // the entry would be hidden by the directory of snapshots
+    assert_eq!(db.lookup(1, ".snapshots").unwrap().unwrap().ino, SNAPSHOT_DIR_INO);
+    assert_eq!(db.lookup(1, ".snapshots.1").unwrap().unwrap().ino, 2);
+}
+
+#[test]
+fn sqlite_load_without_migration() {
+    let mut dbf = helpers::DBWithTempFile::new();
+    // an empty database is not migrated by load
+    assert!(dbf.db.load().is_err());
+    assert_eq!(dbf.db.get_schema_version().unwrap(), 0);
+    dbf.db.set_block_size(8192).unwrap();
+    dbf.db.init().unwrap();
+    let mut db = sqlite::Sqlite::new(dbf.path()).unwrap();
+    db.load().unwrap();
+    assert_eq!(db.get_db_block_size(), 8192);
+}
+
+#[test]
+fn sqlite_block_size() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("filesystem.db");
+    let mut db = sqlite::Sqlite::new(&path).unwrap();
+    assert!(db.set_block_size(1000).is_err());
+    assert!(db.set_block_size(256).is_err());
+    db.set_block_size(512).unwrap();
+    db.init().unwrap();
+    assert_eq!(db.get_db_block_size(), 512);
+    let mut attr = helpers::file_attr(FileType::RegularFile, 0o644, 0, 0);
+    let ino = db.add_inode_and_dentry(1, "file", &attr).unwrap();
+    db.write_data(ino, 1, &[1; 512], 512).unwrap();
+    db.write_data(ino, 2, &[2; 512], 1024).unwrap();
+    // truncate in the middle of the first block
+    attr.ino = ino;
+    attr.size = 100;
+    db.update_inode(&attr, true).unwrap();
+    assert_eq!(db.get_data(ino, 1, 512, false).unwrap(), vec![1; 100]);
+    assert_eq!(db.get_data(ino, 2, 512, false).unwrap(), vec![0; 512]);
+    drop(db);
+
+    // the stored block size is used when the database is opened again
+    let mut db = sqlite::Sqlite::new(&path).unwrap();
+    db.init().unwrap();
+    assert_eq!(db.get_db_block_size(), 512);
+    let mut db = sqlite::Sqlite::new(&path).unwrap();
+    db.set_block_size(512).unwrap();
+    db.init().unwrap();
+    // the block size can't be changed
+    let mut db = sqlite::Sqlite::new(&path).unwrap();
+    db.set_block_size(4096).unwrap();
+    assert!(db.init().is_err());
+}
+
+#[test]
+fn sqlite_noatime() {
+    let mut db = sqlite::Sqlite::new_in_memory().unwrap();
+    db.init().unwrap();
+    let old = SystemTime::UNIX_EPOCH;
+    let attr = DBFileAttr {atime: old, mtime: old, ctime: old, crtime: old, ..helpers::file_attr(FileType::RegularFile, 0o644, 0, 0)};
+    let ino = db.add_inode_and_dentry(1, "file", &attr).unwrap();
+    // writing doesn't update atime
+    db.write_file(ino, 10, b"data").unwrap();
+    assert_eq!(db.get_inode(ino).unwrap().unwrap().atime, old);
+    assert_eq!(db.read_file(ino, 10, 4, true).unwrap(), b"data".to_vec());
+    assert_eq!(db.get_inode(ino).unwrap().unwrap().atime, old);
+    db.read_file(ino, 0, 14, false).unwrap();
+    assert!(db.get_inode(ino).unwrap().unwrap().atime > old);
+}
+
+#[test]
+fn sqlite_options() {
+    let mut options = sqlite::SqliteOptions::default();
+    assert_eq!(options.journal_mode, "wal");
+    assert!(options.parse_option("synchronous=FULL").unwrap());
+    assert_eq!(options.synchronous, "full");
+    assert!(options.parse_option("cache_size=-4000").unwrap());
+    assert!(options.parse_option("mmap_size=1048576").unwrap());
+    assert!(!options.parse_option("allow_other").unwrap());
+    assert!(!options.parse_option("fsname=test").unwrap());
+    assert!(options.parse_option("journal_mode=none").is_err());
+    assert!(options.parse_option("busy_timeout=-1").is_err());
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("filesystem.db");
+    let mut db = sqlite::Sqlite::new_with_options(&path, &options).unwrap();
+    db.init().unwrap();
+    // journal mode is stored in the database file
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    let mode: String = conn.query_row("PRAGMA journal_mode", rusqlite::NO_PARAMS, |row| row.get(0)).unwrap();
+    assert_eq!(mode, "wal");
+    drop(conn);
+    db.checkpoint().unwrap();
+    drop(db);
+
+    options.parse_option("journal_mode=delete").unwrap();
+    let mut db = sqlite::Sqlite::new_with_options(&path, &options).unwrap();
+    db.init().unwrap();
+    // no-op without WAL
+    db.checkpoint().unwrap();
+    drop(db);
+    // Sqlite::new keeps the journal mode
+    let mut db = sqlite::Sqlite::new(&path).unwrap();
+    db.init().unwrap();
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    let mode: String = conn.query_row("PRAGMA journal_mode", rusqlite::NO_PARAMS, |row| row.get(0)).unwrap();
+    assert_eq!(mode, "delete");
+}
+
+#[test]
+fn sqlite_statfs() {
+    let mut db = sqlite::Sqlite::new_in_memory().unwrap();
+    db.init().unwrap();
+    let stat = db.statfs().unwrap();
+    assert_eq!(stat.bsize, 4096);
+    assert!(stat.files >= 1);
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("filesystem.db");
+    let mut options = sqlite::SqliteOptions::default();
+    assert!(options.parse_option("max_size=1048576").unwrap());
+    let mut db = sqlite::Sqlite::new_with_options(&path, &options).unwrap();
+    db.init().unwrap();
+    let stat = db.statfs().unwrap();
+    assert!(stat.blocks <= 256);
+    assert!(stat.bfree > 0 && stat.bfree < stat.blocks);
+    let attr = helpers::file_attr(FileType::RegularFile, 0o644, 0, 0);
+    let ino = db.add_inode_and_dentry(1, "file", &attr).unwrap();
+    assert_eq!(db.statfs().unwrap().files, stat.files + 1);
+    db.write_file(ino, 0, &[1; 65536]).unwrap();
+    assert!(db.statfs().unwrap().bfree < stat.bfree);
+    // the database file can't grow over max_size
+    assert_eq!(db.write_file(ino, 65536, &vec![1; 2 * 1048576]).unwrap_err().errno(), ENOSPC);
+}
+
+#[test]
+fn sqlite_sync() {
+    let mut db = sqlite::Sqlite::new_in_memory().unwrap();
+    db.init().unwrap();
+    db.sync(false).unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    for option in &["synchronous=normal", "synchronous=off", "journal_mode=delete", "synchronous=full"] {
+        let path = dir.path().join("filesystem.db");
+        let mut options = sqlite::SqliteOptions::default();
+        options.parse_option(option).unwrap();
+        let mut db = sqlite::Sqlite::new_with_options(&path, &options).unwrap();
+        db.init().unwrap();
+        db.set_xattr(1, "user.option", option.as_bytes()).unwrap();
+        db.sync(false).unwrap();
+        db.sync(true).unwrap();
+        // committed data is readable by another connection
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        assert_eq!(conn.query_row("SELECT value FROM xattr WHERE name='user.option'", rusqlite::NO_PARAMS, |row| row.get::<_, Vec<u8>>(0)).unwrap(), option.as_bytes());
+    }
+}
+
+#[test]
+fn sqlite_compression() {
+    let dir = tempfile::tempdir().unwrap();
+    let attr = helpers::file_attr(FileType::RegularFile, 0o644, 0, 0);
+    let text: Vec<u8> = b"{\"level\": \"info\", \"message\": \"hello\"}\n".iter().cycle().take(10000).cloned().collect();
+    // bytes which don't get smaller
+    let noise: Vec<u8> = (0..4096u32).map(|n| (n.wrapping_mul(2654435761) >> 13) as u8).collect();
+    for (i, codec) in [Codec::Lz4, Codec::Zstd].iter().enumerate() {
+        let path = dir.path().join(format!("filesystem{}.db", i));
+        let mut db = sqlite::Sqlite::new(&path).unwrap();
+        db.set_compression(*codec);
+        db.init().unwrap();
+        assert_eq!(db.get_compression(), *codec);
+        let ino = db.add_inode_and_dentry(1, "file", &attr).unwrap();
+        db.write_file(ino, 0, &text).unwrap();
+        db.write_file(ino, 12288, &noise).unwrap();
+        assert_eq!(db.read_file(ino, 0, 10000, true).unwrap(), text);
+        assert_eq!(db.read_file(ino, 12288, 4096, true).unwrap(), noise);
+        // truncate a compressed block
+        let mut attr = db.get_inode(ino).unwrap().unwrap();
+        attr.size = 5000;
+        db.update_inode(&attr, true).unwrap();
+        assert_eq!(db.read_file(ino, 0, 5000, true).unwrap(), &text[..5000]);
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        let (stored, tag): (i64, u32) = conn.query_row(
+            "SELECT length(data), codec FROM data WHERE block_num=1", rusqlite::NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?))
+        ).unwrap();
+        assert!(stored < 1000);
+        assert_eq!(tag, i as u32 + 1);
+        let tag: u32 = conn.query_row("SELECT codec FROM data WHERE block_num=4", rusqlite::NO_PARAMS, |row| row.get(0)).unwrap_or(0);
+        assert_eq!(tag, 0);
+        drop(db);
+
+        // compression is decided at creation
+        let mut db = sqlite::Sqlite::new(&path).unwrap();
+        db.set_compression(Codec::None);
+        assert!(db.init().is_err());
+        let mut db = sqlite::Sqlite::new(&path).unwrap();
+        db.init().unwrap();
+        assert_eq!(db.get_compression(), *codec);
+    }
+    let mut db = sqlite::Sqlite::new_in_memory().unwrap();
+    db.init().unwrap();
+    assert_eq!(db.get_compression(), Codec::None);
+    assert!(Codec::from_name("gzip").is_err());
+}
+
+#[test]
+fn sqlite_dedup() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("filesystem.db");
+    let attr = helpers::file_attr(FileType::RegularFile, 0o644, 0, 0);
+    let mut db = sqlite::Sqlite::new(&path).unwrap();
+    db.set_dedup(true);
+    db.set_compression(Codec::Lz4);
+    db.init().unwrap();
+    assert!(db.get_dedup());
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    let refcounts = |conn: &rusqlite::Connection| -> Vec<u32> {
+        let mut stmt = conn.prepare("SELECT refcount FROM block ORDER BY refcount").unwrap();
+        let rows = stmt.query_map(rusqlite::NO_PARAMS, |row| row.get(0)).unwrap();
+        rows.map(|n| n.unwrap()).collect()
+    };
+    let same = vec![0xabu8; 8192];
+    let other = vec![0xcdu8; 4096];
+    let file1 = db.add_inode_and_dentry(1, "file1", &attr).unwrap();
+    let file2 = db.add_inode_and_dentry(1, "file2", &attr).unwrap();
+    db.write_file(file1, 0, &same).unwrap();
+    db.write_file(file2, 0, &same).unwrap();
+    db.write_file(file2, 8192, &other).unwrap();
+    // the 4 blocks of the same content are stored once
+    assert_eq!(refcounts(&conn), vec![1, 4]);
+    assert_eq!(db.read_file(file1, 0, 8192, true).unwrap(), same);
+    assert_eq!(db.read_file(file2, 4096, 8192, true).unwrap(), [&same[..4096], &other[..]].concat());
+
+    // overwrite a shared block
+    db.write_file(file1, 4096, &other).unwrap();
+    assert_eq!(refcounts(&conn), vec![2, 3]);
+    assert_eq!(db.read_file(file2, 0, 8192, true).unwrap(), same);
+
+    // truncate in the middle of a shared block
+    let mut attr2 = db.get_inode(file2).unwrap().unwrap();
+    attr2.size = 6000;
+    db.update_inode(&attr2, true).unwrap();
+    assert_eq!(db.read_file(file2, 0, 6000, true).unwrap(), &same[..6000]);
+    assert_eq!(refcounts(&conn), vec![1, 1, 2]);
+    assert_eq!(db.read_file(file1, 0, 8192, true).unwrap(), [&same[..4096], &other[..]].concat());
+
+    // blocks are freed with the last file which refers to them
+    db.delete_dentry(1, "file1").unwrap();
+    db.delete_inode_if_noref(file1).unwrap();
+    assert_eq!(refcounts(&conn), vec![1, 1]);
+    db.delete_dentry(1, "file2").unwrap();
+    db.delete_inode_if_noref(file2).unwrap();
+    assert!(refcounts(&conn).is_empty());
+    assert!(db.fsck(false).unwrap().is_empty());
+
+    // broken reference counts are repaired
+    let file3 = db.add_inode_and_dentry(1, "file3", &attr).unwrap();
+    db.write_file(file3, 0, &same).unwrap();
+    conn.execute("UPDATE block SET refcount=5", rusqlite::NO_PARAMS).unwrap();
+    conn.execute("INSERT INTO block (hash, data, refcount) VALUES(x'00', x'00', 1)", rusqlite::NO_PARAMS).unwrap();
+    assert_eq!(db.fsck(false).unwrap().len(), 1);
+    db.fsck(true).unwrap();
+    assert!(db.fsck(false).unwrap().is_empty());
+    assert_eq!(refcounts(&conn), vec![2]);
+    assert_eq!(db.read_file(file3, 0, 8192, true).unwrap(), same);
+    drop(db);
+
+    // dedup mode is decided at creation
+    let mut db = sqlite::Sqlite::new(&path).unwrap();
+    db.set_dedup(false);
+    assert!(db.init().is_err());
+    let mut db = sqlite::Sqlite::new_in_memory().unwrap();
+    db.init().unwrap();
+    assert!(!db.get_dedup());
+}
+
+#[test]
+fn sqlite_quota() {
+    let mut db = sqlite::Sqlite::new_in_memory().unwrap();
+    db.init().unwrap();
+    let mut attr = helpers::file_attr(FileType::Directory, 0o755, 1000, 100);
+    let dir_ino = db.add_inode_and_dentry(1, "dir", &attr).unwrap();
+    attr.kind = FileType::RegularFile;
+    let file_ino = db.add_inode_and_dentry(dir_ino, "file", &attr).unwrap();
+    db.write_file(file_ino, 0, &[1; 5000]).unwrap();
+    // usage is counted when a quota is set
+    db.set_quota(QuotaKind::User, 1000, 3 * 4096, 3).unwrap();
+    let quota = db.list_quota().unwrap()[0];
+    // whole blocks are stored
+    assert_eq!((quota.bytes_used, quota.inodes_used), (2 * 4096, 2));
+
+    assert_eq!(db.write_file(file_ino, 5000, &[1; 8000]).unwrap_err().errno(), EDQUOT);
+    db.write_file(file_ino, 5000, &[1; 5000]).unwrap();
+    assert_eq!(db.check_quota(file_ino, 1, 0).unwrap_err().errno(), EDQUOT);
+    let file2_ino = db.add_inode_and_dentry(dir_ino, "file2", &attr).unwrap();
+    assert_eq!(db.add_inode_and_dentry(dir_ino, "file3", &attr).unwrap_err().errno(), EDQUOT);
+    attr.ino = file_ino;
+    attr.size = 4096;
+    db.update_inode(&attr, true).unwrap();
+    assert_eq!(db.list_quota().unwrap()[0].bytes_used, 4096);
+    // chown moves usage to the new owner
+    attr.uid = 1001;
+    db.update_inode(&attr, false).unwrap();
+    assert_eq!(db.list_quota().unwrap()[0].bytes_used, 0);
+    db.delete_dentry(dir_ino, "file2").unwrap();
+    db.delete_inode_if_noref(file2_ino).unwrap();
+    assert_eq!(db.list_quota().unwrap()[0].inodes_used, 1);
+
+    // new files inherit the project of the parent directory
+    db.set_project(dir_ino, 10).unwrap();
+    db.set_quota(QuotaKind::Project, 10, 0, 3).unwrap();
+    let quota = db.list_quota().unwrap()[1];
+    assert_eq!((quota.kind, quota.bytes_used, quota.inodes_used), (QuotaKind::Project, 4096, 2));
+    attr.uid = 1002;
+    attr.kind = FileType::Directory;
+    let sub_ino = db.add_inode_and_dentry(dir_ino, "sub", &attr).unwrap();
+    attr.kind = FileType::RegularFile;
+    assert_eq!(db.add_inode_and_dentry(sub_ino, "file", &attr).unwrap_err().errno(), EDQUOT);
+    // a file of another project can't be linked into the tree
+    let other_ino = db.add_inode_and_dentry(1, "other", &attr).unwrap();
+    assert_eq!(db.link_dentry(other_ino, sub_ino, "link").unwrap_err().errno(), EXDEV);
+    // mv copies it instead
+    assert_eq!(db.move_dentry(1, "other", sub_ino, "other").unwrap_err().errno(), EXDEV);
+    db.move_dentry(1, "other", 1, "renamed").unwrap();
+    db.link_dentry(file_ino, 1, "link").unwrap();
+    assert!(db.fsck(false).unwrap().is_empty());
+
+    db.set_quota(QuotaKind::User, 1000, 0, 0).unwrap();
+    assert_eq!(db.list_quota().unwrap().len(), 1);
+}
+
+#[test]
+fn sqlite_fsck() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("filesystem.db");
+    let mut db = sqlite::Sqlite::new(&path).unwrap();
+    db.init().unwrap();
+    assert!(db.fsck(false).unwrap().is_empty());
+    let mut attr = helpers::file_attr(FileType::Directory, 0o755, 0, 0);
+    let dir_ino = db.add_inode_and_dentry(1, "dir", &attr).unwrap();
+    attr.kind = FileType::RegularFile;
+    let file_ino = db.add_inode_and_dentry(dir_ino, "file", &attr).unwrap();
+    db.write_data(file_ino, 1, &[1; 10], 10).unwrap();
+    {
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch(&format!("
+            DELETE FROM dentry WHERE parent_id=1 AND name='dir';
+            DELETE FROM dentry WHERE parent_id={dir} AND name='.';
+            UPDATE dentry SET file_type=16384 WHERE child_id={file};
+            INSERT INTO data (file_id, block_num, data) VALUES({file}, 5, x'00');
+        ", dir = dir_ino, file = file_ino)).unwrap();
+    }
+    let issues = db.fsck(false).unwrap();
+    assert_eq!(issues.len(), 4);
+    assert!(issues.iter().all(|n| !n.repaired));
+    // check only doesn't change the database
+    assert_eq!(db.fsck(false).unwrap(), issues);
+    let issues = db.fsck(true).unwrap();
+    assert_eq!(issues.len(), 4);
+    assert!(issues.iter().all(|n| n.repaired));
+    assert!(db.fsck(false).unwrap().is_empty());
+    let lost_found = db.lookup(1, "lost+found").unwrap().unwrap();
+    let dir = db.lookup(lost_found.ino, &format!("#{}", dir_ino)).unwrap().unwrap();
+    assert_eq!(dir.ino, dir_ino);
+    assert_eq!(db.lookup(dir_ino, "file").unwrap().unwrap().kind, FileType::RegularFile);
+    assert_eq!(db.lookup(dir_ino, "..").unwrap().unwrap().ino, lost_found.ino);
+    assert_eq!(db.get_inode(file_ino).unwrap().unwrap().blocks, 8);
+}
+
+#[test]
+fn sqlite_copy_file_range() {
+    let mut db = sqlite::Sqlite::new_in_memory().unwrap();
+    db.init().unwrap();
+    let attr = helpers::file_attr(FileType::RegularFile, 0o644, 0, 0);
+    let data: Vec<u8> = (0..20000).map(|n| (n % 251 + 1) as u8).collect();
+    let src = db.add_inode_and_dentry(1, "src", &attr).unwrap();
+    let dst = db.add_inode_and_dentry(1, "dst", &attr).unwrap();
+    db.write_file(src, 0, &data).unwrap();
+    // aligned blocks and the unaligned tail
+    assert_eq!(db.copy_file_range(src, 4096, dst, 8192, 100000).unwrap(), 15904);
+    assert_eq!(db.get_inode(dst).unwrap().unwrap().size, 24096);
+    assert_eq!(db.read_file(dst, 8192, 15904, true).unwrap(), &data[4096..]);
+    assert_eq!(db.read_file(dst, 0, 8192, true).unwrap(), vec![0; 8192]);
+    // unaligned offsets
+    assert_eq!(db.copy_file_range(src, 10, dst, 5000, 9000).unwrap(), 9000);
+    assert_eq!(db.read_file(dst, 5000, 9000, true).unwrap(), &data[10..9010]);
+    assert_eq!(db.read_file(dst, 14000, 10096, true).unwrap(), &data[9904..]);
+    // beyond the end of the source
+    assert_eq!(db.copy_file_range(src, 30000, dst, 0, 10).unwrap(), 0);
+    // overlapping ranges in the same file
+    assert!(db.copy_file_range(src, 0, src, 4096, 8192).is_err());
+    db.copy_file_range(src, 0, src, 20480, 4096).unwrap();
+    assert_eq!(db.read_file(src, 20480, 4096, true).unwrap(), &data[..4096]);
+    assert!(db.copy_file_range(1, 0, dst, 0, 10).is_err());
+
+    let clone = db.add_inode_and_dentry(1, "clone", &attr).unwrap();
+    db.write_file(clone, 0, b"old").unwrap();
+    db.clone_file(dst, clone).unwrap();
+    assert_eq!(db.get_inode(clone).unwrap().unwrap().size, 24096);
+    assert_eq!(db.read_file(clone, 0, 24096, true).unwrap(), db.read_file(dst, 0, 24096, true).unwrap());
+    assert!(db.clone_file(clone, clone).is_err());
+    assert!(db.fsck(false).unwrap().is_empty());
+}
+
+#[test]
+fn sqlite_snapshot() {
+    let mut db = sqlite::Sqlite::new_in_memory().unwrap();
+    db.init().unwrap();
+    let attr = helpers::file_attr(FileType::RegularFile, 0o644, 0, 0);
+    let dir_attr = DBFileAttr {kind: FileType::Directory, perm: 0o755, ..attr};
+    let data: Vec<u8> = (0..10000).map(|n| (n % 251 + 1) as u8).collect();
+    let dir = db.add_inode_and_dentry(1, "dir", &dir_attr).unwrap();
+    let file = db.add_inode_and_dentry(dir, "file", &attr).unwrap();
+    db.write_file(file, 0, &data).unwrap();
+    db.set_xattr(file, "user.key", b"value").unwrap();
+    db.set_quota(QuotaKind::User, 0, 0, 100).unwrap();
+    db.create_snapshot("first").unwrap();
+    assert_eq!(db.create_snapshot("first").unwrap_err().errno(), EEXIST);
+    assert!(db.create_snapshot("a/b").is_err());
+    // the snapshot keeps old data
+    db.write_file(file, 0, b"new").unwrap();
+    db.add_inode_and_dentry(1, "after", &attr).unwrap();
+    let snapshots = db.list_snapshot().unwrap();
+    assert_eq!(snapshots.len(), 1);
+    assert_eq!(snapshots[0].name, "first");
+
+    let snapshot_dir = db.lookup(1, ".snapshots").unwrap().unwrap();
+    assert_eq!(snapshot_dir.ino, SNAPSHOT_DIR_INO);
+    assert_eq!(snapshot_dir.kind, FileType::Directory);
+    let names: Vec<String> = db.get_dentry(SNAPSHOT_DIR_INO).unwrap().into_iter().map(|n| n.filename).collect();
+    assert_eq!(names, vec![".", "..", "first"]);
+    let root = db.lookup(SNAPSHOT_DIR_INO, "first").unwrap().unwrap();
+    assert_eq!(db.lookup(root.ino, "..").unwrap().unwrap().ino, SNAPSHOT_DIR_INO);
+    assert!(db.lookup(root.ino, "after").unwrap().is_none());
+    let old_dir = db.lookup(root.ino, "dir").unwrap().unwrap();
+    let old_file = db.lookup(old_dir.ino, "file").unwrap().unwrap();
+    assert_eq!(old_file.size, 10000);
+    assert_eq!(old_file.nlink, 1);
+    assert_eq!(db.read_file(old_file.ino, 0, 10000, true).unwrap(), data);
+    assert_eq!(db.get_xattr(old_file.ino, "user.key").unwrap(), b"value");
+    assert_eq!(db.list_xattr(old_file.ino).unwrap(), vec!["user.key"]);
+    assert_eq!(db.seek_hole(old_file.ino, 0).unwrap(), 10000);
+    // files in snapshots are read-only
+    assert_eq!(db.write_file(old_file.ino, 0, b"x").unwrap_err().errno(), EROFS);
+    assert_eq!(db.add_inode_and_dentry(old_dir.ino, "x", &attr).unwrap_err().errno(), EROFS);
+    assert_eq!(db.delete_dentry(old_dir.ino, "file").unwrap_err().errno(), EROFS);
+    assert_eq!(db.set_xattr(old_file.ino, "user.key", b"x").unwrap_err().errno(), EROFS);
+    assert_eq!(db.add_inode_and_dentry(1, ".snapshots", &attr).unwrap_err().errno(), EEXIST);
+    // a file is restored by cloning
+    let restored = db.add_inode_and_dentry(1, "restored", &attr).unwrap();
+    db.clone_file(old_file.ino, restored).unwrap();
+    assert_eq!(db.read_file(restored, 0, 10000, true).unwrap(), data);
+    assert!(db.fsck(false).unwrap().is_empty());
+
+    db.rollback_snapshot("first").unwrap();
+    assert!(db.lookup(1, "after").unwrap().is_none());
+    assert!(db.lookup(1, "restored").unwrap().is_none());
+    assert_eq!(db.read_file(file, 0, 10000, true).unwrap(), data);
+    assert_eq!(db.list_quota().unwrap()[0].inodes_used, 3);
+    assert!(db.fsck(false).unwrap().is_empty());
+    db.delete_snapshot("first").unwrap();
+    assert_eq!(db.delete_snapshot("first").unwrap_err().errno(), ENOENT);
+    assert!(db.lookup(SNAPSHOT_DIR_INO, "first").unwrap().is_none());
+    assert_eq!(db.read_file(file, 0, 10000, true).unwrap(), data);
+    assert!(db.fsck(false).unwrap().is_empty());
+}
+
+#[test]
+fn sqlite_snapshot_rollback_busy() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("filesystem.sqlite");
+    let mut db = sqlite::Sqlite::new(&path).unwrap();
+    db.init().unwrap();
+    db.create_snapshot("first").unwrap();
+    // another connection like a mounted filesystem
+    let mut mounted = sqlite::Sqlite::new(&path).unwrap();
+    mounted.init().unwrap();
+    assert_eq!(db.rollback_snapshot("first").unwrap_err().errno(), EBUSY);
+    drop(mounted);
+    db.rollback_snapshot("first").unwrap();
+}
+
+#[test]
+fn sqlite_snapshot_shares_rows() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("filesystem.sqlite");
+    let mut db = sqlite::Sqlite::new(&path).unwrap();
+    db.init().unwrap();
+    let attr = helpers::file_attr(FileType::RegularFile, 0o644, 0, 0);
+    let data: Vec<u8> = (0..16384).map(|n| (n % 251 + 1) as u8).collect();
+    let file = db.add_inode_and_dentry(1, "file", &attr).unwrap();
+    let removed = db.add_inode_and_dentry(1, "removed", &attr).unwrap();
+    db.write_file(file, 0, &data).unwrap();
+    db.write_file(removed, 0, &data).unwrap();
+    db.create_snapshot("first").unwrap();
+    // taking a snapshot doesn't copy data rows
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    let count = |sql: &str| -> i64 { conn.query_row(sql, rusqlite::NO_PARAMS, |row| row.get(0)).unwrap() };
+    assert_eq!(count("SELECT count(*) FROM snapshot_data"), 0);
+    assert_eq!(count("SELECT count(*) FROM block"), 0);
+
+    // a changed block is moved to the snapshot
+    db.write_file(file, 0, b"new").unwrap();
+    assert_eq!(count("SELECT count(*) FROM snapshot_data"), 1);
+    db.create_snapshot("second").unwrap();
+    db.write_file(file, 4096, b"newer").unwrap();
+    db.fallocate(file, 8192, 4096, FALLOC_FL_COLLAPSE_RANGE as u32).unwrap();
+    db.delete_dentry(1, "removed").unwrap();
+    db.delete_inode_if_noref(removed).unwrap();
+
+    let read = |db: &mut sqlite::Sqlite, snapshot: &str, name: &str| {
+        let root = db.lookup(SNAPSHOT_DIR_INO, snapshot).unwrap().unwrap();
+        let attr = db.lookup(root.ino, name).unwrap().unwrap();
+        db.read_file(attr.ino, 0, attr.size as u32, true).unwrap()
+    };
+    assert_eq!(read(&mut db, "first", "file"), data);
+    assert_eq!(read(&mut db, "first", "removed"), data);
+    let mut second = data.clone();
+    second[..3].copy_from_slice(b"new");
+    assert_eq!(read(&mut db, "second", "file"), second);
+    assert_eq!(read(&mut db, "second", "removed"), data);
+    assert_eq!(db.read_file(file, 4096, 5, true).unwrap(), b"newer");
+    assert!(db.fsck(false).unwrap().is_empty());
+
+    db.rollback_snapshot("second").unwrap();
+    assert_eq!(db.read_file(file, 0, 16384, true).unwrap(), second);
+    assert_eq!(read(&mut db, "first", "file"), data);
+    db.delete_snapshot("first").unwrap();
+    db.delete_snapshot("second").unwrap();
+    assert!(db.fsck(false).unwrap().is_empty());
+}
+
+#[test]
+fn sqlite_snapshot_shares_metadata() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("filesystem.sqlite");
+    let mut db = sqlite::Sqlite::new(&path).unwrap();
+    db.init().unwrap();
+    let attr = helpers::file_attr(FileType::RegularFile, 0o644, 0, 0);
+    let dir_attr = DBFileAttr {kind: FileType::Directory, perm: 0o755, ..attr};
+    let sub = db.add_inode_and_dentry(1, "dir", &dir_attr).unwrap();
+    let file = db.add_inode_and_dentry(sub, "file", &attr).unwrap();
+    let removed = db.add_inode_and_dentry(1, "removed", &attr).unwrap();
+    db.set_xattr(file, "user.key", b"value").unwrap();
+    db.create_snapshot("first").unwrap();
+    // taking a snapshot doesn't copy metadata, entries or xattrs
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    let count = |sql: &str| -> i64 { conn.query_row(sql, rusqlite::NO_PARAMS, |row| row.get(0)).unwrap() };
+    for table in &["snapshot_metadata", "snapshot_dentry", "snapshot_xattr"] {
+        assert_eq!(count(&format!("SELECT count(*) FROM {}", table)), 0);
+    }
+
+    // changed rows are copied to the snapshot
+    let mut changed = db.get_inode(file).unwrap().unwrap();
+    changed.perm = 0o600;
+    db.update_inode(&changed, false).unwrap();
+    assert_eq!(count("SELECT count(*) FROM snapshot_metadata"), 1);
+    db.set_xattr(file, "user.key", b"new").unwrap();
+    db.set_xattr(file, "user.other", b"other").unwrap();
+    db.move_dentry(sub, "file", 1, "moved").unwrap();
+    db.delete_dentry(1, "removed").unwrap();
+    db.delete_inode_if_noref(removed).unwrap();
+
+    let root = db.lookup(SNAPSHOT_DIR_INO, "first").unwrap().unwrap();
+    let old_dir = db.lookup(root.ino, "dir").unwrap().unwrap();
+    let old_file = db.lookup(old_dir.ino, "file").unwrap().unwrap();
+    assert_eq!(old_file.perm, 0o644);
+    assert!(db.lookup(root.ino, "moved").unwrap().is_none());
+    assert!(db.lookup(root.ino, "removed").unwrap().is_some());
+    assert_eq!(db.get_xattr(old_file.ino, "user.key").unwrap(), b"value");
+    assert_eq!(db.list_xattr(old_file.ino).unwrap(), vec!["user.key"]);
+    assert_eq!(db.get_inode(file).unwrap().unwrap().perm, 0o600);
+    assert!(db.fsck(false).unwrap().is_empty());
+
+    db.rollback_snapshot("first").unwrap();
+    assert_eq!(db.lookup(sub, "file").unwrap().unwrap().perm, 0o644);
+    assert!(db.lookup(1, "moved").unwrap().is_none());
+    assert_eq!(db.lookup(1, "removed").unwrap().unwrap().ino, removed);
+    assert_eq!(db.list_xattr(file).unwrap(), vec!["user.key"]);
+    assert!(db.fsck(false).unwrap().is_empty());
+    db.delete_snapshot("first").unwrap();
+    for table in &["snapshot_metadata", "snapshot_dentry", "snapshot_xattr"] {
+        assert_eq!(count(&format!("SELECT count(*) FROM {}", table)), 0);
+    }
+}
+
+#[test]
+fn sqlite_fsck_reserved_name() {
+    let mut dbf = helpers::DBWithTempFile::new();
+    dbf.db.init().unwrap();
+    let attr = helpers::file_attr(FileType::RegularFile, 0o644, 0, 0);
+    let file = dbf.db.add_inode_and_dentry(1, "file", &attr).unwrap();
+    dbf.db.add_inode_and_dentry(1, ".snapshots.1", &attr).unwrap();
+    {
+        let conn = rusqlite::Connection::open(dbf.path()).unwrap();
+        conn.execute("UPDATE dentry SET name='.snapshots' WHERE name='file'", rusqlite::NO_PARAMS).unwrap();
+    }
+    let issues = dbf.db.fsck(false).unwrap();
+    assert_eq!(issues.len(), 1);
+    assert_eq!(issues[0].ino, file);
+    assert!(dbf.db.lookup(1, ".snapshots.2").unwrap().is_none());
+    dbf.db.fsck(true).unwrap();
+    assert_eq!(dbf.db.lookup(1, ".snapshots.2").unwrap().unwrap().ino, file);
+    assert!(dbf.db.fsck(false).unwrap().is_empty());
+}
@@ -0,0 +1,176 @@
+use fuser::FileType;
+use libc::{EINVAL, EISDIR, ENOENT, ENOTDIR, ENOTEMPTY, EROFS};
+use sqlite_fs::db_module::DbModule;
+use sqlite_fs::db_module::sqlite::Sqlite;
+use sqlite_fs::vfs::Vfs;
+
+#[test]
+fn vfs_file() {
+    let mut vfs = Vfs::new_in_memory().unwrap();
+    vfs.create_dir_all("/a/b/c", 0o755).unwrap();
+    // already exists
+    vfs.create_dir_all("/a/b", 0o755).unwrap();
+    vfs.write("/a/b/c/file", b"hello").unwrap();
+    assert_eq!(vfs.read("/a/b/c/file").unwrap(), b"hello".to_vec());
+    assert_eq!(vfs.read("a/./b/../b/c/file").unwrap(), b"hello".to_vec());
+    let data: Vec<u8> = (0..10000).map(|n| n as u8).collect();
+    vfs.write_at("/a/b/c/file", 3, &data).unwrap();
+    assert_eq!(vfs.metadata("/a/b/c/file").unwrap().size, 10003);
+    assert_eq!(vfs.read_at("/a/b/c/file", 3, 20000).unwrap(), data);
+    assert_eq!(vfs.read_at("/a/b/c/file", 0, 5).unwrap(), b"hel\x00\x01".to_vec());
+    vfs.set_len("/a/b/c/file", 2).unwrap();
+    assert_eq!(vfs.read("/a/b/c/file").unwrap(), b"he".to_vec());
+    // write truncates the file
+    vfs.write("/a/b/c/file", b"x").unwrap();
+    assert_eq!(vfs.read("/a/b/c/file").unwrap(), b"x".to_vec());
+
+    let entries = vfs.read_dir("/a/b/c").unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].filename, "file");
+    assert_eq!(entries[0].file_type, FileType::RegularFile);
+
+    assert_eq!(vfs.read("/a/b").unwrap_err().errno(), EISDIR);
+    assert_eq!(vfs.read("/a/b/c/file/x").unwrap_err().errno(), ENOTDIR);
+    assert_eq!(vfs.read("/a/none").unwrap_err().errno(), ENOENT);
+    assert_eq!(vfs.write("/none/file", b"").unwrap_err().errno(), ENOENT);
+    // a symlink is not followed, and a special file has no data
+    vfs.symlink("a/b/c/file", "/symlink").unwrap();
+    vfs.mknod("/fifo", FileType::NamedPipe, 0o644, 0).unwrap();
+    assert_eq!(vfs.read("/symlink").unwrap_err().errno(), EINVAL);
+    assert_eq!(vfs.write_at("/symlink", 0, b"x").unwrap_err().errno(), EINVAL);
+    assert_eq!(vfs.set_len("/fifo", 10).unwrap_err().errno(), EINVAL);
+    assert!(vfs.open("/fifo").is_err());
+    assert_eq!(vfs.read_link("/symlink").unwrap(), "a/b/c/file");
+}
+
+#[test]
+fn vfs_link_rename_remove() {
+    let mut vfs = Vfs::new_in_memory().unwrap();
+    vfs.create_dir("/dir", 0o755).unwrap();
+    vfs.write("/dir/file", b"data").unwrap();
+    vfs.symlink("dir/file", "/symlink").unwrap();
+    assert_eq!(vfs.read_link("/symlink").unwrap(), "dir/file");
+    assert_eq!(vfs.metadata("/symlink").unwrap().kind, FileType::Symlink);
+    let attr = vfs.hard_link("/dir/file", "/link").unwrap();
+    assert_eq!(attr.nlink, 2);
+
+    vfs.rename("/link", "/renamed").unwrap();
+    assert!(!vfs.exists("/link").unwrap());
+    assert_eq!(vfs.read("/renamed").unwrap(), b"data".to_vec());
+    // replace an existing file
+    vfs.write("/other", b"other").unwrap();
+    vfs.rename("/other", "/renamed").unwrap();
+    assert_eq!(vfs.read("/renamed").unwrap(), b"other".to_vec());
+    assert_eq!(vfs.metadata("/dir/file").unwrap().nlink, 1);
+    // a directory can't be moved into itself
+    vfs.create_dir("/dir/sub", 0o755).unwrap();
+    assert_eq!(vfs.rename("/dir", "/dir/sub/dir").unwrap_err().errno(), EINVAL);
+
+    assert_eq!(vfs.remove_dir("/dir").unwrap_err().errno(), ENOTEMPTY);
+    assert_eq!(vfs.remove_file("/dir").unwrap_err().errno(), EISDIR);
+    vfs.remove_dir("/dir/sub").unwrap();
+    vfs.remove_file("/dir/file").unwrap();
+    vfs.remove_dir("/dir").unwrap();
+    vfs.remove_file("/symlink").unwrap();
+    let entries = vfs.read_dir("/").unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].filename, "renamed");
+}
+
+#[test]
+fn vfs_mknod() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("filesystem.db");
+    let mut vfs = Vfs::new(&path).unwrap();
+    let dev = vfs.create_dir("/dev", 0o755).unwrap();
+    vfs.set_permissions("/dev", 0o2755).unwrap();
+    // the directory belongs to another group than this process
+    let gid = dev.gid.wrapping_add(1);
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    conn.execute("UPDATE metadata SET gid=$1 WHERE id=$2", rusqlite::params![gid, dev.ino as i64]).unwrap();
+    vfs.mknod("/dev/null", FileType::CharDevice, 0o666, 0x0103).unwrap();
+    let attr = vfs.metadata("/dev/null").unwrap();
+    assert_eq!(attr.gid, gid);
+    assert_eq!(attr.kind, FileType::CharDevice);
+    assert_eq!(attr.rdev, 0x0103);
+    assert_eq!(attr.perm, 0o666);
+    vfs.mknod("/dev/fifo", FileType::NamedPipe, 0o644, 0).unwrap();
+    vfs.mknod("/dev/sda", FileType::BlockDevice, 0o660, 0x0800).unwrap();
+    assert!(vfs.mknod("/dev/dir", FileType::Directory, 0o755, 0).is_err());
+
+    // special files can be renamed and replace each other
+    vfs.rename("/dev/fifo", "/dev/pipe").unwrap();
+    vfs.rename("/dev/pipe", "/dev/sda").unwrap();
+    assert_eq!(vfs.metadata("/dev/sda").unwrap().kind, FileType::NamedPipe);
+    vfs.write("/file", b"data").unwrap();
+    vfs.rename("/file", "/dev/null").unwrap();
+    assert_eq!(vfs.metadata("/dev/null").unwrap().kind, FileType::RegularFile);
+    assert_eq!(vfs.rename("/dev/sda", "/dev").unwrap_err().errno(), EISDIR);
+    vfs.create_dir("/dir", 0o755).unwrap();
+    assert_eq!(vfs.rename("/dir", "/dev/sda").unwrap_err().errno(), ENOTDIR);
+}
+
+#[test]
+fn vfs_copy() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("filesystem.db");
+    let mut vfs = Vfs::new(&path).unwrap();
+    let data: Vec<u8> = (0..20000).map(|n| (n % 251 + 1) as u8).collect();
+    vfs.create_dir_all("/src/sub", 0o750).unwrap();
+    vfs.write("/src/sub/file", &data).unwrap();
+    vfs.symlink("sub/file", "/src/link").unwrap();
+    vfs.mknod("/src/fifo", FileType::NamedPipe, 0o600, 0).unwrap();
+
+    assert_eq!(vfs.copy("/src/sub/file", "/copy").unwrap(), 20000);
+    assert_eq!(vfs.read("/copy").unwrap(), data);
+    assert_eq!(vfs.copy("/src", "/copy2").unwrap_err().errno(), EISDIR);
+    // the source is not truncated
+    vfs.hard_link("/copy", "/copy_link").unwrap();
+    assert_eq!(vfs.copy("/copy", "/copy").unwrap_err().errno(), EINVAL);
+    assert_eq!(vfs.copy("/copy", "/copy_link").unwrap_err().errno(), EINVAL);
+    assert_eq!(vfs.reflink("/copy", "/copy_link").unwrap_err().errno(), EINVAL);
+    assert_eq!(vfs.copy_all("/copy", "/copy_link", true).unwrap_err().errno(), EINVAL);
+    assert_eq!(vfs.read("/copy").unwrap(), data);
+
+    vfs.reflink("/src/sub/file", "/clone").unwrap();
+    assert_eq!(vfs.read("/clone").unwrap(), data);
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    let shared: u32 = conn.query_row("SELECT count(*) FROM block WHERE refcount=2", rusqlite::NO_PARAMS, |row| row.get(0)).unwrap();
+    assert_eq!(shared, 5);
+    // copy on write
+    vfs.write_at("/clone", 0, b"changed").unwrap();
+    assert_eq!(vfs.read("/src/sub/file").unwrap(), data);
+    assert_eq!(&vfs.read("/clone").unwrap()[..7], b"changed");
+    vfs.remove_file("/src/sub/file").unwrap();
+    assert_eq!(vfs.read("/clone").unwrap()[7..], data[7..]);
+    vfs.write("/src/sub/file", &data).unwrap();
+
+    assert_eq!(vfs.copy_all("/src", "/tree", true).unwrap(), 5);
+    assert_eq!(vfs.read("/tree/sub/file").unwrap(), data);
+    assert_eq!(vfs.metadata("/tree/sub").unwrap().perm, 0o750);
+    assert_eq!(vfs.read_link("/tree/link").unwrap(), "sub/file");
+    assert_eq!(vfs.metadata("/tree/fifo").unwrap().kind, FileType::NamedPipe);
+    assert_eq!(vfs.copy_all("/src", "/src/sub/tree", false).unwrap_err().errno(), EINVAL);
+}
+
+#[test]
+fn vfs_snapshot() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("filesystem.db");
+    let mut vfs = Vfs::new(&path).unwrap();
+    vfs.create_dir_all("/dir", 0o755).unwrap();
+    vfs.write("/dir/file", b"old").unwrap();
+    let mut db = Sqlite::new(&path).unwrap();
+    db.init().unwrap();
+    db.create_snapshot("first").unwrap();
+    vfs.write("/dir/file", b"new").unwrap();
+
+    assert_eq!(vfs.read("/.snapshots/first/dir/file").unwrap(), b"old");
+    assert_eq!(vfs.read("/dir/file").unwrap(), b"new");
+    // the directory of snapshots is not listed in the root directory
+    assert!(vfs.read_dir("/").unwrap().iter().all(|n| n.filename != ".snapshots"));
+    assert_eq!(vfs.write("/.snapshots/first/dir/file", b"x").unwrap_err().errno(), EROFS);
+    assert_eq!(vfs.create_dir("/.snapshots/second", 0o755).unwrap_err().errno(), EROFS);
+    vfs.copy("/.snapshots/first/dir/file", "/restored").unwrap();
+    assert_eq!(vfs.read("/restored").unwrap(), b"old");
+}